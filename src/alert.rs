@@ -0,0 +1,136 @@
+use std::time::{Duration, Instant};
+
+/// 一个监听目标的获取动态和发送消息两类故障, 分别计数和提醒
+#[derive(Debug)]
+pub struct TargetAlerts {
+    pub fetch: FailureAlert,
+    pub send: FailureAlert,
+}
+
+impl TargetAlerts {
+    pub fn new(threshold: u32, interval: Duration) -> TargetAlerts {
+        TargetAlerts {
+            fetch: FailureAlert::new(threshold, interval),
+            send: FailureAlert::new(threshold, interval),
+        }
+    }
+}
+
+/// 记录一个监听目标连续发送失败的次数, 决定何时提醒管理员
+#[derive(Debug)]
+pub struct FailureAlert {
+    /// 连续失败这么多次后提醒
+    threshold: u32,
+    /// 两次提醒之间的最小间隔
+    interval: Duration,
+    failures: u32,
+    /// 本次故障已经提醒过, 恢复时需要通知
+    alerted: bool,
+    last_alert: Option<Instant>,
+}
+
+impl FailureAlert {
+    pub fn new(threshold: u32, interval: Duration) -> FailureAlert {
+        FailureAlert {
+            threshold,
+            interval,
+            failures: 0,
+            alerted: false,
+            last_alert: None,
+        }
+    }
+
+    /// 目前连续失败的次数
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// 在 `now` 记录一次失败, 返回是否需要提醒管理员. 每次故障最多提醒一次,
+    /// 距离上次提醒不到 `interval` 时不提醒. 提醒发出后调用 [`FailureAlert::alerted`],
+    /// 提醒没有发出时下次失败会再次尝试
+    pub fn failure(&mut self, now: Instant) -> bool {
+        self.failures += 1;
+        if self.alerted || self.failures < self.threshold {
+            return false;
+        }
+        match self.last_alert {
+            Some(last_alert) => now.duration_since(last_alert) >= self.interval,
+            None => true,
+        }
+    }
+
+    /// 在 `now` 成功向管理员发出了本次故障的提醒
+    pub fn alerted(&mut self, now: Instant) {
+        self.alerted = true;
+        self.last_alert = Some(now);
+    }
+
+    /// 记录一次成功, 返回刚结束的故障是否提醒过管理员, 需要通知已恢复
+    pub fn success(&mut self) -> bool {
+        self.failures = 0;
+        std::mem::take(&mut self.alerted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_alert() {
+        let start = Instant::now();
+        let minutes = |m: u64| start + Duration::from_secs(m * 60);
+        let mut alert = FailureAlert::new(3, Duration::from_secs(3600));
+
+        // 连续失败3次时提醒一次, 之后不再重复
+        assert!(!alert.failure(minutes(0)));
+        assert!(!alert.failure(minutes(1)));
+        assert!(alert.failure(minutes(2)));
+        alert.alerted(minutes(2));
+        assert!(!alert.failure(minutes(3)));
+        assert_eq!(4, alert.failures());
+
+        // 恢复时通知并重新计数
+        assert!(alert.success());
+        assert_eq!(0, alert.failures());
+        assert!(!alert.success());
+
+        // 距离上次提醒不到一小时的故障不提醒, 也就没有恢复通知
+        for m in 10..13 {
+            assert!(!alert.failure(minutes(m)));
+        }
+        assert!(!alert.success());
+
+        // 故障持续到间隔之后仍会提醒
+        for m in 20..23 {
+            assert!(!alert.failure(minutes(m)));
+        }
+        assert!(alert.failure(minutes(70)));
+        alert.alerted(minutes(70));
+        assert!(alert.success());
+    }
+
+    #[test]
+    fn test_failure_alert_not_delivered() {
+        let now = Instant::now();
+        let mut alert = FailureAlert::new(2, Duration::from_secs(3600));
+
+        // 提醒没有发出时下次失败再次提醒, 恢复时也不通知
+        assert!(!alert.failure(now));
+        assert!(alert.failure(now));
+        assert!(alert.failure(now));
+        assert!(!alert.success());
+    }
+
+    #[test]
+    fn test_failure_alert_interrupted() {
+        let now = Instant::now();
+        let mut alert = FailureAlert::new(2, Duration::from_secs(3600));
+
+        // 中间成功一次时重新计数
+        assert!(!alert.failure(now));
+        assert!(!alert.success());
+        assert!(!alert.failure(now));
+        assert!(alert.failure(now));
+    }
+}
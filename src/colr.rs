@@ -0,0 +1,566 @@
+//! 绘制使用 COLR/CPAL 表(多个着色的轮廓图层)的彩色emoji字体
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ab_glyph_rasterizer::{point, Point, Rasterizer};
+use image::{Rgba, RgbaImage};
+use tracing::warn;
+use ttf_parser::{
+    colr::{ClipBox, CompositeMode, Paint, Painter},
+    Face, GlyphId, OutlineBuilder, RgbaColor, Transform,
+};
+
+/// 是否已经提示过不支持的合成模式, 只提示一次
+static UNSUPPORTED_MODE_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// 带有COLR表的字体. 只保存字体数据, 每次绘制时重新解析(解析只读取表目录, 开销很小)
+#[derive(Debug)]
+pub struct ColrFont {
+    data: Box<[u8]>,
+}
+
+impl ColrFont {
+    /// `data` 不是字体或者字体没有COLR表时为 `None`, 有COLR表时复制一份字体数据
+    pub fn from_slice(data: &[u8]) -> Option<ColrFont> {
+        let face = Face::parse(data, 0).ok()?;
+        face.tables().colr?;
+        Some(ColrFont { data: data.into() })
+    }
+
+    fn face(&self) -> Face<'_> {
+        Face::parse(&self.data, 0).expect("字体在创建时已经解析过")
+    }
+
+    /// 字体中 `c` 是否是彩色字形
+    pub fn has_glyph(&self, c: char) -> bool {
+        let face = self.face();
+        face.glyph_index(c)
+            .is_some_and(|glyph_id| face.is_color_glyph(glyph_id))
+    }
+
+    /// 把 `c` 的所有图层合成为高 `height` 像素的图片, 宽度由字形的advance决定.
+    /// 字体中没有 `c` 的彩色字形时为 `None`
+    pub fn render(&self, c: char, height: u32) -> Option<RgbaImage> {
+        let face = self.face();
+        let glyph_id = face
+            .glyph_index(c)
+            .filter(|&glyph_id| face.is_color_glyph(glyph_id))?;
+
+        let ascender = face.ascender() as f32;
+        let mut line_height = ascender - face.descender() as f32;
+        if line_height <= 0.0 {
+            line_height = face.units_per_em() as f32;
+        }
+        let scale = height as f32 / line_height;
+        let advance = face
+            .glyph_hor_advance(glyph_id)
+            .unwrap_or(face.units_per_em());
+        let width = ((advance as f32 * scale).ceil() as u32).max(1);
+
+        let mut painter = LayerPainter::new(
+            &face,
+            width as usize,
+            height as usize,
+            // 字体坐标y轴向上, 基线在ascender下方
+            Transform::new(scale, 0.0, 0.0, -scale, 0.0, ascender * scale),
+        );
+        face.paint_color_glyph(glyph_id, 0, RgbaColor::new(0, 0, 0, 255), &mut painter)?;
+        if let Some(mode) = painter.unsupported_mode {
+            if !UNSUPPORTED_MODE_WARNED.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "emoji {} 使用了不支持的合成模式 {:?}, 按source over绘制, 颜色可能与预期不同",
+                    c, mode
+                );
+            }
+        }
+
+        Some(painter.into_image(width, height))
+    }
+}
+
+/// 预乘alpha的RGBA, 各分量范围 0~1
+type Pixel = [f32; 4];
+
+/// 按 `ttf_parser` 给出的绘制指令把图层合成到画布上.
+/// 所有 `CompositeMode` 都按source over处理(遇到其他模式时记录在 `unsupported_mode` 中),
+/// 渐变只支持pad延伸方式
+struct LayerPainter<'a, 'f> {
+    face: &'f Face<'a>,
+    width: usize,
+    height: usize,
+    /// 字体坐标到像素坐标的变换, 最后一个为当前变换
+    transforms: Vec<Transform>,
+    /// `outline_glyph` 保存的轮廓覆盖率, 由下一次 `paint` 或 `push_clip` 使用
+    outline: Option<Vec<f32>>,
+    clips: Vec<Vec<f32>>,
+    /// 第一个为最终画布, 其余为 `push_layer` 创建的图层
+    layers: Vec<Vec<Pixel>>,
+    /// 遇到的第一个不支持的合成模式
+    unsupported_mode: Option<CompositeMode>,
+}
+
+impl<'a, 'f> LayerPainter<'a, 'f> {
+    fn new(face: &'f Face<'a>, width: usize, height: usize, transform: Transform) -> Self {
+        LayerPainter {
+            face,
+            width,
+            height,
+            transforms: vec![transform],
+            outline: None,
+            clips: Vec::new(),
+            layers: vec![vec![[0.0; 4]; width * height]],
+            unsupported_mode: None,
+        }
+    }
+
+    fn transform(&self) -> Transform {
+        *self.transforms.last().expect("变换栈不会为空")
+    }
+
+    /// 每个像素被 `build` 画出的路径覆盖的比例
+    fn coverage(&self, build: impl FnOnce(&mut PathRasterizer)) -> Vec<f32> {
+        let mut path = PathRasterizer {
+            // 多出的一列接住超出右边界的线段, 避免累加到下一行
+            rasterizer: Rasterizer::new(self.width + 1, self.height),
+            transform: self.transform(),
+            max_x: self.width as f32,
+            start: point(0.0, 0.0),
+            last: point(0.0, 0.0),
+        };
+        build(&mut path);
+        path.close_contour();
+
+        let row = self.width + 1;
+        let mut coverage = vec![0.0; self.width * self.height];
+        path.rasterizer.for_each_pixel(|i, alpha| {
+            let (x, y) = (i % row, i / row);
+            if x < self.width {
+                coverage[y * self.width + x] = alpha.min(1.0);
+            }
+        });
+        coverage
+    }
+
+    fn into_image(mut self, width: u32, height: u32) -> RgbaImage {
+        let canvas = self.layers.swap_remove(0);
+        RgbaImage::from_fn(width, height, |x, y| {
+            let [r, g, b, a] = canvas[y as usize * self.width + x as usize];
+            if a <= 0.0 {
+                return Rgba([0, 0, 0, 0]);
+            }
+            let channel = |v: f32| ((v / a).clamp(0.0, 1.0) * 255.0).round() as u8;
+            Rgba([channel(r), channel(g), channel(b), channel(a)])
+        })
+    }
+}
+
+impl<'a> Painter<'a> for LayerPainter<'a, '_> {
+    fn outline_glyph(&mut self, glyph_id: GlyphId) {
+        let face = self.face;
+        self.outline = Some(self.coverage(|path| {
+            face.outline_glyph(glyph_id, path);
+        }));
+    }
+
+    fn paint(&mut self, paint: Paint<'a>) {
+        let Some(fill) = Fill::new(paint, self.transform()) else {
+            return;
+        };
+        let outline = self.outline.take();
+        let layer = self.layers.last_mut().expect("图层栈不会为空");
+
+        for (i, pixel) in layer.iter_mut().enumerate() {
+            let mut alpha = outline.as_ref().map_or(1.0, |outline| outline[i]);
+            for clip in &self.clips {
+                alpha *= clip[i];
+            }
+            if alpha <= 0.0 {
+                continue;
+            }
+
+            let (x, y) = (i % self.width, i / self.width);
+            let [r, g, b, a] = fill.color_at(x as f32 + 0.5, y as f32 + 0.5);
+            let a = a * alpha;
+            blend(pixel, [r * a, g * a, b * a, a]);
+        }
+    }
+
+    fn push_clip(&mut self) {
+        let clip = self
+            .outline
+            .take()
+            .unwrap_or_else(|| vec![0.0; self.width * self.height]);
+        self.clips.push(clip);
+    }
+
+    fn push_clip_box(&mut self, clipbox: ClipBox) {
+        let clip = self.coverage(|path| {
+            path.move_to(clipbox.x_min, clipbox.y_min);
+            path.line_to(clipbox.x_max, clipbox.y_min);
+            path.line_to(clipbox.x_max, clipbox.y_max);
+            path.line_to(clipbox.x_min, clipbox.y_max);
+            path.close();
+        });
+        self.clips.push(clip);
+    }
+
+    fn pop_clip(&mut self) {
+        self.clips.pop();
+    }
+
+    fn push_layer(&mut self, mode: CompositeMode) {
+        if mode != CompositeMode::SourceOver {
+            self.unsupported_mode.get_or_insert(mode);
+        }
+        self.layers.push(vec![[0.0; 4]; self.width * self.height]);
+    }
+
+    fn pop_layer(&mut self) {
+        if self.layers.len() < 2 {
+            return;
+        }
+        let layer = self.layers.pop().expect("上面已检查图层数");
+        let below = self.layers.last_mut().expect("上面已检查图层数");
+        for (dst, src) in below.iter_mut().zip(layer) {
+            blend(dst, src);
+        }
+    }
+
+    fn push_transform(&mut self, transform: Transform) {
+        let combined = Transform::combine(self.transform(), transform);
+        self.transforms.push(combined);
+    }
+
+    fn pop_transform(&mut self) {
+        if self.transforms.len() > 1 {
+            self.transforms.pop();
+        }
+    }
+}
+
+/// 把预乘alpha的 `src` 以source over方式叠加到 `dst` 上
+fn blend(dst: &mut Pixel, src: Pixel) {
+    let remain = 1.0 - src[3];
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d = s + *d * remain;
+    }
+}
+
+/// 把字形轮廓变换到像素坐标后交给 `Rasterizer`
+struct PathRasterizer {
+    rasterizer: Rasterizer,
+    transform: Transform,
+    /// 超出画布左右边界的点被限制在 `0..=max_x` 中, 不影响边界内的覆盖率
+    max_x: f32,
+    start: Point,
+    last: Point,
+}
+
+impl PathRasterizer {
+    fn to_pixel(&self, x: f32, y: f32) -> Point {
+        let (x, y) = apply(self.transform, x, y);
+        point(x.clamp(0.0, self.max_x), y)
+    }
+
+    fn close_contour(&mut self) {
+        if self.last != self.start {
+            self.rasterizer.draw_line(self.last, self.start);
+        }
+        self.last = self.start;
+    }
+}
+
+impl OutlineBuilder for PathRasterizer {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.close_contour();
+        self.start = self.to_pixel(x, y);
+        self.last = self.start;
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        let p = self.to_pixel(x, y);
+        self.rasterizer.draw_line(self.last, p);
+        self.last = p;
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let p1 = self.to_pixel(x1, y1);
+        let p = self.to_pixel(x, y);
+        self.rasterizer.draw_quad(self.last, p1, p);
+        self.last = p;
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let p1 = self.to_pixel(x1, y1);
+        let p2 = self.to_pixel(x2, y2);
+        let p = self.to_pixel(x, y);
+        self.rasterizer.draw_cubic(self.last, p1, p2, p);
+        self.last = p;
+    }
+
+    fn close(&mut self) {
+        self.close_contour();
+    }
+}
+
+/// 一次 `paint` 的填充方式, 渐变在字体坐标中计算
+enum Fill {
+    Solid([f32; 4]),
+    Gradient {
+        /// 像素坐标到字体坐标的变换
+        inverse: Transform,
+        kind: GradientKind,
+        /// 按位置排序的色标
+        stops: Vec<(f32, [f32; 4])>,
+    },
+}
+
+enum GradientKind {
+    /// 颜色沿 `start` 到 `end` 变化
+    Linear { start: (f32, f32), end: (f32, f32) },
+    /// 圆心和半径从 `(c0, r0)` 变化到 `(c1, r1)`
+    Radial {
+        c0: (f32, f32),
+        r0: f32,
+        c1: (f32, f32),
+        r1: f32,
+    },
+    /// 绕 `center` 从 `start` 角度扫到 `end` 角度(单位为半圈)
+    Sweep {
+        center: (f32, f32),
+        start: f32,
+        end: f32,
+    },
+}
+
+impl Fill {
+    /// 变换不可逆或者渐变没有色标时为 `None`, 此时什么也不画
+    fn new(paint: Paint<'_>, transform: Transform) -> Option<Fill> {
+        let (kind, stops) = match paint {
+            Paint::Solid(color) => return Some(Fill::Solid(rgba(color))),
+            Paint::LinearGradient(gradient) => {
+                // 颜色沿p0→p1在垂直于p0→p2的方向上的投影变化
+                let (x0, y0) = (gradient.x0, gradient.y0);
+                let (dx, dy) = (gradient.x1 - x0, gradient.y1 - y0);
+                let (nx, ny) = (gradient.y2 - y0, x0 - gradient.x2);
+                let n_len = nx * nx + ny * ny;
+                let end = if n_len > 0.0 {
+                    let k = (dx * nx + dy * ny) / n_len;
+                    (x0 + nx * k, y0 + ny * k)
+                } else {
+                    (gradient.x1, gradient.y1)
+                };
+                let stops = gradient.stops(0, &[]).collect::<Vec<_>>();
+                (
+                    GradientKind::Linear {
+                        start: (x0, y0),
+                        end,
+                    },
+                    stops,
+                )
+            }
+            Paint::RadialGradient(gradient) => {
+                let stops = gradient.stops(0, &[]).collect::<Vec<_>>();
+                (
+                    GradientKind::Radial {
+                        c0: (gradient.x0, gradient.y0),
+                        r0: gradient.r0,
+                        c1: (gradient.x1, gradient.y1),
+                        r1: gradient.r1,
+                    },
+                    stops,
+                )
+            }
+            Paint::SweepGradient(gradient) => {
+                let stops = gradient.stops(0, &[]).collect::<Vec<_>>();
+                (
+                    GradientKind::Sweep {
+                        center: (gradient.center_x, gradient.center_y),
+                        start: gradient.start_angle,
+                        end: gradient.end_angle,
+                    },
+                    stops,
+                )
+            }
+        };
+
+        if stops.is_empty() {
+            return None;
+        }
+        let mut stops: Vec<_> = stops
+            .into_iter()
+            .map(|stop| (stop.stop_offset, rgba(stop.color)))
+            .collect();
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        Some(Fill::Gradient {
+            inverse: invert(transform)?,
+            kind,
+            stops,
+        })
+    }
+
+    /// 像素坐标 `(x, y)` 处未预乘的颜色
+    fn color_at(&self, x: f32, y: f32) -> [f32; 4] {
+        match self {
+            Fill::Solid(color) => *color,
+            Fill::Gradient {
+                inverse,
+                kind,
+                stops,
+            } => {
+                let (x, y) = apply(*inverse, x, y);
+                match kind.offset_at(x, y) {
+                    Some(t) => color_at_offset(stops, t),
+                    None => [0.0; 4],
+                }
+            }
+        }
+    }
+}
+
+impl GradientKind {
+    /// 字体坐标 `(x, y)` 在渐变中的位置, 没有被渐变覆盖时为 `None`
+    fn offset_at(&self, x: f32, y: f32) -> Option<f32> {
+        match *self {
+            GradientKind::Linear { start, end } => {
+                let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+                let len = dx * dx + dy * dy;
+                if len == 0.0 {
+                    return Some(0.0);
+                }
+                Some(((x - start.0) * dx + (y - start.1) * dy) / len)
+            }
+            GradientKind::Radial { c0, r0, c1, r1 } => {
+                // 求最大的t使点落在圆心 c0+t(c1-c0), 半径 r0+t(r1-r0) 的圆上
+                let (cdx, cdy) = (c1.0 - c0.0, c1.1 - c0.1);
+                let (pdx, pdy) = (x - c0.0, y - c0.1);
+                let dr = r1 - r0;
+                let a = cdx * cdx + cdy * cdy - dr * dr;
+                let b = pdx * cdx + pdy * cdy + r0 * dr;
+                let c = pdx * pdx + pdy * pdy - r0 * r0;
+                if a.abs() < f32::EPSILON {
+                    if b == 0.0 {
+                        return None;
+                    }
+                    let t = c / (2.0 * b);
+                    return (r0 + t * dr >= 0.0).then_some(t);
+                }
+                let discriminant = b * b - a * c;
+                if discriminant < 0.0 {
+                    return None;
+                }
+                let root = discriminant.sqrt();
+                let (t1, t2) = ((b + root) / a, (b - root) / a);
+                let (high, low) = if t1 > t2 { (t1, t2) } else { (t2, t1) };
+                [high, low].into_iter().find(|t| r0 + t * dr >= 0.0)
+            }
+            GradientKind::Sweep { center, start, end } => {
+                if start == end {
+                    return Some(0.0);
+                }
+                let mut angle = (y - center.1).atan2(x - center.0) / std::f32::consts::PI;
+                if angle < 0.0 {
+                    angle += 2.0;
+                }
+                Some((angle - start) / (end - start))
+            }
+        }
+    }
+}
+
+/// 渐变中位置 `t` 的颜色, 超出色标范围时使用两端的颜色
+fn color_at_offset(stops: &[(f32, [f32; 4])], t: f32) -> [f32; 4] {
+    let (first, last) = (stops[0], stops[stops.len() - 1]);
+    if t <= first.0 {
+        return first.1;
+    }
+    if t >= last.0 {
+        return last.1;
+    }
+
+    let i = stops.partition_point(|stop| stop.0 <= t);
+    let ((o0, c0), (o1, c1)) = (stops[i - 1], stops[i]);
+    let k = if o1 > o0 { (t - o0) / (o1 - o0) } else { 0.0 };
+    std::array::from_fn(|j| c0[j] + (c1[j] - c0[j]) * k)
+}
+
+fn apply(t: Transform, x: f32, y: f32) -> (f32, f32) {
+    (t.a * x + t.c * y + t.e, t.b * x + t.d * y + t.f)
+}
+
+/// 逆变换, 不可逆时为 `None`
+fn invert(t: Transform) -> Option<Transform> {
+    let det = t.a * t.d - t.b * t.c;
+    if det.abs() < f32::EPSILON {
+        return None;
+    }
+    let (a, b, c, d) = (t.d / det, -t.b / det, -t.c / det, t.a / det);
+    Some(Transform::new(
+        a,
+        b,
+        c,
+        d,
+        -(a * t.e + c * t.f),
+        -(b * t.e + d * t.f),
+    ))
+}
+
+fn rgba(color: RgbaColor) -> [f32; 4] {
+    [color.red, color.green, color.blue, color.alpha].map(|v| v as f32 / 255.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_font() -> ColrFont {
+        let data = std::fs::read("test_resources/colr.ttf").unwrap();
+        ColrFont::from_slice(&data).expect("测试字体有COLR表")
+    }
+
+    #[test]
+    fn test_render_layers() {
+        let font = test_font();
+        assert!(font.has_glyph('😀'));
+        assert!(!font.has_glyph('a'));
+        assert!(font.render('a', 100).is_none());
+
+        // 红色方块 100..900 上叠加蓝色方块 350..650, em为1000
+        let image = font.render('😀', 100).unwrap();
+        assert_eq!((100, 100), image.dimensions());
+        assert_eq!(Rgba([0, 0, 0, 0]), *image.get_pixel(5, 5));
+        assert_eq!(Rgba([255, 0, 0, 255]), *image.get_pixel(20, 20));
+        assert_eq!(Rgba([0, 0, 255, 255]), *image.get_pixel(50, 50));
+        assert_eq!(Rgba([255, 0, 0, 255]), *image.get_pixel(80, 80));
+    }
+
+    #[test]
+    fn test_not_colr_font() {
+        let data = std::fs::read("resource/normal.ttf").unwrap();
+        assert!(ColrFont::from_slice(&data).is_none());
+        assert!(ColrFont::from_slice(b"not a font").is_none());
+    }
+
+    #[test]
+    fn test_gradient_offset() {
+        let linear = GradientKind::Linear {
+            start: (0.0, 0.0),
+            end: (100.0, 0.0),
+        };
+        assert_eq!(Some(0.5), linear.offset_at(50.0, 30.0));
+
+        let radial = GradientKind::Radial {
+            c0: (0.0, 0.0),
+            r0: 0.0,
+            c1: (0.0, 0.0),
+            r1: 100.0,
+        };
+        let t = radial.offset_at(30.0, 40.0).unwrap();
+        assert!((t - 0.5).abs() < 1e-4);
+
+        let stops = [(0.0, [0.0, 0.0, 0.0, 1.0]), (1.0, [1.0, 1.0, 1.0, 1.0])];
+        assert_eq!([0.5, 0.5, 0.5, 1.0], color_at_offset(&stops, 0.5));
+        assert_eq!([1.0, 1.0, 1.0, 1.0], color_at_offset(&stops, 2.0));
+    }
+}
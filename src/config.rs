@@ -1,43 +1,1368 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
+use cron::TimeUnitSpec;
+use jiff::{
+    civil::{Date, Time},
+    tz::{self, TimeZone},
+    Timestamp, ToSpan,
+};
 use serde::{Deserialize, Serialize};
 use tokio::fs;
+use tracing::warn;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
     pub db: DbConfig,
     pub mirai: MiraiConfig,
     pub bili: BiliConfig,
+    #[serde(default)]
+    pub render: RenderConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub download: DownloadConfig,
+    #[serde(default)]
     pub target: Vec<TargetConfig>,
+    /// 第i个target首次抓取前等待 i * stagger_sec 秒, 避免同时请求
+    #[serde(default)]
+    pub stagger_sec: u64,
+    /// 从远程地址获取额外的监听目标
+    pub remote_targets: Option<RemoteTargetsConfig>,
+}
+
+/// 远程管理的监听目标列表, 与配置文件中的 `target` 合并
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RemoteTargetsConfig {
+    /// 返回 `[TargetConfig]` 格式JSON的HTTP(S)地址
+    pub url: String,
+    /// 重新获取列表的间隔(秒), 不设置时只在启动时获取
+    pub refresh_sec: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct DbConfig {
     pub path: PathBuf,
+    /// sled页缓存大小(字节), 不设置时使用sled默认值
+    pub cache_capacity_bytes: Option<u64>,
+    /// `low_space` 或 `high_throughput`, 不设置时使用sled默认值
+    pub mode: Option<String>,
+    /// 所有目标共用一棵树, 键以目标的树名为前缀, 而不是每个目标一棵树.
+    /// 开启后启动时把已有的每个目标的树合并进来
+    #[serde(default)]
+    pub single_tree: bool,
+}
+
+impl DbConfig {
+    /// 根据配置构建sled数据库配置, 未设置的项使用sled默认值
+    pub fn sled_config(&self) -> anyhow::Result<sled::Config> {
+        let mut config = sled::Config::new().path(&self.path);
+
+        if let Some(capacity) = self.cache_capacity_bytes {
+            if capacity == 0 {
+                bail!("db.cache_capacity_bytes 必须大于0: 它限制sled在内存中缓存的页大小, 不设置则使用sled默认值(1GB)");
+            }
+            config = config.cache_capacity(capacity);
+        }
+
+        if let Some(mode) = &self.mode {
+            let mode = match mode.as_str() {
+                "low_space" => sled::Mode::LowSpace,
+                "high_throughput" => sled::Mode::HighThroughput,
+                _ => bail!(
+                    "不支持的 db.mode \"{}\": 可选 low_space (更积极地整理磁盘空间, 写入较慢) 或 high_throughput (写入更快, 占用更多磁盘空间), 不设置则使用sled默认值",
+                    mode
+                ),
+            };
+            config = config.mode(mode);
+        }
+
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct HttpConfig {
+    #[serde(default)]
+    pub ip_version: IpVersion,
+    /// 只用于b站请求的代理, 例如 `socks5h://127.0.0.1:1080`, Mirai仍然直接连接
+    pub bili_proxy: Option<String>,
+}
+
+/// 图片下载的限制
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct DownloadConfig {
+    /// 每个目标一轮抓取中最多下载的动态配图和封面字节数, 超过后其余配图以灰色方块代替.
+    /// 头像和表情不计入
+    pub max_bytes_per_poll: Option<u64>,
+}
+
+impl HttpConfig {
+    /// 根据配置构建b站请求(动态列表, 动态详情, 图片)共用的HTTP客户端
+    pub fn client(&self) -> anyhow::Result<reqwest::Client> {
+        let mut builder = self.client_builder();
+        if let Some(proxy) = &self.bili_proxy {
+            let proxy = reqwest::Proxy::all(proxy)
+                .with_context(|| format!("不合法的代理地址: {}", proxy))?;
+            builder = builder.proxy(proxy);
+        }
+
+        builder.build().context("Build http client")
+    }
+
+    pub fn client_builder(&self) -> reqwest::ClientBuilder {
+        let local_address = match self.ip_version {
+            IpVersion::Auto => None,
+            IpVersion::V4 => Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            IpVersion::V6 => Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        };
+
+        // b站按 Accept-Encoding 返回压缩的响应, 明确开启解压, 不依赖reqwest默认开启的功能
+        reqwest::Client::builder()
+            .local_address(local_address)
+            .gzip(true)
+            .brotli(true)
+            .deflate(true)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IpVersion {
+    /// 由系统决定
+    #[default]
+    Auto,
+    /// 只使用IPv4, 用于双栈机器上IPv6不通的情况
+    V4,
+    /// 只使用IPv6
+    V6,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct MiraiConfig {
     pub http_url: String,
+    /// 反向代理把mirai-api-http挂载在子路径下时的路径, 例如 `/mirai`, 加在每个接口路径之前
+    #[serde(default)]
+    pub path_prefix: String,
     pub verify_key: String,
+    /// 启动后向 `admin_qq` 发送一条通知
+    #[serde(default)]
+    pub notify_on_start: bool,
+    /// 接收启动通知和发送失败提醒的QQ号, 启动通知使用第一个监听目标的 `sender_qq` 发送
+    pub admin_qq: Option<i64>,
+    /// 同一个监听目标连续获取动态或发送失败这么多次后提醒 `admin_qq`, 恢复后再通知一次
+    pub alert_after_failures: Option<u32>,
+    /// 两次失败提醒之间的最小间隔(秒), 避免长时间故障或反复断线时刷屏
+    #[serde(default = "default_alert_interval_sec")]
+    pub alert_interval_sec: u64,
+    /// 额外信任的CA证书(PEM), 用于内网反向代理使用私有CA的情况
+    pub tls_ca_cert: Option<PathBuf>,
+    /// 不校验Mirai的TLS证书, 有中间人攻击的风险
+    pub tls_danger_accept_invalid: Option<bool>,
+    /// 同一个监听目标连续发送两条动态之间的最小间隔(毫秒), 避免补发时触发QQ的风控
+    #[serde(default = "default_send_interval_ms")]
+    pub send_interval_ms: u64,
+    /// 测试配置时把所有监听目标的消息都改发给这个QQ, 不打扰真正的接收者
+    pub override_receiver: Option<i64>,
+}
+
+fn default_send_interval_ms() -> u64 {
+    1000
+}
+
+fn default_alert_interval_sec() -> u64 {
+    3600
+}
+
+impl MiraiConfig {
+    /// `http_url` 为 `unix:///path/to.sock` 时Unix socket的路径
+    pub fn unix_socket_path(&self) -> Option<&Path> {
+        self.http_url.strip_prefix("unix://").map(Path::new)
+    }
+
+    /// Mirai接口地址的前缀, 包括 `path_prefix`, 不以 `/` 结尾. 使用Unix socket时主机名只用于请求头
+    pub fn base_url(&self) -> String {
+        let host = match self.unix_socket_path() {
+            Some(_) => "http://localhost",
+            None => self.http_url.trim_end_matches('/'),
+        };
+        // `mirai`, `/mirai` 和 `/mirai/` 相同
+        match self.path_prefix.trim_matches('/') {
+            "" => host.to_string(),
+            prefix => format!("{}/{}", host, prefix),
+        }
+    }
+
+    /// 连接Mirai使用的HTTP客户端, 不使用Unix socket时和其他请求一样按 `http` 配置构建.
+    /// TLS配置只用于Mirai, b站的请求仍使用系统默认的证书
+    pub fn client(&self, http: &HttpConfig) -> anyhow::Result<reqwest::Client> {
+        let mut builder = match self.unix_socket_path() {
+            None => http.client_builder(),
+            #[cfg(unix)]
+            Some(path) => reqwest::Client::builder().unix_socket(path),
+            #[cfg(not(unix))]
+            Some(path) => bail!("当前系统不支持通过Unix socket {} 连接Mirai", path.display()),
+        };
+
+        if let Some(ca_cert) = &self.tls_ca_cert {
+            let pem = std::fs::read(ca_cert)
+                .with_context(|| format!("读取 mirai.tls_ca_cert {} 失败", ca_cert.display()))?;
+            let cert = reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("mirai.tls_ca_cert {} 不是PEM证书", ca_cert.display()))?;
+            builder = builder.add_root_certificate(cert);
+        }
+
+        if self.tls_danger_accept_invalid == Some(true) {
+            warn!(
+                "已开启 mirai.tls_danger_accept_invalid, 不会校验 {} 的TLS证书, 连接可能被中间人劫持",
+                self.http_url
+            );
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        builder.build().context("Build mirai http client")
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct BiliConfig {
     pub sess_data: String,
+    /// 覆盖 `https://api.bilibili.com`, 用于镜像或测试
+    pub api_base_url: Option<String>,
+    /// 覆盖 `https://api.vc.bilibili.com`, 用于镜像或测试
+    pub vc_api_base_url: Option<String>,
+    /// app登录凭证, 设置后网页版动态详情接口被风控(-352/-412)时改用app接口获取
+    pub app_access_key: Option<String>,
+}
+
+impl BiliConfig {
+    /// 获取 `target` 的动态使用的配置: 目标设置了 `sess_data` 时代替全局的登录凭证
+    pub fn for_target(&self, target: &TargetConfig) -> BiliConfig {
+        BiliConfig {
+            sess_data: target
+                .sess_data
+                .clone()
+                .unwrap_or_else(|| self.sess_data.clone()),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RenderConfig {
+    /// 纯文字动态使用更小的头像和更紧凑的边距
+    #[serde(default)]
+    pub compact: bool,
+    /// 发送的图片格式
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    /// JPEG图片质量(1-100), 仅在 `output_format = "jpeg"` 时使用
+    #[serde(default = "default_jpeg_quality")]
+    pub jpeg_quality: u8,
+    /// 不使用emoji字体而是下载图片绘制的emoji码点, 例如 `["1F97A", "U+1F62D"]`
+    #[serde(default)]
+    pub force_image_emoji: Vec<EmojiCodepoint>,
+    /// emoji图片地址模板, `{codepoint}` 会被替换为小写十六进制码点
+    #[serde(default = "default_emoji_cdn")]
+    pub emoji_cdn: String,
+    /// 优先从该目录读取 `{codepoint}.png` 作为emoji图片
+    pub emoji_dir: Option<PathBuf>,
+    /// 正文中所有emoji都优先使用 `emoji_dir` 中的图片绘制, 目录中没有时使用emoji字体
+    #[serde(default)]
+    pub all_emoji_from_dir: bool,
+    /// 字体和图标所在的目录
+    #[serde(default = "default_resource_dir")]
+    pub resource_dir: PathBuf,
+    /// `normal.ttf` 中没有某个字符时依次尝试的字体, 相对于 `resource_dir`
+    #[serde(default)]
+    pub fallback_fonts: Vec<PathBuf>,
+    /// 在直播动态的封面旁绘制直播间链接的二维码
+    #[serde(default)]
+    pub live_qr: bool,
+    /// 在头像外绘制用户佩戴的头像框
+    #[serde(default)]
+    pub show_pendant: bool,
+    /// 在卡片底部绘制动态链接的二维码
+    #[serde(default)]
+    pub dynamic_qr: bool,
+    /// 在被裁剪成正方形的相册图片周围绘制细边框
+    #[serde(default)]
+    pub mark_cropped: bool,
+    /// 卡片的最大高度(像素), 超出时截断内容并提示阅读全文
+    pub max_height: Option<u32>,
+    /// 正文中每个空行(段落之间)留出的高度(像素)
+    #[serde(default = "default_paragraph_space")]
+    pub paragraph_space: u32,
+    /// 消息链中各部分的顺序
+    #[serde(default)]
+    pub message_order: MessageOrder,
+    /// 转发动态中原动态的文字大小(像素), 默认与正文相同
+    pub forward_text_size: Option<f32>,
+    /// 转发动态中原动态的emoji大小(像素), 默认与正文相同
+    pub forward_emoji_size: Option<f32>,
+    /// 在转发动态中 `@原作者` 前绘制原作者的头像
+    #[serde(default)]
+    pub forward_show_avatar: bool,
+    /// 转发动态中原动态的背景色
+    #[serde(default = "default_forward_background")]
+    pub forward_background: Color,
+    /// 转发动态中 `@原作者` 的颜色
+    #[serde(default = "default_at_color")]
+    pub at_color: Color,
+    /// 不下载任何图片, 以同样大小的灰色方块代替, 只显示排版和文字
+    #[serde(default)]
+    pub skeleton: bool,
+    /// 渲染好的消息保存到数据库直到发送成功, 重发(包括重启后)时直接使用, 原动态被删除也能送达
+    #[serde(default)]
+    pub persist_rendered: bool,
+    /// 缩放头像和动态图片使用的插值方法
+    #[serde(default)]
+    pub resize_filter: ResizeFilter,
+    /// 图片前的消息头格式
+    #[serde(default)]
+    pub header_style: HeaderStyle,
+    /// 卡片中发布时间的格式
+    #[serde(default)]
+    pub time_style: TimeStyle,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        RenderConfig {
+            compact: false,
+            output_format: OutputFormat::default(),
+            jpeg_quality: default_jpeg_quality(),
+            force_image_emoji: Vec::new(),
+            emoji_cdn: default_emoji_cdn(),
+            emoji_dir: None,
+            all_emoji_from_dir: false,
+            resource_dir: default_resource_dir(),
+            fallback_fonts: Vec::new(),
+            live_qr: false,
+            show_pendant: false,
+            dynamic_qr: false,
+            mark_cropped: false,
+            max_height: None,
+            paragraph_space: default_paragraph_space(),
+            message_order: MessageOrder::default(),
+            forward_text_size: None,
+            forward_emoji_size: None,
+            forward_show_avatar: false,
+            forward_background: default_forward_background(),
+            at_color: default_at_color(),
+            skeleton: false,
+            persist_rendered: false,
+            resize_filter: ResizeFilter::default(),
+            header_style: HeaderStyle::default(),
+            time_style: TimeStyle::default(),
+        }
+    }
+}
+
+fn default_paragraph_space() -> u32 {
+    50
+}
+
+fn default_jpeg_quality() -> u8 {
+    85
+}
+
+fn default_resource_dir() -> PathBuf {
+    PathBuf::from(crate::resource::DEFAULT_RESOURCE_DIR)
+}
+
+fn default_forward_background() -> Color {
+    Color([244, 244, 244])
+}
+
+fn default_at_color() -> Color {
+    Color([0, 161, 214])
+}
+
+fn default_emoji_cdn() -> String {
+    "https://cdn.jsdelivr.net/gh/jdecked/twemoji@15.1.0/assets/72x72/{codepoint}.png".to_string()
+}
+
+/// 一个emoji码点, 配置中写作十六进制, 可以带 `U+` 前缀
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct EmojiCodepoint(pub char);
+
+impl FromStr for EmojiCodepoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.trim();
+        let hex = hex
+            .strip_prefix("U+")
+            .or_else(|| hex.strip_prefix("u+"))
+            .unwrap_or(hex);
+
+        let code = u32::from_str_radix(hex, 16)
+            .with_context(|| format!("emoji码点 {} 应为十六进制, 例如 1F97A", s))?;
+
+        char::from_u32(code)
+            .map(EmojiCodepoint)
+            .with_context(|| format!("不合法的emoji码点 {}", s))
+    }
+}
+
+impl TryFrom<String> for EmojiCodepoint {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<EmojiCodepoint> for String {
+    fn from(value: EmojiCodepoint) -> Self {
+        format!("U+{:X}", value.0 as u32)
+    }
+}
+
+/// 一个不透明的颜色, 配置中写作 `#rrggbb`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color(pub [u8; 3]);
+
+impl FromStr for Color {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s
+            .trim()
+            .strip_prefix('#')
+            .filter(|hex| hex.len() == 6 && hex.is_ascii())
+            .with_context(|| format!("颜色 {} 应写作 #rrggbb, 例如 #00a1d6", s))?;
+
+        let mut rgb = [0; 3];
+        for (i, channel) in rgb.iter_mut().enumerate() {
+            *channel = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                .with_context(|| format!("颜色 {} 应写作 #rrggbb, 例如 #00a1d6", s))?;
+        }
+
+        Ok(Color(rgb))
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Color> for String {
+    fn from(value: Color) -> Self {
+        let [r, g, b] = value.0;
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    }
+}
+
+/// 消息头的格式
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderStyle {
+    /// "X 发表了新动态" 和动态链接两行
+    #[default]
+    Full,
+    /// 一行 "【X】发布了带图动态", 不包括链接
+    Compact,
+}
+
+/// 卡片中发布时间的格式
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeStyle {
+    /// "2024-01-01 08:00"
+    #[default]
+    Absolute,
+    /// 与b站客户端相同的 "刚刚" / "3分钟前" / "2小时前" / "5天前", 超过一周时为日期时间.
+    /// 只用于发现后立即发送的卡片, 推迟发送的卡片仍为日期时间
+    Relative,
+}
+
+/// 缩放图片的插值方法, 从快到慢, 质量从低到高
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    #[serde(rename = "catmull")]
+    CatmullRom,
+    #[default]
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for image::imageops::FilterType {
+    fn from(value: ResizeFilter) -> Self {
+        match value {
+            ResizeFilter::Nearest => image::imageops::FilterType::Nearest,
+            ResizeFilter::Triangle => image::imageops::FilterType::Triangle,
+            ResizeFilter::CatmullRom => image::imageops::FilterType::CatmullRom,
+            ResizeFilter::Lanczos3 => image::imageops::FilterType::Lanczos3,
+        }
+    }
+}
+
+impl From<Color> for image::Rgba<u8> {
+    fn from(value: Color) -> Self {
+        let [r, g, b] = value.0;
+        image::Rgba([r, g, b, 255])
+    }
+}
+
+/// 发送一条动态的消息链中的一部分
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MessagePart {
+    /// "xx 发表了新动态" 和动态链接, 只在 `target.send_header` 开启时发送
+    Header,
+    /// 动态图片
+    Image,
+    /// 动态的文字内容
+    Text,
+    /// 原图地址, 只在 `target.include_image_urls` 开启时发送
+    Urls,
+}
+
+/// 消息链中各部分的顺序, 必须包含 `image` 且不能重复
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "Vec<MessagePart>", into = "Vec<MessagePart>")]
+pub struct MessageOrder(Vec<MessagePart>);
+
+impl MessageOrder {
+    pub fn parts(&self) -> &[MessagePart] {
+        &self.0
+    }
+}
+
+impl Default for MessageOrder {
+    fn default() -> Self {
+        MessageOrder(vec![
+            MessagePart::Header,
+            MessagePart::Image,
+            MessagePart::Urls,
+        ])
+    }
+}
+
+impl TryFrom<Vec<MessagePart>> for MessageOrder {
+    type Error = anyhow::Error;
+
+    fn try_from(parts: Vec<MessagePart>) -> Result<Self, Self::Error> {
+        if !parts.contains(&MessagePart::Image) {
+            bail!("render.message_order 必须包含 image: 消息链不能为空, 图片总是发送");
+        }
+        for (i, part) in parts.iter().enumerate() {
+            if parts[..i].contains(part) {
+                bail!("render.message_order 中 {:?} 重复出现", part);
+            }
+        }
+
+        Ok(MessageOrder(parts))
+    }
+}
+
+impl From<MessageOrder> for Vec<MessagePart> {
+    fn from(value: MessageOrder) -> Self {
+        value.0
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// 无损, 文字更清晰
+    #[default]
+    Png,
+    /// 图片较多时体积更小
+    Jpeg,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct TargetConfig {
+    /// 监听的用户, `mode = "topic"` 时不需要
+    #[serde(default)]
     pub uid: u64,
     pub interval_sec: u64,
     pub receiver_qq: i64,
     pub sender_qq: i64,
+    /// 免打扰时段, 期间新动态只记录不发送, 结束后再补发
+    pub quiet_hours: Option<QuietHours>,
+    /// 同时推送置顶动态(只推送一次)
+    #[serde(default)]
+    pub include_top: bool,
+    /// 将一条动态的多条消息打包成一条合并转发消息
+    #[serde(default)]
+    pub merged_forward: bool,
+    /// 抓取时间表, 设置后代替 `interval_sec` 决定下一次抓取的时间
+    pub schedule: Option<PollSchedule>,
+    /// 两次抓取之间最多补发的新动态数量
+    #[serde(default = "default_max_catch_up")]
+    pub max_catch_up: usize,
+    /// 在图片前发送 "X 发表了新动态" 和动态链接
+    #[serde(default = "default_send_header")]
+    pub send_header: bool,
+    /// 设置后, 与该时间(秒)内已发送的动态文字和图片都相同的新动态只记录不发送
+    pub dedup_window_sec: Option<u64>,
+    /// 在图片后发送动态中图片的原图地址
+    #[serde(default)]
+    pub include_image_urls: bool,
+    /// 获取动态列表使用的接口
+    #[serde(default)]
+    pub endpoint: DynamicListEndpoint,
+    /// 设置后通过该群向 `receiver_qq` 发送临时会话消息, 用于机器人不是接收者好友的情况
+    pub temp_group: Option<i64>,
+    /// 已发送的动态文字或图片被编辑后重新发送, 只在 `endpoint = "feed_space"` 时生效
+    #[serde(default)]
+    pub resend_on_edit: bool,
+    /// 每条动态单独发送, 或者每天汇总发送一次
+    #[serde(default)]
+    pub mode: TargetMode,
+    /// 摘要模式下每天发送摘要的时间(东8区)
+    #[serde(default = "default_digest_at")]
+    pub digest_at: TimeOfDay,
+    /// 覆盖 `bili.sess_data`, 用另一个账号获取该目标的动态, 例如查看只对该账号可见的充电专属动态
+    pub sess_data: Option<String>,
+    /// `mode = "topic"` 时监听的话题id
+    pub topic_id: Option<u64>,
+    /// 话题名称, 只用于日志
+    pub topic_name: Option<String>,
+    /// 带有内容警告的动态的图片模糊后绘制, 并标注"敏感内容", 文字照常绘制, 也不发送原图地址
+    #[serde(default)]
+    pub blur_flagged: bool,
+}
+
+impl TargetConfig {
+    /// 保存该目标已发现动态的sled tree: 用户目标为uid, 话题目标为 `topic_<topic_id>`
+    pub fn tree_name(&self) -> String {
+        match self.mode {
+            TargetMode::Topic => format!("topic_{}", self.topic_id.unwrap_or_default()),
+            _ => self.uid.to_string(),
+        }
+    }
+
+    /// 日志和通知中使用的目标名称: 话题目标为 `话题 {topic_name}`, 没有名称时使用话题id;
+    /// 用户目标为uid
+    pub fn label(&self) -> String {
+        match self.mode {
+            TargetMode::Topic => match &self.topic_name {
+                Some(name) => format!("话题 {}", name),
+                None => format!("话题 {}", self.topic_id.unwrap_or_default()),
+            },
+            _ => self.uid.to_string(),
+        }
+    }
+
+    /// 检查目标的 `mode` 与 `uid`, `topic_id` 是否匹配
+    pub fn check(&self) -> anyhow::Result<()> {
+        match self.mode {
+            TargetMode::Topic if self.topic_id.is_none() => {
+                bail!("mode = \"topic\" 的目标必须设置 topic_id")
+            }
+            TargetMode::Topic => Ok(()),
+            _ if self.uid == 0 => bail!("监听用户的目标必须设置 uid"),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetMode {
+    /// 发现新动态后立即发送
+    #[default]
+    Realtime,
+    /// 新动态只记录, 每天 `digest_at` 把未发送的动态拼接成一张图片发送
+    Digest,
+    /// 监听 `topic_id` 话题下的新动态, 每条动态单独发送
+    Topic,
+}
+
+fn default_digest_at() -> TimeOfDay {
+    TimeOfDay(21 * 60)
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DynamicListEndpoint {
+    /// `x/polymer/web-dynamic/v1/feed/space`
+    #[default]
+    FeedSpace,
+    /// 旧版 `dynamic_svr/v1/dynamic_svr/space_history`, 部分用户会返回 -352 或空列表
+    SpaceHistory,
+}
+
+fn default_send_header() -> bool {
+    true
+}
+
+fn default_max_catch_up() -> usize {
+    20
+}
+
+/// cron表达式(秒 分 时 日 月 星期 [年]), 例如 `0 */5 18-23 * * Mon-Fri`, 按东8区时间计算
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct PollSchedule(cron::Schedule);
+
+/// cron表达式中年份的上限
+const MAX_SCHEDULE_YEAR: i16 = 2100;
+
+impl PollSchedule {
+    /// 给定时间戳(秒)之后的下一个抓取时间戳, 没有则返回 `None`.
+    /// `cron::Schedule` 的查找只接受chrono的时间, 这里按东8区的时间逐级匹配,
+    /// 某一级不匹配时跳到该级的下一个单位的开始
+    pub fn next_after(&self, timestamp: i64) -> Option<i64> {
+        let tz = china_time_zone();
+        let schedule = &self.0;
+        let mut dt = tz.to_datetime(Timestamp::from_second(timestamp.checked_add(1)?).ok()?);
+
+        while dt.year() <= MAX_SCHEDULE_YEAR {
+            let weekday = dt.weekday().to_sunday_one_offset() as u32;
+            dt = if !schedule.years().includes(dt.year() as u32) {
+                Date::new(dt.year() + 1, 1, 1)
+                    .ok()?
+                    .to_datetime(Time::midnight())
+            } else if !schedule.months().includes(dt.month() as u32) {
+                let next_month = dt.date().first_of_month().checked_add(1.month()).ok()?;
+                next_month.to_datetime(Time::midnight())
+            } else if !schedule.days_of_month().includes(dt.day() as u32)
+                || !schedule.days_of_week().includes(weekday)
+            {
+                dt.date().tomorrow().ok()?.to_datetime(Time::midnight())
+            } else if !schedule.hours().includes(dt.hour() as u32) {
+                dt.date()
+                    .at(dt.hour(), 0, 0, 0)
+                    .checked_add(1.hour())
+                    .ok()?
+            } else if !schedule.minutes().includes(dt.minute() as u32) {
+                let minute_start = dt.date().at(dt.hour(), dt.minute(), 0, 0);
+                minute_start.checked_add(1.minute()).ok()?
+            } else if !schedule.seconds().includes(dt.second() as u32) {
+                dt.checked_add(1.second()).ok()?
+            } else {
+                return Some(dt.to_zoned(tz).ok()?.timestamp().as_second());
+            };
+        }
+
+        None
+    }
+}
+
+impl FromStr for PollSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let schedule = cron::Schedule::from_str(s)
+            .with_context(|| format!("不合法的cron表达式 {}, 应为 秒 分 时 日 月 星期 [年]", s))?;
+
+        Ok(PollSchedule(schedule))
+    }
+}
+
+impl TryFrom<String> for PollSchedule {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<PollSchedule> for String {
+    fn from(value: PollSchedule) -> Self {
+        value.0.to_string()
+    }
+}
+
+/// 解析 `HH:MM` 格式的时间, 返回从零点开始的分钟数
+fn parse_time_of_day(t: &str) -> anyhow::Result<u32> {
+    let (h, m) = t
+        .trim()
+        .split_once(':')
+        .with_context(|| format!("时间 {} 应为 HH:MM 格式", t))?;
+    let (h, m): (u32, u32) = (h.parse()?, m.parse()?);
+    if h > 23 || m > 59 {
+        bail!("不合法的时间 {}", t);
+    }
+    Ok(h * 60 + m)
+}
+
+/// 免打扰时段、摘要时间、cron表达式和消息中的时间使用的东8区(`Asia/Shanghai`).
+/// 时区数据随程序打包, 万一查找失败时使用固定的+8偏移, 该时区目前没有夏令时, 结果相同
+pub fn china_time_zone() -> TimeZone {
+    TimeZone::get("Asia/Shanghai").unwrap_or_else(|_| TimeZone::fixed(tz::offset(8)))
+}
+
+/// 一天中的一个时刻, 例如 `21:00`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(u32);
+
+impl TimeOfDay {
+    pub fn hour(&self) -> u32 {
+        self.0 / 60
+    }
+
+    pub fn minute(&self) -> u32 {
+        self.0 % 60
+    }
 }
 
+impl FromStr for TimeOfDay {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_time_of_day(s).map(TimeOfDay)
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(value: TimeOfDay) -> Self {
+        format!("{:02}:{:02}", value.hour(), value.minute())
+    }
+}
+
+/// 一天中的一个时段, 例如 `23:00-07:00`, 按东8区时间计算, 可以跨越午夜
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct QuietHours {
+    /// 开始时间, 从零点开始的分钟数
+    start: u32,
+    /// 结束时间(不包含), 从零点开始的分钟数
+    end: u32,
+}
+
+impl QuietHours {
+    /// 给定时间(时, 分)是否在该时段内
+    pub fn contains(&self, hour: u32, minute: u32) -> bool {
+        let t = hour * 60 + minute;
+
+        if self.start <= self.end {
+            self.start <= t && t < self.end
+        } else {
+            t >= self.start || t < self.end
+        }
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .with_context(|| format!("免打扰时段 {} 应为 HH:MM-HH:MM 格式", s))?;
+
+        Ok(QuietHours {
+            start: parse_time_of_day(start)?,
+            end: parse_time_of_day(end)?,
+        })
+    }
+}
+
+impl TryFrom<String> for QuietHours {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<QuietHours> for String {
+    fn from(value: QuietHours) -> Self {
+        format!(
+            "{:02}:{:02}-{:02}:{:02}",
+            value.start / 60,
+            value.start % 60,
+            value.end / 60,
+            value.end % 60
+        )
+    }
+}
+
+/// 配置目录中的基础配置文件
+const BASE_CONFIG_FILE: &str = "spider.toml";
+/// 配置目录中只包含监听目标的配置文件所在的子目录
+const TARGETS_DIR: &str = "targets.d";
+
+/// 读取配置并检查每个监听目标, 见 [`TargetConfig::check`]
 pub async fn get_config_from_file(path: impl AsRef<Path>) -> anyhow::Result<Config> {
-    let content = fs::read_to_string(path).await.context("Read config file")?;
+    let config = read_config(path.as_ref()).await?;
+    for (i, target) in config.target.iter().enumerate() {
+        target
+            .check()
+            .with_context(|| format!("第 {} 个监听目标 {} 配置错误", i + 1, target.label()))?;
+    }
+
+    Ok(config)
+}
+
+/// `path` 是目录时读取其中的 `spider.toml`, 再按文件名顺序加入 `targets.d/*.toml`
+/// 中的监听目标, 见 [`merge_config_tables`]
+async fn read_config(path: &Path) -> anyhow::Result<Config> {
+    if !fs::metadata(path)
+        .await
+        .context("Read config file")?
+        .is_dir()
+    {
+        let content = fs::read_to_string(path).await.context("Read config file")?;
+        return toml::from_str(&content).context("Parse config file");
+    }
+
+    let mut files = vec![path.join(BASE_CONFIG_FILE)];
+
+    let targets_dir = path.join(TARGETS_DIR);
+    if fs::try_exists(&targets_dir).await? {
+        let mut target_files = Vec::new();
+        let mut entries = fs::read_dir(&targets_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file = entry.path();
+            if file.extension().is_some_and(|ext| ext == "toml") {
+                target_files.push(file);
+            }
+        }
+        target_files.sort();
+        files.extend(target_files);
+    }
+
+    let mut tables = Vec::with_capacity(files.len());
+    for file in files {
+        let content = fs::read_to_string(&file)
+            .await
+            .with_context(|| format!("读取配置文件 {} 失败", file.display()))?;
+        let table: toml::Table = toml::from_str(&content)
+            .with_context(|| format!("解析配置文件 {} 失败", file.display()))?;
+        tables.push((file, table));
+    }
+
+    toml::Value::Table(merge_config_tables(tables)?)
+        .try_into()
+        .with_context(|| format!("Parse config directory {}", path.display()))
+}
+
+/// 合并多个配置文件: `target` 数组依次拼接; 其他设置可以出现在多个文件中, 但必须完全相同
+fn merge_config_tables(tables: Vec<(PathBuf, toml::Table)>) -> anyhow::Result<toml::Table> {
+    let mut merged = toml::Table::new();
+    // 每项设置第一次出现的文件
+    let mut sources: HashMap<String, PathBuf> = HashMap::new();
+    let mut targets = Vec::new();
+
+    for (file, table) in tables {
+        for (key, value) in table {
+            if key == "target" {
+                let toml::Value::Array(array) = value else {
+                    bail!("{} 中的 target 应写作 [[target]]", file.display());
+                };
+                targets.extend(array);
+                continue;
+            }
+
+            match merged.get(&key) {
+                Some(existing) if *existing != value => bail!(
+                    "{} 中的 {} 与 {} 中的设置不同, 请只在一个文件中配置",
+                    file.display(),
+                    key,
+                    sources[&key].display()
+                ),
+                Some(_) => {}
+                None => {
+                    sources.insert(key.clone(), file.clone());
+                    merged.insert(key, value);
+                }
+            }
+        }
+    }
+
+    merged.insert("target".to_string(), toml::Value::Array(targets));
+
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_target;
+
+    #[test]
+    fn test_mirai_unix_socket() {
+        let mut mirai = MiraiConfig {
+            http_url: "http://localhost:7827/".to_string(),
+            path_prefix: String::new(),
+            verify_key: "INITKEY".to_string(),
+            notify_on_start: false,
+            admin_qq: None,
+            alert_after_failures: None,
+            alert_interval_sec: default_alert_interval_sec(),
+            tls_ca_cert: None,
+            tls_danger_accept_invalid: None,
+            send_interval_ms: default_send_interval_ms(),
+            override_receiver: None,
+        };
+        assert_eq!(None, mirai.unix_socket_path());
+        assert_eq!("http://localhost:7827", mirai.base_url());
+
+        mirai.http_url = "unix:///run/mirai/http.sock".to_string();
+        assert_eq!(
+            Some(Path::new("/run/mirai/http.sock")),
+            mirai.unix_socket_path()
+        );
+        assert_eq!("http://localhost", mirai.base_url());
+        assert!(mirai.client(&HttpConfig::default()).is_ok());
+
+        for prefix in ["mirai", "/mirai", "/mirai/"] {
+            mirai.path_prefix = prefix.to_string();
+            assert_eq!("http://localhost/mirai", mirai.base_url());
+        }
+        mirai.http_url = "https://example.com/".to_string();
+        mirai.path_prefix = "/bot/mirai/".to_string();
+        assert_eq!("https://example.com/bot/mirai", mirai.base_url());
+
+        let mirai: MiraiConfig =
+            toml::from_str("http_url = \"unix:///run/mirai/http.sock\"\nverify_key = \"INITKEY\"")
+                .unwrap();
+        assert_eq!(1000, mirai.send_interval_ms);
+        assert_eq!(None, mirai.alert_after_failures);
+        assert_eq!(3600, mirai.alert_interval_sec);
+    }
+
+    #[test]
+    fn test_mirai_tls() {
+        let mut mirai = MiraiConfig {
+            http_url: "https://mirai.internal".to_string(),
+            path_prefix: String::new(),
+            verify_key: "INITKEY".to_string(),
+            notify_on_start: false,
+            admin_qq: None,
+            alert_after_failures: None,
+            alert_interval_sec: default_alert_interval_sec(),
+            tls_ca_cert: Some(PathBuf::from("./test_resources/test_ca.pem")),
+            tls_danger_accept_invalid: Some(true),
+            send_interval_ms: default_send_interval_ms(),
+            override_receiver: None,
+        };
+        assert!(mirai.client(&HttpConfig::default()).is_ok());
+
+        mirai.tls_ca_cert = Some(PathBuf::from("./test_resources/missing_ca.pem"));
+        let err = mirai.client(&HttpConfig::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("missing_ca.pem"));
+    }
+
+    #[test]
+    fn test_bili_for_target() {
+        let bili = BiliConfig {
+            sess_data: "GLOBAL".to_string(),
+            api_base_url: Some("http://localhost:8080".to_string()),
+            vc_api_base_url: None,
+            app_access_key: None,
+        };
+        let mut target = test_target();
+        assert_eq!("GLOBAL", bili.for_target(&target).sess_data);
+
+        target.sess_data = Some("TARGET".to_string());
+        let overridden = bili.for_target(&target);
+        assert_eq!("TARGET", overridden.sess_data);
+        assert_eq!(bili.api_base_url, overridden.api_base_url);
+    }
+
+    #[test]
+    fn test_resize_filter() {
+        assert_eq!(
+            ResizeFilter::Lanczos3,
+            RenderConfig::default().resize_filter
+        );
+        let render: RenderConfig = toml::from_str("resize_filter = \"catmull\"").unwrap();
+        assert_eq!(
+            image::imageops::FilterType::CatmullRom,
+            render.resize_filter.into()
+        );
+        assert!(toml::from_str::<RenderConfig>("resize_filter = \"bicubic\"").is_err());
+    }
+
+    #[test]
+    fn test_topic_target() {
+        let user = test_target();
+        assert!(user.check().is_ok());
+        assert_eq!("1", user.tree_name());
+
+        let mut topic: TargetConfig = toml::from_str(
+            "mode = \"topic\"\ntopic_id = 5\ninterval_sec = 10\nreceiver_qq = 2\nsender_qq = 3",
+        )
+        .unwrap();
+        assert_eq!(TargetMode::Topic, topic.mode);
+        assert!(topic.check().is_ok());
+        assert_eq!("topic_5", topic.tree_name());
+        assert_eq!("1", user.label());
+        assert_eq!("话题 5", topic.label());
+        topic.topic_name = Some("测试话题".to_string());
+        assert_eq!("话题 测试话题", topic.label());
+
+        topic.topic_id = None;
+        assert!(topic.check().is_err());
+        topic.mode = TargetMode::Realtime;
+        assert!(topic.check().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_config_dir() {
+        let dir = std::env::temp_dir().join("bili-dynamic-spider-config-dir");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join(TARGETS_DIR)).unwrap();
+
+        let target = |uid: u64| {
+            format!(
+                "[[target]]\nuid = {}\ninterval_sec = 10\nreceiver_qq = 2\nsender_qq = 3\n",
+                uid
+            )
+        };
+        std::fs::write(
+            dir.join(BASE_CONFIG_FILE),
+            format!(
+                "[db]\npath = \"spider.db\"\n[mirai]\nhttp_url = \"http://localhost:7827\"\nverify_key = \"KEY\"\n[bili]\nsess_data = \"SESSDATA\"\n{}",
+                target(1)
+            ),
+        )
+        .unwrap();
+        std::fs::write(dir.join(TARGETS_DIR).join("b.toml"), target(3)).unwrap();
+        // 与基础配置相同的设置可以重复出现
+        std::fs::write(
+            dir.join(TARGETS_DIR).join("a.toml"),
+            format!(
+                "[bili]\nsess_data = \"SESSDATA\"\n{}{}",
+                target(2),
+                target(4)
+            ),
+        )
+        .unwrap();
+        std::fs::write(dir.join(TARGETS_DIR).join("notes.txt"), "[[target]]").unwrap();
+
+        let config = get_config_from_file(&dir).await.unwrap();
+        assert_eq!(
+            vec![1, 2, 4, 3],
+            config.target.iter().map(|t| t.uid).collect::<Vec<_>>()
+        );
+        assert_eq!("SESSDATA", config.bili.sess_data);
+
+        // 读取配置时检查目标
+        std::fs::write(dir.join(TARGETS_DIR).join("d.toml"), target(0)).unwrap();
+        let err = get_config_from_file(&dir).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("uid"), "{:#}", err);
+        std::fs::remove_file(dir.join(TARGETS_DIR).join("d.toml")).unwrap();
+
+        // 不同的设置
+        std::fs::write(
+            dir.join(TARGETS_DIR).join("c.toml"),
+            "[bili]\nsess_data = \"OTHER\"\n",
+        )
+        .unwrap();
+        let err = get_config_from_file(&dir).await.unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("c.toml 中的 bili"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_target_defaults() {
+        let target = test_target();
+
+        assert!(target.send_header);
+        assert!(!target.include_top && !target.merged_forward);
+        assert_eq!(20, target.max_catch_up);
+        assert!(target.quiet_hours.is_none() && target.schedule.is_none());
+        assert!(target.dedup_window_sec.is_none());
+        assert!(!target.include_image_urls);
+        assert_eq!(DynamicListEndpoint::FeedSpace, target.endpoint);
+        assert_eq!(TargetMode::Realtime, target.mode);
+        assert_eq!("21:00", String::from(target.digest_at));
+
+        let target: TargetConfig = toml::from_str(
+            "uid = 1\ninterval_sec = 10\nreceiver_qq = 2\nsender_qq = 3\nendpoint = \"space_history\"",
+        )
+        .unwrap();
+        assert_eq!(DynamicListEndpoint::SpaceHistory, target.endpoint);
+    }
+
+    #[test]
+    fn test_poll_schedule() {
+        // 工作日 18:00-23:59 每5分钟
+        let schedule: PollSchedule = "0 */5 18-23 * * Mon-Fri".parse().unwrap();
+
+        // 2024-11-22 (周五) 18:02:30 +08:00
+        let friday_evening = 1732269750;
+        assert_eq!(Some(1732269900), schedule.next_after(friday_evening));
+
+        // 2024-11-23 (周六) 12:00:00 +08:00 -> 2024-11-25 (周一) 18:00:00 +08:00
+        assert_eq!(Some(1732528800), schedule.next_after(1732334400));
+        // 正好在抓取时间时返回下一次
+        assert_eq!(Some(1732270200), schedule.next_after(1732269900));
+
+        // 跨年的周日 09:30: 2024-12-31 23:59:30 -> 2025-01-05 09:30:00
+        let schedule: PollSchedule = "0 30 9 * * Sun".parse().unwrap();
+        assert_eq!(Some(1736040600), schedule.next_after(1735660770));
+
+        // 闰日: 2024-03-01 -> 2028-02-29 00:00:00
+        let schedule: PollSchedule = "0 0 0 29 2 *".parse().unwrap();
+        assert_eq!(Some(1835366400), schedule.next_after(1709222400));
+
+        // 已经过去的年份
+        let schedule: PollSchedule = "0 0 0 1 1 * 2020".parse().unwrap();
+        assert_eq!(None, schedule.next_after(1709222400));
+
+        assert!("every five minutes".parse::<PollSchedule>().is_err());
+    }
+
+    #[test]
+    fn test_http_ip_version() {
+        let http: HttpConfig = toml::from_str(r#"ip_version = "v4""#).unwrap();
+        assert_eq!(IpVersion::V4, http.ip_version);
+        assert_eq!(IpVersion::Auto, HttpConfig::default().ip_version);
+        assert!(toml::from_str::<HttpConfig>(r#"ip_version = "v5""#).is_err());
+
+        for ip_version in [IpVersion::Auto, IpVersion::V4, IpVersion::V6] {
+            let http = HttpConfig {
+                ip_version,
+                bili_proxy: None,
+            };
+            assert!(http.client().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_http_bili_proxy() {
+        let http: HttpConfig =
+            toml::from_str(r#"bili_proxy = "socks5h://127.0.0.1:1080""#).unwrap();
+        assert_eq!(Some("socks5h://127.0.0.1:1080"), http.bili_proxy.as_deref());
+        assert!(http.client().is_ok());
+        assert!(HttpConfig::default().bili_proxy.is_none());
+
+        let http = HttpConfig {
+            ip_version: IpVersion::Auto,
+            bili_proxy: Some("not a proxy".to_string()),
+        };
+        assert!(http.client().is_err());
+    }
+
+    #[test]
+    fn test_china_time_zone() {
+        // 使用打包的时区数据, 不会退回到固定偏移
+        let tz = china_time_zone();
+        assert_eq!(Some("Asia/Shanghai"), tz.iana_name());
+        // 2024-01-01 00:00 UTC
+        let zoned = jiff::Timestamp::from_second(1704067200)
+            .unwrap()
+            .to_zoned(tz);
+        assert_eq!((8, 0), (zoned.hour(), zoned.minute()));
+    }
+
+    #[test]
+    fn test_quiet_hours_across_midnight() {
+        let quiet: QuietHours = "23:00-07:00".parse().unwrap();
+
+        assert!(quiet.contains(23, 0));
+        assert!(quiet.contains(2, 30));
+        assert!(!quiet.contains(7, 0));
+        assert!(!quiet.contains(12, 0));
+        assert_eq!("23:00-07:00", String::from(quiet));
+    }
+
+    #[test]
+    fn test_quiet_hours_same_day() {
+        let quiet: QuietHours = "12:30-14:00".parse().unwrap();
+
+        assert!(quiet.contains(12, 30));
+        assert!(quiet.contains(13, 59));
+        assert!(!quiet.contains(14, 0));
+        assert!(!quiet.contains(23, 0));
+    }
+
+    #[test]
+    fn test_emoji_codepoint() {
+        assert_eq!(
+            EmojiCodepoint('🥺'),
+            "1F97A".parse::<EmojiCodepoint>().unwrap()
+        );
+        assert_eq!(
+            EmojiCodepoint('😭'),
+            "U+1f62d".parse::<EmojiCodepoint>().unwrap()
+        );
+        assert_eq!("U+1F97A", String::from(EmojiCodepoint('🥺')));
+        assert!("xyz".parse::<EmojiCodepoint>().is_err());
+        assert!("D800".parse::<EmojiCodepoint>().is_err());
+    }
+
+    #[test]
+    fn test_message_order() {
+        let render: RenderConfig = toml::from_str("").unwrap();
+        assert_eq!(
+            &[MessagePart::Header, MessagePart::Image, MessagePart::Urls],
+            render.message_order.parts()
+        );
+
+        let render: RenderConfig =
+            toml::from_str("message_order = [\"image\", \"text\", \"header\"]").unwrap();
+        assert_eq!(
+            &[MessagePart::Image, MessagePart::Text, MessagePart::Header],
+            render.message_order.parts()
+        );
+
+        for order in [
+            "[\"header\", \"video\"]",
+            "[\"header\", \"text\"]",
+            "[\"image\", \"image\"]",
+        ] {
+            assert!(
+                toml::from_str::<RenderConfig>(&format!("message_order = {}", order)).is_err(),
+                "{}",
+                order
+            );
+        }
+    }
+
+    #[test]
+    fn test_color() {
+        assert_eq!(Color([0, 161, 214]), "#00a1d6".parse::<Color>().unwrap());
+        assert_eq!(Color([244, 244, 244]), " #F4F4F4".parse::<Color>().unwrap());
+        assert_eq!("#00a1d6", String::from(Color([0, 161, 214])));
+        assert!("00a1d6".parse::<Color>().is_err());
+        assert!("#00a1d".parse::<Color>().is_err());
+        assert!("#00a1dz".parse::<Color>().is_err());
+        assert!("#00a1d我".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn test_digest_mode() {
+        let target: TargetConfig = toml::from_str(
+            "uid = 1\ninterval_sec = 10\nreceiver_qq = 2\nsender_qq = 3\nmode = \"digest\"\ndigest_at = \"8:05\"",
+        )
+        .unwrap();
+        assert_eq!(TargetMode::Digest, target.mode);
+        assert_eq!((8, 5), (target.digest_at.hour(), target.digest_at.minute()));
+        assert_eq!("08:05", String::from(target.digest_at));
+        assert!("24:00".parse::<TimeOfDay>().is_err());
+    }
 
-    toml::from_str(&content).context("Parse config file")
+    #[test]
+    fn test_quiet_hours_invalid() {
+        assert!("23:00".parse::<QuietHours>().is_err());
+        assert!("24:00-07:00".parse::<QuietHours>().is_err());
+        assert!("23:00-7".parse::<QuietHours>().is_err());
+    }
 }
@@ -0,0 +1,127 @@
+//! Bilibili API endpoints. Base urls can be overridden in `[bili]` config to point the
+//! spider at a mirror or a mock server.
+
+use crate::config::BiliConfig;
+
+pub const DEFAULT_API_BASE_URL: &str = "https://api.bilibili.com";
+pub const DEFAULT_VC_API_BASE_URL: &str = "https://api.vc.bilibili.com";
+
+fn api_base_url(bili: &BiliConfig) -> &str {
+    bili.api_base_url
+        .as_deref()
+        .unwrap_or(DEFAULT_API_BASE_URL)
+        .trim_end_matches('/')
+}
+
+fn vc_api_base_url(bili: &BiliConfig) -> &str {
+    bili.vc_api_base_url
+        .as_deref()
+        .unwrap_or(DEFAULT_VC_API_BASE_URL)
+        .trim_end_matches('/')
+}
+
+/// 动态详情 `https://api.bilibili.com/x/polymer/web-dynamic/v1/detail`
+pub fn dynamic_detail(bili: &BiliConfig) -> String {
+    format!("{}/x/polymer/web-dynamic/v1/detail", api_base_url(bili))
+}
+
+/// app使用的旧版动态详情 `https://api.vc.bilibili.com/dynamic_svr/v1/dynamic_svr/get_dynamic_detail`
+pub fn app_dynamic_detail(bili: &BiliConfig) -> String {
+    format!(
+        "{}/dynamic_svr/v1/dynamic_svr/get_dynamic_detail",
+        vc_api_base_url(bili)
+    )
+}
+
+/// 用户动态列表 `https://api.bilibili.com/x/polymer/web-dynamic/v1/feed/space`
+pub fn feed_space(bili: &BiliConfig) -> String {
+    format!("{}/x/polymer/web-dynamic/v1/feed/space", api_base_url(bili))
+}
+
+/// 话题动态列表 `https://api.bilibili.com/x/polymer/web-dynamic/v1/feed/topic`
+pub fn feed_topic(bili: &BiliConfig) -> String {
+    format!("{}/x/polymer/web-dynamic/v1/feed/topic", api_base_url(bili))
+}
+
+/// 旧版用户动态列表 `https://api.vc.bilibili.com/dynamic_svr/v1/dynamic_svr/space_history`
+pub fn space_history(bili: &BiliConfig) -> String {
+    format!(
+        "{}/dynamic_svr/v1/dynamic_svr/space_history",
+        vc_api_base_url(bili)
+    )
+}
+
+/// 互动抽奖详情 `https://api.vc.bilibili.com/lottery_svr/v1/lottery_svr/lottery_notice`,
+/// `business_id` 为抽奖动态的id
+pub fn lottery_notice(bili: &BiliConfig, business_id: &str) -> String {
+    format!(
+        "{}/lottery_svr/v1/lottery_svr/lottery_notice?business_type=1&business_id={}",
+        vc_api_base_url(bili),
+        business_id
+    )
+}
+
+/// 投票详情 `https://api.vc.bilibili.com/vote_svr/v1/vote_svr/vote_info`
+pub fn vote_info(bili: &BiliConfig, vote_id: &str) -> String {
+    format!(
+        "{}/vote_svr/v1/vote_svr/vote_info?vote_id={}",
+        vc_api_base_url(bili),
+        vote_id
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoints_override() {
+        let mut bili = BiliConfig {
+            sess_data: "SESSDATA".to_string(),
+            api_base_url: None,
+            vc_api_base_url: None,
+            app_access_key: None,
+        };
+
+        assert_eq!(
+            "https://api.bilibili.com/x/polymer/web-dynamic/v1/detail",
+            dynamic_detail(&bili)
+        );
+        assert_eq!(
+            "https://api.bilibili.com/x/polymer/web-dynamic/v1/feed/space",
+            feed_space(&bili)
+        );
+        assert_eq!(
+            "https://api.bilibili.com/x/polymer/web-dynamic/v1/feed/topic",
+            feed_topic(&bili)
+        );
+        assert_eq!(
+            "https://api.vc.bilibili.com/dynamic_svr/v1/dynamic_svr/space_history",
+            space_history(&bili)
+        );
+        assert_eq!(
+            "https://api.vc.bilibili.com/lottery_svr/v1/lottery_svr/lottery_notice?business_type=1&business_id=123",
+            lottery_notice(&bili, "123")
+        );
+        assert_eq!(
+            "https://api.vc.bilibili.com/vote_svr/v1/vote_svr/vote_info?vote_id=456",
+            vote_info(&bili, "456")
+        );
+
+        bili.api_base_url = Some("http://127.0.0.1:8080/".to_string());
+        bili.vc_api_base_url = Some("http://127.0.0.1:8081".to_string());
+
+        assert_eq!(
+            "http://127.0.0.1:8080/x/polymer/web-dynamic/v1/detail",
+            dynamic_detail(&bili)
+        );
+        assert_eq!(
+            "http://127.0.0.1:8080/x/polymer/web-dynamic/v1/feed/space",
+            feed_space(&bili)
+        );
+        assert_eq!(
+            "http://127.0.0.1:8081/dynamic_svr/v1/dynamic_svr/space_history",
+            space_history(&bili)
+        );
+    }
+}
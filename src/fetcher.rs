@@ -0,0 +1,410 @@
+use std::{
+    future::Future,
+    io::{BufReader, Cursor},
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use anyhow::Context;
+use image::{
+    codecs::webp::WebPDecoder, AnimationDecoder, ImageFormat, ImageReader, Rgba, RgbaImage,
+};
+use reqwest::Client;
+use tracing::warn;
+
+/// Source of the images (avatars, emoji, pictures, covers) referenced by a dynamic, and of
+/// the public details (e.g. lottery results) fetched while rendering it
+pub trait ImageFetcher: Sync {
+    /// Fetch the raw bytes at `url`
+    fn fetch_bytes(&self, url: &str) -> impl Future<Output = anyhow::Result<Vec<u8>>> + Send;
+
+    /// Fetch the image at `url` and decode it into RGBA
+    fn fetch_image(&self, url: &str) -> impl Future<Output = anyhow::Result<RgbaImage>> + Send {
+        async move {
+            let bytes = self.fetch_bytes(url).await?;
+
+            decode_image(&bytes)
+        }
+    }
+
+    /// Fetch a picture of the dynamic's content at `url`, such as an album image or a cover.
+    /// Unlike avatars and emoji these can be large, so wrappers may limit them. `size` is the
+    /// original width and height of the picture when the API reports it, used to give
+    /// placeholders the picture's aspect ratio
+    fn fetch_picture(
+        &self,
+        url: &str,
+        _size: Option<(u32, u32)>,
+    ) -> impl Future<Output = anyhow::Result<RgbaImage>> + Send {
+        self.fetch_image(url)
+    }
+}
+
+/// Decode an image into RGBA. Animated WebP is decoded to a representative still, since its
+/// default frame may be blank
+pub fn decode_image(bytes: &[u8]) -> anyhow::Result<RgbaImage> {
+    let reader = ImageReader::new(BufReader::new(Cursor::new(bytes))).with_guessed_format()?;
+
+    if reader.format() == Some(ImageFormat::WebP) {
+        let decoder = WebPDecoder::new(Cursor::new(bytes))?;
+        if decoder.has_animation() {
+            return representative_frame(decoder);
+        }
+    }
+
+    Ok(reader.decode()?.into_rgba8())
+}
+
+/// The first frame with a visible pixel, or the middle frame if all of them are transparent
+fn representative_frame(decoder: WebPDecoder<Cursor<&[u8]>>) -> anyhow::Result<RgbaImage> {
+    let mut frames = Vec::new();
+
+    for frame in decoder.into_frames() {
+        let frame = frame?.into_buffer();
+        if frame.pixels().any(|p| p[3] != 0) {
+            return Ok(frame);
+        }
+        frames.push(frame);
+    }
+
+    let middle = frames.len() / 2;
+    frames
+        .into_iter()
+        .nth(middle)
+        .ok_or_else(|| anyhow::anyhow!("Animated WebP has no frames"))
+}
+
+/// Download images over HTTP
+pub struct ReqwestFetcher {
+    client: Client,
+}
+
+impl ReqwestFetcher {
+    pub fn new(client: Client) -> ReqwestFetcher {
+        ReqwestFetcher { client }
+    }
+}
+
+impl ImageFetcher for ReqwestFetcher {
+    async fn fetch_bytes(&self, url: &str) -> anyhow::Result<Vec<u8>> {
+        let response = self.client.get(url).send().await?.error_for_status()?;
+
+        let bytes = response.bytes().await?;
+
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Read images archived under a directory at `<host>/<path>` of their urls, the layout
+/// `wget -x` creates, so a dynamic can be re-rendered without network access.
+/// Bilibili image processing suffixes like `@518w.webp` and query strings are ignored
+pub struct LocalFetcher {
+    dir: PathBuf,
+}
+
+impl LocalFetcher {
+    pub fn new(dir: impl AsRef<Path>) -> LocalFetcher {
+        LocalFetcher {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Path of the archived file for `url`, `None` if the url can't be mapped into the directory
+    pub fn path_for(&self, url: &str) -> Option<PathBuf> {
+        let url = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"))
+            .or_else(|| url.strip_prefix("//"))?;
+        let url = url.split(['@', '?']).next()?;
+
+        let relative = Path::new(url);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return None;
+        }
+
+        Some(self.dir.join(relative))
+    }
+}
+
+impl ImageFetcher for LocalFetcher {
+    async fn fetch_bytes(&self, url: &str) -> anyhow::Result<Vec<u8>> {
+        let path = self
+            .path_for(url)
+            .ok_or_else(|| anyhow::anyhow!("No local file for {}", url))?;
+
+        tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("Read {} for {}", path.display(), url))
+    }
+}
+
+/// Replace every image with a gray placeholder instead of downloading it when `enabled`, so a
+/// card shows only its layout and text. Other requests (e.g. lottery results) still go to `inner`
+pub struct SkeletonFetcher<F> {
+    inner: F,
+    enabled: bool,
+}
+
+/// 占位图片的颜色
+pub const PLACEHOLDER_COLOR: Rgba<u8> = Rgba([220, 220, 220, 255]);
+/// 地址中没有指定大小时占位图片的边长
+const PLACEHOLDER_SIZE: u32 = 100;
+
+impl<F> SkeletonFetcher<F> {
+    pub fn new(inner: F, enabled: bool) -> SkeletonFetcher<F> {
+        SkeletonFetcher { inner, enabled }
+    }
+}
+
+impl<F: ImageFetcher> ImageFetcher for SkeletonFetcher<F> {
+    fn fetch_bytes(&self, url: &str) -> impl Future<Output = anyhow::Result<Vec<u8>>> + Send {
+        self.inner.fetch_bytes(url)
+    }
+
+    async fn fetch_image(&self, url: &str) -> anyhow::Result<RgbaImage> {
+        if !self.enabled {
+            return self.inner.fetch_image(url).await;
+        }
+
+        let (width, height) = placeholder_size(url, None);
+        Ok(RgbaImage::from_pixel(width, height, PLACEHOLDER_COLOR))
+    }
+
+    async fn fetch_picture(
+        &self,
+        url: &str,
+        size: Option<(u32, u32)>,
+    ) -> anyhow::Result<RgbaImage> {
+        if !self.enabled {
+            return self.inner.fetch_picture(url, size).await;
+        }
+
+        let (width, height) = placeholder_size(url, size);
+        Ok(RgbaImage::from_pixel(width, height, PLACEHOLDER_COLOR))
+    }
+}
+
+/// Stop downloading content pictures once `max_bytes` of them have been downloaded, and return
+/// gray placeholders for the rest. Downloads already in flight when the cap is reached still
+/// complete. Avatars, emoji and other requests (e.g. lottery results) are neither counted nor
+/// limited
+pub struct CappedFetcher<F> {
+    inner: F,
+    max_bytes: Option<u64>,
+    downloaded: AtomicU64,
+    // 只在第一次超过上限时记录日志
+    capped: AtomicBool,
+}
+
+impl<F> CappedFetcher<F> {
+    pub fn new(inner: F, max_bytes: Option<u64>) -> CappedFetcher<F> {
+        CappedFetcher {
+            inner,
+            max_bytes,
+            downloaded: AtomicU64::new(0),
+            capped: AtomicBool::new(false),
+        }
+    }
+
+    /// Bytes of content pictures downloaded so far
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+}
+
+impl<F: ImageFetcher> ImageFetcher for CappedFetcher<F> {
+    fn fetch_bytes(&self, url: &str) -> impl Future<Output = anyhow::Result<Vec<u8>>> + Send {
+        self.inner.fetch_bytes(url)
+    }
+
+    fn fetch_image(&self, url: &str) -> impl Future<Output = anyhow::Result<RgbaImage>> + Send {
+        self.inner.fetch_image(url)
+    }
+
+    async fn fetch_picture(
+        &self,
+        url: &str,
+        size: Option<(u32, u32)>,
+    ) -> anyhow::Result<RgbaImage> {
+        if let Some(max_bytes) = self.max_bytes {
+            if self.downloaded() >= max_bytes {
+                if !self.capped.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        "本轮已下载 {} 字节图片, 超过上限 {}, 其余图片以占位图代替",
+                        self.downloaded(),
+                        max_bytes
+                    );
+                }
+                let (width, height) = placeholder_size(url, size);
+                return Ok(RgbaImage::from_pixel(width, height, PLACEHOLDER_COLOR));
+            }
+        }
+
+        let bytes = self.inner.fetch_bytes(url).await?;
+        self.downloaded
+            .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+
+        decode_image(&bytes)
+    }
+}
+
+/// Size of the placeholder for `url`, from the bilibili image processing suffix like
+/// `@518w.webp` or `@203w_127h_1e_1c.webp`. A missing height follows the aspect ratio of the
+/// original `size` when known, and makes a square otherwise
+fn placeholder_size(url: &str, size: Option<(u32, u32)>) -> (u32, u32) {
+    let size = size.filter(|&(width, height)| width > 0 && height > 0);
+    let Some((_, suffix)) = url.rsplit_once('@') else {
+        return size.unwrap_or((PLACEHOLDER_SIZE, PLACEHOLDER_SIZE));
+    };
+
+    let mut width = None;
+    let mut height = None;
+    for part in suffix.split(['_', '.']) {
+        if let Some(w) = part.strip_suffix('w').and_then(|w| w.parse().ok()) {
+            width = Some(w);
+        } else if let Some(h) = part.strip_suffix('h').and_then(|h| h.parse().ok()) {
+            height = Some(h);
+        }
+    }
+
+    let width = width.unwrap_or(PLACEHOLDER_SIZE).max(1);
+    let height = height.unwrap_or_else(|| match size {
+        Some((original_width, original_height)) => {
+            (width as u64 * original_height as u64 / original_width as u64) as u32
+        }
+        None => width,
+    });
+    (width, height.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_animated_webp() {
+        // 三帧: 全透明, 红色, 蓝色
+        let bytes = include_bytes!("../test_resources/animated.webp");
+        let image = decode_image(bytes).unwrap();
+        assert_eq!((8, 8), image.dimensions());
+        assert_eq!([255, 0, 0, 255], image.get_pixel(4, 4).0);
+
+        let png = decode_image(include_bytes!("../test_resources/face.png")).unwrap();
+        assert!(png.width() > 0);
+    }
+
+    #[test]
+    fn test_local_fetcher_path() {
+        let fetcher = LocalFetcher::new("archive");
+
+        assert_eq!(
+            Some(PathBuf::from("archive/i0.hdslb.com/bfs/new_dyn/a.jpg")),
+            fetcher.path_for("https://i0.hdslb.com/bfs/new_dyn/a.jpg@518w.webp")
+        );
+        assert_eq!(
+            Some(PathBuf::from("archive/i0.hdslb.com/bfs/face/b.png")),
+            fetcher.path_for("http://i0.hdslb.com/bfs/face/b.png?x=1")
+        );
+        assert_eq!(
+            None,
+            fetcher.path_for("https://i0.hdslb.com/../../etc/passwd")
+        );
+        assert_eq!(None, fetcher.path_for("/etc/passwd"));
+    }
+
+    #[test]
+    fn test_placeholder_size() {
+        assert_eq!(
+            (518, 518),
+            placeholder_size("https://i0.hdslb.com/a.jpg@518w.webp", None)
+        );
+        assert_eq!(
+            (203, 127),
+            placeholder_size("https://i0.hdslb.com/a.jpg@203w_127h_1e_1c.webp", None)
+        );
+        assert_eq!(
+            (270, 270),
+            placeholder_size("https://i0.hdslb.com/a.jpg@270w_270h_!header.webp", None)
+        );
+        assert_eq!(
+            (100, 100),
+            placeholder_size("https://i0.hdslb.com/face.png", None)
+        );
+        // 没有指定高度时按原图的宽高比
+        assert_eq!(
+            (518, 259),
+            placeholder_size("https://i0.hdslb.com/a.jpg@518w.webp", Some((2000, 1000)))
+        );
+        assert_eq!(
+            (203, 127),
+            placeholder_size(
+                "https://i0.hdslb.com/a.jpg@203w_127h_1e_1c.webp",
+                Some((2000, 1000))
+            )
+        );
+        assert_eq!(
+            (2000, 1000),
+            placeholder_size("https://i0.hdslb.com/a.jpg", Some((2000, 1000)))
+        );
+        assert_eq!(
+            (518, 518),
+            placeholder_size("https://i0.hdslb.com/a.jpg@518w.webp", Some((0, 0)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_skeleton_fetcher() {
+        let url = "https://i0.hdslb.com/bfs/new_dyn/a.jpg@203w_127h_1e_1c.webp";
+
+        // 不读取本地文件, 直接返回占位图片
+        let fetcher = SkeletonFetcher::new(LocalFetcher::new("missing"), true);
+        let image = fetcher.fetch_image(url).await.unwrap();
+        assert_eq!((203, 127), image.dimensions());
+        assert!(image.pixels().all(|p| *p == PLACEHOLDER_COLOR));
+        assert!(fetcher.fetch_bytes(url).await.is_err());
+
+        let fetcher = SkeletonFetcher::new(LocalFetcher::new("missing"), false);
+        assert!(fetcher.fetch_image(url).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_capped_fetcher() {
+        let dir = std::env::temp_dir().join("bili-dynamic-spider-capped-fetcher");
+        let image_dir = dir.join("i0.hdslb.com/bfs/new_dyn");
+        std::fs::create_dir_all(&image_dir).unwrap();
+        let face = include_bytes!("../test_resources/face.png");
+        std::fs::write(image_dir.join("a.png"), face).unwrap();
+        let url = "https://i0.hdslb.com/bfs/new_dyn/a.png@50w_40h.webp";
+
+        // 第一张图片下载后超过上限, 之后的图片使用占位图
+        let fetcher = CappedFetcher::new(LocalFetcher::new(&dir), Some(1));
+        let image = fetcher.fetch_picture(url, None).await.unwrap();
+        assert_ne!((50, 40), image.dimensions());
+        assert_eq!(face.len() as u64, fetcher.downloaded());
+        let placeholder = fetcher.fetch_picture(url, None).await.unwrap();
+        assert_eq!((50, 40), placeholder.dimensions());
+        assert!(placeholder.pixels().all(|p| *p == PLACEHOLDER_COLOR));
+        assert_eq!(face.len() as u64, fetcher.downloaded());
+        // 头像、表情等图片和其他请求不受限制, 也不计入下载量
+        assert_ne!(
+            (50, 40),
+            fetcher.fetch_image(url).await.unwrap().dimensions()
+        );
+        assert!(fetcher.fetch_bytes(url).await.is_ok());
+        assert_eq!(face.len() as u64, fetcher.downloaded());
+
+        let fetcher = CappedFetcher::new(LocalFetcher::new(&dir), None);
+        for _ in 0..2 {
+            assert_ne!(
+                (50, 40),
+                fetcher.fetch_picture(url, None).await.unwrap().dimensions()
+            );
+        }
+        assert_eq!(2 * face.len() as u64, fetcher.downloaded());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
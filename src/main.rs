@@ -1,35 +1,55 @@
+mod alert;
+mod colr;
 mod config;
+mod endpoints;
+mod fetcher;
 mod painter;
 mod resource;
+mod stats;
+mod store;
 
 use std::{
     cmp,
-    io::{BufReader, Cursor},
+    collections::HashMap,
+    future::Future,
+    io::Cursor,
+    path::{Path, PathBuf},
     str::FromStr,
     time::Duration,
 };
 
 use ab_glyph::PxScale;
+use alert::TargetAlerts;
 use anyhow::{anyhow, Context};
 use base64::Engine;
-use config::{get_config_from_file, BiliConfig, Config, MiraiConfig, TargetConfig};
+use config::{
+    china_time_zone, get_config_from_file, BiliConfig, Config, DownloadConfig, DynamicListEndpoint,
+    EmojiCodepoint, HeaderStyle, MessagePart, MiraiConfig, OutputFormat, RemoteTargetsConfig,
+    RenderConfig, TargetConfig, TargetMode, TimeOfDay, TimeStyle,
+};
+use fetcher::{
+    CappedFetcher, ImageFetcher, LocalFetcher, ReqwestFetcher, SkeletonFetcher, PLACEHOLDER_COLOR,
+};
+use futures::StreamExt;
 use image::{
+    codecs::jpeg::JpegEncoder,
     imageops::{self, FilterType},
-    ImageReader, Rgba, RgbaImage,
+    Rgb, RgbImage, Rgba, RgbaImage,
 };
-use jiff::{
-    fmt::strtime,
-    tz::{Offset, TimeZone},
-    Timestamp,
-};
-use painter::{create_circular_image, draw_content_image, PicGenerator};
-use reqwest::{Client, IntoUrl};
-use resource::RESOURCE;
+use jiff::{fmt::strtime, Timestamp};
+use painter::{create_circular_image, draw_content_image, line_height, PicGenerator};
+use qrcode::QrCode;
+use reqwest::Client;
+use resource::resource;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use sled::Tree;
-use tokio::task::JoinSet;
-use tracing::{debug, error, info, warn};
+use serde_json::{json, Value};
+use stats::Outcome;
+use store::TargetTree;
+use tokio::{
+    sync::mpsc,
+    task::{AbortHandle, JoinSet},
+};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use tracing_subscriber::{filter::Targets, layer::SubscriberExt, util::SubscriberInitExt};
 
 const TEXT_SCALE: PxScale = uniform_scale(30.0);
@@ -43,31 +63,82 @@ const fn uniform_scale(s: f32) -> PxScale {
 const WHITE: Rgba<u8> = Rgba::<u8>([255, 255, 255, 255]);
 const BLACK: Rgba<u8> = Rgba::<u8>([0, 0, 0, 255]);
 const GRAY: Rgba<u8> = Rgba::<u8>([169, 169, 169, 255]);
-const LIGHT_GRAY: Rgba<u8> = Rgba::<u8>([244, 244, 244, 255]);
 const PINK: Rgba<u8> = Rgba::<u8>([251, 114, 153, 255]);
-const DEEP_BLUE: Rgba<u8> = Rgba::<u8>([175, 238, 238, 255]);
+const ORANGE: Rgba<u8> = Rgba::<u8>([246, 154, 0, 255]);
+// 比 `GRAY` 深, 在浅灰色的占位图片上也看得清
+const FAILED_IMAGE_TEXT_COLOR: Rgba<u8> = Rgba::<u8>([120, 120, 120, 255]);
+
+// 动态卡片宽度
+const CARD_WIDTH: u32 = 740;
+// 动态内容距卡片左右边缘的距离
+const CONTENT_X: u32 = 25;
+// 转发动态中原动态相对转发内容的缩进
+const FORWARD_INDENT: u32 = 20;
+// 原作者头像和 `@原作者` 之间的距离
+const FORWARD_AVATAR_GAP: u32 = 8;
+// 定位图标和地点名称之间的间距
+const LOCATION_ICON_GAP: u32 = 6;
+// 视频和专栏卡片中封面缩略图的宽和高
+const ARCHIVE_COVER_SIZE: (u32, u32) = (192, 120);
+// 封面缩略图和标题之间的间距
+const ARCHIVE_COVER_GAP: u32 = 15;
+// 相册图片之间的间距
+const IMAGE_MARGIN: u32 = 10;
+// 直播间二维码的最大边长
+const LIVE_QR_SIZE: u32 = 120;
+// 动态链接二维码的最大边长为卡片宽度的 1/6
+const DYNAMIC_QR_WIDTH_DIVISOR: u32 = 6;
+// 限制卡片高度时为截断处"阅读全文"一行留出的高度
+const READ_MORE_HEIGHT: u32 = 50;
+// 头像框边长相对头像直径的百分比
+const PENDANT_SCALE_PERCENT: u32 = 170;
+// 投票结果横条的高度
+const VOTE_BAR_HEIGHT: u32 = 20;
+// 被裁剪的相册图片边框的宽度
+const CROPPED_BORDER_WIDTH: u32 = 2;
+// 模糊有内容警告的图片前先缩小的倍数
+const FLAGGED_BLUR_DOWNSCALE: u32 = 8;
+// 缩小后的图片上高斯模糊的sigma
+const FLAGGED_BLUR_SIGMA: f32 = 4.0;
 
 const DYNAMIC_TYPE_DRAW: &str = "DYNAMIC_TYPE_DRAW"; // 带图动态
 const DYNAMIC_TYPE_FORWARD: &str = "DYNAMIC_TYPE_FORWARD"; //转发动态
 const DYNAMIC_TYPE_WORD: &str = "DYNAMIC_TYPE_WORD"; // 纯文字动态
 const DYNAMIC_TYPE_LIVE: &str = "DYNAMIC_TYPE_LIVE"; // 直播动态
+const DYNAMIC_TYPE_AV: &str = "DYNAMIC_TYPE_AV"; // 视频动态
+const DYNAMIC_TYPE_ARTICLE: &str = "DYNAMIC_TYPE_ARTICLE"; // 专栏动态
+
+/// 动态中的投票
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct VoteInfo {
+    options: Vec<VoteOption>,
+    // 投票是否已结束
+    concluded: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct VoteOption {
+    desc: String,
+    // 得票数
+    count: u64,
+}
 
 #[derive(Debug)]
 enum RichTextNode {
     // RICH_TEXT_NODE_TYPE_TEXT
     Text { text: String },
-    // RICH_TEXT_NODE_TYPE_EMOJI
-    Emoji { img: RgbaImage },
+    // RICH_TEXT_NODE_TYPE_EMOJI, `alt`为表情的文字形式, 如`[doge]`
+    Emoji { img: RgbaImage, alt: String },
     // RICH_TEXT_NODE_TYPE_WEB
     Web,
     // RICH_TEXT_NODE_TYPE_BV
     Bv,
-    // RICH_TEXT_NODE_TYPE_LOTTERY
-    Lottery,
-    // RICH_TEXT_NODE_TYPE_VOTE
-    Vote,
-    // RICH_TEXT_NODE_TYPE_GOODS
-    Goods,
+    // RICH_TEXT_NODE_TYPE_LOTTERY, `concluded`为抽奖是否已开奖
+    Lottery { concluded: bool },
+    // RICH_TEXT_NODE_TYPE_VOTE, `info`为投票详情, 获取失败时为`None`
+    Vote { info: Option<VoteInfo> },
+    // RICH_TEXT_NODE_TYPE_GOODS, 商品名
+    Goods { text: Option<String> },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -77,8 +148,163 @@ struct DbEntry {
     // 当前动态类型(`https://github.com/SocialSisterYi/bilibili-API-collect/blob/master/docs/dynamic/card_info.md`)
     #[serde(rename = "type")]
     type_: i32,
+    // 创建消息失败的次数
+    #[serde(default)]
+    attempts: u32,
+    // 是否是置顶动态
+    #[serde(default)]
+    pinned: bool,
+    // 第一次发现该动态的时间戳(秒), 旧数据为0
+    #[serde(default)]
+    first_seen_ts: i64,
+    // 发送成功的时间戳(秒)
+    #[serde(default)]
+    sent_ts: Option<i64>,
+    // 动态文字和图片地址的哈希, 用于跳过内容相同的动态, 开启 `resend_on_edit` 时也用于发现动态被编辑
+    #[serde(default)]
+    content_hash: Option<u64>,
+    // 最近一次发送编辑通知的时间戳(秒)
+    #[serde(default)]
+    edit_sent_ts: Option<i64>,
+    // 发送前先把 `sent` 记为true, 发送成功后才确认. 已发送但未确认说明上次在发送之后、
+    // 确认之前退出, 消息很可能已经发出, 重启后不再重发
+    #[serde(default = "default_confirmed")]
+    confirmed: bool,
+}
+
+fn default_confirmed() -> bool {
+    true
+}
+
+/// 数据库中动态的键, 为动态ID的十进制字符串. 与旧版本用 `serde_json` 序列化的 `i64` 相同
+fn dynamic_key(dynamic_id: i64) -> Vec<u8> {
+    dynamic_id.to_string().into_bytes()
+}
+
+/// 数据库中保存动态已渲染消息的键, 见 [`RenderedMessages`]
+fn rendered_key(dynamic_id: i64) -> Vec<u8> {
+    format!("rendered_{}", dynamic_id).into_bytes()
+}
+
+/// 开启 `render.persist_rendered` 时保存的已渲染消息, 发送成功后删除
+#[derive(Debug, Serialize, Deserialize)]
+struct RenderedMessages {
+    content_hash: u64,
+    messages: Vec<Message>,
+}
+
+/// 读取保存的已渲染消息, 读取失败时重新渲染
+fn load_rendered(db: &TargetTree, dynamic_id: i64) -> Option<RenderedMessages> {
+    let value = db
+        .get(rendered_key(dynamic_id))
+        .inspect_err(|e| warn!("读取动态 {} 保存的消息失败: {}", dynamic_id, e))
+        .ok()??;
+    serde_json::from_slice(&value)
+        .inspect_err(|e| warn!("动态 {} 保存的消息格式错误: {}", dynamic_id, e))
+        .ok()
+}
+
+/// 数据库中保存 [`TreeMeta`] 的键, 不是合法的动态ID
+const META_KEY: &[u8] = b"meta";
+
+/// 每个目标的数据库中动态记录的汇总, 避免每轮抓取都遍历整个数据库.
+/// 只由 [`insert_entry`], 重发时的遍历和 [`send_digest`] 更新
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct TreeMeta {
+    // 数据库中最新的非置顶动态ID
+    newest_dynamic_id: Option<i64>,
+    // 数据库中可能有未发送的动态, 需要遍历数据库重发
+    has_unsent: bool,
+    // 摘要模式下最近一次发送摘要的时间戳(秒)
+    #[serde(default)]
+    last_digest_ts: Option<i64>,
+}
+
+/// 读取数据库的汇总信息. 旧数据库没有汇总时遍历一次数据库, 并假设有未发送的动态
+fn tree_meta(db: &TargetTree) -> TreeMeta {
+    let saved = db
+        .get(META_KEY)
+        .ok()
+        .flatten()
+        .and_then(|v| serde_json::from_slice(&v).ok());
+
+    saved.unwrap_or_else(|| TreeMeta {
+        newest_dynamic_id: scan_newest_dynamic(db),
+        has_unsent: true,
+        last_digest_ts: None,
+    })
+}
+
+fn save_tree_meta(db: &TargetTree, meta: &TreeMeta) -> anyhow::Result<()> {
+    db.insert(META_KEY, serde_json::to_vec(meta)?)?;
+    Ok(())
+}
+
+/// 写入一条新的或者变为未发送的动态记录, 同时原子地更新汇总信息
+fn insert_entry(db: &TargetTree, dynamic_id: i64, entry: &DbEntry) -> anyhow::Result<()> {
+    let mut meta = tree_meta(db);
+    if !entry.pinned {
+        meta.newest_dynamic_id = cmp::max(meta.newest_dynamic_id, Some(dynamic_id));
+    }
+    meta.has_unsent |= !entry.sent;
+
+    db.insert_all([
+        (dynamic_key(dynamic_id), serde_json::to_vec(entry)?),
+        (META_KEY.to_vec(), serde_json::to_vec(&meta)?),
+    ])?;
+    if entry.sent && entry.confirmed {
+        db.remove(rendered_key(dynamic_id))?;
+    }
+
+    Ok(())
+}
+
+/// 更新一条已有的动态记录. 已发送并确认的动态不会再重发, 同时删除保存的已渲染消息
+fn save_entry(db: &TargetTree, dynamic_id: i64, entry: &DbEntry) -> anyhow::Result<()> {
+    db.insert(dynamic_key(dynamic_id), serde_json::to_vec(entry)?)?;
+    if entry.sent && entry.confirmed {
+        db.remove(rendered_key(dynamic_id))?;
+    }
+
+    Ok(())
+}
+
+/// 从数据库的键解析动态ID
+fn parse_dynamic_key(key: &[u8]) -> Option<i64> {
+    std::str::from_utf8(key).ok()?.parse().ok()
+}
+
+/// 解析接口返回的动态ID. 优先使用字符串形式(`id_str`/`dynamic_id_str`),
+/// 数字形式只接受整数, 不经过浮点数以免丢失精度
+fn parse_dynamic_id(value: &Value) -> anyhow::Result<i64> {
+    match value {
+        Value::String(s) => s
+            .parse()
+            .with_context(|| format!("不合法的动态ID: {:?}", s)),
+        Value::Number(n) => n.as_i64().ok_or_else(|| anyhow!("不合法的动态ID: {}", n)),
+        _ => Err(anyhow!("缺少动态ID: {}", value)),
+    }
 }
 
+// 达到该次数后即使图片没有全部下载成功也发送
+const MAX_BUILD_ATTEMPTS: u32 = 3;
+// 第一次监听一个用户时获取的最新动态数量
+const INITIAL_DYNAMICS: usize = 3;
+// 渲染完成等待发送的动态数量, 渲染和发送同时进行
+const RENDER_QUEUE_SIZE: usize = 1;
+// 一轮抓取中同时获取详情并渲染的动态数量, 避免同时发出过多请求
+const RENDER_CONCURRENCY: usize = 3;
+// 每小时在日志中输出一次按动态类型统计的处理结果
+const STATS_LOG_INTERVAL_SEC: u64 = 3600;
+// 接口返回格式异常时最多等待 2^6 倍的抓取间隔
+const MAX_BACKOFF_EXPONENT: u32 = 6;
+// 同一条动态两次编辑通知的最小间隔(秒)
+const EDIT_RESEND_INTERVAL_SEC: i64 = 600;
+// 摘要中相邻两张动态卡片的间距
+const DIGEST_GAP: u32 = 20;
+// 摘要中分隔动态卡片的横条的颜色
+const DIGEST_GAP_COLOR: Rgba<u8> = Rgba::<u8>([230, 230, 230, 255]);
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // set log collector
@@ -90,964 +316,7090 @@ async fn main() -> anyhow::Result<()> {
         .with(format_layer)
         .init();
 
-    info!("日志配置完成, 从spider.toml中读取爬虫配置");
+    let Args {
+        config_path,
+        check_mirai,
+        mark,
+        render_archive,
+        transfer,
+    } = parse_args(std::env::args().skip(1))?;
+
+    info!("日志配置完成, 从{}中读取爬虫配置", config_path.display());
 
     let Config {
         db: db_config,
         mirai,
         bili,
+        render,
+        http,
+        download,
         target,
-    } = get_config_from_file("spider.toml")
+        stagger_sec,
+        remote_targets,
+    } = get_config_from_file(&config_path)
         .await
         .context("Get config for spider")?;
 
-    let db = sled::open(db_config.path)?;
+    if check_mirai {
+        return check_mirai_connection(&mirai, &target, &mirai.client(&http)?).await;
+    }
+
+    if let Some(mark) = mark {
+        let db = db_config.sled_config()?.open()?;
+        let tree = TargetTree::open(&db, &mark.uid.to_string(), db_config.single_tree)?;
+        let entry = mark_dynamic(&tree, mark.dynamic_id, mark.sent)?;
+        db.flush_async().await?;
+        println!(
+            "用户 {} 的动态 {} 已标记为{}: {:?}",
+            mark.uid,
+            mark.dynamic_id,
+            if mark.sent { "已发送" } else { "未发送" },
+            entry
+        );
+        return Ok(());
+    }
+
+    if let Some(transfer) = transfer {
+        let db = db_config.sled_config()?.open()?;
+        let tree = TargetTree::open(&db, &transfer.uid.to_string(), db_config.single_tree)?;
+        let path = transfer.path.display();
+        if transfer.export {
+            let entries = export_entries(&tree)?;
+            std::fs::write(&transfer.path, serde_json::to_vec_pretty(&entries)?)
+                .with_context(|| format!("写入 {} 失败", path))?;
+            println!(
+                "已将用户 {} 的 {} 条动态记录导出到 {}",
+                transfer.uid,
+                entries.len(),
+                path
+            );
+        } else {
+            let bytes =
+                std::fs::read(&transfer.path).with_context(|| format!("读取 {} 失败", path))?;
+            let entries = serde_json::from_slice(&bytes)
+                .with_context(|| format!("{} 不是导出的动态记录", path))?;
+            let count = import_entries(&tree, entries)?;
+            db.flush_async().await?;
+            println!(
+                "已从 {} 导入用户 {} 的 {} 条动态记录",
+                path, transfer.uid, count
+            );
+        }
+        return Ok(());
+    }
+
+    let emoji_dir = render
+        .emoji_dir
+        .as_deref()
+        .filter(|_| render.all_emoji_from_dir);
+    resource::init(&render.resource_dir, &render.fallback_fonts, emoji_dir)
+        .context("加载绘图资源")?;
+
+    if let Some(archive) = render_archive {
+        let detail: Value = serde_json::from_slice(
+            &tokio::fs::read(&archive.detail_path)
+                .await
+                .with_context(|| format!("读取 {} 失败", archive.detail_path.display()))?,
+        )?;
+        let image = render_archived(&bili, &render, &detail, &archive.image_dir).await?;
+        image.save(&archive.output_path)?;
+        println!("已绘制到 {}", archive.output_path.display());
+        return Ok(());
+    }
+
+    let db = db_config.sled_config()?.open()?;
+    if db_config.single_tree {
+        let migrated = store::migrate_to_single_tree(&db)?;
+        if migrated > 0 {
+            info!("已将 {} 个目标的数据库树合并到共用的树中", migrated);
+        }
+    }
+    let client = http.client()?;
+    let mirai_client = mirai.client(&http)?;
+
+    if let Some(qq) = mirai.override_receiver {
+        warn!("已设置 override_receiver, 所有动态都将改发给 QQ {}", qq);
+    }
+    if mirai.alert_after_failures.is_some() && mirai.admin_qq.is_none() {
+        warn!("设置了 mirai.alert_after_failures 但没有配置 mirai.admin_qq, 不会发送失败提醒");
+    }
+
+    let file_targets = target;
+    let targets = match &remote_targets {
+        Some(remote) => merge_targets(
+            &file_targets,
+            load_remote_targets(remote, &client, &db).await,
+        ),
+        None => file_targets.clone(),
+    };
+
+    if mirai.notify_on_start {
+        notify_start(&mirai, &targets, &mirai_client).await;
+    }
 
+    let context = TargetContext {
+        db: db.clone(),
+        single_tree: db_config.single_tree,
+        mirai,
+        bili,
+        render,
+        download,
+        client: client.clone(),
+        mirai_client,
+    };
     let mut target_set = JoinSet::new();
+    // 正在运行的监听任务, 用于远程列表变化时停止或重启
+    let mut running = HashMap::new();
+
+    for (i, t) in targets.into_iter().enumerate() {
+        let delay = Duration::from_secs(stagger_sec * i as u64);
+        match spawn_target(&mut target_set, &context, t.clone(), delay) {
+            Ok(handle) => {
+                running.insert(target_key(&t), (t, handle));
+            }
+            Err(e) => error!("跳过监听目标 {}: {:#}", t.tree_name(), e),
+        }
+    }
+
+    tokio::spawn(log_stats_periodically());
 
-    for t in target {
-        let tree = db.open_tree(format!("{}", t.uid))?;
-        let m = mirai.clone();
-        let b = bili.clone();
-        target_set.spawn(run_target(tree, m, b, t));
+    let mut refresh = remote_targets
+        .as_ref()
+        .and_then(|remote| remote.refresh_sec)
+        .map(|sec| tokio::time::interval(Duration::from_secs(sec)));
+    if let Some(refresh) = &mut refresh {
+        // 第一次tick立即完成, 启动时已经获取过
+        refresh.tick().await;
     }
 
-    while let Some(res) = target_set.join_next().await {
-        res??;
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            res = target_set.join_next(), if !target_set.is_empty() => match res {
+                Some(Err(e)) if e.is_cancelled() => {}
+                Some(res) => res??,
+                None => {}
+            },
+            _ = async { refresh.as_mut().unwrap().tick().await }, if refresh.is_some() => {
+                let remote = remote_targets.as_ref().unwrap();
+                let targets =
+                    merge_targets(&file_targets, load_remote_targets(remote, &client, &db).await);
+                update_targets(&mut target_set, &context, &mut running, targets);
+            },
+            res = &mut shutdown => {
+                res?;
+                info!("收到退出信号, 写入数据库后退出");
+                break;
+            },
+            else => break,
+        }
     }
 
+    // 未发送的动态和保存的消息在重启后继续发送
+    db.flush_async().await?;
+
     Ok(())
 }
 
-async fn run_target(
-    db: Tree,
+/// 等待ctrl-c, 或者 `systemctl stop`, `docker stop` 等发送的SIGTERM
+async fn shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
+
+/// 所有监听任务共用的配置和连接
+#[derive(Clone)]
+struct TargetContext {
+    db: sled::Db,
+    /// 见 `db.single_tree`
+    single_tree: bool,
     mirai: MiraiConfig,
     bili: BiliConfig,
+    render: RenderConfig,
+    download: DownloadConfig,
+    client: Client,
+    mirai_client: Client,
+}
+
+/// 等待 `delay` 后开始监听 `target`, 任务出错或panic时自动重启.
+/// 目标配置不合法(远程列表中的目标到这里才检查)或者无法打开数据库时返回错误, 不启动任务
+fn spawn_target(
+    target_set: &mut JoinSet<anyhow::Result<()>>,
+    context: &TargetContext,
     target: TargetConfig,
-) -> anyhow::Result<()> {
-    info!(
-        "开始监听b站用户UID {} 的动态并发送给 QQ 号{}",
-        target.uid, target.receiver_qq
-    );
+    delay: Duration,
+) -> anyhow::Result<AbortHandle> {
+    target.check()?;
+    let tree = TargetTree::open(&context.db, &target.tree_name(), context.single_tree)?;
+    // 该目标使用的配置, 包含目标自己覆盖的部分
+    let context = TargetContext {
+        bili: context.bili.for_target(&target),
+        ..context.clone()
+    };
 
-    let client = reqwest::Client::new();
+    Ok(target_set.spawn(async move {
+        tokio::time::sleep(delay).await;
+        let label = target.label();
+        // 单个目标的任务出错或panic时只重启该任务, 不影响其他目标
+        supervise(&label, &RestartPolicy::DEFAULT, || {
+            let (context, tree, target) = (context.clone(), tree.clone(), target.clone());
+            async move { run_target(&context, &tree, &target).await }
+        })
+        .await
+    }))
+}
 
-    let cookie = format!("SESSDATA={}", bili.sess_data);
+/// 区分监听目标的键: 同一个用户或话题发给不同的QQ是不同的目标
+fn target_key(target: &TargetConfig) -> (String, i64) {
+    (target.tree_name(), target.receiver_qq)
+}
 
-    loop {
-        let mut resent_entries = Vec::new();
+/// 按新的目标列表停止被移除或配置改变的任务, 启动新增的任务. 无法启动的目标记录日志后跳过
+fn update_targets(
+    target_set: &mut JoinSet<anyhow::Result<()>>,
+    context: &TargetContext,
+    running: &mut HashMap<(String, i64), (TargetConfig, AbortHandle)>,
+    targets: Vec<TargetConfig>,
+) {
+    let keys: Vec<_> = targets.iter().map(target_key).collect();
+    running.retain(|key, (_, handle)| {
+        let keep = keys.contains(key);
+        if !keep {
+            info!("停止监听已从列表中移除的用户 {}", key.0);
+            handle.abort();
+        }
+        keep
+    });
 
-        let mut it = db.iter();
-        // 首先尝试重发在数据库中但是并未发出过的消息
-        while let Some(Ok((k, v))) = it.next() {
-            let dynamic_id: i64 = serde_json::from_slice(&k).unwrap();
-            let mut entry: DbEntry = serde_json::from_slice(&v).unwrap();
+    for target in targets {
+        let key = target_key(&target);
+        if let Some((old, handle)) = running.get(&key) {
+            if same_target(old, &target) {
+                continue;
+            }
+            info!("目标 {} 的监听配置已改变, 重启监听任务", target.label());
+            handle.abort();
+        } else {
+            info!("开始监听新加入列表的目标 {}", target.label());
+        }
 
-            if !entry.sent {
-                info!("重发动态 {}", dynamic_id);
+        match spawn_target(target_set, context, target.clone(), Duration::ZERO) {
+            Ok(handle) => {
+                running.insert(key, (target, handle));
+            }
+            Err(e) => {
+                running.remove(&key);
+                error!("跳过监听目标 {}: {:#}", target.label(), e);
+            }
+        }
+    }
+}
 
-                match create_message_from_dynamic(&bili, &client, dynamic_id).await {
-                    Ok(msg) => match send_qq_message(&mirai, &target, &client, msg).await {
-                        Ok(_) => {
-                            entry.sent = true;
+fn same_target(a: &TargetConfig, b: &TargetConfig) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
 
-                            let v = serde_json::to_vec(&entry).unwrap();
+/// 数据库中保存的最近一次成功获取的远程监听目标列表
+const REMOTE_TARGETS_KEY: &[u8] = b"remote_targets";
 
-                            resent_entries.push((k, v));
-                        }
-                        Err(e) => {
-                            error!("重发错过的动态失败: {}", e)
-                        }
-                    },
-                    Err(e) => {
-                        error!("无法创建消息: {}", e);
-                    }
+/// 获取远程监听目标列表并保存到数据库. 获取失败时使用上次成功获取的列表
+async fn load_remote_targets(
+    remote: &RemoteTargetsConfig,
+    client: &Client,
+    db: &sled::Db,
+) -> Vec<TargetConfig> {
+    match fetch_remote_targets(&remote.url, client).await {
+        Ok(targets) => {
+            if let Err(e) = serde_json::to_vec(&targets)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok(db.insert(REMOTE_TARGETS_KEY, bytes)?))
+            {
+                warn!("保存远程监听目标列表失败: {}", e);
+            }
+            targets
+        }
+        Err(e) => {
+            let cached = db
+                .get(REMOTE_TARGETS_KEY)
+                .ok()
+                .flatten()
+                .and_then(|bytes| serde_json::from_slice::<Vec<TargetConfig>>(&bytes).ok());
+            match cached {
+                Some(targets) => {
+                    warn!("获取远程监听目标列表失败, 使用上次的列表: {:#}", e);
+                    targets
+                }
+                None => {
+                    warn!("获取远程监听目标列表失败, 只监听配置文件中的目标: {:#}", e);
+                    Vec::new()
                 }
             }
         }
+    }
+}
 
-        for (k, v) in resent_entries {
-            db.insert(k, v)?;
-        }
+async fn fetch_remote_targets(url: &str, client: &Client) -> anyhow::Result<Vec<TargetConfig>> {
+    let targets = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .context("解析远程监听目标列表")?;
 
-        // 获取新动态并发送
-        let response: Value = client
-            .get("https://api.vc.bilibili.com/dynamic_svr/v1/dynamic_svr/space_history")
-            .header("COOKIE", &cookie)
-            .query(&[
-                ("host_uid", target.uid),
-                ("offset_dynamic_id", 0),
-                ("need_top", 0),
-            ])
-            .send()
-            .await
-            .context("Request dynamic from Bilibili")?
-            .json()
-            .await
-            .context("Parse dynamic response from Bilibili")?;
+    Ok(targets)
+}
 
-        let code = response["code"].as_i64().unwrap();
+/// 配置文件中的目标加上远程列表中不重复的目标, 重复时以配置文件为准
+fn merge_targets(file: &[TargetConfig], remote: Vec<TargetConfig>) -> Vec<TargetConfig> {
+    let mut targets = file.to_vec();
 
-        if code != 0 {
-            error!("获取用户动态失败: {:?}", response);
+    for target in remote {
+        let key = target_key(&target);
+        if targets.iter().any(|t| target_key(t) == key) {
+            debug!("远程列表中的目标 {} 已在配置文件中, 跳过", target.label());
             continue;
         }
+        targets.push(target);
+    }
 
-        let Some(cards) = response["data"]["cards"].as_array() else {
-            error!("没有获取到任何动态: {:?}", response);
-            continue;
-        };
+    targets
+}
 
-        // 获取三条最新动态, 按照时间戳从小到大排列
-        for card in cards.iter().take(3).rev() {
-            let desc = &card["desc"];
-            let uname = desc["user_profile"]["info"]["uname"].as_str().unwrap();
+#[derive(Debug, PartialEq, Eq)]
+struct Args {
+    /// 配置文件路径, 默认为 `spider.toml`
+    config_path: PathBuf,
+    /// 只检查Mirai连接后退出
+    check_mirai: bool,
+    /// 只修改数据库中一条动态的发送状态后退出
+    mark: Option<MarkDynamic>,
+    /// 只用保存的动态详情和图片绘制一张动态图片后退出
+    render_archive: Option<RenderArchive>,
+    /// 只导出或导入一个用户的动态记录后退出
+    transfer: Option<TransferEntries>,
+}
 
-            let dynamic_id = desc["dynamic_id"].as_i64().unwrap();
-            let dynamic_type = desc.get("type").unwrap().as_i64().unwrap();
+/// `--render-archive` 的参数
+#[derive(Debug, PartialEq, Eq)]
+struct RenderArchive {
+    /// 保存的动态详情接口返回
+    detail_path: PathBuf,
+    /// 保存图片的目录, 见 [`LocalFetcher`]
+    image_dir: PathBuf,
+    /// 输出图片路径
+    output_path: PathBuf,
+}
 
-            if dynamic_type != 2 && dynamic_type != 4 && dynamic_type != 1 && dynamic_type != 4200 {
-                debug!("跳过不支持的动态类型 {} ({})", dynamic_id, dynamic_type);
-                continue;
-            }
+/// `--db-mark-sent` / `--db-mark-unsent` 的参数
+#[derive(Debug, PartialEq, Eq)]
+struct MarkDynamic {
+    uid: u64,
+    dynamic_id: i64,
+    /// 标记为已发送, 否则标记为未发送, 下一轮抓取时重新发送
+    sent: bool,
+}
 
-            let dynamic_key = serde_json::to_vec(&dynamic_id).unwrap();
-            if db.contains_key(&dynamic_key)? {
-                debug!("跳过已经收录过的动态 {}", dynamic_id);
-                continue;
-            }
+/// `--db-export` / `--db-import` 的参数
+#[derive(Debug, PartialEq, Eq)]
+struct TransferEntries {
+    uid: u64,
+    /// JSON文件路径
+    path: PathBuf,
+    /// 导出到文件, 否则从文件导入
+    export: bool,
+}
 
-            let mut entry = DbEntry {
-                sent: false,
-                type_: dynamic_type as i32,
+const USAGE: &str = "用法: bili-dynamic-spider [--config <path>] [--check-mirai] \
+                     [--db-mark-sent <uid> <dynamic_id> | --db-mark-unsent <uid> <dynamic_id>] \
+                     [--render-archive <detail.json> <image_dir> <output.png>] \
+                     [--db-export <uid> <file> | --db-import <uid> <file>]";
+
+/// 解析命令行参数 `[--config <path> | --config=<path>] [--check-mirai]
+/// [--db-mark-sent <uid> <dynamic_id> | --db-mark-unsent <uid> <dynamic_id>]
+/// [--render-archive <detail.json> <image_dir> <output.png>]
+/// [--db-export <uid> <file> | --db-import <uid> <file>]`
+fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut path = PathBuf::from("spider.toml");
+    let mut check_mirai = false;
+    let mut mark = None;
+    let mut render_archive = None;
+    let mut transfer = None;
+
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            path = args
+                .next()
+                .map(PathBuf::from)
+                .ok_or_else(|| anyhow!("--config 需要指定配置文件路径"))?;
+        } else if let Some(p) = arg.strip_prefix("--config=") {
+            path = PathBuf::from(p);
+        } else if arg == "--check-mirai" {
+            check_mirai = true;
+        } else if arg == "--db-mark-sent" || arg == "--db-mark-unsent" {
+            let (Some(uid), Some(dynamic_id)) = (args.next(), args.next()) else {
+                return Err(anyhow!("{} 需要指定用户uid和动态ID, {}", arg, USAGE));
             };
+            mark = Some(MarkDynamic {
+                uid: uid
+                    .parse()
+                    .with_context(|| format!("不合法的用户uid: {}", uid))?,
+                dynamic_id: parse_dynamic_id(&Value::String(dynamic_id))?,
+                sent: arg == "--db-mark-sent",
+            });
+        } else if arg == "--render-archive" {
+            let (Some(detail_path), Some(image_dir), Some(output_path)) =
+                (args.next(), args.next(), args.next())
+            else {
+                return Err(anyhow!(
+                    "--render-archive 需要指定动态详情, 图片目录和输出路径, {}",
+                    USAGE
+                ));
+            };
+            render_archive = Some(RenderArchive {
+                detail_path: PathBuf::from(detail_path),
+                image_dir: PathBuf::from(image_dir),
+                output_path: PathBuf::from(output_path),
+            });
+        } else if arg == "--db-export" || arg == "--db-import" {
+            let (Some(uid), Some(path)) = (args.next(), args.next()) else {
+                return Err(anyhow!("{} 需要指定用户uid和文件路径, {}", arg, USAGE));
+            };
+            transfer = Some(TransferEntries {
+                uid: uid
+                    .parse()
+                    .with_context(|| format!("不合法的用户uid: {}", uid))?,
+                path: PathBuf::from(path),
+                export: arg == "--db-export",
+            });
+        } else {
+            return Err(anyhow!("未知参数: {}, {}", arg, USAGE));
+        }
+    }
 
-            db.insert(&dynamic_key, serde_json::to_vec(&entry).unwrap())?;
+    Ok(Args {
+        config_path: path,
+        check_mirai,
+        mark,
+        render_archive,
+        transfer,
+    })
+}
 
-            info!("监听到 {} 新动态 {}", uname, dynamic_id);
+/// 不访问网络, 用保存的动态详情接口返回 `detail` 和 `image_dir` 中的图片绘制动态,
+/// 用于对大量真实动态反复检查排版
+async fn render_archived(
+    bili: &BiliConfig,
+    render: &RenderConfig,
+    detail: &Value,
+    image_dir: &Path,
+) -> anyhow::Result<RgbaImage> {
+    let fetcher = SkeletonFetcher::new(LocalFetcher::new(image_dir), render.skeleton);
+    let dynamic =
+        BiliDynamic::from_detail_json(bili, render, None, &fetcher, &detail["data"]["item"])
+            .await?;
+
+    draw_dynamic(&dynamic, render)
+}
 
-            match create_message_from_dynamic(&bili, &client, dynamic_id).await {
-                Ok(messages) => match send_qq_message(&mirai, &target, &client, messages).await {
-                    Ok(_) => {
-                        entry.sent = true;
-                        db.insert(&dynamic_key, serde_json::to_vec(&entry).unwrap())
-                            .unwrap();
-                    }
-                    Err(e) => {
-                        error!("发送qq消息失败: {}", e);
-                    }
-                },
-                Err(e) => {
-                    error!("{}", e)
-                }
-            }
-        }
+/// 数据库中动态的记录
+fn get_entry(db: &TargetTree, dynamic_id: i64) -> anyhow::Result<Option<DbEntry>> {
+    let Some(value) = db.get(dynamic_key(dynamic_id))? else {
+        return Ok(None);
+    };
 
-        tokio::time::sleep(Duration::from_secs(target.interval_sec)).await;
-    }
+    Ok(Some(serde_json::from_slice(&value)?))
 }
 
-async fn create_message_from_dynamic(
-    bili: &BiliConfig,
-    client: &Client,
+/// `--db-export` 导出的一条动态记录
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedEntry {
     dynamic_id: i64,
-) -> anyhow::Result<Vec<Message>> {
-    // 访问网络获取动态数据结构
-    let dynamic = BiliDynamic::fetch(bili, client, dynamic_id).await?;
-    // 画一张动态图
-    let image = draw_dynamic(&dynamic);
+    #[serde(flatten)]
+    entry: DbEntry,
+}
 
-    // 图片base64编码传到qq API
-    let mut png_buffer = Vec::new();
-    let mut cursor = Cursor::new(&mut png_buffer);
-    image.write_to(&mut cursor, image::ImageFormat::Png)?;
-    let image_b64 = base64::engine::general_purpose::STANDARD.encode(&png_buffer);
+/// 数据库中所有动态的记录, 按动态ID排列. 不包括汇总信息和保存的已渲染消息
+fn export_entries(db: &TargetTree) -> anyhow::Result<Vec<ExportedEntry>> {
+    let mut entries = Vec::new();
+    for item in db.iter() {
+        let (k, v) = item?;
+        let Some(dynamic_id) = parse_dynamic_key(&k) else {
+            continue;
+        };
+        entries.push(ExportedEntry {
+            dynamic_id,
+            entry: serde_json::from_slice(&v)?,
+        });
+    }
+    entries.sort_by_key(|e| e.dynamic_id);
+    Ok(entries)
+}
 
-    // 构造QQ消息链
-    let mut messages = Vec::new();
+/// 写入导出的动态记录, 覆盖已有的同一动态的记录, 返回写入的数量
+fn import_entries(db: &TargetTree, entries: Vec<ExportedEntry>) -> anyhow::Result<usize> {
+    let count = entries.len();
+    for ExportedEntry { dynamic_id, entry } in entries {
+        insert_entry(db, dynamic_id, &entry)?;
+    }
+    Ok(count)
+}
 
-    let header = match &dynamic.content {
-        Content::Forward {
-            texts: _,
-            original_author: _,
-            original: _,
-        } => {
-            format!(
-                "{} 转发了动态\nhttps://t.bilibili.com/{}\n",
-                dynamic.author.uname, dynamic_id
-            )
-        }
-        Content::Draw { texts: _, pics: _ } => format!(
-            "{} 发表了新动态\nhttps://t.bilibili.com/{}\n",
-            dynamic.author.uname, dynamic_id
-        ),
-        Content::Word { texts: _ } => format!(
-            "{} 发表了新动态\nhttps://t.bilibili.com/{}\n",
-            dynamic.author.uname, dynamic_id
-        ),
-        Content::Live {
-            live_id,
-            live_title: _,
-            live_cover: _,
-        } => format!(
-            "{} 直播了\nhttps://live.bilibili.com/{}\n",
-            dynamic.author.uname, live_id
-        ),
-    };
-    messages.push(Message::Plain { text: header });
-    messages.push(Message::Image { base64: image_b64 });
+/// 修改动态的发送状态, 数据库中没有该动态时新建一条记录. 标记为未发送时重置失败次数,
+/// 下一轮抓取会重新发送
+fn mark_dynamic(db: &TargetTree, dynamic_id: i64, sent: bool) -> anyhow::Result<DbEntry> {
+    let now = Timestamp::now().as_second();
+    let mut entry = get_entry(db, dynamic_id)?.unwrap_or(DbEntry {
+        sent,
+        type_: 0,
+        attempts: 0,
+        pinned: false,
+        first_seen_ts: now,
+        sent_ts: None,
+        content_hash: None,
+        edit_sent_ts: None,
+        confirmed: true,
+    });
+
+    entry.sent = sent;
+    entry.confirmed = true;
+    if sent {
+        entry.sent_ts = Some(now);
+    } else {
+        entry.sent_ts = None;
+        entry.attempts = 0;
+    }
+
+    insert_entry(db, dynamic_id, &entry)?;
 
-    Ok(messages)
+    Ok(entry)
 }
 
-async fn send_qq_message(
+/// 只进行 verify, bind 和 release, 检查 `http_url`, `verify_key` 和每个目标的 `sender_qq` 是否正确
+async fn check_mirai_connection(
     mirai: &MiraiConfig,
-    target: &TargetConfig,
+    targets: &[TargetConfig],
     client: &Client,
-    messages: Vec<Message>,
 ) -> anyhow::Result<()> {
-    let verify_request = VerifyRequest {
-        verify_key: mirai.verify_key.clone(),
-    };
+    let mut sender_qqs: Vec<i64> = targets.iter().map(|t| t.sender_qq).collect();
+    sender_qqs.sort_unstable();
+    sender_qqs.dedup();
 
-    let verify_response: VerifyResponse = client
-        .post(format!("{}/verify", mirai.http_url))
-        .json(&verify_request)
-        .send()
-        .await?
-        .json()
-        .await?;
+    let check = async {
+        let session_key = mirai_verify(mirai, client).await.with_context(|| {
+            format!(
+                "verify {} 失败, 请检查 http_url 和 verify_key",
+                mirai.http_url
+            )
+        })?;
+
+        for qq in sender_qqs {
+            mirai_bind(mirai, client, &session_key, qq)
+                .await
+                .with_context(|| {
+                    format!("绑定 sender_qq {} 失败, 请检查该QQ是否已在Mirai登录", qq)
+                })?;
+            mirai_release(mirai, client, &session_key, qq).await?;
+            println!("sender_qq {} 可用", qq);
+        }
 
-    if verify_response.code != 0 {
-        return Err(anyhow!(
-            "{}: {}",
-            verify_response.code,
-            verify_response.msg.unwrap()
-        ));
+        anyhow::Ok(())
+    };
+
+    match check.await {
+        Ok(_) => {
+            println!("Mirai连接正常: {}", mirai.http_url);
+            Ok(())
+        }
+        Err(e) => {
+            println!("Mirai连接失败: {:#}", e);
+            std::process::exit(1);
+        }
     }
+}
 
-    let session_key = verify_response.session.unwrap();
+/// 向管理员QQ发送一条启动通知, 发送失败不影响启动
+async fn notify_start(mirai: &MiraiConfig, targets: &[TargetConfig], client: &Client) {
+    let Some(admin_qq) = mirai.admin_qq else {
+        warn!("开启了 mirai.notify_on_start 但没有配置 mirai.admin_qq, 跳过启动通知");
+        return;
+    };
 
-    let bind_request = BindRequest {
-        session_key: session_key.clone(),
-        qq: target.sender_qq,
+    let Some(sender_qq) = targets.first().map(|t| t.sender_qq) else {
+        warn!("没有配置任何监听目标, 跳过启动通知");
+        return;
     };
 
-    let bind_response: BindResponse = client
-        .post(format!("{}/bind", mirai.http_url))
-        .json(&bind_request)
-        .send()
-        .await?
-        .json()
-        .await?;
+    let messages = vec![Message::Plain {
+        text: format!(
+            "bili-dynamic-spider 已启动, 正在监听 {} 个目标: {}",
+            targets.len(),
+            targets
+                .iter()
+                .map(TargetConfig::label)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }];
 
-    if bind_response.code != 0 {
-        return Err(anyhow!("{}: {}", bind_response.code, bind_response.msg));
+    match send_qq_message(
+        mirai,
+        sender_qq,
+        Receiver::Friend(admin_qq),
+        client,
+        messages,
+    )
+    .await
+    {
+        Ok(_) => info!("已向 QQ {} 发送启动通知", admin_qq),
+        Err(e) => warn!("发送启动通知失败: {}", e),
     }
+}
 
-    let send_request = SendFriendMessageRequest {
-        session_key: session_key.clone(),
-        target: target.receiver_qq,
-        message_chain: messages,
+/// 监听任务异常退出后的重启间隔
+struct RestartPolicy {
+    /// 第一次重启前的等待时间, 连续异常时加倍
+    min_backoff: Duration,
+    /// 最长等待时间
+    max_backoff: Duration,
+    /// 运行超过该时间后才退出的任务视为恢复过正常, 重新从 `min_backoff` 开始等待
+    stable_after: Duration,
+}
+
+impl RestartPolicy {
+    const DEFAULT: RestartPolicy = RestartPolicy {
+        min_backoff: Duration::from_secs(10),
+        max_backoff: Duration::from_secs(600),
+        stable_after: Duration::from_secs(600),
     };
+}
 
-    let send_response: SendFriendMessageResponse = client
-        .post(format!("{}/sendFriendMessage", mirai.http_url))
-        .json(&send_request)
-        .send()
-        .await
-        .context("Request MIRAI /sendFriendMessage")?
-        .json()
-        .await?;
+/// 在单独的任务中运行 `make_task` 创建的监听任务, 返回错误或panic时记录目标名称 `label`
+/// 并按 `policy` 等待后重启. 任务正常结束时返回
+async fn supervise<F>(
+    label: &str,
+    policy: &RestartPolicy,
+    mut make_task: impl FnMut() -> F,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let mut backoff = policy.min_backoff;
 
-    if send_response.code != 0 {
-        return Err(anyhow!("{}: {}", send_response.code, send_response.msg));
+    loop {
+        let started = tokio::time::Instant::now();
+
+        match tokio::spawn(make_task()).await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => error!("目标 {} 的监听任务出错: {:#}", label, e),
+            Err(e) if e.is_panic() => {
+                error!(
+                    "目标 {} 的监听任务panic: {}",
+                    label,
+                    panic_message(&*e.into_panic())
+                )
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        if started.elapsed() >= policy.stable_after {
+            backoff = policy.min_backoff;
+        }
+        warn!(
+            "{} 秒后重启目标 {} 的监听任务",
+            backoff.as_secs_f32(),
+            label
+        );
+        tokio::time::sleep(backoff).await;
+        backoff = cmp::min(backoff * 2, policy.max_backoff);
     }
+}
 
-    let release_request = ReleaseRequest {
-        session_key: session_key.clone(),
-        qq: target.sender_qq,
-    };
+/// 每隔 [`STATS_LOG_INTERVAL_SEC`] 输出启动以来各类动态的处理结果
+async fn log_stats_periodically() {
+    let mut interval = tokio::time::interval(Duration::from_secs(STATS_LOG_INTERVAL_SEC));
+    // 第一次tick立即完成, 跳过
+    interval.tick().await;
 
-    let release_response: ReleaseResponse = client
-        .post(format!("{}/release", mirai.http_url))
-        .json(&release_request)
-        .send()
-        .await?
-        .json()
-        .await?;
-
-    if release_response.code != 0 {
-        return Err(anyhow!(
-            "{}: {}",
-            release_response.code,
-            release_response.msg
-        ));
+    loop {
+        interval.tick().await;
+        let summary = stats::summary();
+        if !summary.is_empty() {
+            info!("动态处理统计: {}", summary);
+        }
     }
-
-    Ok(())
 }
 
-#[derive(Debug)]
-struct BiliDynamic {
-    author: AuthorInfo,
-    content: Content,
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "未知错误"
+    }
 }
 
-#[derive(Debug)]
-struct AuthorInfo {
-    uname: String,
-    vip: bool,
-    publish_timestamp: i64,
-    avatar_image: RgbaImage,
-}
+/// 监听一个目标直到出错. `context` 中的配置已经按 `target` 覆盖
+async fn run_target(
+    context: &TargetContext,
+    db: &TargetTree,
+    target: &TargetConfig,
+) -> anyhow::Result<()> {
+    let TargetContext {
+        mirai,
+        render,
+        download,
+        client,
+        ..
+    } = context;
 
-#[derive(Debug)]
-enum Content {
-    // 转发动态
-    Forward {
-        texts: Vec<RichTextNode>,
-        original_author: String,
-        original: Box<Content>,
-    },
-    // 带图动态
-    Draw {
-        texts: Vec<RichTextNode>,
-        pics: Vec<RgbaImage>,
-    },
-    // 纯文字动态
-    Word {
-        texts: Vec<RichTextNode>,
-    },
-    // 直播动态
-    Live {
-        live_id: i64,
-        live_title: String,
-        live_cover: RgbaImage,
-    },
-}
+    info!(
+        "开始监听 {} 的动态并发送给 QQ 号{}",
+        target.label(),
+        target.receiver_qq
+    );
 
-impl BiliDynamic {
-    async fn fetch(
-        bili: &BiliConfig,
-        client: &Client,
-        dynamic_id: i64,
-    ) -> anyhow::Result<BiliDynamic> {
-        let detail_response: Value =  client
-                        .get(format!("https://api.bilibili.com/x/polymer/web-dynamic/v1/detail?timezone_offset=-480&id={}&features=itemOpusStyle,opusBigCover,onlyfansVote", dynamic_id))
-                        .header("COOKIE", format!("SESSDATA={}", bili.sess_data))
-                        .send()
-                        .await?
-                        .json()
-                        .await?;
+    if target.resend_on_edit && target.endpoint == DynamicListEndpoint::SpaceHistory {
+        warn!("旧版动态列表接口不返回动态内容, resend_on_edit 不会生效");
+    }
 
-        let item = &detail_response["data"]["item"];
+    reconcile_unconfirmed(db)?;
 
-        // 构建作者
-        let author_info = &item["modules"]["module_author"];
-        let uname = author_info["name"].as_str().unwrap().to_string();
-        let face_url = author_info.get("face").and_then(Value::as_str);
-        let face_image = if let Some(face_url) = face_url {
-            download_image(face_url).await?
-        } else {
-            RESOURCE.no_face_image.clone()
-        };
-        let vip = author_info
-            .get("vip")
-            .and_then(|v| v.get("nickname_color"))
-            .and_then(|c| c.as_str())
-            .map(|s| !s.is_empty())
-            .unwrap_or_default();
-        let timestamp = author_info
-            .get("pub_ts")
-            .and_then(|v| v.as_i64())
-            .unwrap_or_default();
-        let author = AuthorInfo {
-            uname,
-            vip,
-            publish_timestamp: timestamp,
-            avatar_image: face_image,
-        };
+    // 接口返回格式异常的连续次数, 每次将等待时间加倍
+    let mut unexpected_responses = 0;
+    let mut alerts = mirai.alert_after_failures.map(|threshold| {
+        TargetAlerts::new(threshold, Duration::from_secs(mirai.alert_interval_sec))
+    });
 
-        // 构建内容
-        let content = Content::from_detail_json(bili, client, item).await?;
+    loop {
+        // 每轮抓取重新计算图片下载量
+        let fetcher = SkeletonFetcher::new(
+            CappedFetcher::new(
+                ReqwestFetcher::new(client.clone()),
+                download.max_bytes_per_poll,
+            ),
+            render.skeleton,
+        );
+        let result = poll_target(context, db, target, &fetcher, &mut alerts)
+            .instrument(info_span!("poll", target = %target.label()))
+            .await;
+
+        match result {
+            Ok(_) => unexpected_responses = 0,
+            Err(e) if e.downcast_ref::<UnexpectedResponse>().is_some() => {
+                unexpected_responses = cmp::min(unexpected_responses + 1, MAX_BACKOFF_EXPONENT);
+                warn!("{}, {} 倍间隔后重试", e, 1 << unexpected_responses);
+            }
+            Err(e) => return Err(e),
+        }
 
-        Ok(BiliDynamic { author, content })
+        tokio::time::sleep(next_poll_delay(target) * (1 << unexpected_responses)).await;
     }
 }
 
-impl Content {
-    /// * `response["data"]["item"]` field of response from dynamic detail API https://api.bilibili.com/x/polymer/web-dynamic/v1/detail
-    async fn from_detail_json(
-        bili: &BiliConfig,
-        client: &Client,
-        item: &Value,
-    ) -> anyhow::Result<Content> {
-        let dynamic_type = item["type"].as_str().unwrap();
-        match dynamic_type {
-            DYNAMIC_TYPE_FORWARD => {
-                let raw_text_nodes = item["modules"]["module_dynamic"]["desc"]["rich_text_nodes"]
-                    .as_array()
-                    .unwrap();
-                let texts = build_text_nodes(None, raw_text_nodes).await?;
-
-                let orig_author = item["orig"]["modules"]["module_author"]["name"]
-                    .as_str()
-                    .unwrap()
-                    .to_string();
-                let orig = Box::pin(Content::from_detail_json(bili, client, &item["orig"])).await?;
+/// 确认上次退出前记录了发送意图但没有确认的动态. 它们很可能已经发出, 当作已发送而不是重发
+fn reconcile_unconfirmed(db: &TargetTree) -> anyhow::Result<()> {
+    for (k, v) in db.iter().filter_map(Result::ok) {
+        let Some(dynamic_id) = parse_dynamic_key(&k) else {
+            continue;
+        };
+        let mut entry: DbEntry = serde_json::from_slice(&v)?;
+        if entry.confirmed {
+            continue;
+        }
 
-                Ok(Content::Forward {
-                    texts,
-                    original_author: orig_author,
-                    original: Box::new(orig),
-                })
-            }
-            DYNAMIC_TYPE_DRAW => {
-                let opus = &item["modules"]["module_dynamic"]["major"]["opus"];
-                let title = opus["title"].as_str().map(str::to_string);
-                let raw_text_nodes = opus["summary"]["rich_text_nodes"].as_array().unwrap();
-                let texts = build_text_nodes(title, raw_text_nodes).await?;
+        warn!(
+            "动态 {} 上次发送后没有确认, 很可能已经发出, 不再重发",
+            dynamic_id
+        );
+        entry.confirmed = true;
+        save_entry(db, dynamic_id, &entry)?;
+    }
 
-                let pics = match opus["pics"].as_array() {
-                    Some(pics) => download_dynamic_images(pics, 740, 10).await?,
-                    None => Vec::new(),
-                };
+    Ok(())
+}
 
-                Ok(Content::Draw { texts, pics })
-            }
-            DYNAMIC_TYPE_WORD => {
-                let opus = &item["modules"]["module_dynamic"]["major"]["opus"];
-                let title = opus["title"].as_str().map(str::to_string);
-                let raw_text_nodes = opus["summary"]["rich_text_nodes"].as_array().unwrap();
-                let texts = build_text_nodes(title, raw_text_nodes).await?;
+/// 距离下一次抓取的时间: 配置了 `schedule` 时按时间表计算, 否则为 `interval_sec`
+fn next_poll_delay(target: &TargetConfig) -> Duration {
+    let interval = Duration::from_secs(target.interval_sec);
 
-                Ok(Content::Word { texts })
-            }
-            DYNAMIC_TYPE_LIVE => {
-                let live = &item["modules"]["module_dynamic"]["major"]["live"];
-                let live_id = live["id"].as_i64().unwrap();
-                let live_title = live["title"].as_str().unwrap().to_string();
-                let live_cover_url =
-                    format!("{}@203w_127h_1e_1c.webp", live["cover"].as_str().unwrap());
-                let live_cover = download_image(live_cover_url).await?;
+    let Some(schedule) = &target.schedule else {
+        return interval;
+    };
 
-                Ok(Content::Live {
-                    live_id,
-                    live_title,
-                    live_cover,
-                })
-            }
-            _ => Err(anyhow!("不支持的动态类型: {}", dynamic_type)),
+    let now = Timestamp::now().as_second();
+    match schedule.next_after(now) {
+        Some(next) => Duration::from_secs(cmp::max(next - now, 1) as u64),
+        None => {
+            warn!("抓取时间表没有下一次抓取时间, 使用 interval_sec");
+            interval
         }
     }
 }
 
-fn draw_dynamic(dynamic: &BiliDynamic) -> RgbaImage {
-    let mut generator = PicGenerator::new(740, 10000);
-    generator.draw_rectangle(0, 0, 10000, 740, WHITE);
-
-    // 绘制用户头像
-    let resized_face =
-        imageops::resize(&dynamic.author.avatar_image, 100, 100, FilterType::Lanczos3);
-    let circular_face = create_circular_image(&resized_face, 100);
-    generator.draw_img_alpha(&circular_face, Some((50, 50)));
-    // 绘制大会员下标
-    if dynamic.author.vip {
-        generator.draw_img_alpha(&RESOURCE.vip_image, Some((118, 118)));
-    }
-    generator.set_pos(175, 60);
-    let uname_color = if dynamic.author.vip { PINK } else { BLACK };
-    let ts = {
-        // 东8区时间
-        let tz = TimeZone::fixed(Offset::constant(8));
-        let ts = Timestamp::from_second(dynamic.author.publish_timestamp).unwrap();
-        let zoned_ts = ts.to_zoned(tz);
-        strtime::format("%Y-%m-%d %H:%M", &zoned_ts).unwrap()
+/// 轮询一次目标用户: 先重发未发出的动态, 再获取并发送新动态
+async fn poll_target(
+    context: &TargetContext,
+    db: &TargetTree,
+    target: &TargetConfig,
+    fetcher: &impl ImageFetcher,
+    alerts: &mut Option<TargetAlerts>,
+) -> anyhow::Result<()> {
+    let TargetContext {
+        mirai,
+        mirai_client,
+        ..
+    } = context;
+    let ctx = RenderContext {
+        db,
+        bili: &context.bili,
+        render: &context.render,
+        target,
+        client: &context.client,
+        fetcher,
     };
-    // 绘制用户名和动态时间戳
-    generator.draw_text(
-        &[&dynamic.author.uname],
-        &[uname_color],
-        &RESOURCE.text_normal_font,
-        TEXT_SCALE,
-        None,
-    );
-    generator.draw_text(&[&ts], &[GRAY], &RESOURCE.text_normal_font, TIP_SCALE, None);
 
-    // 开始绘制动态内容
-    generator.set_x(25);
-    generator.set_row_space(10);
+    // 渲染和发送通过有界队列流水线进行, 发送当前动态的同时渲染下一条, 发送顺序不变
+    let (tx, rx) = mpsc::channel(RENDER_QUEUE_SIZE);
 
-    draw_content(&mut generator, &dynamic.content);
+    let (rendered, ()) = tokio::join!(
+        render_messages(&ctx, tx),
+        send_messages(db, mirai, target, mirai_client, rx, alerts),
+    );
 
-    generator.crop_bottom();
+    // 获取动态列表失败时本轮不再处理新动态, 摘要仍然照常发送
+    let fetched = match rendered {
+        Ok(()) => Ok(()),
+        Err(e) if e.downcast_ref::<FetchFailed>().is_some() => {
+            error!("{}", e);
+            Err(e)
+        }
+        Err(e) if e.downcast_ref::<UnexpectedResponse>().is_some() => {
+            // 返回格式异常时由 `run_target` 延长间隔后重试
+            let fetched = Err(e);
+            track_result(
+                mirai,
+                target,
+                mirai_client,
+                alerts,
+                FailureKind::Fetch,
+                &fetched,
+            )
+            .await;
+            return fetched;
+        }
+        Err(e) => return Err(e),
+    };
+    track_result(
+        mirai,
+        target,
+        mirai_client,
+        alerts,
+        FailureKind::Fetch,
+        &fetched,
+    )
+    .await;
+
+    if target.mode == TargetMode::Digest {
+        send_digest(&ctx, mirai, mirai_client, alerts).await?;
+    }
 
-    generator.into_image()
+    Ok(())
 }
 
-fn draw_content(generator: &mut PicGenerator, content: &Content) {
-    match content {
-        Content::Forward {
-            texts,
-            original_author,
-            original,
-        } => {
-            let text_images = draw_content_image(
-                texts,
-                generator.width() - 50,
-                TEXT_SCALE,
-                EMOJI_SCALE,
-                &RESOURCE,
-            );
-            for image in text_images {
-                generator.draw_img_alpha(&image, None);
-            }
-
-            // 绘制原动态的灰色背景
-            let y = generator.y();
-            generator.draw_rectangle(0, y, generator.height() - y, generator.width(), LIGHT_GRAY);
-            // 绘制原作者AT
-            let orig_author_at = format!("@{}", original_author);
-            generator.draw_text(
-                &[&orig_author_at],
-                &[DEEP_BLUE],
-                &RESOURCE.text_normal_font,
-                TEXT_SCALE,
-                None,
-            );
-            // 绘制原动态内容
-            draw_content(generator, original);
-        }
-        Content::Draw { texts, pics } => {
-            let text_images = draw_content_image(
-                texts,
-                generator.width() - 50,
-                TEXT_SCALE,
-                EMOJI_SCALE,
-                &RESOURCE,
-            );
-            for image in text_images {
-                generator.draw_img_alpha(&image, None);
-            }
-
-            let num_pics_per_line = match pics.len() {
-                1 => 1,
-                2 | 4 => 2,
-                _ => 3,
-            };
+/// 摘要模式下, 今天的 `digest_at` 之后第一次抓取时把所有未发送的动态依次绘制,
+/// 上下拼接成一张图片发送. 发送失败时下一轮重试
+async fn send_digest(
+    ctx: &RenderContext<'_, impl ImageFetcher>,
+    mirai: &MiraiConfig,
+    mirai_client: &Client,
+    alerts: &mut Option<TargetAlerts>,
+) -> anyhow::Result<()> {
+    let RenderContext {
+        db,
+        bili,
+        render,
+        target,
+        client,
+        fetcher,
+    } = *ctx;
+    let now = Timestamp::now();
+    if !digest_due(target.digest_at, tree_meta(db).last_digest_ts, now) {
+        return Ok(());
+    }
 
-            let image_lines: Vec<&[RgbaImage]> = pics.chunks(num_pics_per_line).collect();
+    let mut cards = Vec::new();
+    let mut included = Vec::new();
 
-            let (mut x, mut y) = (generator.x(), generator.y());
+    for (dynamic_key, dynamic_id, mut entry) in unsent_entries(db) {
+        let span = info_span!("dynamic", target = %target.label(), dynamic_id);
 
-            for line in image_lines {
-                for (i, img) in line.iter().enumerate() {
-                    generator.draw_img(img, Some((x, y)));
+        let card = BiliDynamic::fetch(bili, render, client, fetcher, dynamic_id)
+            .instrument(span)
+            .await
+            .and_then(|mut dynamic| {
+                dynamic.blur_flagged = target.blur_flagged;
+                dynamic.deferred = true;
+                draw_dynamic(&dynamic, render)
+            });
+        match card {
+            Ok(card) => {
+                cards.push(card);
+                included.push((dynamic_key, dynamic_id, entry));
+            }
+            Err(e) if e.downcast_ref::<DynamicUnavailable>().is_some() => {
+                warn!("{}, 不加入摘要", e);
+                stats::record(entry.type_ as i64, Outcome::Skipped);
 
-                    x += img.width() + 10;
-                    generator.set_x(x);
+                entry.sent = true;
+                save_entry(db, dynamic_id, &entry)?;
+            }
+            Err(e) => {
+                error!("无法绘制摘要中的动态 {}: {:#}", dynamic_id, e);
+                stats::record(entry.type_ as i64, Outcome::Failed);
 
-                    if i == line.len() - 1 {
-                        y += img.height() + 10;
-                        generator.set_y(y);
-                        x = 10;
-                        generator.set_x(x);
-                    }
-                }
+                entry.attempts += 1;
+                db.insert(&dynamic_key, serde_json::to_vec(&entry)?)?;
             }
+        }
+    }
 
-            // bottom margin
-            generator.set_y(y + 20);
+    if !cards.is_empty() {
+        let image = stack_cards(&cards, DIGEST_GAP_COLOR);
+        let image_b64 =
+            base64::engine::general_purpose::STANDARD.encode(encode_image(&image, render)?);
+
+        let mut messages = Vec::new();
+        if target.send_header {
+            let links: Vec<String> = included
+                .iter()
+                .map(|(_, dynamic_id, _)| format!("https://t.bilibili.com/{}", dynamic_id))
+                .collect();
+            messages.push(Message::Plain {
+                text: format!("今日动态 {} 条\n{}\n", included.len(), links.join("\n")),
+            });
         }
-        Content::Word { texts } => {
-            let text_images = draw_content_image(
-                texts,
-                generator.width() - 50,
-                TEXT_SCALE,
-                EMOJI_SCALE,
-                &RESOURCE,
-            );
-            for image in text_images {
-                generator.draw_img_alpha(&image, None);
+        messages.push(Message::Image { base64: image_b64 });
+
+        // 先记录发送意图, 发送之后、确认之前退出时重启不会重复发送
+        for (dynamic_key, _, entry) in &mut included {
+            entry.sent = true;
+            entry.confirmed = false;
+            db.insert(&*dynamic_key, serde_json::to_vec(entry)?)?;
+        }
+
+        let delivered = deliver_message(mirai, target, mirai_client, messages).await;
+        track_result(
+            mirai,
+            target,
+            mirai_client,
+            alerts,
+            FailureKind::Send,
+            &delivered,
+        )
+        .await;
+
+        let sent_ts = Timestamp::now().as_second();
+        for (_, dynamic_id, entry) in &mut included {
+            entry.confirmed = true;
+            match delivered {
+                Ok(_) => {
+                    stats::record(entry.type_ as i64, Outcome::Sent);
+                    entry.sent_ts = Some(sent_ts);
+                }
+                Err(_) => entry.sent = false,
             }
+            save_entry(db, *dynamic_id, entry)?;
         }
-        Content::Live {
-            live_id: _,
-            live_title,
-            live_cover,
-        } => {
-            generator.draw_text(
-                &[live_title],
-                &[BLACK],
-                &RESOURCE.text_normal_font,
-                TEXT_SCALE,
-                None,
-            );
-            generator.draw_img(live_cover, None);
+        // 发送失败时不记录摘要时间, 动态保持未发送, 下一轮重试
+        if let Err(e) = delivered {
+            error!("发送摘要失败, 下一轮重试: {:#}", e);
+            return Ok(());
         }
+        info!("已发送 {} 条动态的摘要", included.len());
     }
+
+    save_tree_meta(
+        db,
+        &TreeMeta {
+            last_digest_ts: Some(now.as_second()),
+            ..tree_meta(db)
+        },
+    )
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct VerifyRequest {
-    #[serde(rename = "verifyKey")]
-    verify_key: String,
+/// 今天(东8区)的 `digest_at` 已经到达, 并且此后还没有发送过摘要
+fn digest_due(digest_at: TimeOfDay, last_digest_ts: Option<i64>, now: Timestamp) -> bool {
+    let Ok(scheduled) = now
+        .to_zoned(china_time_zone())
+        .with()
+        .hour(digest_at.hour() as i8)
+        .minute(digest_at.minute() as i8)
+        .second(0)
+        .subsec_nanosecond(0)
+        .build()
+    else {
+        return false;
+    };
+    let scheduled = scheduled.timestamp().as_second();
+
+    now.as_second() >= scheduled && !matches!(last_digest_ts, Some(ts) if ts >= scheduled)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// 把多张动态卡片上下拼接成一张图片, 卡片之间用 `gap_color` 的横条分隔
+fn stack_cards(cards: &[RgbaImage], gap_color: Rgba<u8>) -> RgbaImage {
+    let width = cards
+        .iter()
+        .map(RgbaImage::width)
+        .max()
+        .unwrap_or(CARD_WIDTH);
+    let height = cards.iter().map(RgbaImage::height).sum::<u32>()
+        + DIGEST_GAP * cards.len().saturating_sub(1) as u32;
+
+    let mut generator = PicGenerator::new(width, height.max(1));
+    let mut y = 0;
+    for (i, card) in cards.iter().enumerate() {
+        if i > 0 {
+            generator.draw_rectangle(0, y as i32, DIGEST_GAP, width, gap_color);
+            y += DIGEST_GAP;
+        }
+        generator.draw_img(card, Some((0, y)));
+        y += card.height();
+    }
 
-struct VerifyResponse {
-    code: i32,
-    msg: Option<String>,     // When fail
-    session: Option<String>, // When success
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct BindRequest {
-    #[serde(rename = "sessionKey")]
-    session_key: String,
-    qq: i64,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct BindResponse {
-    code: i32,
-    msg: String,
+    generator.into_image()
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct ReleaseRequest {
-    #[serde(rename = "sessionKey")]
-    session_key: String,
-    qq: i64,
+/// 数据库中所有未发送的动态, 按键的顺序排列
+fn unsent_entries(db: &TargetTree) -> Vec<(Vec<u8>, i64, DbEntry)> {
+    db.iter()
+        .filter_map(Result::ok)
+        .filter_map(|(k, v)| {
+            let dynamic_id = parse_dynamic_key(&k)?;
+            let entry: DbEntry = serde_json::from_slice(&v)
+                .inspect_err(|e| warn!("动态 {} 的记录格式错误, 跳过: {}", dynamic_id, e))
+                .ok()?;
+            (!entry.sent).then(|| (k.to_vec(), dynamic_id, entry))
+        })
+        .collect()
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct ReleaseResponse {
-    code: i32,
-    msg: String,
+/// 渲染完成等待发送的动态
+struct PendingMessage {
+    dynamic_key: Vec<u8>,
+    dynamic_id: i64,
+    entry: DbEntry,
+    messages: Vec<Message>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct SendFriendMessageRequest {
-    session_key: String,
-    target: i64,
-    message_chain: Vec<Message>,
+/// 等待渲染的动态
+struct RenderJob {
+    dynamic_key: Vec<u8>,
+    dynamic_id: i64,
+    entry: DbEntry,
+    // 图片没有全部下载成功时也创建消息
+    allow_partial: bool,
+    // 上次保存的已渲染消息, 有则直接发送
+    rendered: Option<RenderedMessages>,
+    // 重发数据库中未发送的动态, 而不是刚发现的新动态
+    deferred: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct SendFriendMessageResponse {
-    code: i32,
-    msg: String,
+/// 一轮抓取中渲染一个目标的动态时用到的配置和连接
+struct RenderContext<'a, F> {
+    db: &'a TargetTree,
+    bili: &'a BiliConfig,
+    render: &'a RenderConfig,
+    target: &'a TargetConfig,
+    client: &'a Client,
+    fetcher: &'a F,
 }
 
-/// `https://github.com/project-mirai/mirai-api-http/blob/e9d5609b1cd580217a868f2daa789360283ba289/docs/api/MessageType.md`
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-enum Message {
-    Plain { text: String },
-    Image { base64: String },
-}
+/// 渲染未发出的动态和新动态, 按从旧到新的顺序放入发送队列. 队列满时等待发送
+async fn render_messages(
+    ctx: &RenderContext<'_, impl ImageFetcher>,
+    tx: mpsc::Sender<PendingMessage>,
+) -> anyhow::Result<()> {
+    let RenderContext {
+        db,
+        bili,
+        target,
+        client,
+        ..
+    } = *ctx;
+    let quiet = in_quiet_hours(target);
+    let digest = target.mode == TargetMode::Digest;
+    // 免打扰时段内和摘要模式下新动态只记录不发送
+    let hold = quiet || digest;
+    // 本轮已经放入发送队列的动态内容
+    let mut queued_hashes = Vec::new();
+
+    // 首先尝试重发在数据库中但是并未发出过的消息, 免打扰时段内不重发.
+    // 汇总信息记录没有未发送的动态时不需要遍历数据库
+    let meta = tree_meta(db);
+    let unsent_entries = if hold || !meta.has_unsent {
+        Vec::new()
+    } else {
+        unsent_entries(db)
+    };
 
-#[tokio::test]
-async fn test_send_qq() {
-    const MIRAI_URL: &str = "http://localhost:7827";
-    const MIRAI_VERIFY_KEY: &str = "INITKEYLunaRyu";
-    const BOT_QQ: i64 = 1320117484;
-    const TARGET_QQ: i64 = 3922347898;
+    if !hold && meta.has_unsent && unsent_entries.is_empty() {
+        save_tree_meta(
+            db,
+            &TreeMeta {
+                has_unsent: false,
+                ..meta
+            },
+        )?;
+    }
 
-    let client = reqwest::Client::new();
+    let unsent_jobs = unsent_entries
+        .into_iter()
+        .map(|(dynamic_key, dynamic_id, entry)| {
+            info!(
+                "重发动态 {} (首次发现于 {}, 已失败 {} 次)",
+                dynamic_id,
+                format_timestamp(entry.first_seen_ts)
+                    .unwrap_or_else(|_| entry.first_seen_ts.to_string()),
+                entry.attempts
+            );
 
-    let verify_request = VerifyRequest {
-        verify_key: MIRAI_VERIFY_KEY.to_string(),
-    };
+            RenderJob {
+                dynamic_key,
+                dynamic_id,
+                allow_partial: entry.attempts + 1 >= MAX_BUILD_ATTEMPTS,
+                entry,
+                rendered: load_rendered(db, dynamic_id),
+                deferred: true,
+            }
+        })
+        .collect();
+    if !queue_rendered(ctx, &tx, &mut queued_hashes, unsent_jobs).await? {
+        return Ok(());
+    }
 
-    let verify_response: VerifyResponse = client
-        .post(format!("{}/verify", MIRAI_URL))
-        .json(&verify_request)
-        .send()
-        .await
-        .unwrap()
-        .json()
-        .await
-        .unwrap();
+    // 获取新动态并发送
+    let fetched = fetch_new_cards(db, bili, target, client).await?;
 
-    assert_eq!(0, verify_response.code, "verify failed");
+    let mut new_jobs = Vec::new();
+    for card in &fetched.new {
+        let SpaceCard {
+            dynamic_id,
+            dynamic_type,
+            uname,
+            pinned,
+            content_hash: _,
+        } = card;
+        let (dynamic_id, dynamic_type) = (*dynamic_id, *dynamic_type);
+
+        let dynamic_key = dynamic_key(dynamic_id);
+        if db.contains_key(&dynamic_key)? {
+            debug!("跳过已经收录过的动态 {}", dynamic_id);
+            continue;
+        }
 
-    let session_key = verify_response.session.unwrap();
+        if !card.is_supported() {
+            debug!("跳过不支持的动态类型 {} ({})", dynamic_id, dynamic_type);
+            stats::record(dynamic_type, Outcome::Skipped);
+            // 记为已发送, 之后不再重复获取和统计
+            let entry = DbEntry {
+                sent: true,
+                type_: dynamic_type as i32,
+                attempts: 0,
+                pinned: *pinned,
+                first_seen_ts: Timestamp::now().as_second(),
+                sent_ts: None,
+                content_hash: None,
+                edit_sent_ts: None,
+                confirmed: true,
+            };
+            insert_entry(db, dynamic_id, &entry)?;
+            continue;
+        }
 
-    println!("Got session key: {}", session_key);
+        let entry = DbEntry {
+            sent: false,
+            type_: dynamic_type as i32,
+            attempts: 0,
+            pinned: *pinned,
+            first_seen_ts: Timestamp::now().as_second(),
+            sent_ts: None,
+            content_hash: None,
+            edit_sent_ts: None,
+            confirmed: true,
+        };
 
-    let bind_request = BindRequest {
-        session_key: session_key.clone(),
-        qq: BOT_QQ,
-    };
+        insert_entry(db, dynamic_id, &entry)?;
 
-    let bind_response: BindResponse = client
-        .post(format!("{}/bind", MIRAI_URL))
-        .json(&bind_request)
-        .send()
-        .await
-        .unwrap()
-        .json()
-        .await
-        .unwrap();
+        info!("监听到 {} 新动态 {}", uname, dynamic_id);
 
-    assert_eq!(0, bind_response.code, "bind failed: {}", bind_response.msg);
+        if quiet {
+            info!("免打扰时段内, 动态 {} 将在时段结束后发送", dynamic_id);
+            continue;
+        }
+        if digest {
+            info!(
+                "动态 {} 将在 {} 的摘要中发送",
+                dynamic_id,
+                String::from(target.digest_at)
+            );
+            continue;
+        }
 
-    println!("bind session key {} to qq {}", session_key, BOT_QQ);
+        new_jobs.push(RenderJob {
+            dynamic_key,
+            dynamic_id,
+            entry,
+            allow_partial: false,
+            rendered: None,
+            deferred: false,
+        });
+    }
+    if !queue_rendered(ctx, &tx, &mut queued_hashes, new_jobs).await? {
+        return Ok(());
+    }
 
-    let send_request = SendFriendMessageRequest {
-        session_key: session_key.clone(),
-        target: TARGET_QQ,
-        message_chain: vec![Message::Plain {
-            text: "Hello world".to_string(),
-        }],
-    };
+    if !hold && target.resend_on_edit {
+        queue_edited(ctx, &tx, &fetched.first_page).await?;
+    }
 
-    let send_response: serde_json::Value = client
-        .post(format!("{}/sendFriendMessage", MIRAI_URL))
-        .json(&send_request)
-        .send()
-        .await
-        .unwrap()
-        .json()
-        .await
-        .unwrap();
+    Ok(())
+}
 
-    let ss = serde_json::to_string_pretty(&send_response).unwrap();
+/// 最多同时渲染 [`RENDER_CONCURRENCY`] 条动态, 按 `jobs` 的顺序放入发送队列,
+/// 发送队列满时暂停渲染. 创建消息失败的动态记录失败次数, 下一轮重试.
+/// 发送队列已关闭时返回 `false`
+async fn queue_rendered(
+    ctx: &RenderContext<'_, impl ImageFetcher>,
+    tx: &mpsc::Sender<PendingMessage>,
+    queued_hashes: &mut Vec<u64>,
+    jobs: Vec<RenderJob>,
+) -> anyhow::Result<bool> {
+    let RenderContext {
+        db, render, target, ..
+    } = *ctx;
+    let mut rendered = futures::stream::iter(jobs)
+        .map(|mut job| async move {
+            if let Some(saved) = job.rendered.take() {
+                info!("使用保存的消息重发动态 {}", job.dynamic_id);
+                return (job, Ok((saved.messages, saved.content_hash)));
+            }
 
-    println!("{}", ss);
+            let span = info_span!("dynamic", target = %target.label(), dynamic_id = job.dynamic_id);
+            let result = create_message_from_dynamic(
+                ctx,
+                job.dynamic_id,
+                job.entry.pinned,
+                job.allow_partial,
+                job.deferred,
+            )
+            .instrument(span)
+            .await;
+            (job, result)
+        })
+        .buffered(RENDER_CONCURRENCY);
+
+    while let Some((job, result)) = rendered.next().await {
+        let RenderJob {
+            dynamic_key,
+            dynamic_id,
+            mut entry,
+            allow_partial: _,
+            rendered: _,
+            deferred,
+        } = job;
+        // 带相对时间的消息不保存, 重发时重新绘制
+        let persist =
+            render.persist_rendered && (deferred || render.time_style == TimeStyle::Absolute);
 
-    let release_request = ReleaseRequest {
-        session_key: session_key.clone(),
-        qq: BOT_QQ,
-    };
+        match result {
+            Ok((messages, content_hash)) => {
+                let pending = PendingMessage {
+                    dynamic_key,
+                    dynamic_id,
+                    entry,
+                    messages,
+                };
+                if !queue_message(
+                    db,
+                    target,
+                    tx,
+                    queued_hashes,
+                    pending,
+                    content_hash,
+                    persist,
+                )
+                .await?
+                {
+                    return Ok(false);
+                }
+            }
+            Err(e) if e.downcast_ref::<DynamicUnavailable>().is_some() => {
+                warn!("{}, 不再发送", e);
+                stats::record(entry.type_ as i64, Outcome::Skipped);
 
-    let release_response: ReleaseResponse = client
-        .post(format!("{}/release", MIRAI_URL))
-        .json(&release_request)
-        .send()
-        .await
-        .unwrap()
-        .json()
-        .await
-        .unwrap();
+                entry.sent = true;
+                save_entry(db, dynamic_id, &entry)?;
+            }
+            Err(e) => {
+                error!("无法创建动态 {} 的消息: {}", dynamic_id, e);
+                stats::record(entry.type_ as i64, Outcome::Failed);
 
-    assert_eq!(
-        0, release_response.code,
-        "release failed: {}",
-        release_response.msg
-    );
+                entry.attempts += 1;
+                db.insert(&dynamic_key, serde_json::to_vec(&entry).unwrap())?;
+            }
+        }
+    }
 
-    println!("released session key {}", session_key);
+    Ok(true)
 }
 
-async fn download_image(url: impl IntoUrl) -> anyhow::Result<RgbaImage> {
-    let response = reqwest::get(url).await?;
-
-    let bytes = response.bytes().await?;
+/// 重新发送内容哈希与上次发送时不同的已发送动态, 消息前加上 "(已编辑)".
+/// 每次改变只处理一次, 同一条动态两次编辑通知至少间隔 [`EDIT_RESEND_INTERVAL_SEC`]
+async fn queue_edited(
+    ctx: &RenderContext<'_, impl ImageFetcher>,
+    tx: &mpsc::Sender<PendingMessage>,
+    cards: &[SpaceCard],
+) -> anyhow::Result<()> {
+    let RenderContext { db, target, .. } = *ctx;
+    let now = Timestamp::now().as_second();
 
-    let cursor = Cursor::new(&*bytes);
+    for card in cards.iter().filter(|card| card.is_supported()) {
+        let dynamic_id = card.dynamic_id;
+        let Some(content_hash) = card.content_hash else {
+            continue;
+        };
+        // 未发送的动态重发时本来就使用最新内容
+        let Some(mut entry) = get_entry(db, dynamic_id)?.filter(|entry| entry.sent) else {
+            continue;
+        };
 
-    let image = ImageReader::new(BufReader::new(cursor))
-        .with_guessed_format()?
-        .decode()?
-        .into_rgba8();
+        match entry.content_hash {
+            Some(sent_hash) if sent_hash != content_hash => {}
+            Some(_) => continue,
+            None => {
+                // 没有记录哈希的旧动态以当前内容为准
+                entry.content_hash = Some(content_hash);
+                db.insert(dynamic_key(dynamic_id), serde_json::to_vec(&entry)?)?;
+                continue;
+            }
+        }
 
-    Ok(image)
-}
+        if entry
+            .edit_sent_ts
+            .is_some_and(|ts| now - ts < EDIT_RESEND_INTERVAL_SEC)
+        {
+            debug!("动态 {} 刚发送过编辑通知, 稍后再发送", dynamic_id);
+            continue;
+        }
 
-async fn build_text_nodes(
-    title: Option<String>,
-    raw_text_nodes: &[Value],
-) -> anyhow::Result<Vec<RichTextNode>> {
-    let mut res = Vec::with_capacity(raw_text_nodes.len() + 1);
+        info!("动态 {} 被编辑, 重新发送", dynamic_id);
 
-    if let Some(title) = title {
-        res.push(RichTextNode::Text { text: title });
-    }
+        // 无论是否发送成功都记录这次改变, 避免反复重发
+        entry.content_hash = Some(content_hash);
+        entry.edit_sent_ts = Some(now);
+        db.insert(dynamic_key(dynamic_id), serde_json::to_vec(&entry)?)?;
 
-    for node in raw_text_nodes {
-        let type_ = node.get("type").unwrap().as_str().unwrap();
+        let span = info_span!("dynamic", target = %target.label(), dynamic_id);
 
-        match type_ {
-            "RICH_TEXT_NODE_TYPE_EMOJI" => match download_emoji(node).await {
-                Ok(img) => res.push(RichTextNode::Emoji { img }),
-                Err(e) => {
-                    error!("无法下载emoji, 使用文字代替: {}", e);
-                    if let Some(Some(text)) = node.get("text").map(Value::as_str) {
-                        res.push(RichTextNode::Text {
-                            text: text.to_string(),
-                        });
-                    }
-                }
-            },
-            "RICH_TEXT_NODE_TYPE_WEB" => res.push(RichTextNode::Web),
-            "RICH_TEXT_NODE_TYPE_BV" => res.push(RichTextNode::Bv),
-            "RICH_TEXT_NODE_TYPE_LOTTERY" => res.push(RichTextNode::Lottery),
-            "RICH_TEXT_NODE_TYPE_VOTE" => res.push(RichTextNode::Vote),
-            "RICH_TEXT_NODE_TYPE_GOODS" => res.push(RichTextNode::Goods),
-            _ => {
-                if let Some(Some(text)) = node.get("text").map(Value::as_str) {
-                    res.push(RichTextNode::Text {
-                        text: text.to_string(),
-                    });
+        match create_message_from_dynamic(ctx, dynamic_id, entry.pinned, false, false)
+            .instrument(span)
+            .await
+        {
+            Ok((mut messages, _)) => {
+                messages.insert(
+                    0,
+                    Message::Plain {
+                        text: "(已编辑) ".to_string(),
+                    },
+                );
+                let pending = PendingMessage {
+                    dynamic_key: dynamic_key(dynamic_id),
+                    dynamic_id,
+                    entry,
+                    messages,
+                };
+                if tx.send(pending).await.is_err() {
+                    return Ok(());
                 }
             }
+            Err(e) => error!("无法创建编辑后的消息: {}", e),
         }
     }
 
-    Ok(res)
+    Ok(())
 }
 
-async fn download_emoji(emoji_node: &Value) -> anyhow::Result<RgbaImage> {
-    if let Some(emoji) = emoji_node.get("emoji") {
-        if let Some(Some(icon_url)) = emoji.get("icon_url").map(Value::as_str) {
-            let response = reqwest::get(icon_url).await?;
-
-            let bytes = response.bytes().await?;
-
-            let cursor = Cursor::new(&*bytes);
-
-            let image = ImageReader::new(BufReader::new(cursor))
-                .with_guessed_format()?
-                .decode()?
-                .into_rgba8();
-
-            return Ok(image);
+/// 开启去重时, 内容与 `dedup_window_sec` 内已发送或本轮已排队的动态相同则只记录不发送,
+/// 否则放入发送队列, `persist` 时先把消息保存到数据库. 发送队列已关闭时返回 `false`
+async fn queue_message(
+    db: &TargetTree,
+    target: &TargetConfig,
+    tx: &mpsc::Sender<PendingMessage>,
+    queued_hashes: &mut Vec<u64>,
+    mut pending: PendingMessage,
+    content_hash: u64,
+    persist: bool,
+) -> anyhow::Result<bool> {
+    pending.entry.content_hash = Some(content_hash);
+
+    if let Some(window) = target.dedup_window_sec {
+        let now = Timestamp::now().as_second();
+        if queued_hashes.contains(&content_hash) || recently_sent(db, content_hash, window, now) {
+            info!(
+                "动态 {} 与最近发送的动态内容相同, 跳过发送",
+                pending.dynamic_id
+            );
+            stats::record(pending.entry.type_ as i64, Outcome::Skipped);
+            pending.entry.sent = true;
+            save_entry(db, pending.dynamic_id, &pending.entry)?;
+            return Ok(true);
         }
+        queued_hashes.push(content_hash);
     }
 
-    Err(anyhow!("No emoji icon url found"))
-}
+    // 在放入队列前保存, 发送前退出时重启后也能直接发送
+    if persist {
+        let saved = RenderedMessages {
+            content_hash,
+            messages: pending.messages.clone(),
+        };
+        db.insert(
+            rendered_key(pending.dynamic_id),
+            serde_json::to_vec(&saved)?,
+        )?;
+    }
 
-pub async fn download_dynamic_images(
-    pictures: &[Value],
-    image_area_width: u32,
-    image_margin: u32,
-) -> anyhow::Result<Vec<RgbaImage>> {
-    let num_pictures = pictures.len();
+    Ok(tx.send(pending).await.is_ok())
+}
 
-    // - 1 picture -> Just show one
-    // - 2 or 4 picture -> show 2 pictures in a line and do 1 or 2 lines
-    // - other -> show 3 pictures in a line
-    let (num_pictures_in_line, picture_square_size) = match num_pictures {
-        1 => (1, image_area_width - image_margin * 2),
-        2 | 4 => (2, (image_area_width - image_margin * 3) / 2),
-        _ => (3, (image_area_width - image_margin * 4) / 3),
-    };
+/// 数据库中是否有 `now` 之前 `window_sec` 秒内发送的, 内容哈希为 `content_hash` 的动态
+fn recently_sent(db: &TargetTree, content_hash: u64, window_sec: u64, now: i64) -> bool {
+    db.iter()
+        .filter_map(Result::ok)
+        .filter_map(|(_, v)| serde_json::from_slice::<DbEntry>(&v).ok())
+        .any(|entry| {
+            entry.sent
+                && entry.content_hash == Some(content_hash)
+                && entry
+                    .sent_ts
+                    .is_some_and(|ts| now - ts <= window_sec as i64)
+        })
+}
 
-    // https://github.com/Starlwr/StarBot/blob/f92b4d71366e19046f5c1ae87fe85f2f2461cd69/starbot/painter/DynamicPicGenerator.py#L452-L469
-    let mut set = Vec::with_capacity(pictures.len());
-    for pic in pictures {
-        let (src, height, width) = match (
-            pic.get("url").and_then(Value::as_str).map(str::to_string),
-            pic.get("height").and_then(Value::as_f64),
-            pic.get("width").and_then(Value::as_f64),
+/// 按顺序发送队列中的动态, 发送成功后记录到数据库. 发送失败的动态留在数据库中下一轮重发
+async fn send_messages(
+    db: &TargetTree,
+    mirai: &MiraiConfig,
+    target: &TargetConfig,
+    mirai_client: &Client,
+    mut rx: mpsc::Receiver<PendingMessage>,
+    alerts: &mut Option<TargetAlerts>,
+) {
+    let send_interval = Duration::from_millis(mirai.send_interval_ms);
+    let mut last_sent = None;
+
+    while let Some(mut pending) = rx.recv().await {
+        let span = info_span!("dynamic", target = %target.label(), dynamic_id = pending.dynamic_id);
+
+        // 渲染通常比间隔更久, 只在需要时等待
+        if let Some(last_sent) = last_sent {
+            tokio::time::sleep_until(last_sent + send_interval).await;
+        }
+        last_sent = Some(tokio::time::Instant::now());
+
+        // 先记录发送意图, 发送之后、确认之前退出时重启不会重复发送
+        let was_sent = pending.entry.sent;
+        pending.entry.sent = true;
+        pending.entry.confirmed = false;
+        if let Err(e) = db.insert(
+            &pending.dynamic_key,
+            serde_json::to_vec(&pending.entry).unwrap(),
         ) {
-            (Some(src), Some(height), Some(width)) => (src, height, width),
-            _ => {
-                warn!("不合法的图片定义: {}，请检查API变动", pic);
-                continue;
+            error!(
+                "记录动态 {} 的发送意图失败, 下一轮重发: {}",
+                pending.dynamic_id, e
+            );
+            continue;
+        }
+
+        let delivered = deliver_message(mirai, target, mirai_client, pending.messages)
+            .instrument(span)
+            .await;
+        track_result(
+            mirai,
+            target,
+            mirai_client,
+            alerts,
+            FailureKind::Send,
+            &delivered,
+        )
+        .await;
+
+        match delivered {
+            Ok(_) => {
+                stats::record(pending.entry.type_ as i64, Outcome::Sent);
+                pending.entry.sent_ts = Some(Timestamp::now().as_second());
             }
-        };
+            Err(e) => {
+                error!("发送qq消息失败: {}", e);
+                stats::record(pending.entry.type_ as i64, Outcome::Failed);
+                pending.entry.sent = was_sent;
+            }
+        }
 
-        if num_pictures_in_line == 1 {
-            set.push(download_image(format!("{}@518w.webp", src)));
-        } else if height / width >= 3.0 {
-            set.push(download_image(format!(
-                "{}@{}w_{}h_!header.webp",
-                src, picture_square_size, picture_square_size
-            )));
-        } else {
-            set.push(download_image(format!(
-                "{}@{}w_{}h_1e_1c.webp",
-                src, picture_square_size, picture_square_size
-            )));
+        pending.entry.confirmed = true;
+        if let Err(e) = save_entry(db, pending.dynamic_id, &pending.entry) {
+            error!("记录动态 {} 的发送结果失败: {}", pending.dynamic_id, e);
         }
     }
+}
 
-    let results = futures::future::join_all(set.into_iter()).await;
-
-    let mut images = Vec::with_capacity(results.len());
+/// [`fetch_new_cards`] 获取的动态
+struct FetchedCards {
+    // 需要处理的动态
+    new: Vec<SpaceCard>,
+    // 第一页中的所有动态, 用于发现被编辑的动态
+    first_page: Vec<SpaceCard>,
+}
 
-    for result in results {
-        match result {
-            Ok(img) => {
-                let resized_image = match img.height().cmp(&img.width()) {
-                    cmp::Ordering::Equal => imageops::resize(
-                        &img,
-                        picture_square_size,
-                        picture_square_size,
-                        FilterType::Lanczos3,
-                    ),
-                    cmp::Ordering::Less => {
-                        // Image is wider, make height -> size and crop width from left and right
-                        let nheight = picture_square_size;
-                        let nwidth = ((picture_square_size as f64) * (img.width() as f64)
-                            / (img.height() as f64))
-                            .round() as u32;
+/// 获取需要处理的动态: 置顶动态在前, 其余按时间从旧到新排列.
+/// 向前翻页直到遇到数据库中已有的动态, 最多 `target.max_catch_up` 条; 第一次监听时只取最新的三条.
+/// 接口返回错误时返回 [`FetchFailed`], 下一轮重新获取
+async fn fetch_new_cards(
+    db: &TargetTree,
+    bili: &BiliConfig,
+    target: &TargetConfig,
+    client: &Client,
+) -> anyhow::Result<FetchedCards> {
+    let newest_known = newest_known_dynamic(db);
+    let limit = match newest_known {
+        Some(_) => target.max_catch_up,
+        None => INITIAL_DYNAMICS,
+    };
 
-                        let resized = imageops::resize(&img, nwidth, nheight, FilterType::Lanczos3);
+    let mut pinned_cards = Vec::new();
+    let mut new_cards = Vec::new();
+    let mut first_page = Vec::new();
+    let mut offset = None;
 
-                        imageops::crop_imm(
-                            &resized,
-                            (nwidth - picture_square_size) / 2,
-                            0,
-                            picture_square_size,
-                            picture_square_size,
-                        )
-                        .to_image()
-                    }
-                    cmp::Ordering::Greater => {
-                        // Image is longer, make width -> size and crop height from top and bottom
-                        let nwidth = picture_square_size;
-                        let nheight = ((picture_square_size as f64) * (img.height() as f64)
-                            / (img.width() as f64))
-                            .round() as u32;
+    loop {
+        let page = fetch_space_page(bili, target, client, offset.as_deref()).await?;
 
-                        let resized = imageops::resize(&img, nwidth, nheight, FilterType::Lanczos3);
+        // 置顶动态不占用最新动态的名额, 只在第一页出现
+        if offset.is_none() {
+            if page.cards.is_empty() {
+                debug!("用户 {} 没有公开的动态", target.uid);
+            }
+            if target.include_top {
+                pinned_cards.extend(page.cards.iter().filter(|card| card.pinned).cloned());
+            }
+            first_page = page.cards.clone();
+        }
 
-                        imageops::crop_imm(
-                            &resized,
-                            0,
-                            (nheight - picture_square_size) / 2,
-                            picture_square_size,
-                            picture_square_size,
-                        )
-                        .to_image()
-                    }
+        if take_new_cards(&page.cards, newest_known, limit, &mut new_cards) {
+            break;
+        }
+
+        match page.next_offset {
+            Some(next_offset) => offset = Some(next_offset),
+            None => break,
+        }
+    }
+
+    if newest_known.is_some() && new_cards.len() >= limit {
+        warn!(
+            "新动态超过 {} 条, 更早的动态不再补发, 可以调大 max_catch_up",
+            limit
+        );
+    }
+
+    pinned_cards.extend(new_cards.into_iter().rev());
+
+    Ok(FetchedCards {
+        new: pinned_cards,
+        first_page,
+    })
+}
+
+/// 从目标配置的动态列表接口获取一页动态, `offset` 为上一页返回的翻页位置.
+/// 接口返回错误时返回 [`FetchFailed`]
+async fn fetch_space_page(
+    bili: &BiliConfig,
+    target: &TargetConfig,
+    client: &Client,
+    offset: Option<&str>,
+) -> anyhow::Result<SpacePage> {
+    let request = match target.endpoint {
+        // 话题的动态列表与用户使用的接口无关
+        _ if target.mode == TargetMode::Topic => {
+            client.get(endpoints::feed_topic(bili)).query(&[
+                (
+                    "topic_id",
+                    target.topic_id.unwrap_or_default().to_string().as_str(),
+                ),
+                ("offset", offset.unwrap_or_default()),
+                // 按发布时间从新到旧排列
+                ("sort_by", "3"),
+                ("page_size", "20"),
+                ("source", "Web"),
+                ("features", "itemOpusStyle"),
+            ])
+        }
+        DynamicListEndpoint::FeedSpace => client.get(endpoints::feed_space(bili)).query(&[
+            ("host_mid", target.uid.to_string().as_str()),
+            ("offset", offset.unwrap_or_default()),
+            ("timezone_offset", "-480"),
+            ("features", "itemOpusStyle"),
+        ]),
+        DynamicListEndpoint::SpaceHistory => client.get(endpoints::space_history(bili)).query(&[
+            ("host_uid", target.uid.to_string().as_str()),
+            ("offset_dynamic_id", offset.unwrap_or("0")),
+            (
+                "need_top",
+                if target.include_top && offset.is_none() {
+                    "1"
+                } else {
+                    "0"
+                },
+            ),
+        ]),
+    };
+
+    let response: Value = request
+        .header("COOKIE", format!("SESSDATA={}", bili.sess_data))
+        .send()
+        .await
+        .context("Request dynamic from Bilibili")?
+        .json()
+        .await
+        .context("Parse dynamic response from Bilibili")?;
+
+    let code = response["code"]
+        .as_i64()
+        .ok_or_else(|| UnexpectedResponse(response.to_string()))?;
+
+    if code != 0 {
+        return Err(FetchFailed {
+            code,
+            message: response["message"].as_str().unwrap_or_default().to_string(),
+        }
+        .into());
+    }
+
+    let page = match target.endpoint {
+        _ if target.mode == TargetMode::Topic => SpacePage::from_feed_topic(&response["data"])?,
+        DynamicListEndpoint::FeedSpace => SpacePage::from_feed_space(&response["data"])?,
+        DynamicListEndpoint::SpaceHistory => SpacePage::from_space_history(&response["data"])?,
+    };
+
+    Ok(page)
+}
+
+/// 动态列表中的一条动态
+#[derive(Debug, Clone)]
+struct SpaceCard {
+    dynamic_id: i64,
+    // 旧版动态类型编号, 与 `DbEntry::type_` 相同
+    dynamic_type: i64,
+    uname: String,
+    pinned: bool,
+    // 新版接口返回完整的动态内容, 可以计算与 `DbEntry::content_hash` 相同的哈希
+    content_hash: Option<u64>,
+}
+
+impl SpaceCard {
+    /// 是否是可以生成消息的动态类型
+    fn is_supported(&self) -> bool {
+        matches!(self.dynamic_type, 1 | 2 | 4 | 4200)
+    }
+
+    /// * `item` dynamic item in the same format as the dynamic detail API
+    fn from_feed_item(item: &Value) -> Option<SpaceCard> {
+        let dynamic_id = parse_dynamic_id(&item["id_str"])
+            .inspect_err(|e| warn!("跳过动态: {}", e))
+            .ok()?;
+        Some(SpaceCard {
+            dynamic_id,
+            dynamic_type: legacy_dynamic_type(item["type"].as_str()?),
+            uname: item["modules"]["module_author"]["name"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            pinned: item["modules"]["module_tag"]["text"].as_str() == Some("置顶"),
+            content_hash: Some(item_content_hash(item)),
+        })
+    }
+}
+
+/// 动态列表的一页, 从新到旧排列
+#[derive(Debug)]
+struct SpacePage {
+    cards: Vec<SpaceCard>,
+    // 下一页的翻页位置, 没有更多动态时为 `None`
+    next_offset: Option<String>,
+}
+
+impl SpacePage {
+    /// * `data` field of response from legacy space history API https://api.vc.bilibili.com/dynamic_svr/v1/dynamic_svr/space_history
+    fn from_space_history(data: &Value) -> Result<SpacePage, UnexpectedResponse> {
+        // 没有动态的用户不返回 `cards`, 这时 `has_more` 为0
+        let cards = match &data["cards"] {
+            Value::Array(cards) => cards.as_slice(),
+            Value::Null if data["has_more"].as_i64() == Some(0) => &[],
+            _ => return Err(UnexpectedResponse(data.to_string())),
+        };
+
+        let cards = cards
+            .iter()
+            .filter_map(|card| {
+                let desc = &card["desc"];
+                let dynamic_id = match &desc["dynamic_id_str"] {
+                    Value::Null => &desc["dynamic_id"],
+                    id_str => id_str,
                 };
+                let dynamic_id = parse_dynamic_id(dynamic_id)
+                    .inspect_err(|e| warn!("跳过动态: {}", e))
+                    .ok()?;
+                Some(SpaceCard {
+                    dynamic_id,
+                    dynamic_type: desc["type"].as_i64()?,
+                    uname: desc["user_profile"]["info"]["uname"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    pinned: card["extra"]["is_space_top"].as_i64() == Some(1),
+                    content_hash: None,
+                })
+            })
+            .collect();
+
+        let next_offset = match &data["next_offset"] {
+            Value::Number(n) => n.as_u64().map(|n| n.to_string()),
+            Value::String(s) => Some(s.clone()),
+            _ => None,
+        };
+
+        Ok(SpacePage {
+            cards,
+            next_offset: next_offset.filter(|_| data["has_more"].as_i64() == Some(1)),
+        })
+    }
+
+    /// * `data` field of response from space feed API https://api.bilibili.com/x/polymer/web-dynamic/v1/feed/space
+    fn from_feed_space(data: &Value) -> Result<SpacePage, UnexpectedResponse> {
+        let items = match &data["items"] {
+            Value::Array(items) => items.as_slice(),
+            Value::Null if data["has_more"].as_bool() == Some(false) => &[],
+            _ => return Err(UnexpectedResponse(data.to_string())),
+        };
+
+        let cards = items.iter().filter_map(SpaceCard::from_feed_item).collect();
+
+        let next_offset = data["offset"]
+            .as_str()
+            .filter(|offset| !offset.is_empty())
+            .map(str::to_string);
+
+        Ok(SpacePage {
+            cards,
+            next_offset: next_offset.filter(|_| data["has_more"].as_bool() == Some(true)),
+        })
+    }
+
+    /// * `data` field of response from topic feed API https://api.bilibili.com/x/polymer/web-dynamic/v1/feed/topic
+    fn from_feed_topic(data: &Value) -> Result<SpacePage, UnexpectedResponse> {
+        let list = &data["topic_card_list"];
+        // 还没有动态的话题不返回 `topic_card_list`
+        let items = match &list["items"] {
+            Value::Array(items) => items.as_slice(),
+            Value::Null if list.is_null() || list["has_more"].as_bool() == Some(false) => &[],
+            _ => return Err(UnexpectedResponse(data.to_string())),
+        };
+
+        // 话题列表中还可能有其他类型的卡片, 只保留动态
+        let cards = items
+            .iter()
+            .filter_map(|item| item.get("dynamic_card_item"))
+            .filter_map(SpaceCard::from_feed_item)
+            .collect();
+
+        let next_offset = list["offset"]
+            .as_str()
+            .filter(|offset| !offset.is_empty())
+            .map(str::to_string);
+
+        Ok(SpacePage {
+            cards,
+            next_offset: next_offset.filter(|_| list["has_more"].as_bool() == Some(true)),
+        })
+    }
+}
+
+/// 动态列表接口返回的数据结构不符合预期, 可能是接口发生了变动
+#[derive(Debug)]
+struct UnexpectedResponse(String);
+
+impl std::fmt::Display for UnexpectedResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "动态列表的返回格式不符合预期, 请检查API变动: {}", self.0)
+    }
+}
+
+impl std::error::Error for UnexpectedResponse {}
+
+/// 动态列表接口返回了错误码, 例如cookie过期(-101)或者被风控(-352)
+#[derive(Debug)]
+struct FetchFailed {
+    code: i64,
+    message: String,
+}
+
+impl std::fmt::Display for FetchFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "获取动态列表失败: {} {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for FetchFailed {}
+
+/// Mirai发送消息接口返回的非0状态码
+#[derive(Debug)]
+struct MiraiStatus {
+    code: i32,
+    msg: String,
+}
+
+impl std::fmt::Display for MiraiStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.msg)
+    }
+}
+
+impl std::error::Error for MiraiStatus {}
+
+/// 动态详情接口没有返回动态内容, 通常是有地区或年龄限制的动态, 重试也无法获取
+#[derive(Debug)]
+struct DynamicUnavailable(i64);
+
+impl std::fmt::Display for DynamicUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "动态 {} 无法查看, 可能有地区或年龄限制", self.0)
+    }
+}
+
+impl std::error::Error for DynamicUnavailable {}
+
+// 安卓客户端的 appkey 和 appsec, app接口的 `access_key` 需要与签发它的 appkey 一起使用
+const APP_KEY: &str = "1d8b6a7cd3c4d0e3";
+const APP_SEC: &str = "560c52ccd288fed045859ed18bffd973";
+
+/// app接口的参数签名: 加上 `appkey` 后按键排序并编码, 拼接 appsec 后取md5作为 `sign` 参数
+fn sign_app_params(mut params: Vec<(&'static str, String)>) -> Vec<(&'static str, String)> {
+    params.push(("appkey", APP_KEY.to_string()));
+    params.sort();
+    let mut url = reqwest::Url::parse("http://localhost").unwrap();
+    url.query_pairs_mut().extend_pairs(&params);
+    let query = url.query().unwrap_or_default();
+    let sign = format!("{:x}", md5::compute(format!("{}{}", query, APP_SEC)));
+    params.push(("sign", sign));
+    params
+}
+
+/// 通过app接口获取动态详情, 并转换为网页版动态详情接口中 `data.item` 的格式
+async fn fetch_app_detail(
+    bili: &BiliConfig,
+    client: &Client,
+    access_key: &str,
+    dynamic_id: i64,
+) -> anyhow::Result<Value> {
+    let params = sign_app_params(vec![
+        ("access_key", access_key.to_string()),
+        ("dynamic_id", dynamic_id.to_string()),
+        ("ts", Timestamp::now().as_second().to_string()),
+    ]);
+    let response: Value = client
+        .get(endpoints::app_dynamic_detail(bili))
+        .query(&params)
+        .send()
+        .await?
+        .json()
+        .await?;
+    if response["code"].as_i64() != Some(0) {
+        return Err(anyhow!(
+            "app接口获取动态 {} 详情失败: {} {}",
+            dynamic_id,
+            response["code"],
+            response["message"]
+        ));
+    }
+
+    let card = &response["data"]["card"];
+    let desc = &card["desc"];
+    let profile = &desc["user_profile"];
+    let author = json!({
+        "name": profile["info"]["uname"],
+        "face": profile["info"]["face"],
+        "pub_ts": desc["timestamp"],
+        "vip": profile["vip"],
+        "level": profile["level_info"]["current_level"],
+        "pendant": profile["pendant"],
+    });
+
+    legacy_detail_item(
+        desc["type"].as_i64().unwrap_or_default(),
+        desc["dynamic_id_str"].as_str().unwrap_or_default(),
+        card["card"].as_str().context("app接口没有返回动态内容")?,
+        author,
+    )
+}
+
+/// 把旧版接口中类型为 `type_`, 内容为 `card`(JSON字符串)的动态转换为网页版动态详情的格式.
+/// 只支持文字, 图文和转发它们的动态
+fn legacy_detail_item(
+    type_: i64,
+    dynamic_id: &str,
+    card: &str,
+    author: Value,
+) -> anyhow::Result<Value> {
+    let card: Value = serde_json::from_str(card).context("app接口返回的动态内容不是JSON")?;
+    let text_nodes = |text: &Value| {
+        json!([{
+            "type": "RICH_TEXT_NODE_TYPE_TEXT",
+            "text": text.as_str().unwrap_or_default(),
+        }])
+    };
+
+    let (type_, module_dynamic, orig) = match type_ {
+        1 => {
+            let origin_author = &card["origin_user"]["info"];
+            let orig = legacy_detail_item(
+                card["item"]["orig_type"].as_i64().unwrap_or_default(),
+                card["item"]["orig_dy_id_str"].as_str().unwrap_or_default(),
+                card["origin"].as_str().context("转发动态没有原动态")?,
+                json!({ "name": origin_author["uname"], "face": origin_author["face"] }),
+            )?;
+            let module_dynamic =
+                json!({ "desc": { "rich_text_nodes": text_nodes(&card["item"]["content"]) } });
+            (DYNAMIC_TYPE_FORWARD, module_dynamic, orig)
+        }
+        2 => {
+            let pics: Vec<Value> = card["item"]["pictures"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|pic| {
+                    json!({
+                        "url": pic["img_src"],
+                        "width": pic["img_width"],
+                        "height": pic["img_height"],
+                    })
+                })
+                .collect();
+            let module_dynamic = json!({
+                "major": { "opus": {
+                    "summary": { "rich_text_nodes": text_nodes(&card["item"]["description"]) },
+                    "pics": pics,
+                } }
+            });
+            (DYNAMIC_TYPE_DRAW, module_dynamic, Value::Null)
+        }
+        4 => {
+            let module_dynamic = json!({
+                "major": { "opus": {
+                    "summary": { "rich_text_nodes": text_nodes(&card["item"]["content"]) },
+                } }
+            });
+            (DYNAMIC_TYPE_WORD, module_dynamic, Value::Null)
+        }
+        _ => return Err(anyhow!("app接口不支持的动态类型: {}", type_)),
+    };
+
+    Ok(json!({
+        "id_str": dynamic_id,
+        "type": type_,
+        "modules": {
+            "module_author": author,
+            "module_dynamic": module_dynamic,
+        },
+        "orig": orig,
+    }))
+}
+
+/// 新版接口的动态类型对应的旧版编号, 不认识的类型为0
+fn legacy_dynamic_type(type_: &str) -> i64 {
+    match type_ {
+        DYNAMIC_TYPE_FORWARD => 1,
+        DYNAMIC_TYPE_DRAW => 2,
+        DYNAMIC_TYPE_WORD => 4,
+        DYNAMIC_TYPE_AV => 8,
+        DYNAMIC_TYPE_ARTICLE => 64,
+        "DYNAMIC_TYPE_MUSIC" => 256,
+        "DYNAMIC_TYPE_COMMON_SQUARE" => 2048,
+        DYNAMIC_TYPE_LIVE => 4200,
+        "DYNAMIC_TYPE_MEDIALIST" => 4300,
+        "DYNAMIC_TYPE_COURSES_SEASON" => 4302,
+        "DYNAMIC_TYPE_LIVE_RCMD" => 4308,
+        "DYNAMIC_TYPE_UGC_SEASON" => 4310,
+        _ => 0,
+    }
+}
+
+/// 把一页动态(从新到旧)中比 `newest_known` 更新的非置顶动态加入 `new_cards`, 最多 `limit` 条.
+/// 返回是否不需要再向前翻页
+fn take_new_cards(
+    cards: &[SpaceCard],
+    newest_known: Option<i64>,
+    limit: usize,
+    new_cards: &mut Vec<SpaceCard>,
+) -> bool {
+    for card in cards.iter().filter(|card| !card.pinned) {
+        if new_cards.len() >= limit {
+            return true;
+        }
+
+        if newest_known.is_some_and(|known| card.dynamic_id <= known) {
+            return true;
+        }
+
+        new_cards.push(card.clone());
+    }
+
+    new_cards.len() >= limit
+}
+
+/// 数据库中最新的非置顶动态ID, 动态ID随发布时间递增
+fn newest_known_dynamic(db: &TargetTree) -> Option<i64> {
+    tree_meta(db).newest_dynamic_id
+}
 
-                images.push(resized_image);
+/// 遍历数据库得到最新的非置顶动态ID
+fn scan_newest_dynamic(db: &TargetTree) -> Option<i64> {
+    db.iter()
+        .filter_map(Result::ok)
+        .filter_map(|(k, v)| {
+            let entry: DbEntry = serde_json::from_slice(&v).ok()?;
+            if entry.pinned {
+                return None;
             }
-            Err(e) => {
-                println!("{}", e);
-                continue;
+            parse_dynamic_key(&k)
+        })
+        .max()
+}
+
+/// 将秒级时间戳格式化为东8区时间
+fn format_timestamp(ts: i64) -> anyhow::Result<String> {
+    let tz = china_time_zone();
+    let zoned_ts = Timestamp::from_second(ts)
+        .with_context(|| format!("时间戳 {} 超出范围", ts))?
+        .to_zoned(tz);
+    Ok(strtime::format("%Y-%m-%d %H:%M", &zoned_ts)?)
+}
+
+/// 像b站客户端一样把秒级时间戳格式化为相对 `now` 的时间, 超过一周时使用 [`format_timestamp`]
+fn format_relative(ts: i64, now: i64) -> anyhow::Result<String> {
+    const MINUTE: i64 = 60;
+    const HOUR: i64 = 60 * MINUTE;
+    const DAY: i64 = 24 * HOUR;
+    const WEEK: i64 = 7 * DAY;
+
+    let delta = now.saturating_sub(ts);
+    Ok(match delta {
+        // 本机时间比发布时间稍慢时也是刚刚
+        ..MINUTE => "刚刚".to_string(),
+        MINUTE..HOUR => format!("{}分钟前", delta / MINUTE),
+        HOUR..DAY => format!("{}小时前", delta / HOUR),
+        DAY..=WEEK => format!("{}天前", delta / DAY),
+        _ => format_timestamp(ts)?,
+    })
+}
+
+/// 当前东8区时间是否在目标的免打扰时段内
+fn in_quiet_hours(target: &TargetConfig) -> bool {
+    let Some(quiet_hours) = target.quiet_hours else {
+        return false;
+    };
+
+    let now = Timestamp::now().to_zoned(china_time_zone());
+
+    quiet_hours.contains(now.hour() as u32, now.minute() as u32)
+}
+
+async fn create_message_from_dynamic(
+    ctx: &RenderContext<'_, impl ImageFetcher>,
+    dynamic_id: i64,
+    pinned: bool,
+    allow_partial: bool,
+    deferred: bool,
+) -> anyhow::Result<(Vec<Message>, u64)> {
+    let RenderContext {
+        bili,
+        render,
+        target,
+        client,
+        fetcher,
+        ..
+    } = *ctx;
+    // 访问网络获取动态数据结构
+    let mut dynamic = BiliDynamic::fetch(bili, render, client, fetcher, dynamic_id).await?;
+    dynamic.pinned |= pinned;
+    dynamic.blur_flagged = target.blur_flagged;
+    dynamic.deferred = deferred;
+
+    // 图片没有全部下载成功时返回错误, 下一轮重试, 多次失败后发送不完整的动态
+    let missing_pics = dynamic.content.missing_pics();
+    if missing_pics > 0 {
+        if !allow_partial {
+            return Err(anyhow!(
+                "动态 {} 有 {} 张图片下载失败, 稍后重试",
+                dynamic_id,
+                missing_pics
+            ));
+        }
+        warn!(
+            "动态 {} 有 {} 张图片下载失败, 发送不完整的动态",
+            dynamic_id, missing_pics
+        );
+    }
+    // 画一张动态图, 绘制失败时只发送文字
+    let image = match draw_dynamic(&dynamic, render) {
+        Ok(image) => image,
+        Err(e) => {
+            error!("绘制动态 {} 失败, 只发送文字: {:#}", dynamic_id, e);
+            return Ok((
+                text_only_message(&dynamic, dynamic_id, render.header_style),
+                dynamic.content_hash,
+            ));
+        }
+    };
+
+    // 图片base64编码传到qq API
+    let image_buffer = encode_image(&image, render)?;
+    debug!(
+        "动态图片 {}x{}, {:?} {} 字节",
+        image.width(),
+        image.height(),
+        render.output_format,
+        image_buffer.len()
+    );
+    let image_b64 = base64::engine::general_purpose::STANDARD.encode(&image_buffer);
+
+    let messages = build_message_chain(&dynamic, dynamic_id, image_b64, render, target);
+
+    Ok((messages, dynamic.content_hash))
+}
+
+/// 动态图片无法绘制时代替消息链的纯文字消息: 消息头和动态的文字内容
+fn text_only_message(dynamic: &BiliDynamic, dynamic_id: i64, style: HeaderStyle) -> Vec<Message> {
+    vec![Message::Plain {
+        text: format!(
+            "{}{}",
+            message_header(dynamic, dynamic_id, style),
+            dynamic.content.to_plain_text()
+        ),
+    }]
+}
+
+/// 按 `render.message_order` 的顺序构造QQ消息链, 跳过没有开启的部分
+fn build_message_chain(
+    dynamic: &BiliDynamic,
+    dynamic_id: i64,
+    image_b64: String,
+    render: &RenderConfig,
+    target: &TargetConfig,
+) -> Vec<Message> {
+    let header = message_header(dynamic, dynamic_id, render.header_style);
+
+    let mut messages = Vec::new();
+    // 配置保证顺序中包含图片, 消息链不会为空
+    let mut image_b64 = Some(image_b64);
+
+    for part in render.message_order.parts() {
+        match part {
+            MessagePart::Header if target.send_header => {
+                messages.push(Message::Plain {
+                    text: header.clone(),
+                });
+            }
+            MessagePart::Image => {
+                if let Some(base64) = image_b64.take() {
+                    messages.push(Message::Image { base64 });
+                }
+            }
+            MessagePart::Text => {
+                let text = dynamic.content.to_plain_text();
+                if !text.is_empty() {
+                    messages.push(Message::Plain { text });
+                }
+            }
+            MessagePart::Urls if target.include_image_urls => {
+                let urls = dynamic.content.pic_urls(dynamic.blur_flagged);
+                if !urls.is_empty() {
+                    messages.push(Message::Plain {
+                        text: format!("原图:\n{}", urls.join("\n")),
+                    });
+                }
             }
+            MessagePart::Header | MessagePart::Urls => {}
         }
     }
 
-    Ok(images)
+    messages
 }
 
-// I use this as a quick hack to look up dynamic details
-#[tokio::test]
-async fn test_get_detail() {
-    let client = reqwest::Client::new();
-    let dynamic_id: i64 = 729922047097962504;
-    let sess_data = "SESSDATA";
-    let detail_response: Value =  client
-                        .get(format!("https://api.bilibili.com/x/polymer/web-dynamic/v1/detail?timezone_offset=-480&id={}&features=itemOpusStyle,opusBigCover,onlyfansVote", dynamic_id))
-                        .header("COOKIE", format!("SESSDATA={}", sess_data))
-                        .send()
-                        .await.unwrap()
-                        .json()
-                        .await.unwrap();
+/// 消息头: 谁发表了动态(或开始直播), `HeaderStyle::Full` 时还包括链接
+fn message_header(dynamic: &BiliDynamic, dynamic_id: i64, style: HeaderStyle) -> String {
+    let dynamic_url = format!("https://t.bilibili.com/{}", dynamic_id);
+    // 完整格式的动作, 一行格式的动作, 链接
+    let (action, compact_action, url) = match &dynamic.content {
+        Content::Forward {
+            texts: _,
+            original_author: _,
+            original_avatar: _,
+            original: _,
+        } => ("转发了动态", "转发了动态", dynamic_url),
+        Content::Draw {
+            texts: _,
+            cover: _,
+            pics: _,
+            missing_pics: _,
+            location: _,
+            flagged: _,
+        } => ("发表了新动态", "发布了带图动态", dynamic_url),
+        Content::Word { texts: _ } => ("发表了新动态", "发布了文字动态", dynamic_url),
+        Content::Live {
+            live_id,
+            live_title: _,
+            live_cover: _,
+            live_qr: _,
+        } => (
+            "直播了",
+            "开始直播了",
+            format!("https://live.bilibili.com/{}", live_id),
+        ),
+        Content::Video {
+            title: _,
+            cover: _,
+            duration: _,
+        } => ("投稿了视频", "投稿了视频", dynamic_url),
+        Content::Article { title: _, cover: _ } => ("发表了专栏", "发表了专栏", dynamic_url),
+    };
 
-    let s = serde_json::to_string_pretty(&detail_response).unwrap();
-    println!("{}", s)
+    match style {
+        HeaderStyle::Full => format!("{} {}\n{}\n", dynamic.author.uname, action, url),
+        HeaderStyle::Compact => format!("【{}】{}\n", dynamic.author.uname, compact_action),
+    }
+}
+
+/// 按配置的格式编码图片, JPEG没有透明通道, 先与白色背景混合
+fn encode_image(image: &RgbaImage, render: &RenderConfig) -> anyhow::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut cursor = Cursor::new(&mut buffer);
+
+    match render.output_format {
+        OutputFormat::Png => image.write_to(&mut cursor, image::ImageFormat::Png)?,
+        OutputFormat::Jpeg => {
+            let flattened = flatten_image(image, WHITE);
+            let encoder =
+                JpegEncoder::new_with_quality(&mut cursor, render.jpeg_quality.clamp(1, 100));
+            flattened.write_with_encoder(encoder)?;
+        }
+    }
+
+    Ok(buffer)
+}
+
+/// Blend an image against an opaque background color, dropping the alpha channel
+fn flatten_image(image: &RgbaImage, background: Rgba<u8>) -> RgbImage {
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let pixel = image.get_pixel(x, y);
+        let alpha = pixel[3] as f32 / 255.0;
+
+        let blend = |c: usize| -> u8 {
+            (pixel[c] as f32 * alpha + background[c] as f32 * (1.0 - alpha)).round() as u8
+        };
+
+        Rgb([blend(0), blend(1), blend(2)])
+    })
+}
+
+/// 发送动态消息给目标, 开启合并转发时将消息链的每一部分打包成一条转发消息,
+/// Mirai不支持时退回到直接发送原消息链
+async fn deliver_message(
+    mirai: &MiraiConfig,
+    target: &TargetConfig,
+    client: &Client,
+    messages: Vec<Message>,
+) -> anyhow::Result<()> {
+    let (receiver, messages) = route_messages(mirai, target, messages);
+
+    if target.merged_forward {
+        let forward = merge_as_forward(&messages, target.sender_qq);
+
+        match send_qq_message(mirai, target.sender_qq, receiver, client, forward).await {
+            Ok(_) => return Ok(()),
+            Err(e) if forward_rejected(&e) => {
+                warn!("Mirai不接受合并转发消息, 改为直接发送: {}", e)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    send_qq_message(mirai, target.sender_qq, receiver, client, messages).await
+}
+
+/// Mirai明确拒绝了合并转发消息: 不支持该消息类型(400)或者消息过长(30).
+/// 网络错误等其他失败不确定消息是否已经送达, 直接返回错误, 避免重复发送
+fn forward_rejected(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<MiraiStatus>(),
+        Some(MiraiStatus { code: 30 | 400, .. })
+    )
+}
+
+/// 监听目标的故障类型, 分别计数和提醒
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FailureKind {
+    /// 获取动态列表失败, 例如cookie过期或者被风控
+    Fetch,
+    /// 发送QQ消息失败
+    Send,
+}
+
+/// 记录一次获取或发送的结果. 连续失败达到 `mirai.alert_after_failures` 次时提醒管理员, 提醒过的故障恢复后再通知.
+/// 提醒本身发送失败只记录日志, 下次失败时再次尝试提醒
+async fn track_result(
+    mirai: &MiraiConfig,
+    target: &TargetConfig,
+    client: &Client,
+    alerts: &mut Option<TargetAlerts>,
+    kind: FailureKind,
+    result: &anyhow::Result<()>,
+) {
+    let (Some(alerts), Some(admin_qq)) = (alerts.as_mut(), mirai.admin_qq) else {
+        return;
+    };
+    let alert = match kind {
+        FailureKind::Fetch => &mut alerts.fetch,
+        FailureKind::Send => &mut alerts.send,
+    };
+
+    let now = std::time::Instant::now();
+    let text = match (kind, result) {
+        (FailureKind::Fetch, Ok(_)) if alert.success() => {
+            format!("{} 的动态已恢复获取", target.label())
+        }
+        (FailureKind::Send, Ok(_)) if alert.success() => format!(
+            "{} 的动态已恢复发送给 QQ {}",
+            target.label(),
+            target.receiver_qq
+        ),
+        (FailureKind::Fetch, Err(e)) if alert.failure(now) => format!(
+            "{} 的动态连续 {} 次获取失败: {:#}",
+            target.label(),
+            alert.failures(),
+            e
+        ),
+        (FailureKind::Send, Err(e)) if alert.failure(now) => format!(
+            "{} 的动态连续 {} 次发送给 QQ {} 失败: {:#}",
+            target.label(),
+            alert.failures(),
+            target.receiver_qq,
+            e
+        ),
+        _ => return,
+    };
+
+    let messages = vec![Message::Plain { text }];
+    match send_qq_message(
+        mirai,
+        target.sender_qq,
+        Receiver::Friend(admin_qq),
+        client,
+        messages,
+    )
+    .await
+    {
+        Ok(_) => {
+            if result.is_err() {
+                alert.alerted(now);
+            }
+            info!("已向 QQ {} 发送 {} 的状态提醒", admin_qq, target.label())
+        }
+        Err(e) => warn!("向 QQ {} 发送提醒失败: {}", admin_qq, e),
+    }
+}
+
+/// 消息的实际接收者. 设置了 `mirai.override_receiver` 时改发给该QQ, 并在消息前注明原接收者
+fn route_messages(
+    mirai: &MiraiConfig,
+    target: &TargetConfig,
+    mut messages: Vec<Message>,
+) -> (Receiver, Vec<Message>) {
+    let Some(qq) = mirai.override_receiver else {
+        return (Receiver::of_target(target), messages);
+    };
+
+    messages.insert(
+        0,
+        Message::Plain {
+            text: format!("[测试] 原接收者: {}\n", target.receiver_qq),
+        },
+    );
+    (Receiver::Friend(qq), messages)
+}
+
+/// QQ消息的接收者
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Receiver {
+    /// 好友消息
+    Friend(i64),
+    /// 通过群 `group` 发起的临时会话消息
+    Temp { qq: i64, group: i64 },
+}
+
+impl Receiver {
+    fn of_target(target: &TargetConfig) -> Receiver {
+        match target.temp_group {
+            Some(group) => Receiver::Temp {
+                qq: target.receiver_qq,
+                group,
+            },
+            None => Receiver::Friend(target.receiver_qq),
+        }
+    }
+}
+
+/// 将消息链的每一部分作为一个节点, 打包成一条合并转发消息
+fn merge_as_forward(messages: &[Message], sender_qq: i64) -> Vec<Message> {
+    let time = Timestamp::now().as_second();
+
+    let node_list = messages
+        .iter()
+        .map(|m| ForwardNode {
+            sender_id: sender_qq,
+            time,
+            sender_name: "bilibili动态".to_string(),
+            message_chain: vec![m.clone()],
+        })
+        .collect();
+
+    vec![Message::Forward { node_list }]
+}
+
+async fn send_qq_message(
+    mirai: &MiraiConfig,
+    sender_qq: i64,
+    receiver: Receiver,
+    client: &Client,
+    messages: Vec<Message>,
+) -> anyhow::Result<()> {
+    let session_key = mirai_verify(mirai, client).await?;
+
+    mirai_bind(mirai, client, &session_key, sender_qq).await?;
+
+    let (path, request) = match receiver {
+        Receiver::Friend(qq) => (
+            "sendFriendMessage",
+            client
+                .post(format!("{}/sendFriendMessage", mirai.base_url()))
+                .json(&SendFriendMessageRequest {
+                    session_key: session_key.clone(),
+                    target: qq,
+                    message_chain: messages,
+                }),
+        ),
+        Receiver::Temp { qq, group } => (
+            "sendTempMessage",
+            client
+                .post(format!("{}/sendTempMessage", mirai.base_url()))
+                .json(&SendTempMessageRequest {
+                    session_key: session_key.clone(),
+                    qq,
+                    group,
+                    message_chain: messages,
+                }),
+        ),
+    };
+
+    let send_response: SendMessageResponse = request
+        .send()
+        .await
+        .with_context(|| format!("Request MIRAI /{}", path))?
+        .json()
+        .await?;
+
+    if send_response.code != 0 {
+        return Err(MiraiStatus {
+            code: send_response.code,
+            msg: send_response.msg,
+        }
+        .into());
+    }
+
+    mirai_release(mirai, client, &session_key, sender_qq).await
+}
+
+/// Mirai `/verify`, 返回session key
+async fn mirai_verify(mirai: &MiraiConfig, client: &Client) -> anyhow::Result<String> {
+    let verify_request = VerifyRequest {
+        verify_key: mirai.verify_key.clone(),
+    };
+
+    let verify_response: VerifyResponse = client
+        .post(format!("{}/verify", mirai.base_url()))
+        .json(&verify_request)
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    if verify_response.code != 0 {
+        return Err(anyhow!(
+            "{}: {}",
+            verify_response.code,
+            verify_response.msg.unwrap_or_default()
+        ));
+    }
+
+    verify_response
+        .session
+        .ok_or_else(|| anyhow!("Mirai /verify 没有返回session"))
+}
+
+/// Mirai `/bind`, 将session绑定到 `qq`
+async fn mirai_bind(
+    mirai: &MiraiConfig,
+    client: &Client,
+    session_key: &str,
+    qq: i64,
+) -> anyhow::Result<()> {
+    let bind_request = BindRequest {
+        session_key: session_key.to_string(),
+        qq,
+    };
+
+    let bind_response: BindResponse = client
+        .post(format!("{}/bind", mirai.base_url()))
+        .json(&bind_request)
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    if bind_response.code != 0 {
+        return Err(anyhow!("{}: {}", bind_response.code, bind_response.msg));
+    }
+
+    Ok(())
+}
+
+/// Mirai `/release`, 释放session
+async fn mirai_release(
+    mirai: &MiraiConfig,
+    client: &Client,
+    session_key: &str,
+    qq: i64,
+) -> anyhow::Result<()> {
+    let release_request = ReleaseRequest {
+        session_key: session_key.to_string(),
+        qq,
+    };
+
+    let release_response: ReleaseResponse = client
+        .post(format!("{}/release", mirai.base_url()))
+        .json(&release_request)
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    if release_response.code != 0 {
+        return Err(anyhow!(
+            "{}: {}",
+            release_response.code,
+            release_response.msg
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct BiliDynamic {
+    // 动态ID
+    dynamic_id: i64,
+    author: AuthorInfo,
+    content: Content,
+    // 是否是置顶动态
+    pinned: bool,
+    // 充电专属动态, 没有充电时接口只返回预览
+    charge_only: bool,
+    // 文字和图片地址的哈希
+    content_hash: u64,
+    // 模糊有内容警告的图片并且不发送原图地址, 由监听目标的 `blur_flagged` 决定
+    blur_flagged: bool,
+    // 卡片不会在绘制后立即发送, 不使用相对时间
+    deferred: bool,
+}
+
+#[derive(Debug)]
+struct AuthorInfo {
+    uname: String,
+    vip: bool,
+    // 年度大会员
+    annual_vip: bool,
+    // 用户等级, API没有返回时为 `None`
+    level: Option<u64>,
+    publish_timestamp: i64,
+    avatar_image: RgbaImage,
+    // 头像框(装扮挂件), `render.show_pendant` 开启且用户佩戴了头像框时下载
+    pendant_image: Option<RgbaImage>,
+}
+
+#[derive(Debug)]
+enum Content {
+    // 转发动态
+    Forward {
+        texts: Vec<RichTextNode>,
+        original_author: String,
+        // 原作者的头像, 只在 `render.forward_show_avatar` 开启时下载
+        original_avatar: Option<RgbaImage>,
+        original: Box<Content>,
+    },
+    // 带图动态
+    Draw {
+        texts: Vec<RichTextNode>,
+        // 大图封面(opusBigCover), 按原比例通栏绘制在相册之前
+        cover: Option<AlbumImage>,
+        pics: Vec<AlbumImage>,
+        // 下载失败的图片数量
+        missing_pics: usize,
+        // 定位的地点名称, 大多数动态没有
+        location: Option<String>,
+        // 有内容警告, 目标开启了 `blur_flagged` 时图片模糊后绘制
+        flagged: bool,
+    },
+    // 纯文字动态
+    Word {
+        texts: Vec<RichTextNode>,
+    },
+    // 直播动态
+    Live {
+        live_id: i64,
+        live_title: String,
+        live_cover: RgbaImage,
+        // 直播间链接的二维码, `render.live_qr` 开启时生成
+        live_qr: Option<RgbaImage>,
+    },
+    // 视频动态, 作为转发的原动态时只绘制封面和标题
+    Video {
+        title: String,
+        // 封面缩略图, 下载失败时为 `None`
+        cover: Option<RgbaImage>,
+        // 时长, 例如 "03:21"
+        duration: Option<String>,
+    },
+    // 专栏动态, 作为转发的原动态时只绘制封面和标题
+    Article {
+        title: String,
+        cover: Option<RgbaImage>,
+    },
+}
+
+impl BiliDynamic {
+    async fn fetch(
+        bili: &BiliConfig,
+        render: &RenderConfig,
+        client: &Client,
+        fetcher: &impl ImageFetcher,
+        dynamic_id: i64,
+    ) -> anyhow::Result<BiliDynamic> {
+        let detail_response: Value = client
+            .get(endpoints::dynamic_detail(bili))
+            .header("COOKIE", format!("SESSDATA={}", bili.sess_data))
+            .query(&[
+                ("timezone_offset", "-480"),
+                ("id", &dynamic_id.to_string()),
+                ("features", "itemOpusStyle,opusBigCover,onlyfansVote"),
+            ])
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        let code = detail_response["code"].as_i64();
+        if let (Some(code @ (-352 | -412)), Some(access_key)) = (code, &bili.app_access_key) {
+            warn!(
+                "动态详情接口被风控({}), 改用app接口获取动态 {}",
+                code, dynamic_id
+            );
+            let item = fetch_app_detail(bili, client, access_key, dynamic_id).await?;
+            return BiliDynamic::from_detail_json(bili, render, Some(client), fetcher, &item).await;
+        }
+        if code != Some(0) {
+            return Err(anyhow!(
+                "获取动态 {} 详情失败: {} {}",
+                dynamic_id,
+                detail_response["code"],
+                detail_response["message"]
+            ));
+        }
+
+        // 有地区或年龄限制的动态返回成功, 但没有内容
+        let item = &detail_response["data"]["item"];
+        if item.is_null() {
+            return Err(DynamicUnavailable(dynamic_id).into());
+        }
+
+        BiliDynamic::from_detail_json(bili, render, Some(client), fetcher, item).await
+    }
+
+    /// * `response["data"]["item"]` field of response from dynamic detail API https://api.bilibili.com/x/polymer/web-dynamic/v1/detail
+    /// * `client` used to look up lotteries and votes in the text, `None` to stay offline
+    async fn from_detail_json(
+        bili: &BiliConfig,
+        render: &RenderConfig,
+        client: Option<&Client>,
+        fetcher: &impl ImageFetcher,
+        item: &Value,
+    ) -> anyhow::Result<BiliDynamic> {
+        // 没有ID的动态无法记录, 不能当作ID为0的动态处理
+        let dynamic_id = parse_dynamic_id(&item["id_str"])?;
+
+        // 构建作者
+        let author_info = &item["modules"]["module_author"];
+        let uname = author_info["name"].as_str().unwrap().to_string();
+        let face_url = author_info
+            .get("face")
+            .and_then(Value::as_str)
+            .filter(|url| !url.is_empty());
+        let face_image = match face_url {
+            Some(url) => download_face(fetcher, url).await.unwrap_or_else(|e| {
+                warn!("下载头像失败, 使用默认头像: {}", e);
+                resource().no_face_image.clone()
+            }),
+            None => resource().no_face_image.clone(),
+        };
+        let vip = author_info
+            .get("vip")
+            .and_then(|v| v.get("nickname_color"))
+            .and_then(|c| c.as_str())
+            .map(|s| !s.is_empty())
+            .unwrap_or_default();
+        // vip.type: 1 月度大会员, 2 年度大会员
+        let annual_vip = vip && author_info["vip"]["type"].as_i64() == Some(2);
+        let level = author_info["level"].as_u64();
+        let timestamp = author_info
+            .get("pub_ts")
+            .and_then(|v| v.as_i64())
+            .unwrap_or_default();
+        let pendant_url = author_info["pendant"]["image"]
+            .as_str()
+            .filter(|url| !url.is_empty());
+        let pendant_image = match pendant_url {
+            Some(url) if render.show_pendant => match download_image(fetcher, url).await {
+                Ok(img) => Some(img),
+                Err(e) => {
+                    warn!("下载头像框失败, 不绘制头像框: {}", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let author = AuthorInfo {
+            uname,
+            vip,
+            annual_vip,
+            level,
+            publish_timestamp: timestamp,
+            avatar_image: face_image,
+            pendant_image,
+        };
+
+        // 构建内容
+        let content = Content::from_detail_json(
+            bili,
+            render,
+            client,
+            fetcher,
+            item,
+            CARD_WIDTH - 2 * CONTENT_X,
+        )
+        .await?;
+
+        let pinned = item["modules"]["module_tag"]["text"].as_str() == Some("置顶");
+        let charge_only = item["basic"]["is_only_fans"].as_bool() == Some(true) || is_blocked(item);
+        let content_hash = item_content_hash(item);
+
+        Ok(BiliDynamic {
+            dynamic_id,
+            author,
+            content,
+            pinned,
+            charge_only,
+            content_hash,
+            blur_flagged: false,
+            deferred: false,
+        })
+    }
+}
+
+/// 动态及其转发的原动态中的图片地址
+fn image_urls(item: &Value) -> Vec<&str> {
+    let major = &item["modules"]["module_dynamic"]["major"];
+
+    let mut urls: Vec<&str> = major["opus"]["pics"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|pic| pic["url"].as_str())
+        .collect();
+    urls.extend(major["live"]["cover"].as_str());
+
+    if item["orig"].is_object() {
+        urls.extend(image_urls(&item["orig"]));
+    }
+
+    urls
+}
+
+/// 图文动态定位的地点名称, 优先使用显示的标题. 大多数动态没有定位
+fn location_name(module_dynamic: &Value) -> Option<String> {
+    const POINTERS: [&str; 2] = ["/additional/lbs/show_title", "/additional/lbs/title"];
+
+    POINTERS
+        .iter()
+        .filter_map(|pointer| module_dynamic.pointer(pointer))
+        .filter_map(Value::as_str)
+        .map(str::trim)
+        .find(|name| !name.is_empty())
+        .map(str::to_string)
+}
+
+/// 动态及其转发的原动态中未经处理的文字, 包括标题
+fn raw_text(item: &Value) -> String {
+    let module_dynamic = &item["modules"]["module_dynamic"];
+    let major = &module_dynamic["major"];
+
+    let mut text = String::new();
+    text.push_str(major["opus"]["title"].as_str().unwrap_or_default());
+    for nodes in [
+        &module_dynamic["desc"]["rich_text_nodes"],
+        &major["opus"]["summary"]["rich_text_nodes"],
+    ] {
+        for node in nodes.as_array().into_iter().flatten() {
+            text.push_str(node["text"].as_str().unwrap_or_default());
+        }
+    }
+    text.push_str(major["live"]["title"].as_str().unwrap_or_default());
+
+    if item["orig"].is_object() {
+        text.push_str(&raw_text(&item["orig"]));
+    }
+
+    text
+}
+
+/// 动态列表和动态详情中同一条动态的文字和图片地址的哈希
+fn item_content_hash(item: &Value) -> u64 {
+    content_hash(&raw_text(item), &image_urls(item))
+}
+
+/// 文字和图片地址的FNV-1a哈希. 不使用 `DefaultHasher`, 因为它的结果在不同Rust版本间不保证相同
+fn content_hash(text: &str, image_urls: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    for part in std::iter::once(text).chain(image_urls.iter().copied()) {
+        // 0xff不会出现在UTF-8中, 用于分隔各部分
+        for b in part.bytes().chain([0xff]) {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    hash
+}
+
+impl Content {
+    /// Number of album images that failed to download, including those of a forwarded original
+    fn missing_pics(&self) -> usize {
+        match self {
+            Content::Forward {
+                texts: _,
+                original_author: _,
+                original_avatar: _,
+                original,
+            } => original.missing_pics(),
+            Content::Draw {
+                texts: _,
+                cover: _,
+                pics: _,
+                missing_pics,
+                location: _,
+                flagged: _,
+            } => *missing_pics,
+            _ => 0,
+        }
+    }
+
+    /// Original urls of the downloaded album images, including those of a forwarded original.
+    /// Those of flagged content are left out when `blur_flagged`, as their images are blurred
+    fn pic_urls(&self, blur_flagged: bool) -> Vec<&str> {
+        match self {
+            Content::Forward {
+                texts: _,
+                original_author: _,
+                original_avatar: _,
+                original,
+            } => original.pic_urls(blur_flagged),
+            Content::Draw {
+                texts: _,
+                cover: _,
+                pics: _,
+                missing_pics: _,
+                location: _,
+                flagged: true,
+            } if blur_flagged => Vec::new(),
+            Content::Draw {
+                texts: _,
+                cover,
+                pics,
+                missing_pics: _,
+                location: _,
+                flagged: _,
+            } => cover
+                .iter()
+                .chain(pics)
+                .map(|pic| pic.original_url.as_str())
+                // 图片定义不合法时没有原图地址
+                .filter(|url| !url.is_empty())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Plain text of the content, emoji as their alt text. A forward includes its comment
+    /// followed by the original as `//@作者: 原动态`
+    fn to_plain_text(&self) -> String {
+        match self {
+            Content::Forward {
+                texts,
+                original_author,
+                original_avatar: _,
+                original,
+            } => format!(
+                "{}//@{}: {}",
+                plain_text(texts),
+                original_author,
+                original.to_plain_text()
+            ),
+            Content::Draw {
+                texts,
+                cover: _,
+                pics: _,
+                missing_pics: _,
+                location: _,
+                flagged: _,
+            } => plain_text(texts),
+            Content::Word { texts } => plain_text(texts),
+            Content::Live {
+                live_id: _,
+                live_title,
+                live_cover: _,
+                live_qr: _,
+            } => live_title.clone(),
+            Content::Video {
+                title,
+                cover: _,
+                duration: _,
+            } => title.clone(),
+            Content::Article { title, cover: _ } => title.clone(),
+        }
+    }
+
+    /// * `response["data"]["item"]` field of response from dynamic detail API https://api.bilibili.com/x/polymer/web-dynamic/v1/detail
+    /// * `client` used to look up lotteries and votes in the text, `None` to stay offline
+    /// * `content_width` width of the area the content will be drawn in, used to size images
+    async fn from_detail_json(
+        bili: &BiliConfig,
+        render: &RenderConfig,
+        client: Option<&Client>,
+        fetcher: &impl ImageFetcher,
+        item: &Value,
+        content_width: u32,
+    ) -> anyhow::Result<Content> {
+        // 没有充电时充电专属动态的内容被替换为提示, 只绘制能看到的预览
+        if is_blocked(item) {
+            return Content::charge_preview(bili, render, client, fetcher, item).await;
+        }
+
+        let dynamic_type = item["type"].as_str().unwrap();
+        match dynamic_type {
+            DYNAMIC_TYPE_FORWARD => {
+                let raw_text_nodes = item["modules"]["module_dynamic"]["desc"]["rich_text_nodes"]
+                    .as_array()
+                    .unwrap();
+                let texts =
+                    build_text_nodes(bili, render, client, fetcher, None, raw_text_nodes).await?;
+
+                let orig_author = item["orig"]["modules"]["module_author"]["name"]
+                    .as_str()
+                    .unwrap()
+                    .to_string();
+                // 原动态缩进绘制, 图片按缩进后的宽度下载
+                let orig = Box::pin(Content::from_detail_json(
+                    bili,
+                    render,
+                    client,
+                    fetcher,
+                    &item["orig"],
+                    content_width - 2 * FORWARD_INDENT,
+                ))
+                .await?;
+
+                let orig_face_url = item["orig"]["modules"]["module_author"]["face"]
+                    .as_str()
+                    .filter(|url| !url.is_empty());
+                let original_avatar = match orig_face_url {
+                    Some(url) if render.forward_show_avatar => {
+                        match download_face(fetcher, url).await {
+                            Ok(image) => Some(image),
+                            Err(e) => {
+                                warn!("下载原作者头像失败, 不绘制头像: {}", e);
+                                None
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+
+                Ok(Content::Forward {
+                    texts,
+                    original_author: orig_author,
+                    original_avatar,
+                    original: Box::new(orig),
+                })
+            }
+            DYNAMIC_TYPE_DRAW => {
+                let opus = &item["modules"]["module_dynamic"]["major"]["opus"];
+                let title = opus["title"].as_str().map(str::to_string);
+                let raw_text_nodes = opus["summary"]["rich_text_nodes"].as_array().unwrap();
+                let texts =
+                    build_text_nodes(bili, render, client, fetcher, title, raw_text_nodes).await?;
+
+                let mut pics = opus["pics"].as_array().map_or(&[][..], Vec::as_slice);
+
+                // 大图封面的第一张图片不裁剪成正方形, 其余图片仍按相册排列
+                let mut cover = None;
+                let mut missing_cover = 0;
+                if is_big_cover(opus) && !pics.is_empty() {
+                    match download_cover_image(
+                        fetcher,
+                        &pics[0],
+                        content_width,
+                        render.resize_filter.into(),
+                    )
+                    .await
+                    {
+                        Ok(image) => {
+                            cover = Some(AlbumImage {
+                                image,
+                                original_url: pics[0]["url"]
+                                    .as_str()
+                                    .unwrap_or_default()
+                                    .to_string(),
+                                cropped: false,
+                            })
+                        }
+                        Err(e) => {
+                            warn!("下载封面图片失败: {}", e);
+                            missing_cover = 1;
+                        }
+                    }
+                    pics = &pics[1..];
+                }
+
+                let (pics, missing_pics) = download_dynamic_images(
+                    fetcher,
+                    pics,
+                    content_width + 2 * IMAGE_MARGIN,
+                    IMAGE_MARGIN,
+                    render.resize_filter.into(),
+                )
+                .await?;
+
+                Ok(Content::Draw {
+                    texts,
+                    cover,
+                    pics,
+                    missing_pics: missing_pics + missing_cover,
+                    location: location_name(&item["modules"]["module_dynamic"]),
+                    flagged: is_flagged(item),
+                })
+            }
+            DYNAMIC_TYPE_WORD => {
+                let opus = &item["modules"]["module_dynamic"]["major"]["opus"];
+                let title = opus["title"].as_str().map(str::to_string);
+                let raw_text_nodes = opus["summary"]["rich_text_nodes"].as_array().unwrap();
+                let texts =
+                    build_text_nodes(bili, render, client, fetcher, title, raw_text_nodes).await?;
+
+                Ok(Content::Word { texts })
+            }
+            DYNAMIC_TYPE_LIVE => {
+                let live = &item["modules"]["module_dynamic"]["major"]["live"];
+                let live_id = live["id"].as_i64().unwrap();
+                let live_title = live["title"].as_str().unwrap().to_string();
+                let live_cover_url =
+                    format!("{}@203w_127h_1e_1c.webp", live["cover"].as_str().unwrap());
+                let live_cover = download_picture(fetcher, &live_cover_url, None).await?;
+                let live_qr = if render.live_qr {
+                    Some(live_qr_image(live_id)?)
+                } else {
+                    None
+                };
+
+                Ok(Content::Live {
+                    live_id,
+                    live_title,
+                    live_cover,
+                    live_qr,
+                })
+            }
+            DYNAMIC_TYPE_AV => {
+                let archive = &item["modules"]["module_dynamic"]["major"]["archive"];
+                let title = archive["title"]
+                    .as_str()
+                    .with_context(|| format!("视频动态缺少标题: {}", archive))?
+                    .to_string();
+                let cover = match archive["cover"].as_str() {
+                    Some(url) => download_archive_cover(fetcher, url, render).await,
+                    None => None,
+                };
+
+                Ok(Content::Video {
+                    title,
+                    cover,
+                    duration: archive["duration_text"].as_str().map(str::to_string),
+                })
+            }
+            DYNAMIC_TYPE_ARTICLE => {
+                // 旧版专栏为 `major.article`, 新版与图文动态相同为 `major.opus`
+                let major = &item["modules"]["module_dynamic"]["major"];
+                let (title, cover_url) = match &major["article"] {
+                    Value::Null => (&major["opus"]["title"], &major["opus"]["pics"][0]["url"]),
+                    article => (&article["title"], &article["covers"][0]),
+                };
+                let title = title
+                    .as_str()
+                    .with_context(|| format!("专栏动态缺少标题: {}", major))?
+                    .to_string();
+                let cover = match cover_url.as_str() {
+                    Some(url) => download_archive_cover(fetcher, url, render).await,
+                    None => None,
+                };
+
+                Ok(Content::Article { title, cover })
+            }
+            _ => Err(anyhow!("不支持的动态类型: {}", dynamic_type)),
+        }
+    }
+
+    /// 充电专属动态的预览: 接口返回的描述文字(如果有)和充电提示
+    async fn charge_preview(
+        bili: &BiliConfig,
+        render: &RenderConfig,
+        client: Option<&Client>,
+        fetcher: &impl ImageFetcher,
+        item: &Value,
+    ) -> anyhow::Result<Content> {
+        let module_dynamic = &item["modules"]["module_dynamic"];
+        let raw_text_nodes = module_dynamic["desc"]["rich_text_nodes"]
+            .as_array()
+            .map_or(&[][..], Vec::as_slice);
+        let mut texts =
+            build_text_nodes(bili, render, client, fetcher, None, raw_text_nodes).await?;
+
+        let hint = module_dynamic["major"]["blocked"]["hint_message"]
+            .as_str()
+            .filter(|hint| !hint.is_empty())
+            .unwrap_or("开通充电后可查看");
+        let separator = if texts.is_empty() { "" } else { "\n" };
+        texts.push(RichTextNode::Text {
+            text: format!("{}{}", separator, hint),
+        });
+
+        Ok(Content::Word { texts })
+    }
+}
+
+/// 下载视频或专栏的封面并缩放为 [`ARCHIVE_COVER_SIZE`], 下载失败时只绘制标题
+async fn download_archive_cover(
+    fetcher: &impl ImageFetcher,
+    url: &str,
+    render: &RenderConfig,
+) -> Option<RgbaImage> {
+    let (width, height) = ARCHIVE_COVER_SIZE;
+    let url = format!("{}@{}w_{}h_1e_1c.webp", url, width, height);
+    match download_picture(fetcher, &url, None).await {
+        Ok(image) => Some(imageops::resize(
+            &image,
+            width,
+            height,
+            render.resize_filter.into(),
+        )),
+        Err(e) => {
+            warn!("下载封面失败, 只绘制标题: {}", e);
+            None
+        }
+    }
+}
+
+/// 内容被替换为充电提示的动态
+fn is_blocked(item: &Value) -> bool {
+    item["modules"]["module_dynamic"]["major"]["type"].as_str() == Some("MAJOR_TYPE_BLOCKED")
+}
+
+/// 动态是否带有内容警告(动态下方的争议提示条)
+fn is_flagged(item: &Value) -> bool {
+    item["modules"]["module_dispute"]["title"]
+        .as_str()
+        .is_some_and(|title| !title.is_empty())
+}
+
+/// 动态卡片头部(头像, 用户名, 发布时间)和边距的排版参数
+struct CardLayout {
+    /// 头像左上角坐标
+    face_xy: (u32, u32),
+    /// 头像直径
+    face_size: u32,
+    /// 大会员下标边长
+    vip_size: u32,
+    /// 用户名左上角坐标
+    name_xy: (u32, u32),
+    /// 用户名和时间戳之间以及时间戳下方的间距
+    header_row_space: u32,
+    /// 卡片底部从最后一行之后的间距中去掉的高度
+    bottom_trim: u32,
+}
+
+const NORMAL_LAYOUT: CardLayout = CardLayout {
+    face_xy: (50, 50),
+    face_size: 100,
+    vip_size: 33,
+    name_xy: (175, 60),
+    header_row_space: 25,
+    bottom_trim: 0,
+};
+
+// 纯文字动态的紧凑排版
+const COMPACT_LAYOUT: CardLayout = CardLayout {
+    face_xy: (25, 25),
+    face_size: 60,
+    vip_size: 20,
+    name_xy: (100, 25),
+    header_row_space: 5,
+    bottom_trim: 10,
+};
+
+/// Draw the card of `dynamic`. Content that can't be laid out is an error rather than a panic,
+/// so the caller can fall back to sending text
+fn draw_dynamic(dynamic: &BiliDynamic, render: &RenderConfig) -> anyhow::Result<RgbaImage> {
+    let layout = match dynamic.content {
+        Content::Word { texts: _ } if render.compact => &COMPACT_LAYOUT,
+        _ => &NORMAL_LAYOUT,
+    };
+    let filter = render.resize_filter.into();
+
+    let mut generator = PicGenerator::new(CARD_WIDTH, 10000);
+    generator.draw_rectangle(0, 0, 10000, CARD_WIDTH, WHITE);
+
+    // 绘制用户头像
+    let resized_face = imageops::resize(
+        &dynamic.author.avatar_image,
+        layout.face_size,
+        layout.face_size,
+        filter,
+    );
+    let circular_face = create_circular_image(&resized_face, layout.face_size, filter);
+    generator.draw_img_alpha(&circular_face, Some(layout.face_xy));
+    // 绘制头像框, 以头像为中心
+    if let Some(pendant) = &dynamic.author.pendant_image {
+        let size = layout.face_size * PENDANT_SCALE_PERCENT / 100;
+        let offset = (size - layout.face_size) / 2;
+        let pendant = imageops::resize(pendant, size, size, filter);
+        let (face_x, face_y) = layout.face_xy;
+        generator.draw_img_alpha(
+            &pendant,
+            Some((face_x.saturating_sub(offset), face_y.saturating_sub(offset))),
+        );
+    }
+    // 绘制大会员下标
+    if dynamic.author.vip {
+        let (face_x, face_y) = layout.face_xy;
+        let offset = layout.face_size - layout.vip_size + 1;
+        let vip_image = if resource().vip_image.width() == layout.vip_size {
+            resource().vip_image.clone()
+        } else {
+            imageops::resize(
+                &resource().vip_image,
+                layout.vip_size,
+                layout.vip_size,
+                filter,
+            )
+        };
+        generator.draw_img_alpha(&vip_image, Some((face_x + offset, face_y + offset)));
+    }
+    generator.set_pos(layout.name_xy.0, layout.name_xy.1);
+    generator.set_row_space(layout.header_row_space);
+    let uname_color = if dynamic.author.vip { PINK } else { BLACK };
+    let publish_ts = dynamic.author.publish_timestamp;
+    // 推迟发送的卡片中的相对时间到发送时已经过时
+    let ts = match render.time_style {
+        TimeStyle::Relative if !dynamic.deferred => {
+            format_relative(publish_ts, Timestamp::now().as_second())
+        }
+        _ => format_timestamp(publish_ts),
+    }
+    .context("动态发布时间")?;
+    // 绘制用户名和动态时间戳
+    generator.draw_text(
+        &[&dynamic.author.uname],
+        &[uname_color],
+        &resource().text_normal_font,
+        TEXT_SCALE,
+        None,
+    );
+    generator.draw_text(
+        &[&ts],
+        &[GRAY],
+        &resource().text_normal_font,
+        TIP_SCALE,
+        None,
+    );
+
+    // 在用户名后绘制年度大会员、等级和充电专属标记
+    let (name_width, _) = imageproc::drawing::text_size(
+        TEXT_SCALE,
+        &resource().text_normal_font,
+        &dynamic.author.uname,
+    );
+    let mut badge_x = layout.name_xy.0 + name_width + 10;
+    if dynamic.author.annual_vip {
+        badge_x += draw_badge(
+            &mut generator,
+            badge_x,
+            layout.name_xy.1,
+            "年度大会员",
+            PINK,
+        ) + 6;
+    }
+    if let Some(level) = dynamic.author.level {
+        badge_x += draw_badge(
+            &mut generator,
+            badge_x,
+            layout.name_xy.1,
+            &format!("LV{}", level),
+            GRAY,
+        ) + 6;
+    }
+    if dynamic.charge_only {
+        draw_badge(
+            &mut generator,
+            badge_x,
+            layout.name_xy.1,
+            "充电专属动态",
+            ORANGE,
+        );
+    }
+
+    // 绘制置顶标记
+    if dynamic.pinned {
+        draw_pinned_badge(&mut generator, layout.name_xy.1);
+    }
+
+    // 开始绘制动态内容
+    generator.set_x(CONTENT_X);
+    generator.set_row_space(10);
+
+    // 限制卡片高度时, 为"阅读全文"一行和底部二维码留出空间
+    let max_y = render.max_height.map(|max_height| {
+        let mut reserved = READ_MORE_HEIGHT;
+        if render.dynamic_qr {
+            reserved += CARD_WIDTH / DYNAMIC_QR_WIDTH_DIVISOR + 10;
+        }
+        max_height.saturating_sub(reserved)
+    });
+
+    let truncated = draw_content(
+        &mut generator,
+        &dynamic.content,
+        CARD_WIDTH - 2 * CONTENT_X,
+        CONTENT_SCALES,
+        render,
+        max_y,
+        dynamic.blur_flagged,
+    )?;
+
+    if truncated {
+        generator.set_x(CONTENT_X);
+        generator.draw_text(
+            &[&format!(
+                "……阅读全文 https://t.bilibili.com/{}",
+                dynamic.dynamic_id
+            )],
+            &[GRAY],
+            &resource().text_normal_font,
+            TIP_SCALE,
+            None,
+        );
+    }
+
+    generator.set_y(generator.y().saturating_sub(layout.bottom_trim));
+
+    // 在卡片底部右侧绘制动态链接的二维码, 裁剪前移动坐标为它留出空间
+    if render.dynamic_qr {
+        let url = format!("https://t.bilibili.com/{}", dynamic.dynamic_id);
+        match qr_image(&url, CARD_WIDTH / DYNAMIC_QR_WIDTH_DIVISOR) {
+            Ok(qr) => {
+                let y = generator.y();
+                generator.draw_img(&qr, Some((CARD_WIDTH - CONTENT_X - qr.width(), y)));
+                generator.set_y(y + qr.height() + 10);
+            }
+            Err(e) => warn!("生成动态二维码失败: {}", e),
+        }
+    }
+
+    generator.crop_bottom();
+
+    Ok(generator.into_image())
+}
+
+/// QR code of the live room `live_id`. Dark modules on white with a quiet zone, so it scans
+/// regardless of the card background
+fn live_qr_image(live_id: i64) -> anyhow::Result<RgbaImage> {
+    qr_image(
+        &format!("https://live.bilibili.com/{}", live_id),
+        LIVE_QR_SIZE,
+    )
+}
+
+/// QR code of `url` no larger than `max_size`
+fn qr_image(url: &str, max_size: u32) -> anyhow::Result<RgbaImage> {
+    let code = QrCode::new(url)?;
+
+    Ok(code
+        .render::<Rgba<u8>>()
+        .quiet_zone(true)
+        .dark_color(BLACK)
+        .light_color(WHITE)
+        .max_dimensions(max_size, max_size)
+        .build())
+}
+
+/// Draw a "置顶" badge at the right end of the header row starting at `y`. This won't move the coordinate
+fn draw_pinned_badge(generator: &mut PicGenerator, y: u32) {
+    let badge_x = generator.width() - CONTENT_X - badge_width("置顶");
+
+    draw_badge(generator, badge_x, y, "置顶", PINK);
+}
+
+const BADGE_PADDING: u32 = 6;
+
+fn badge_width(text: &str) -> u32 {
+    let (w, _) = imageproc::drawing::text_size(TIP_SCALE, &resource().text_normal_font, text);
+    w + 2 * BADGE_PADDING
+}
+
+/// Draw white `text` on a `color` background at `(x, y)` and return the badge width. This won't move the coordinate
+fn draw_badge(generator: &mut PicGenerator, x: u32, y: u32, text: &str, color: Rgba<u8>) -> u32 {
+    let (w, h) = imageproc::drawing::text_size(TIP_SCALE, &resource().text_normal_font, text);
+
+    generator.draw_rectangle(
+        x as i32,
+        y as i32,
+        h + 2 * BADGE_PADDING,
+        w + 2 * BADGE_PADDING,
+        color,
+    );
+    generator.draw_text(
+        &[text],
+        &[WHITE],
+        &resource().text_normal_font,
+        TIP_SCALE,
+        Some((x + BADGE_PADDING, y + BADGE_PADDING)),
+    );
+
+    w + 2 * BADGE_PADDING
+}
+
+/// 正文文字和emoji的大小
+#[derive(Debug, Clone, Copy)]
+struct ContentScales {
+    text: PxScale,
+    emoji: PxScale,
+}
+
+const CONTENT_SCALES: ContentScales = ContentScales {
+    text: TEXT_SCALE,
+    emoji: EMOJI_SCALE,
+};
+
+impl ContentScales {
+    /// Scales for the quoted original of a forward, overridden by `render.forward_text_size`
+    /// and `render.forward_emoji_size`
+    fn forward(self, render: &RenderConfig) -> ContentScales {
+        ContentScales {
+            text: render.forward_text_size.map_or(self.text, uniform_scale),
+            emoji: render.forward_emoji_size.map_or(self.emoji, uniform_scale),
+        }
+    }
+}
+
+/// Draw content from the current position of the generator within an area of `width`.
+/// Nothing is drawn below `max_y`; returns whether some content was left out because of it.
+/// Images of flagged content are blurred when `blur_flagged`
+fn draw_content(
+    generator: &mut PicGenerator,
+    content: &Content,
+    width: u32,
+    scales: ContentScales,
+    render: &RenderConfig,
+    max_y: Option<u32>,
+    blur_flagged: bool,
+) -> anyhow::Result<bool> {
+    match content {
+        Content::Forward {
+            texts,
+            original_author,
+            original_avatar,
+            original,
+        } => {
+            if draw_texts(generator, texts, width, scales, render, max_y) {
+                return Ok(true);
+            }
+            let original_width = width
+                .checked_sub(2 * FORWARD_INDENT)
+                .ok_or_else(|| anyhow!("宽度 {} 不足以缩进绘制转发的原动态", width))?;
+
+            // 绘制原动态的灰色背景
+            let y = generator.y();
+            generator.draw_rectangle(
+                0,
+                y as i32,
+                generator.height().saturating_sub(y),
+                generator.width(),
+                render.forward_background.into(),
+            );
+            // 原动态缩进绘制
+            let x = generator.x();
+            generator.set_x(x + FORWARD_INDENT);
+            let forward_scales = scales.forward(render);
+            // 绘制原作者头像和AT, 头像与文字等高
+            if let Some(avatar) = original_avatar {
+                let size = forward_scales.text.y.round() as u32;
+                let (avatar_x, avatar_y) = (generator.x(), generator.y());
+                generator.draw_img_alpha(
+                    &create_circular_image(avatar, size, render.resize_filter.into()),
+                    Some((avatar_x, avatar_y)),
+                );
+                generator.set_x(avatar_x + size + FORWARD_AVATAR_GAP);
+            }
+            let orig_author_at = format!("@{}", original_author);
+            generator.draw_text(
+                &[&orig_author_at],
+                &[render.at_color.into()],
+                &resource().text_normal_font,
+                forward_scales.text,
+                None,
+            );
+            generator.set_x(x + FORWARD_INDENT);
+            // 绘制原动态内容
+            let truncated = draw_content(
+                generator,
+                original,
+                original_width,
+                forward_scales,
+                render,
+                max_y,
+                blur_flagged,
+            )
+            .context("转发的原动态")?;
+            generator.set_x(x);
+            Ok(truncated)
+        }
+        Content::Draw {
+            texts,
+            cover,
+            pics,
+            missing_pics: _,
+            location,
+            flagged,
+        } => {
+            if draw_texts(generator, texts, width, scales, render, max_y) {
+                return Ok(true);
+            }
+
+            let fits = |y: u32, height: u32| max_y.is_none_or(|max_y| y + height <= max_y);
+
+            let blurred =
+                |image: &RgbaImage| (blur_flagged && *flagged).then(|| blur_flagged_image(image));
+
+            if let Some(cover) = cover {
+                let (x, y) = (generator.x(), generator.y());
+                if !fits(y, cover.image.height()) {
+                    return Ok(true);
+                }
+                let blurred_cover = blurred(&cover.image);
+                generator.draw_img(blurred_cover.as_ref().unwrap_or(&cover.image), Some((x, y)));
+                generator.set_y(y + cover.image.height() + IMAGE_MARGIN);
+            }
+
+            let num_pics_per_line = match pics.len() {
+                1 => 1,
+                2 | 4 => 2,
+                _ => 3,
+            };
+
+            let image_lines: Vec<&[AlbumImage]> = pics.chunks(num_pics_per_line).collect();
+
+            let left = generator.x();
+            let (mut x, mut y) = (left, generator.y());
+            let mut truncated = false;
+
+            for line in image_lines {
+                let line_height = line.iter().map(|pic| pic.image.height()).max();
+                if !fits(y, line_height.unwrap_or(0)) {
+                    truncated = true;
+                    break;
+                }
+                for (i, pic) in line.iter().enumerate() {
+                    let img = &pic.image;
+                    let blurred_img = blurred(img);
+                    generator.draw_img(blurred_img.as_ref().unwrap_or(img), Some((x, y)));
+                    if pic.cropped && render.mark_cropped {
+                        draw_border(generator, x, y, img.width(), img.height());
+                    }
+
+                    x += img.width() + IMAGE_MARGIN;
+                    generator.set_x(x);
+
+                    if i == line.len() - 1 {
+                        y += img.height() + IMAGE_MARGIN;
+                        generator.set_y(y);
+                        x = left;
+                        generator.set_x(x);
+                    }
+                }
+            }
+
+            // 在图片下方绘制定位图标和地点名称
+            if let Some(location) = location.as_deref().filter(|_| !truncated) {
+                let font = &resource().text_normal_font;
+                let (_, text_height) = imageproc::drawing::text_size(TIP_SCALE, font, location);
+                // 资源目录中没有定位图标时只绘制地点名称
+                let icon = resource().location_image.as_ref();
+                let icon_size = icon.map_or(0, |_| TIP_SCALE.y.round() as u32);
+                if fits(y, cmp::max(icon_size, text_height)) {
+                    let mut text_x = left;
+                    if let Some(icon) = icon {
+                        let icon = imageops::resize(
+                            icon,
+                            icon_size,
+                            icon_size,
+                            render.resize_filter.into(),
+                        );
+                        generator.draw_img_alpha(&icon, Some((left, y)));
+                        text_x += icon_size + LOCATION_ICON_GAP;
+                    }
+                    generator.draw_text(&[location], &[GRAY], font, TIP_SCALE, Some((text_x, y)));
+                    y += cmp::max(icon_size, text_height) + IMAGE_MARGIN;
+                } else {
+                    truncated = true;
+                }
+            }
+
+            // bottom margin
+            generator.set_y(y + 20);
+            Ok(truncated)
+        }
+        Content::Word { texts } => Ok(draw_texts(generator, texts, width, scales, render, max_y)),
+        Content::Live {
+            live_id: _,
+            live_title,
+            live_cover,
+            live_qr,
+        } => {
+            // 标题和封面一行放不下时整体不绘制
+            let font = &resource().text_normal_font;
+            let (_, title_height) = imageproc::drawing::text_size(scales.text, font, live_title);
+            let cover_height = cmp::max(
+                live_cover.height(),
+                live_qr.as_ref().map_or(0, RgbaImage::height),
+            );
+            if max_y.is_some_and(|max_y| {
+                generator.y() + title_height + generator.row_space() + cover_height > max_y
+            }) {
+                return Ok(true);
+            }
+
+            generator.draw_text(&[live_title], &[BLACK], font, scales.text, None);
+            let y = generator.y();
+            generator.draw_img(live_cover, None);
+            // 二维码画在封面一行的右侧
+            if let Some(qr) = live_qr {
+                let x = (generator.x() + width).saturating_sub(qr.width());
+                generator.draw_img(qr, Some((x, y)));
+            }
+            Ok(false)
+        }
+        Content::Video {
+            title,
+            cover,
+            duration,
+        } => {
+            let label = match duration {
+                Some(duration) => format!("视频 · {}", duration),
+                None => "视频".to_string(),
+            };
+            let card = ArchiveCard {
+                title,
+                label: &label,
+                cover: cover.as_ref(),
+            };
+            Ok(draw_archive_card(
+                generator, card, width, scales, render, max_y,
+            ))
+        }
+        Content::Article { title, cover } => {
+            let card = ArchiveCard {
+                title,
+                label: "专栏",
+                cover: cover.as_ref(),
+            };
+            Ok(draw_archive_card(
+                generator, card, width, scales, render, max_y,
+            ))
+        }
+    }
+}
+
+/// 视频和专栏的紧凑卡片中绘制的内容
+struct ArchiveCard<'a> {
+    title: &'a str,
+    // 标题下方灰色的类型标签
+    label: &'a str,
+    cover: Option<&'a RgbaImage>,
+}
+
+/// 视频和专栏的紧凑卡片: 左侧封面缩略图, 右侧标题和灰色的类型标签. 返回是否有内容被截断
+fn draw_archive_card(
+    generator: &mut PicGenerator,
+    card: ArchiveCard,
+    width: u32,
+    scales: ContentScales,
+    render: &RenderConfig,
+    max_y: Option<u32>,
+) -> bool {
+    let ArchiveCard {
+        title,
+        label,
+        cover,
+    } = card;
+    let (x, y) = (generator.x(), generator.y());
+    let cover_height = cover.map_or(0, RgbaImage::height);
+    if max_y.is_some_and(|max_y| y + cover_height > max_y) {
+        return true;
+    }
+
+    let text_x = match cover {
+        Some(cover) => {
+            generator.draw_img(cover, Some((x, y)));
+            x + cover.width() + ARCHIVE_COVER_GAP
+        }
+        None => x,
+    };
+
+    generator.set_pos(text_x, y);
+    let title = [RichTextNode::Text {
+        text: title.to_string(),
+    }];
+    let truncated = draw_texts(
+        generator,
+        &title,
+        (x + width).saturating_sub(text_x),
+        scales,
+        render,
+        max_y,
+    );
+    if !truncated {
+        generator.draw_text(
+            &[label],
+            &[GRAY],
+            &resource().text_normal_font,
+            TIP_SCALE,
+            None,
+        );
+    }
+
+    let bottom = cmp::max(generator.y(), y + cover_height + generator.row_space());
+    generator.set_pos(x, bottom);
+    truncated
+}
+
+/// 绘制正文文字和投票, 超出 `max_y` 的行不再绘制. 返回是否有内容被截断
+fn draw_texts(
+    generator: &mut PicGenerator,
+    texts: &[RichTextNode],
+    width: u32,
+    scales: ContentScales,
+    render: &RenderConfig,
+    max_y: Option<u32>,
+) -> bool {
+    // 每行占行高加行距, 最后一行之后的行距不计. 空行也算作一行, 按一行和段落间距中较高的计算
+    let row_space = generator.row_space();
+    let line_cost = cmp::max(
+        line_height(scales.text, scales.emoji) + row_space,
+        render.paragraph_space,
+    );
+    let max_lines = match max_y {
+        Some(max_y) => ((max_y + row_space).saturating_sub(generator.y()) / line_cost) as usize,
+        None => usize::MAX,
+    };
+
+    let (text_images, truncated) = draw_content_image(
+        texts,
+        width,
+        scales.text,
+        scales.emoji,
+        render.paragraph_space,
+        resource(),
+        max_lines,
+    );
+    for image in text_images {
+        generator.draw_img_alpha(&image, None);
+    }
+    truncated || draw_votes(generator, texts, width, scales.text, max_y)
+}
+
+/// 在文字之后列出投票的选项. 已结束的投票把每个选项画成按得票比例的横条, 末尾标出百分比.
+/// 超出 `max_y` 的选项不再绘制, 返回是否有选项被截断
+fn draw_votes(
+    generator: &mut PicGenerator,
+    texts: &[RichTextNode],
+    width: u32,
+    scale: PxScale,
+    max_y: Option<u32>,
+) -> bool {
+    let font = &resource().text_normal_font;
+
+    for node in texts {
+        let RichTextNode::Vote { info: Some(vote) } = node else {
+            continue;
+        };
+
+        let total: u64 = vote.options.iter().map(|option| option.count).sum();
+        let show_result = vote.concluded && total > 0;
+
+        for option in &vote.options {
+            let label = if show_result {
+                option.desc.clone()
+            } else {
+                format!("· {}", option.desc)
+            };
+            let (_, label_height) = imageproc::drawing::text_size(scale, font, &label);
+            let percent =
+                show_result.then(|| format!("{:.1}%", option.count as f64 * 100.0 / total as f64));
+            let (percent_width, percent_height) = percent.as_deref().map_or((0, 0), |percent| {
+                imageproc::drawing::text_size(TIP_SCALE, font, percent)
+            });
+            // 结束的投票在选项下方还有一行横条
+            let row_height = match percent {
+                Some(_) => {
+                    label_height + generator.row_space() + cmp::max(VOTE_BAR_HEIGHT, percent_height)
+                }
+                None => label_height,
+            };
+            if max_y.is_some_and(|max_y| generator.y() + row_height > max_y) {
+                return true;
+            }
+
+            generator.draw_text(&[&label], &[BLACK], font, scale, None);
+            let Some(percent) = percent else {
+                continue;
+            };
+
+            let max_bar_width = width.saturating_sub(percent_width + IMAGE_MARGIN);
+            let bar_width = (max_bar_width as u64 * option.count / total) as u32;
+
+            let (x, y) = (generator.x(), generator.y());
+            if bar_width > 0 {
+                generator.draw_rectangle(x as i32, y as i32, VOTE_BAR_HEIGHT, bar_width, PINK);
+            }
+            generator.draw_text(
+                &[&percent],
+                &[GRAY],
+                font,
+                TIP_SCALE,
+                Some((x + bar_width + IMAGE_MARGIN, y)),
+            );
+            generator.set_y(y + cmp::max(VOTE_BAR_HEIGHT, percent_height) + IMAGE_MARGIN);
+        }
+    }
+
+    false
+}
+
+/// 模糊有内容警告的图片, 压暗后在中央绘制"敏感内容"提示
+fn blur_flagged_image(image: &RgbaImage) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    // 缩小后模糊再放大, 比直接对原图做大半径的模糊快得多
+    let small = imageops::resize(
+        image,
+        (width / FLAGGED_BLUR_DOWNSCALE).max(1),
+        (height / FLAGGED_BLUR_DOWNSCALE).max(1),
+        FilterType::Triangle,
+    );
+    let small = imageops::blur(&small, FLAGGED_BLUR_SIGMA);
+    let mut blurred = imageops::resize(&small, width, height, FilterType::Triangle);
+    for pixel in blurred.pixels_mut() {
+        for c in &mut pixel.0[..3] {
+            *c = (*c as u32 * 3 / 5) as u8;
+        }
+    }
+
+    let text = "敏感内容";
+    let font = &resource().text_normal_font;
+    let (text_width, text_height) = imageproc::drawing::text_size(TEXT_SCALE, font, text);
+    if text_width <= width && text_height <= height {
+        imageproc::drawing::draw_text_mut(
+            &mut blurred,
+            WHITE,
+            ((width - text_width) / 2) as i32,
+            ((height - text_height) / 2) as i32,
+            TEXT_SCALE,
+            font,
+            text,
+        );
+    }
+    blurred
+}
+
+/// 在 `(x, y)` 处宽 `width` 高 `height` 的区域内侧绘制灰色细边框, 提示图片被裁剪
+fn draw_border(generator: &mut PicGenerator, x: u32, y: u32, width: u32, height: u32) {
+    let border = CROPPED_BORDER_WIDTH;
+    let (x, y) = (x as i32, y as i32);
+    let right = x + width.saturating_sub(border) as i32;
+    let bottom = y + height.saturating_sub(border) as i32;
+    generator.draw_rectangle(x, y, border, width, GRAY);
+    generator.draw_rectangle(x, bottom, border, width, GRAY);
+    generator.draw_rectangle(x, y, height, border, GRAY);
+    generator.draw_rectangle(right, y, height, border, GRAY);
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct VerifyRequest {
+    #[serde(rename = "verifyKey")]
+    verify_key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+
+struct VerifyResponse {
+    code: i32,
+    msg: Option<String>,     // When fail
+    session: Option<String>, // When success
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BindRequest {
+    #[serde(rename = "sessionKey")]
+    session_key: String,
+    qq: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BindResponse {
+    code: i32,
+    msg: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ReleaseRequest {
+    #[serde(rename = "sessionKey")]
+    session_key: String,
+    qq: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ReleaseResponse {
+    code: i32,
+    msg: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SendFriendMessageRequest {
+    session_key: String,
+    target: i64,
+    message_chain: Vec<Message>,
+}
+
+/// 临时会话消息, `qq` 需要是群 `group` 的成员
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SendTempMessageRequest {
+    session_key: String,
+    qq: i64,
+    group: i64,
+    message_chain: Vec<Message>,
+}
+
+/// `/sendFriendMessage` 和 `/sendTempMessage` 的返回
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SendMessageResponse {
+    code: i32,
+    msg: String,
+}
+
+/// `https://github.com/project-mirai/mirai-api-http/blob/e9d5609b1cd580217a868f2daa789360283ba289/docs/api/MessageType.md`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum Message {
+    Plain {
+        text: String,
+    },
+    Image {
+        base64: String,
+    },
+    Forward {
+        #[serde(rename = "nodeList")]
+        node_list: Vec<ForwardNode>,
+    },
+}
+
+/// 合并转发中的一条消息
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ForwardNode {
+    sender_id: i64,
+    time: i64,
+    sender_name: String,
+    message_chain: Vec<Message>,
+}
+
+#[tokio::test]
+async fn test_send_qq() {
+    const MIRAI_URL: &str = "http://localhost:7827";
+    const MIRAI_VERIFY_KEY: &str = "INITKEYLunaRyu";
+    const BOT_QQ: i64 = 1320117484;
+    const TARGET_QQ: i64 = 3922347898;
+
+    let client = reqwest::Client::new();
+
+    let verify_request = VerifyRequest {
+        verify_key: MIRAI_VERIFY_KEY.to_string(),
+    };
+
+    let verify_response: VerifyResponse = client
+        .post(format!("{}/verify", MIRAI_URL))
+        .json(&verify_request)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(0, verify_response.code, "verify failed");
+
+    let session_key = verify_response.session.unwrap();
+
+    println!("Got session key: {}", session_key);
+
+    let bind_request = BindRequest {
+        session_key: session_key.clone(),
+        qq: BOT_QQ,
+    };
+
+    let bind_response: BindResponse = client
+        .post(format!("{}/bind", MIRAI_URL))
+        .json(&bind_request)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(0, bind_response.code, "bind failed: {}", bind_response.msg);
+
+    println!("bind session key {} to qq {}", session_key, BOT_QQ);
+
+    let send_request = SendFriendMessageRequest {
+        session_key: session_key.clone(),
+        target: TARGET_QQ,
+        message_chain: vec![Message::Plain {
+            text: "Hello world".to_string(),
+        }],
+    };
+
+    let send_response: serde_json::Value = client
+        .post(format!("{}/sendFriendMessage", MIRAI_URL))
+        .json(&send_request)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    let ss = serde_json::to_string_pretty(&send_response).unwrap();
+
+    println!("{}", ss);
+
+    let release_request = ReleaseRequest {
+        session_key: session_key.clone(),
+        qq: BOT_QQ,
+    };
+
+    let release_response: ReleaseResponse = client
+        .post(format!("{}/release", MIRAI_URL))
+        .json(&release_request)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        0, release_response.code,
+        "release failed: {}",
+        release_response.msg
+    );
+
+    println!("released session key {}", session_key);
+}
+
+async fn download_image(fetcher: &impl ImageFetcher, url: &str) -> anyhow::Result<RgbaImage> {
+    fetcher.fetch_image(url).await
+}
+
+/// 下载动态内容中的配图或封面, 受 `download.max_bytes_per_poll` 限制.
+/// `size` 为接口给出的原图宽高, 用于占位图
+async fn download_picture(
+    fetcher: &impl ImageFetcher,
+    url: &str,
+    size: Option<(u32, u32)>,
+) -> anyhow::Result<RgbaImage> {
+    fetcher.fetch_picture(url, size).await
+}
+
+/// 接口中图片定义的原图宽高
+fn picture_size(picture: &Value) -> Option<(u32, u32)> {
+    let width = picture.get("width").and_then(number_or_numeric_str)?;
+    let height = picture.get("height").and_then(number_or_numeric_str)?;
+    Some((width as u32, height as u32))
+}
+
+/// 下载头像, 先把地址统一为https
+async fn download_face(fetcher: &impl ImageFetcher, url: &str) -> anyhow::Result<RgbaImage> {
+    let Some(url) = normalize_image_url(url) else {
+        return Err(anyhow!("头像地址 {} 不是b站图片服务器", url));
+    };
+
+    download_image(fetcher, &url).await
+}
+
+/// b站图片服务器的域名
+const IMAGE_HOSTS: [&str; 2] = ["hdslb.com", "biliimg.com"];
+
+/// 把协议相对(`//i0.hdslb.com/...`)、缺少协议或使用http的图片地址统一为https,
+/// 域名不属于b站图片服务器时返回 `None`
+fn normalize_image_url(url: &str) -> Option<String> {
+    let rest = if let Some(rest) = url.strip_prefix("//") {
+        rest
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        rest
+    } else if let Some(rest) = url.strip_prefix("https://") {
+        rest
+    } else if url.contains("://") {
+        return None;
+    } else {
+        url
+    };
+
+    let url = reqwest::Url::parse(&format!("https://{}", rest)).ok()?;
+    let host = url.host_str()?;
+    IMAGE_HOSTS
+        .iter()
+        .any(|image_host| {
+            host == *image_host
+                || host
+                    .strip_suffix(image_host)
+                    .is_some_and(|sub| sub.ends_with('.'))
+        })
+        .then(|| url.to_string())
+}
+
+/// 把接口中的富文本节点转换为绘制用的节点. `client` 为 `None` 时不查询抽奖和投票详情,
+/// 抽奖按未开奖绘制, 投票只绘制图标
+async fn build_text_nodes(
+    bili: &BiliConfig,
+    render: &RenderConfig,
+    client: Option<&Client>,
+    fetcher: &impl ImageFetcher,
+    title: Option<String>,
+    raw_text_nodes: &[Value],
+) -> anyhow::Result<Vec<RichTextNode>> {
+    let mut res = Vec::with_capacity(raw_text_nodes.len() + 1);
+
+    if let Some(title) = title {
+        push_text_node(&mut res, render, fetcher, title).await;
+    }
+
+    for node in raw_text_nodes {
+        let type_ = node.get("type").unwrap().as_str().unwrap();
+
+        match type_ {
+            "RICH_TEXT_NODE_TYPE_EMOJI" => match download_emoji(fetcher, node).await {
+                Ok(img) => {
+                    let alt = node
+                        .get("text")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_string();
+                    res.push(RichTextNode::Emoji { img, alt });
+                }
+                Err(e) => {
+                    error!("无法下载emoji, 使用文字代替: {}", e);
+                    if let Some(Some(text)) = node.get("text").map(Value::as_str) {
+                        res.push(RichTextNode::Text {
+                            text: text.to_string(),
+                        });
+                    }
+                }
+            },
+            "RICH_TEXT_NODE_TYPE_WEB" => res.push(RichTextNode::Web),
+            "RICH_TEXT_NODE_TYPE_BV" => res.push(RichTextNode::Bv),
+            "RICH_TEXT_NODE_TYPE_LOTTERY" => {
+                let concluded = match (client, node["rid"].as_str()) {
+                    (Some(client), Some(rid)) => fetch_lottery_concluded(bili, client, rid)
+                        .await
+                        .unwrap_or_else(|e| {
+                            warn!("获取抽奖 {} 详情失败: {}", rid, e);
+                            false
+                        }),
+                    _ => false,
+                };
+                res.push(RichTextNode::Lottery { concluded });
+            }
+            "RICH_TEXT_NODE_TYPE_VOTE" => {
+                let info = match (client, node["rid"].as_str()) {
+                    (Some(client), Some(rid)) => fetch_vote_info(bili, client, rid)
+                        .await
+                        .inspect_err(|e| warn!("获取投票 {} 详情失败: {}", rid, e))
+                        .ok(),
+                    _ => None,
+                };
+                res.push(RichTextNode::Vote { info });
+            }
+            "RICH_TEXT_NODE_TYPE_GOODS" => {
+                let text = node
+                    .get("text")
+                    .and_then(Value::as_str)
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string);
+                res.push(RichTextNode::Goods { text });
+            }
+            _ => {
+                if let Some(Some(text)) = node.get("text").map(Value::as_str) {
+                    push_text_node(&mut res, render, fetcher, text.to_string()).await;
+                }
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+/// Concatenate the text of rich text nodes, emoji as their alt text and icons omitted
+fn plain_text(nodes: &[RichTextNode]) -> String {
+    let mut res = String::new();
+    for node in nodes {
+        match node {
+            RichTextNode::Text { text } => res.push_str(text),
+            RichTextNode::Emoji { img: _, alt } => res.push_str(alt),
+            RichTextNode::Goods { text: Some(text) } => res.push_str(text),
+            _ => {}
+        }
+    }
+    res
+}
+
+/// Push a text node, splitting out emoji listed in `render.force_image_emoji` as image nodes
+/// so they don't get drawn with the emoji font. Falls back to the font if the image is unavailable.
+async fn push_text_node(
+    res: &mut Vec<RichTextNode>,
+    render: &RenderConfig,
+    fetcher: &impl ImageFetcher,
+    text: String,
+) {
+    if !text
+        .chars()
+        .any(|c| render.force_image_emoji.contains(&EmojiCodepoint(c)))
+    {
+        res.push(RichTextNode::Text { text });
+        return;
+    }
+
+    let mut buffer = String::new();
+
+    for c in text.chars() {
+        if !render.force_image_emoji.contains(&EmojiCodepoint(c)) {
+            buffer.push(c);
+            continue;
+        }
+
+        match download_forced_emoji(render, fetcher, c).await {
+            Ok(img) => {
+                if !buffer.is_empty() {
+                    res.push(RichTextNode::Text {
+                        text: std::mem::take(&mut buffer),
+                    });
+                }
+                res.push(RichTextNode::Emoji {
+                    img,
+                    alt: c.to_string(),
+                });
+            }
+            Err(e) => {
+                warn!("无法获取emoji {} 的图片, 使用字体绘制: {}", c, e);
+                buffer.push(c);
+            }
+        }
+    }
+
+    if !buffer.is_empty() {
+        res.push(RichTextNode::Text { text: buffer });
+    }
+}
+
+/// Load the image of an emoji from `render.emoji_dir` if present, otherwise from `render.emoji_cdn`
+async fn download_forced_emoji(
+    render: &RenderConfig,
+    fetcher: &impl ImageFetcher,
+    c: char,
+) -> anyhow::Result<RgbaImage> {
+    let codepoint = format!("{:x}", c as u32);
+
+    if let Some(dir) = &render.emoji_dir {
+        let path = dir.join(format!("{}.png", codepoint));
+        if path.exists() {
+            return Ok(image::open(path)?.into_rgba8());
+        }
+    }
+
+    download_image(
+        fetcher,
+        &render.emoji_cdn.replace("{codepoint}", &codepoint),
+    )
+    .await
+}
+
+async fn download_emoji(
+    fetcher: &impl ImageFetcher,
+    emoji_node: &Value,
+) -> anyhow::Result<RgbaImage> {
+    if let Some(emoji) = emoji_node.get("emoji") {
+        if let Some(Some(icon_url)) = emoji.get("icon_url").map(Value::as_str) {
+            return download_image(fetcher, icon_url).await;
+        }
+    }
+
+    Err(anyhow!("No emoji icon url found"))
+}
+
+/// Whether the lottery with the given business id has drawn its winners
+async fn fetch_lottery_concluded(
+    bili: &BiliConfig,
+    client: &Client,
+    business_id: &str,
+) -> anyhow::Result<bool> {
+    let response: Value = client
+        .get(endpoints::lottery_notice(bili, business_id))
+        .send()
+        .await?
+        .json()
+        .await?;
+    if response["code"].as_i64() != Some(0) {
+        return Err(anyhow!("{}", response["message"]));
+    }
+
+    Ok(lottery_concluded(&response["data"]))
+}
+
+/// * `data` field of response from lottery notice API
+fn lottery_concluded(data: &Value) -> bool {
+    // status: 0 未开奖, 2 已开奖
+    data["status"].as_i64() == Some(2) || data["lottery_result"].is_object()
+}
+
+/// Options and vote counts of the vote with the given id
+async fn fetch_vote_info(
+    bili: &BiliConfig,
+    client: &Client,
+    vote_id: &str,
+) -> anyhow::Result<VoteInfo> {
+    let response: Value = client
+        .get(endpoints::vote_info(bili, vote_id))
+        .send()
+        .await?
+        .json()
+        .await?;
+    if response["code"].as_i64() != Some(0) {
+        return Err(anyhow!("{}", response["message"]));
+    }
+
+    parse_vote_info(&response["data"]["info"], Timestamp::now().as_second())
+        .ok_or_else(|| anyhow!("投票详情格式不符合预期: {}", response["data"]))
+}
+
+/// * `data.info` field of response from vote info API, `now` is the current unix timestamp
+fn parse_vote_info(info: &Value, now: i64) -> Option<VoteInfo> {
+    let options = info["options"]
+        .as_array()?
+        .iter()
+        .map(|option| VoteOption {
+            desc: option["desc"].as_str().unwrap_or_default().to_string(),
+            count: option["cnt"].as_u64().unwrap_or_default(),
+        })
+        .collect();
+
+    Some(VoteInfo {
+        options,
+        concluded: info["endtime"].as_i64().is_some_and(|end| end <= now),
+    })
+}
+
+/// Read a JSON number that may also arrive as a numeric string, e.g. `1080` or `"1080"`
+fn number_or_numeric_str(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Whether an opus post presents its first picture as a big cover (`opusBigCover`)
+fn is_big_cover(opus: &Value) -> bool {
+    opus["opusBigCover"].as_bool().unwrap_or(false)
+}
+
+/// Download a big cover picture and scale it to `width`, preserving its aspect ratio
+async fn download_cover_image(
+    fetcher: &impl ImageFetcher,
+    picture: &Value,
+    width: u32,
+    filter: FilterType,
+) -> anyhow::Result<RgbaImage> {
+    let src = picture
+        .get("url")
+        .and_then(Value::as_str)
+        .with_context(|| format!("不合法的图片定义: {}", picture))?;
+
+    let url = format!("{}@{}w.webp", src, width);
+    let img = download_picture(fetcher, &url, picture_size(picture)).await?;
+    let height = ((width as f64) * (img.height() as f64) / (img.width() as f64)).round() as u32;
+
+    Ok(imageops::resize(&img, width, height.max(1), filter))
+}
+
+/// A downloaded album image resized for the card, with the url of the original
+#[derive(Debug)]
+pub struct AlbumImage {
+    pub image: RgbaImage,
+    pub original_url: String,
+    /// Whether parts of the picture were cut off to fit it in a square
+    pub cropped: bool,
+}
+
+/// Whether fitting a `width` x `height` picture into a `size` x `size` square cuts off
+/// at least one pixel along its longer side
+fn crops_to_square(width: f64, height: f64, size: u32) -> bool {
+    let longer = size as f64 * width.max(height) / width.min(height);
+    longer.round() as u32 > size
+}
+
+/// Download and square-crop album pictures. A picture that fails to download is replaced by a
+/// placeholder of the same size so the album keeps its layout. Returns the images and the
+/// number of pictures that failed to download.
+pub async fn download_dynamic_images(
+    fetcher: &impl ImageFetcher,
+    pictures: &[Value],
+    image_area_width: u32,
+    image_margin: u32,
+    filter: FilterType,
+) -> anyhow::Result<(Vec<AlbumImage>, usize)> {
+    let num_pictures = pictures.len();
+
+    // - 1 picture -> Just show one
+    // - 2 or 4 picture -> show 2 pictures in a line and do 1 or 2 lines
+    // - other -> show 3 pictures in a line
+    let (num_pictures_in_line, picture_square_size) = match num_pictures {
+        1 => (1, image_area_width - image_margin * 2),
+        2 | 4 => (2, (image_area_width - image_margin * 3) / 2),
+        _ => (3, (image_area_width - image_margin * 4) / 3),
+    };
+
+    // https://github.com/Starlwr/StarBot/blob/f92b4d71366e19046f5c1ae87fe85f2f2461cd69/starbot/painter/DynamicPicGenerator.py#L452-L469
+    let mut urls = Vec::with_capacity(pictures.len());
+    for pic in pictures {
+        let (src, height, width) = match (
+            pic.get("url").and_then(Value::as_str).map(str::to_string),
+            pic.get("height").and_then(number_or_numeric_str),
+            pic.get("width").and_then(number_or_numeric_str),
+        ) {
+            (Some(src), Some(height), Some(width)) => (src, height, width),
+            _ => {
+                warn!("不合法的图片定义: {}，请检查API变动", pic);
+                // 同样以占位图片代替, 保持相册的排版和图片数量
+                let src = pic["url"].as_str().unwrap_or_default().to_string();
+                urls.push((src, None, None, false));
+                continue;
+            }
+        };
+        let cropped = crops_to_square(width, height, picture_square_size);
+
+        let url = if num_pictures_in_line == 1 {
+            format!("{}@518w.webp", src)
+        } else if height / width >= 3.0 {
+            format!(
+                "{}@{}w_{}h_!header.webp",
+                src, picture_square_size, picture_square_size
+            )
+        } else {
+            format!(
+                "{}@{}w_{}h_1e_1c.webp",
+                src, picture_square_size, picture_square_size
+            )
+        };
+        urls.push((src, Some(url), picture_size(pic), cropped));
+    }
+
+    let results = futures::future::join_all(urls.iter().map(|(_, url, size, _)| async move {
+        match url {
+            Some(url) => Some(download_picture(fetcher, url, *size).await),
+            None => None,
+        }
+    }))
+    .await;
+
+    let mut images = Vec::with_capacity(results.len());
+    let mut failed = 0;
+
+    for ((original_url, _, _, cropped), result) in urls.into_iter().zip(results) {
+        match result {
+            Some(Ok(img)) => {
+                let resized_image = match img.height().cmp(&img.width()) {
+                    cmp::Ordering::Equal => {
+                        imageops::resize(&img, picture_square_size, picture_square_size, filter)
+                    }
+                    cmp::Ordering::Less => {
+                        // Image is wider, make height -> size and crop width from left and right
+                        let nheight = picture_square_size;
+                        let nwidth = ((picture_square_size as f64) * (img.width() as f64)
+                            / (img.height() as f64))
+                            .round() as u32;
+
+                        let resized = imageops::resize(&img, nwidth, nheight, filter);
+
+                        imageops::crop_imm(
+                            &resized,
+                            (nwidth - picture_square_size) / 2,
+                            0,
+                            picture_square_size,
+                            picture_square_size,
+                        )
+                        .to_image()
+                    }
+                    cmp::Ordering::Greater => {
+                        // Image is longer, make width -> size and crop height from top and bottom
+                        let nwidth = picture_square_size;
+                        let nheight = ((picture_square_size as f64) * (img.height() as f64)
+                            / (img.width() as f64))
+                            .round() as u32;
+
+                        let resized = imageops::resize(&img, nwidth, nheight, filter);
+
+                        imageops::crop_imm(
+                            &resized,
+                            0,
+                            (nheight - picture_square_size) / 2,
+                            picture_square_size,
+                            picture_square_size,
+                        )
+                        .to_image()
+                    }
+                };
+
+                images.push(AlbumImage {
+                    image: resized_image,
+                    original_url,
+                    cropped,
+                });
+            }
+            result => {
+                if let Some(Err(e)) = result {
+                    warn!("下载图片 {} 失败, 以占位图片代替: {}", original_url, e);
+                }
+                images.push(AlbumImage {
+                    image: failed_image_placeholder(picture_square_size),
+                    original_url,
+                    cropped: false,
+                });
+                failed += 1;
+            }
+        }
+    }
+
+    Ok((images, failed))
+}
+
+/// 下载失败的相册图片的占位: 边长 `size` 的灰色方块, 中央写着"图片加载失败"
+fn failed_image_placeholder(size: u32) -> RgbaImage {
+    let mut image = RgbaImage::from_pixel(size, size, PLACEHOLDER_COLOR);
+
+    let text = "图片加载失败";
+    let font = &resource().text_normal_font;
+    let (text_width, text_height) = imageproc::drawing::text_size(TIP_SCALE, font, text);
+    if text_width <= size && text_height <= size {
+        imageproc::drawing::draw_text_mut(
+            &mut image,
+            FAILED_IMAGE_TEXT_COLOR,
+            ((size - text_width) / 2) as i32,
+            ((size - text_height) / 2) as i32,
+            TIP_SCALE,
+            font,
+            text,
+        );
+    }
+    image
+}
+
+// I use this as a quick hack to look up dynamic details
+#[tokio::test]
+async fn test_get_detail() {
+    let client = reqwest::Client::new();
+    let dynamic_id: i64 = 729922047097962504;
+    let sess_data = "SESSDATA";
+    let detail_response: Value =  client
+                        .get(format!("https://api.bilibili.com/x/polymer/web-dynamic/v1/detail?timezone_offset=-480&id={}&features=itemOpusStyle,opusBigCover,onlyfansVote", dynamic_id))
+                        .header("COOKIE", format!("SESSDATA={}", sess_data))
+                        .send()
+                        .await.unwrap()
+                        .json()
+                        .await.unwrap();
+
+    let s = serde_json::to_string_pretty(&detail_response).unwrap();
+    println!("{}", s)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::config::Color;
+
+    /// Serve images from bundled fixture bytes keyed by url, ignoring bilibili image
+    /// processing suffixes like `@518w.webp`.
+    struct FixtureFetcher {
+        images: HashMap<&'static str, &'static [u8]>,
+    }
+
+    impl FixtureFetcher {
+        fn new() -> FixtureFetcher {
+            let images = HashMap::from([
+                (
+                    "https://i0.hdslb.com/bfs/face/test_face.png",
+                    include_bytes!("../test_resources/face.png").as_slice(),
+                ),
+                (
+                    "https://i0.hdslb.com/bfs/emote/test_doge.png",
+                    include_bytes!("../test_resources/emoji.png").as_slice(),
+                ),
+                (
+                    "https://i0.hdslb.com/bfs/new_dyn/test_image.jpg",
+                    include_bytes!("../test_resources/image.JPG").as_slice(),
+                ),
+                (
+                    "https://i0.hdslb.com/bfs/new_dyn/test_background.jpg",
+                    include_bytes!("../test_resources/background.jpg").as_slice(),
+                ),
+                (
+                    "https://i0.hdslb.com/bfs/live/test_cover.jpg",
+                    include_bytes!("../test_resources/background.jpg").as_slice(),
+                ),
+            ]);
+
+            FixtureFetcher { images }
+        }
+    }
+
+    impl ImageFetcher for FixtureFetcher {
+        async fn fetch_bytes(&self, url: &str) -> anyhow::Result<Vec<u8>> {
+            let url = url.split('@').next().unwrap();
+
+            self.images
+                .get(url)
+                .map(|bytes| bytes.to_vec())
+                .ok_or_else(|| anyhow!("No fixture image for {}", url))
+        }
+    }
+
+    /// Load a saved detail API response from `test_resources/`
+    fn load_detail_fixture(name: &str) -> Value {
+        let content = std::fs::read_to_string(format!("test_resources/{}", name)).unwrap();
+
+        serde_json::from_str(&content).unwrap()
+    }
+
+    /// `[bili]` config using the default API base urls
+    fn test_bili_config() -> BiliConfig {
+        BiliConfig {
+            sess_data: "SESSDATA".to_string(),
+            api_base_url: None,
+            vc_api_base_url: None,
+            app_access_key: None,
+        }
+    }
+
+    /// A user target with only the required fields set
+    pub(crate) fn test_target() -> TargetConfig {
+        toml::from_str("uid = 1\ninterval_sec = 10\nreceiver_qq = 2\nsender_qq = 3").unwrap()
+    }
+
+    /// A confirmed record of a type 4 dynamic first seen at 0
+    fn test_entry(sent: bool, pinned: bool) -> DbEntry {
+        DbEntry {
+            sent,
+            type_: 4,
+            attempts: 0,
+            pinned,
+            first_seen_ts: 0,
+            sent_ts: None,
+            content_hash: None,
+            edit_sent_ts: None,
+            confirmed: true,
+        }
+    }
+
+    /// A `200 OK` response with a JSON body. `headers` are extra header lines ending with `\r\n`
+    fn json_response(body: &[u8], headers: &str) -> Vec<u8> {
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+            headers,
+            body.len()
+        );
+        [head.as_bytes(), body].concat()
+    }
+
+    /// Start an HTTP server that answers one request with each of `responses` in order.
+    /// Returns the base url and a handle resolving to the received requests
+    async fn serve(responses: Vec<Vec<u8>>) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::with_capacity(responses.len());
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 4096];
+                let n = stream.read(&mut buf).await.unwrap();
+                stream.write_all(&response).await.unwrap();
+                requests.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+            }
+            requests
+        });
+
+        (url, server)
+    }
+
+    /// [`serve`] a single request with `response`
+    async fn serve_once(response: Vec<u8>) -> (String, tokio::task::JoinHandle<String>) {
+        let (url, server) = serve(vec![response]).await;
+        (
+            url,
+            tokio::spawn(async move { server.await.unwrap().remove(0) }),
+        )
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = |a: &[&str]| {
+            a.iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .into_iter()
+        };
+        let config_path = |a: &[&str]| parse_args(args(a)).unwrap().config_path;
+
+        assert_eq!(PathBuf::from("spider.toml"), config_path(&[]));
+        assert_eq!(
+            PathBuf::from("/etc/spider/a.toml"),
+            config_path(&["--config", "/etc/spider/a.toml"])
+        );
+        assert_eq!(PathBuf::from("b.toml"), config_path(&["--config=b.toml"]));
+        assert!(parse_args(args(&["--config"])).is_err());
+        assert!(parse_args(args(&["--verbose"])).is_err());
+
+        assert!(!parse_args(args(&[])).unwrap().check_mirai);
+        assert_eq!(
+            Args {
+                config_path: PathBuf::from("b.toml"),
+                check_mirai: true,
+                mark: None,
+                render_archive: None,
+                transfer: None,
+            },
+            parse_args(args(&["--check-mirai", "--config=b.toml"])).unwrap()
+        );
+
+        assert_eq!(
+            Some(MarkDynamic {
+                uid: 1234,
+                dynamic_id: 1000000000000000001,
+                sent: true,
+            }),
+            parse_args(args(&["--db-mark-sent", "1234", "1000000000000000001"]))
+                .unwrap()
+                .mark
+        );
+        assert!(
+            !parse_args(args(&["--db-mark-unsent", "1234", "1"]))
+                .unwrap()
+                .mark
+                .unwrap()
+                .sent
+        );
+        assert!(parse_args(args(&["--db-mark-sent", "1234"])).is_err());
+        assert!(parse_args(args(&["--db-mark-sent", "uid", "1"])).is_err());
+
+        assert_eq!(
+            Some(RenderArchive {
+                detail_path: PathBuf::from("detail.json"),
+                image_dir: PathBuf::from("images"),
+                output_path: PathBuf::from("out.png"),
+            }),
+            parse_args(args(&[
+                "--render-archive",
+                "detail.json",
+                "images",
+                "out.png"
+            ]))
+            .unwrap()
+            .render_archive
+        );
+        assert!(parse_args(args(&["--render-archive", "detail.json", "images"])).is_err());
+
+        assert_eq!(
+            Some(TransferEntries {
+                uid: 1234,
+                path: PathBuf::from("1234.json"),
+                export: true,
+            }),
+            parse_args(args(&["--db-export", "1234", "1234.json"]))
+                .unwrap()
+                .transfer
+        );
+        assert!(
+            !parse_args(args(&["--db-import", "1234", "1234.json"]))
+                .unwrap()
+                .transfer
+                .unwrap()
+                .export
+        );
+        assert!(parse_args(args(&["--db-import", "1234"])).is_err());
+    }
+
+    #[test]
+    fn test_export_import_entries() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = TargetTree::open(&db, "1234", false).unwrap();
+        mark_dynamic(&tree, 2, true).unwrap();
+        mark_dynamic(&tree, 10, false).unwrap();
+        tree.insert(rendered_key(10), b"{}".as_slice()).unwrap();
+
+        let entries = export_entries(&tree).unwrap();
+        let json = serde_json::to_value(&entries).unwrap();
+        assert_eq!(2, json.as_array().unwrap().len());
+        assert_eq!(json!(2), json[0]["dynamic_id"]);
+        assert_eq!(json!(true), json[0]["sent"]);
+        assert!(json[0]["type"].is_i64() && json[0]["first_seen_ts"].is_i64());
+
+        let other = sled::Config::new().temporary(true).open().unwrap();
+        let imported = TargetTree::open(&other, "1234", true).unwrap();
+        let entries = serde_json::from_value(json).unwrap();
+        assert_eq!(2, import_entries(&imported, entries).unwrap());
+        assert!(get_entry(&imported, 2).unwrap().unwrap().sent);
+        assert!(!get_entry(&imported, 10).unwrap().unwrap().sent);
+        assert!(imported.get(rendered_key(10)).unwrap().is_none());
+        // 导入的未发送动态在下一轮抓取时发送
+        let meta = tree_meta(&imported);
+        assert_eq!(Some(10), meta.newest_dynamic_id);
+        assert!(meta.has_unsent);
+    }
+
+    #[test]
+    fn test_reconcile_unconfirmed() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = TargetTree::open(&db, "1234", false).unwrap();
+
+        // 旧数据没有确认标记, 视为已确认
+        tree.insert(dynamic_key(1), br#"{"sent":true,"type":2}"#.as_slice())
+            .unwrap();
+        assert!(get_entry(&tree, 1).unwrap().unwrap().confirmed);
+
+        // 记录发送意图后退出
+        let mut entry = mark_dynamic(&tree, 2, false).unwrap();
+        entry.sent = true;
+        entry.confirmed = false;
+        tree.insert(dynamic_key(2), serde_json::to_vec(&entry).unwrap())
+            .unwrap();
+        mark_dynamic(&tree, 3, false).unwrap();
+
+        reconcile_unconfirmed(&tree).unwrap();
+        let entry = get_entry(&tree, 2).unwrap().unwrap();
+        assert!(entry.sent && entry.confirmed);
+        // 格式错误的记录跳过
+        tree.insert(dynamic_key(4), b"{".as_slice()).unwrap();
+        // 只重发确定没有发出的动态
+        let unsent: Vec<i64> = unsent_entries(&tree).iter().map(|(_, id, _)| *id).collect();
+        assert_eq!(vec![3], unsent);
+    }
+
+    #[tokio::test]
+    async fn test_persist_rendered() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = TargetTree::open(&db, "1234", false).unwrap();
+        let target = test_target();
+        let entry = mark_dynamic(&tree, 5, false).unwrap();
+        let (tx, mut rx) = mpsc::channel(4);
+
+        let pending = PendingMessage {
+            dynamic_key: dynamic_key(5),
+            dynamic_id: 5,
+            entry: entry.clone(),
+            messages: vec![Message::Plain {
+                text: "已渲染".to_string(),
+            }],
+        };
+        assert!(
+            queue_message(&tree, &target, &tx, &mut Vec::new(), pending, 42, true)
+                .await
+                .unwrap()
+        );
+        rx.recv().await.unwrap();
+        let saved = load_rendered(&tree, 5).unwrap();
+        assert_eq!(42, saved.content_hash);
+        // 保存的消息不是动态记录
+        let unsent: Vec<i64> = unsent_entries(&tree).iter().map(|(_, id, _)| *id).collect();
+        assert_eq!(vec![5], unsent);
+
+        // 重发时直接使用保存的消息, 不获取动态
+        let bili = BiliConfig {
+            api_base_url: Some("http://127.0.0.1:1".to_string()),
+            vc_api_base_url: Some("http://127.0.0.1:1".to_string()),
+            ..test_bili_config()
+        };
+        let job = RenderJob {
+            dynamic_key: dynamic_key(5),
+            dynamic_id: 5,
+            entry,
+            allow_partial: false,
+            rendered: Some(saved),
+            deferred: true,
+        };
+        let ctx = RenderContext {
+            db: &tree,
+            bili: &bili,
+            render: &RenderConfig::default(),
+            target: &target,
+            client: &Client::new(),
+            fetcher: &FixtureFetcher::new(),
+        };
+        assert!(queue_rendered(&ctx, &tx, &mut Vec::new(), vec![job])
+            .await
+            .unwrap());
+        let pending = rx.recv().await.unwrap();
+        assert!(
+            matches!(pending.messages.as_slice(), [Message::Plain { text }] if text == "已渲染")
+        );
+        assert_eq!(Some(42), pending.entry.content_hash);
+
+        // 标记为已发送后不再保留消息
+        mark_dynamic(&tree, 5, true).unwrap();
+        assert!(load_rendered(&tree, 5).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_remote_targets() {
+        let file = vec![test_target()];
+        let body = r#"[
+            {"uid": 1, "interval_sec": 60, "receiver_qq": 2, "sender_qq": 3},
+            {"uid": 1, "interval_sec": 60, "receiver_qq": 4, "sender_qq": 3},
+            {"uid": 5, "interval_sec": 60, "receiver_qq": 2, "sender_qq": 3}
+        ]"#;
+
+        let (url, _) = serve_once(json_response(body.as_bytes(), "")).await;
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let client = Client::new();
+        let remote = RemoteTargetsConfig {
+            url: format!("{}/targets.json", url),
+            refresh_sec: None,
+        };
+        let remote_targets = load_remote_targets(&remote, &client, &db).await;
+        assert_eq!(3, remote_targets.len());
+
+        // 与配置文件中重复的目标以配置文件为准
+        let targets = merge_targets(&file, remote_targets);
+        let keys: Vec<_> = targets.iter().map(target_key).collect();
+        assert_eq!(
+            vec![
+                ("1".to_string(), 2),
+                ("1".to_string(), 4),
+                ("5".to_string(), 2)
+            ],
+            keys
+        );
+        assert_eq!(10, targets[0].interval_sec);
+
+        // 服务不可用时使用上次的列表
+        let cached = load_remote_targets(&remote, &client, &db).await;
+        assert_eq!(3, cached.len());
+
+        let empty = sled::Config::new().temporary(true).open().unwrap();
+        assert!(load_remote_targets(&remote, &client, &empty)
+            .await
+            .is_empty());
+    }
+
+    #[test]
+    fn test_mark_dynamic() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = TargetTree::open(&db, "1234", false).unwrap();
+        assert!(get_entry(&tree, 1).unwrap().is_none());
+
+        // 不存在时新建
+        let entry = mark_dynamic(&tree, 1, true).unwrap();
+        assert!(entry.sent && entry.sent_ts.is_some());
+        assert!(get_entry(&tree, 1).unwrap().unwrap().sent);
+
+        // 标记为未发送时重置失败次数
+        let mut failed = entry.clone();
+        failed.attempts = 2;
+        failed.type_ = 2;
+        tree.insert(dynamic_key(1), serde_json::to_vec(&failed).unwrap())
+            .unwrap();
+        mark_dynamic(&tree, 1, false).unwrap();
+        let entry = get_entry(&tree, 1).unwrap().unwrap();
+        assert!(!entry.sent && entry.sent_ts.is_none());
+        assert_eq!((0, 2), (entry.attempts, entry.type_));
+    }
+
+    #[tokio::test]
+    async fn test_supervise_restarts() {
+        let policy = RestartPolicy {
+            min_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            stable_after: Duration::from_secs(600),
+        };
+        let runs = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        // 第一次panic, 第二次出错, 第三次正常结束
+        let result = supervise("1234", &policy, || {
+            let runs = runs.clone();
+            async move {
+                match runs.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                    0 => panic!("malformed card"),
+                    1 => Err(anyhow!("request failed")),
+                    _ => Ok(()),
+                }
+            }
+        })
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(3, runs.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!("malformed card", panic_message(&"malformed card"));
+        assert_eq!(
+            "index out of bounds",
+            panic_message(&"index out of bounds".to_string())
+        );
+    }
+
+    #[test]
+    fn test_db_entry_compatibility() {
+        // 旧版本数据库中的记录没有后加入的字段
+        let entry: DbEntry = serde_json::from_slice(br#"{"sent":true,"type":2}"#).unwrap();
+
+        assert!(entry.sent);
+        assert_eq!(2, entry.type_);
+        assert_eq!(0, entry.attempts);
+        assert!(!entry.pinned);
+        assert_eq!(0, entry.first_seen_ts);
+        assert_eq!(None, entry.sent_ts);
+        assert_eq!(None, entry.content_hash);
+        assert_eq!(None, entry.edit_sent_ts);
+    }
+
+    fn card(dynamic_id: i64, pinned: bool) -> SpaceCard {
+        SpaceCard {
+            dynamic_id,
+            dynamic_type: 4,
+            uname: "用户".to_string(),
+            pinned,
+            content_hash: None,
+        }
+    }
+
+    #[test]
+    fn test_take_new_cards() {
+        let page1 = vec![
+            card(50, true),
+            card(9, false),
+            card(8, false),
+            card(7, false),
+        ];
+        let page2 = vec![card(6, false), card(5, false), card(4, false)];
+
+        // 翻页直到遇到已知的动态5, 置顶动态不计入
+        let mut new_cards = Vec::new();
+        assert!(!take_new_cards(&page1, Some(5), 20, &mut new_cards));
+        assert!(take_new_cards(&page2, Some(5), 20, &mut new_cards));
+        let ids: Vec<i64> = new_cards.iter().map(|c| c.dynamic_id).collect();
+        assert_eq!(vec![9, 8, 7, 6], ids);
+
+        // 达到上限时停止
+        let mut new_cards = Vec::new();
+        assert!(take_new_cards(&page1, Some(5), 2, &mut new_cards));
+        assert_eq!(2, new_cards.len());
+
+        // 第一次监听时只取最新的几条
+        let mut new_cards = Vec::new();
+        assert!(take_new_cards(
+            &page1,
+            None,
+            INITIAL_DYNAMICS,
+            &mut new_cards
+        ));
+        assert_eq!(3, new_cards.len());
+    }
+
+    #[test]
+    fn test_space_history_page() {
+        let data = serde_json::json!({
+            "cards": [
+                {
+                    "desc": { "dynamic_id": 2, "type": 2, "user_profile": { "info": { "uname": "用户" } } },
+                    "extra": { "is_space_top": 1 },
+                },
+                { "desc": { "dynamic_id": 1, "type": 4 } },
+                // 优先使用字符串形式的ID
+                { "desc": { "dynamic_id": 9.223372036854776e18, "dynamic_id_str": "9223372036854775807", "type": 4 } },
+                // 无法解析ID的动态被跳过
+                { "desc": { "dynamic_id": 1.5, "type": 4 } },
+            ],
+            "has_more": 1,
+            "next_offset": 1,
+        });
+        let page = SpacePage::from_space_history(&data).unwrap();
+        assert_eq!(3, page.cards.len());
+        assert_eq!(
+            (2, 2, true),
+            (
+                page.cards[0].dynamic_id,
+                page.cards[0].dynamic_type,
+                page.cards[0].pinned
+            )
+        );
+        assert_eq!("用户", page.cards[0].uname);
+        assert!(!page.cards[1].pinned);
+        assert_eq!(i64::MAX, page.cards[2].dynamic_id);
+        assert_eq!(Some("1"), page.next_offset.as_deref());
+
+        // 没有动态的用户
+        let empty = serde_json::json!({ "cards": [], "has_more": 0 });
+        assert!(SpacePage::from_space_history(&empty)
+            .unwrap()
+            .cards
+            .is_empty());
+        let no_cards = serde_json::json!({ "has_more": 0, "next_offset": 0 });
+        let page = SpacePage::from_space_history(&no_cards).unwrap();
+        assert!(page.cards.is_empty() && page.next_offset.is_none());
+
+        // 接口变动
+        let changed = serde_json::json!({ "items": [] });
+        assert!(SpacePage::from_space_history(&changed).is_err());
+        assert!(SpacePage::from_space_history(&Value::Null).is_err());
+    }
+
+    #[test]
+    fn test_feed_space_page() {
+        let data = serde_json::json!({
+            "items": [
+                {
+                    "id_str": "1000000000000000002",
+                    "type": "DYNAMIC_TYPE_DRAW",
+                    "modules": {
+                        "module_author": { "name": "用户" },
+                        "module_tag": { "text": "置顶" },
+                    },
+                },
+                {
+                    "id_str": "1000000000000000001",
+                    "type": "DYNAMIC_TYPE_AV",
+                    "modules": { "module_author": { "name": "用户" } },
+                },
+            ],
+            "has_more": true,
+            "offset": "1000000000000000001",
+        });
+        let page = SpacePage::from_feed_space(&data).unwrap();
+        assert_eq!(2, page.cards.len());
+        assert_eq!(1000000000000000002, page.cards[0].dynamic_id);
+        assert_eq!(2, page.cards[0].dynamic_type);
+        assert!(page.cards[0].pinned);
+        assert_eq!("用户", page.cards[0].uname);
+        assert_eq!(8, page.cards[1].dynamic_type);
+        assert!(!page.cards[1].pinned);
+        assert!(page.cards[1].content_hash.is_some());
+        assert_eq!(Some("1000000000000000001"), page.next_offset.as_deref());
+
+        // 最后一页
+        let last = serde_json::json!({ "items": [], "has_more": false, "offset": "" });
+        let page = SpacePage::from_feed_space(&last).unwrap();
+        assert!(page.cards.is_empty() && page.next_offset.is_none());
+        let no_items = serde_json::json!({ "has_more": false });
+        assert!(SpacePage::from_feed_space(&no_items)
+            .unwrap()
+            .cards
+            .is_empty());
+
+        // 接口变动
+        let changed = serde_json::json!({ "cards": [] });
+        assert!(SpacePage::from_feed_space(&changed).is_err());
+    }
+
+    #[test]
+    fn test_feed_topic_page() {
+        let data = serde_json::json!({
+            "topic_card_list": {
+                "items": [
+                    {
+                        "topic_type": "DYNAMIC",
+                        "dynamic_card_item": {
+                            "id_str": "1000000000000000002",
+                            "type": "DYNAMIC_TYPE_WORD",
+                            "modules": { "module_author": { "name": "用户A" } },
+                        },
+                    },
+                    { "topic_type": "OTHER" },
+                    {
+                        "topic_type": "DYNAMIC",
+                        "dynamic_card_item": {
+                            "id_str": "1000000000000000001",
+                            "type": "DYNAMIC_TYPE_DRAW",
+                            "modules": { "module_author": { "name": "用户B" } },
+                        },
+                    },
+                ],
+                "has_more": true,
+                "offset": "abc",
+            },
+        });
+        let page = SpacePage::from_feed_topic(&data).unwrap();
+        assert_eq!(2, page.cards.len());
+        assert_eq!(1000000000000000002, page.cards[0].dynamic_id);
+        assert_eq!(4, page.cards[0].dynamic_type);
+        assert_eq!("用户A", page.cards[0].uname);
+        assert_eq!("用户B", page.cards[1].uname);
+        assert_eq!(Some("abc"), page.next_offset.as_deref());
+
+        // 没有动态的话题
+        let empty = serde_json::json!({});
+        let page = SpacePage::from_feed_topic(&empty).unwrap();
+        assert!(page.cards.is_empty() && page.next_offset.is_none());
+
+        // 接口变动
+        let changed = serde_json::json!({ "topic_card_list": { "cards": [], "has_more": true } });
+        assert!(SpacePage::from_feed_topic(&changed).is_err());
+    }
+
+    #[test]
+    fn test_parse_dynamic_id() {
+        let max = "9223372036854775807";
+        assert_eq!(i64::MAX, parse_dynamic_id(&Value::from(max)).unwrap());
+        assert_eq!(i64::MAX, parse_dynamic_id(&Value::from(i64::MAX)).unwrap());
+        assert_eq!(Some(i64::MAX), parse_dynamic_key(&dynamic_key(i64::MAX)));
+        assert_eq!(max.as_bytes(), dynamic_key(i64::MAX));
+
+        // 超出范围或经过浮点数的ID不能被截断
+        assert!(parse_dynamic_id(&Value::from("9223372036854775808")).is_err());
+        assert!(parse_dynamic_id(&Value::from(9.223372036854776e18)).is_err());
+        assert!(parse_dynamic_id(&Value::Null).is_err());
+
+        // 与旧版本数据库的键兼容
+        let id = 1000000000000000001i64;
+        assert_eq!(serde_json::to_vec(&id).unwrap(), dynamic_key(id));
+        assert_eq!(None, parse_dynamic_key(b"not an id"));
+    }
+
+    #[test]
+    fn test_newest_known_dynamic() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = TargetTree::open(&db, "1234", false).unwrap();
+        assert_eq!(None, newest_known_dynamic(&tree));
+
+        for (dynamic_id, pinned) in [(7i64, false), (10, false), (20, true)] {
+            tree.insert(
+                dynamic_key(dynamic_id),
+                serde_json::to_vec(&test_entry(true, pinned)).unwrap(),
+            )
+            .unwrap();
+        }
+
+        // 置顶动态不参与比较
+        assert_eq!(Some(10), newest_known_dynamic(&tree));
+    }
+
+    #[test]
+    fn test_tree_meta() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = TargetTree::open(&db, "1234", false).unwrap();
+
+        // 没有汇总信息时遍历数据库
+        tree.insert(
+            dynamic_key(7),
+            serde_json::to_vec(&test_entry(true, false)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            TreeMeta {
+                newest_dynamic_id: Some(7),
+                has_unsent: true,
+                last_digest_ts: None,
+            },
+            tree_meta(&tree)
+        );
+
+        save_tree_meta(
+            &tree,
+            &TreeMeta {
+                newest_dynamic_id: Some(7),
+                has_unsent: false,
+                last_digest_ts: None,
+            },
+        )
+        .unwrap();
+        insert_entry(&tree, 20, &test_entry(true, true)).unwrap();
+        assert_eq!(
+            TreeMeta {
+                newest_dynamic_id: Some(7),
+                has_unsent: false,
+                last_digest_ts: None,
+            },
+            tree_meta(&tree)
+        );
+
+        insert_entry(&tree, 10, &test_entry(false, false)).unwrap();
+        assert_eq!(
+            TreeMeta {
+                newest_dynamic_id: Some(10),
+                has_unsent: true,
+                last_digest_ts: None,
+            },
+            tree_meta(&tree)
+        );
+        assert_eq!(Some(10), newest_known_dynamic(&tree));
+
+        // 汇总信息不会被当作动态记录
+        assert_eq!(Some(10), scan_newest_dynamic(&tree));
+        assert!(get_entry(&tree, 10).unwrap().is_some());
+        assert_eq!(None, parse_dynamic_key(META_KEY));
+    }
+
+    #[tokio::test]
+    async fn test_item_content_hash() {
+        let item = load_detail_fixture("detail_forward_draw.json")["data"]["item"].clone();
+        let hash = item_content_hash(&item);
+
+        // 动态列表中的哈希与发送时记录的哈希相同
+        let dynamic = BiliDynamic::from_detail_json(
+            &test_bili_config(),
+            &RenderConfig::default(),
+            None,
+            &FixtureFetcher::new(),
+            &item,
+        )
+        .await
+        .unwrap();
+        assert_eq!(hash, dynamic.content_hash);
+
+        // 修改转发评论
+        let mut edited = item.clone();
+        edited["modules"]["module_dynamic"]["desc"]["rich_text_nodes"][0]["text"] =
+            Value::from("编辑后的评论");
+        assert_ne!(hash, item_content_hash(&edited));
+
+        // 修改原动态的图片
+        let mut edited = item.clone();
+        edited["orig"]["modules"]["module_dynamic"]["major"]["opus"]["pics"][0]["url"] =
+            Value::from("https://i0.hdslb.com/bfs/new_dyn/edited.jpg");
+        assert_ne!(hash, item_content_hash(&edited));
+    }
+
+    #[test]
+    fn test_digest_due() {
+        let digest_at: TimeOfDay = "21:00".parse().unwrap();
+        // 2024-01-01 21:00 东8区
+        let scheduled = 1704114000;
+        let at = |ts: i64| Timestamp::from_second(ts).unwrap();
+
+        assert!(!digest_due(digest_at, None, at(scheduled - 1)));
+        assert!(digest_due(digest_at, None, at(scheduled)));
+        assert!(digest_due(
+            digest_at,
+            Some(scheduled - 86400),
+            at(scheduled + 60)
+        ));
+        // 今天已经发送过
+        assert!(!digest_due(
+            digest_at,
+            Some(scheduled + 1),
+            at(scheduled + 60)
+        ));
+        // 第二天零点之后还没到发送时间
+        assert!(!digest_due(
+            digest_at,
+            Some(scheduled + 1),
+            at(scheduled + 4 * 3600)
+        ));
+    }
+
+    #[test]
+    fn test_stack_cards() {
+        let cards = [
+            RgbaImage::from_pixel(CARD_WIDTH, 100, WHITE),
+            RgbaImage::from_pixel(CARD_WIDTH, 50, WHITE),
+        ];
+        let gap = Rgba([1, 2, 3, 255]);
+        let image = stack_cards(&cards, gap);
+
+        assert_eq!((CARD_WIDTH, 100 + DIGEST_GAP + 50), image.dimensions());
+        assert_eq!(WHITE, *image.get_pixel(0, 99));
+        assert_eq!(gap, *image.get_pixel(0, 100));
+        assert_eq!(WHITE, *image.get_pixel(0, 100 + DIGEST_GAP));
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let item = &load_detail_fixture("detail_forward_draw.json")["data"]["item"];
+        let urls = image_urls(item);
+        assert_eq!(1, urls.len());
+
+        let hash = content_hash("转发", &urls);
+        assert_eq!(hash, content_hash("转发", &urls));
+        assert_ne!(hash, content_hash("转发", &[]));
+        assert_ne!(hash, content_hash("转发了", &urls));
+        // 各部分之间有分隔
+        assert_ne!(content_hash("ab", &["c"]), content_hash("a", &["bc"]));
+    }
+
+    #[test]
+    fn test_recently_sent() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = TargetTree::open(&db, "1234", false).unwrap();
+
+        let entry = DbEntry {
+            first_seen_ts: 1000,
+            sent_ts: Some(1000),
+            content_hash: Some(42),
+            ..test_entry(true, false)
+        };
+        tree.insert(
+            serde_json::to_vec(&1i64).unwrap(),
+            serde_json::to_vec(&entry).unwrap(),
+        )
+        .unwrap();
+
+        assert!(recently_sent(&tree, 42, 600, 1500));
+        assert!(!recently_sent(&tree, 42, 600, 2000));
+        assert!(!recently_sent(&tree, 43, 600, 1500));
+    }
+
+    #[test]
+    fn test_temp_receiver() {
+        let target = test_target();
+        assert_eq!(Receiver::Friend(2), Receiver::of_target(&target));
+
+        let target = TargetConfig {
+            temp_group: Some(4),
+            ..target
+        };
+        assert_eq!(
+            Receiver::Temp { qq: 2, group: 4 },
+            Receiver::of_target(&target)
+        );
+
+        let request = SendTempMessageRequest {
+            session_key: "key".to_string(),
+            qq: 2,
+            group: 4,
+            message_chain: vec![],
+        };
+        assert_eq!(
+            serde_json::json!({"sessionKey": "key", "qq": 2, "group": 4, "messageChain": []}),
+            serde_json::to_value(&request).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_override_receiver() {
+        let target = TargetConfig {
+            temp_group: Some(4),
+            ..test_target()
+        };
+        let mut mirai: MiraiConfig =
+            toml::from_str("http_url = \"http://localhost:7827\"\nverify_key = \"INITKEY\"")
+                .unwrap();
+        let messages = vec![Message::Plain {
+            text: "动态".to_string(),
+        }];
+
+        let (receiver, routed) = route_messages(&mirai, &target, messages.clone());
+        assert_eq!(Receiver::Temp { qq: 2, group: 4 }, receiver);
+        assert_eq!(
+            serde_json::to_value(&messages).unwrap(),
+            serde_json::to_value(&routed).unwrap()
+        );
+
+        mirai.override_receiver = Some(5);
+        let (receiver, routed) = route_messages(&mirai, &target, messages.clone());
+        assert_eq!(Receiver::Friend(5), receiver);
+        assert_eq!(
+            serde_json::json!([
+                {"type": "Plain", "text": "[测试] 原接收者: 2\n"},
+                {"type": "Plain", "text": "动态"},
+            ]),
+            serde_json::to_value(&routed).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_merge_as_forward() {
+        let messages = vec![
+            Message::Plain {
+                text: "测试用户 发表了新动态".to_string(),
+            },
+            Message::Image {
+                base64: "aGVsbG8=".to_string(),
+            },
+        ];
+
+        let forward = serde_json::to_value(merge_as_forward(&messages, 1234)).unwrap();
+
+        assert_eq!("Forward", forward[0]["type"]);
+        let nodes = forward[0]["nodeList"].as_array().unwrap();
+        assert_eq!(2, nodes.len());
+        assert_eq!(1234, nodes[0]["senderId"]);
+        assert_eq!("bilibili动态", nodes[0]["senderName"]);
+        assert_eq!("Plain", nodes[0]["messageChain"][0]["type"]);
+        assert_eq!("Image", nodes[1]["messageChain"][0]["type"]);
+    }
+
+    #[test]
+    fn test_forward_rejected() {
+        let status = |code| {
+            anyhow::Error::from(MiraiStatus {
+                code,
+                msg: String::new(),
+            })
+        };
+
+        assert!(forward_rejected(&status(400)));
+        assert!(forward_rejected(&status(30)));
+        assert!(!forward_rejected(&status(3)));
+        assert!(!forward_rejected(&anyhow!(
+            "Request MIRAI /sendFriendMessage"
+        )));
+    }
+
+    #[test]
+    fn test_number_or_numeric_str() {
+        let pic = serde_json::json!({
+            "width": 1080,
+            "height": "1440",
+            "size": 120.5,
+            "url": "https://i0.hdslb.com/bfs/new_dyn/test_image.jpg",
+        });
+
+        assert_eq!(Some(1080.0), number_or_numeric_str(&pic["width"]));
+        assert_eq!(Some(1440.0), number_or_numeric_str(&pic["height"]));
+        assert_eq!(Some(120.5), number_or_numeric_str(&pic["size"]));
+        assert_eq!(None, number_or_numeric_str(&pic["url"]));
+        assert_eq!(None, number_or_numeric_str(&pic["missing"]));
+    }
+
+    #[tokio::test]
+    async fn test_lottery_nodes() {
+        // 已开奖, 未开奖, 获取失败
+        let responses = [
+            r#"{"code":0,"message":"","data":{"status":2,"lottery_result":{"first_prize_result":[]}}}"#,
+            r#"{"code":0,"message":"","data":{"status":0}}"#,
+            r#"{"code":-400,"message":"请求错误"}"#,
+        ];
+        let (url, server) = serve(
+            responses
+                .iter()
+                .map(|body| json_response(body.as_bytes(), ""))
+                .collect(),
+        )
+        .await;
+        let bili = BiliConfig {
+            vc_api_base_url: Some(url),
+            ..test_bili_config()
+        };
+        let raw_text_nodes = ["1", "2", "3"].map(|rid| {
+            serde_json::json!({ "type": "RICH_TEXT_NODE_TYPE_LOTTERY", "rid": rid, "text": "互动抽奖" })
+        });
+
+        let nodes = build_text_nodes(
+            &bili,
+            &RenderConfig::default(),
+            Some(&Client::new()),
+            &FixtureFetcher::new(),
+            None,
+            &raw_text_nodes,
+        )
+        .await
+        .unwrap();
+        let requests = server.await.unwrap();
+        assert!(requests[0]
+            .contains("/lottery_svr/v1/lottery_svr/lottery_notice?business_type=1&business_id=1"));
+
+        assert!(matches!(
+            nodes[0],
+            RichTextNode::Lottery { concluded: true }
+        ));
+        assert!(matches!(
+            nodes[1],
+            RichTextNode::Lottery { concluded: false }
+        ));
+        assert!(matches!(
+            nodes[2],
+            RichTextNode::Lottery { concluded: false }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_vote_nodes() {
+        let responses = [
+            r#"{"code":0,"message":"","data":{"info":{"endtime":1,"options":[{"desc":"A","cnt":3},{"desc":"B","cnt":1}]}}}"#,
+            r#"{"code":-400,"message":"请求错误"}"#,
+        ];
+        let (url, server) = serve(
+            responses
+                .iter()
+                .map(|body| json_response(body.as_bytes(), ""))
+                .collect(),
+        )
+        .await;
+        let bili = BiliConfig {
+            vc_api_base_url: Some(url),
+            ..test_bili_config()
+        };
+        let raw_text_nodes = ["1", "2"].map(|rid| {
+            serde_json::json!({ "type": "RICH_TEXT_NODE_TYPE_VOTE", "rid": rid, "text": "投票" })
+        });
+
+        let nodes = build_text_nodes(
+            &bili,
+            &RenderConfig::default(),
+            Some(&Client::new()),
+            &FixtureFetcher::new(),
+            None,
+            &raw_text_nodes,
+        )
+        .await
+        .unwrap();
+        assert!(server.await.unwrap()[0].contains("/vote_svr/v1/vote_svr/vote_info?vote_id=1"));
+
+        let RichTextNode::Vote { info: Some(vote) } = &nodes[0] else {
+            panic!("expect vote info, got {:?}", nodes[0]);
+        };
+        assert!(vote.concluded);
+        assert_eq!(
+            vec![("A", 3), ("B", 1)],
+            vote.options
+                .iter()
+                .map(|o| (o.desc.as_str(), o.count))
+                .collect::<Vec<_>>()
+        );
+        // 获取失败时只绘制图标
+        assert!(matches!(nodes[1], RichTextNode::Vote { info: None }));
+
+        let info = serde_json::json!({ "endtime": 100, "options": [] });
+        assert!(!parse_vote_info(&info, 99).unwrap().concluded);
+        assert!(parse_vote_info(&info, 100).unwrap().concluded);
+        assert_eq!(None, parse_vote_info(&serde_json::json!({}), 0));
+    }
+
+    #[test]
+    fn test_draw_votes() {
+        let vote = |concluded: bool| {
+            vec![RichTextNode::Vote {
+                info: Some(VoteInfo {
+                    options: vec![
+                        VoteOption {
+                            desc: "A".to_string(),
+                            count: 3,
+                        },
+                        VoteOption {
+                            desc: "B".to_string(),
+                            count: 0,
+                        },
+                    ],
+                    concluded,
+                }),
+            }]
+        };
+        let draw = |texts: &[RichTextNode]| {
+            let mut generator = PicGenerator::new(CARD_WIDTH, 500);
+            draw_votes(&mut generator, texts, 600, TEXT_SCALE, None);
+            (generator.y(), generator.into_image())
+        };
+
+        // 进行中的投票只列出选项
+        let (ongoing_height, ongoing) = draw(&vote(false));
+        assert!(ongoing_height > 0);
+        assert!(!ongoing.pixels().any(|p| *p == PINK));
+
+        // 已结束的投票按比例绘制横条, 得票最多的选项占满除百分比外的宽度
+        let (concluded_height, concluded) = draw(&vote(true));
+        assert!(concluded_height > ongoing_height);
+        let pink_width = (0..concluded.width())
+            .filter(|&x| (0..concluded.height()).any(|y| *concluded.get_pixel(x, y) == PINK))
+            .count() as u32;
+        assert!(pink_width > 400 && pink_width < 600);
+
+        let (none_height, _) = draw(&[RichTextNode::Vote { info: None }]);
+        assert_eq!(0, none_height);
+    }
+
+    #[test]
+    fn test_to_plain_text() {
+        let texts = || {
+            vec![
+                RichTextNode::Text {
+                    text: "今天".to_string(),
+                },
+                RichTextNode::Emoji {
+                    img: RgbaImage::new(1, 1),
+                    alt: "[doge]".to_string(),
+                },
+                RichTextNode::Web,
+                RichTextNode::Goods {
+                    text: Some("商品".to_string()),
+                },
+            ]
+        };
+
+        let word = Content::Word { texts: texts() };
+        assert_eq!("今天[doge]商品", word.to_plain_text());
+
+        let draw = Content::Draw {
+            texts: texts(),
+            cover: None,
+            pics: vec![AlbumImage {
+                image: RgbaImage::new(1, 1),
+                original_url: "https://i0.hdslb.com/bfs/new_dyn/test_image.jpg".to_string(),
+                cropped: false,
+            }],
+            missing_pics: 0,
+            location: None,
+            flagged: false,
+        };
+        assert_eq!("今天[doge]商品", draw.to_plain_text());
+
+        let live = Content::Live {
+            live_id: 1,
+            live_title: "直播标题".to_string(),
+            live_cover: RgbaImage::new(1, 1),
+            live_qr: None,
+        };
+        assert_eq!("直播标题", live.to_plain_text());
+
+        let forward = Content::Forward {
+            texts: vec![RichTextNode::Text {
+                text: "转发".to_string(),
+            }],
+            original_author: "原作者".to_string(),
+            original_avatar: None,
+            original: Box::new(word),
+        };
+        assert_eq!("转发//@原作者: 今天[doge]商品", forward.to_plain_text());
+    }
+
+    #[test]
+    fn test_encode_jpeg() {
+        let mut image = RgbaImage::from_pixel(10, 10, Rgba([0, 0, 0, 0]));
+        image.put_pixel(0, 0, BLACK);
+
+        let render = RenderConfig {
+            output_format: OutputFormat::Jpeg,
+            ..RenderConfig::default()
+        };
+
+        let buffer = encode_image(&image, &render).unwrap();
+
+        let decoded = image::load_from_memory_with_format(&buffer, image::ImageFormat::Jpeg)
+            .unwrap()
+            .into_rgb8();
+        assert_eq!((10, 10), decoded.dimensions());
+        // 透明像素与白色背景混合
+        assert!(decoded.get_pixel(9, 9)[0] > 240);
+    }
+
+    async fn render_fixture(name: &str) -> (BiliDynamic, RgbaImage) {
+        render_fixture_with(name, &RenderConfig::default()).await
+    }
+
+    async fn render_fixture_with(name: &str, render: &RenderConfig) -> (BiliDynamic, RgbaImage) {
+        let response = load_detail_fixture(name);
+
+        let bili = test_bili_config();
+
+        let dynamic = BiliDynamic::from_detail_json(
+            &bili,
+            render,
+            None,
+            &FixtureFetcher::new(),
+            &response["data"]["item"],
+        )
+        .await
+        .unwrap();
+
+        let image = draw_dynamic(&dynamic, render).unwrap();
+
+        (dynamic, image)
+    }
+
+    #[test]
+    fn test_location_name() {
+        assert_eq!(
+            None,
+            location_name(&serde_json::json!({"additional": null}))
+        );
+        assert_eq!(
+            Some("上海市·外滩".to_string()),
+            location_name(
+                &serde_json::json!({"additional": {"lbs": {"show_title": " 上海市·外滩 "}}})
+            )
+        );
+        // 空的名称跳过
+        assert_eq!(
+            Some("外滩".to_string()),
+            location_name(&serde_json::json!({
+                "additional": {"lbs": {"show_title": "", "title": "外滩"}}
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_render_location() {
+        let (_, without) = render_fixture("detail_draw.json").await;
+
+        let mut response = load_detail_fixture("detail_draw.json");
+        response["data"]["item"]["modules"]["module_dynamic"]["additional"] = serde_json::json!({"type": "ADDITIONAL_TYPE_LBS", "lbs": {"show_title": "上海市·外滩"}});
+        let bili = test_bili_config();
+        let render = RenderConfig::default();
+        let dynamic = BiliDynamic::from_detail_json(
+            &bili,
+            &render,
+            None,
+            &FixtureFetcher::new(),
+            &response["data"]["item"],
+        )
+        .await
+        .unwrap();
+        let Content::Draw { location, .. } = &dynamic.content else {
+            panic!("expect draw content, got {:?}", dynamic.content);
+        };
+        assert_eq!(Some("上海市·外滩"), location.as_deref());
+
+        // 图片下方多出一行定位
+        let image = draw_dynamic(&dynamic, &render).unwrap();
+        assert!(image.height() > without.height());
+    }
+
+    #[test]
+    fn test_format_relative() {
+        let now = 1704067200;
+        let relative = |delta: i64| format_relative(now - delta, now).unwrap();
+
+        assert_eq!("刚刚", relative(0));
+        assert_eq!("刚刚", relative(59));
+        // 发布时间比本机时间晚
+        assert_eq!("刚刚", relative(-30));
+        assert_eq!("1分钟前", relative(60));
+        assert_eq!("59分钟前", relative(3599));
+        assert_eq!("1小时前", relative(3600));
+        assert_eq!("23小时前", relative(86399));
+        assert_eq!("1天前", relative(86400));
+        assert_eq!("7天前", relative(7 * 86400));
+        assert_eq!("2023-12-25 07:59", relative(7 * 86400 + 1));
+
+        assert!(format_relative(i64::MIN, now).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_draw_errors() {
+        assert_eq!("2024-01-01 08:00", format_timestamp(1704067200).unwrap());
+        assert!(format_timestamp(i64::MAX).is_err());
+
+        // 无法绘制时返回错误而不是panic
+        let (mut dynamic, _) = render_fixture("detail_word.json").await;
+        dynamic.author.publish_timestamp = i64::MAX;
+        let err = draw_dynamic(&dynamic, &RenderConfig::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("超出范围"));
+
+        let (dynamic, _) = render_fixture("detail_forward.json").await;
+        let mut generator = PicGenerator::new(CARD_WIDTH, 1000);
+        let narrow = draw_content(
+            &mut generator,
+            &dynamic.content,
+            FORWARD_INDENT,
+            CONTENT_SCALES,
+            &RenderConfig::default(),
+            None,
+            false,
+        );
+        assert!(narrow.is_err());
+
+        // 绘制失败时发送的文字消息
+        let (dynamic, _) = render_fixture("detail_word.json").await;
+        let messages = text_only_message(&dynamic, 1, HeaderStyle::Full);
+        let Message::Plain { text } = &messages[0] else {
+            panic!("不是文字消息");
+        };
+        assert_eq!(
+            format!(
+                "测试用户 发表了新动态\nhttps://t.bilibili.com/1\n{}",
+                dynamic.content.to_plain_text()
+            ),
+            *text
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_gzip() {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder
+            .write_all(&std::fs::read("test_resources/detail_word.json").unwrap())
+            .unwrap();
+        let body = encoder.finish().unwrap();
+
+        // 返回gzip压缩的动态详情
+        let (url, server) = serve_once(json_response(&body, "Content-Encoding: gzip\r\n")).await;
+
+        let bili = BiliConfig {
+            api_base_url: Some(url),
+            ..test_bili_config()
+        };
+        let client = config::HttpConfig::default().client().unwrap();
+        let dynamic = BiliDynamic::fetch(
+            &bili,
+            &RenderConfig::default(),
+            &client,
+            &FixtureFetcher::new(),
+            1000000000000000001,
+        )
+        .await
+        .unwrap();
+        assert_eq!("测试用户", dynamic.author.uname);
+
+        let request = server.await.unwrap().to_lowercase();
+        assert!(request.contains("accept-encoding") && request.contains("gzip"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_space_page_unexpected() {
+        // 没有 code 字段的返回与缺少动态列表一样按接口变化处理
+        let (url, server) = serve_once(json_response(br#"{"message":"ok"}"#, "")).await;
+
+        let bili = BiliConfig {
+            api_base_url: Some(url),
+            ..test_bili_config()
+        };
+        let target = test_target();
+        let e = fetch_space_page(&bili, &target, &Client::new(), None)
+            .await
+            .unwrap_err();
+        assert!(e.downcast_ref::<UnexpectedResponse>().is_some());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_unavailable() {
+        let body = std::fs::read("test_resources/detail_unavailable.json").unwrap();
+
+        // 返回没有内容的动态详情
+        let (url, server) = serve_once(json_response(&body, "")).await;
+
+        let bili = BiliConfig {
+            api_base_url: Some(url),
+            ..test_bili_config()
+        };
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = TargetTree::open(&db, "1234", false).unwrap();
+        let target = test_target();
+        let entry = mark_dynamic(&tree, 5, false).unwrap();
+        let (tx, mut rx) = mpsc::channel(1);
+
+        // 不会成功的动态直接标记为已发送, 不再重试
+        let job = RenderJob {
+            dynamic_key: dynamic_key(5),
+            dynamic_id: 5,
+            entry,
+            allow_partial: false,
+            rendered: None,
+            deferred: true,
+        };
+        let ctx = RenderContext {
+            db: &tree,
+            bili: &bili,
+            render: &RenderConfig::default(),
+            target: &target,
+            client: &Client::new(),
+            fetcher: &FixtureFetcher::new(),
+        };
+        assert!(queue_rendered(&ctx, &tx, &mut Vec::new(), vec![job])
+            .await
+            .unwrap());
+        assert!(rx.try_recv().is_err());
+        let entry = get_entry(&tree, 5).unwrap().unwrap();
+        assert!(entry.sent);
+        assert_eq!(0, entry.attempts);
+        assert!(server.await.unwrap().contains("id=5"));
+    }
+
+    #[test]
+    fn test_sign_app_params() {
+        let params = sign_app_params(vec![
+            ("ts", "1700000000".to_string()),
+            ("access_key", "ACCESS_KEY".to_string()),
+        ]);
+        let keys: Vec<_> = params.iter().map(|(key, _)| *key).collect();
+        assert_eq!(vec!["access_key", "appkey", "ts", "sign"], keys);
+
+        let query = format!("access_key=ACCESS_KEY&appkey={}&ts=1700000000", APP_KEY);
+        let sign = format!("{:x}", md5::compute(format!("{}{}", query, APP_SEC)));
+        assert_eq!(sign, params[3].1);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_app_fallback() {
+        let forward_card = json!({
+            "item": { "content": "转发评论", "orig_type": 2, "orig_dy_id_str": "2" },
+            "origin_user": { "info": { "uname": "原作者", "face": "" } },
+            "origin": json!({
+                "item": {
+                    "description": "原动态",
+                    "pictures": [{
+                        "img_src": "https://i0.hdslb.com/bfs/new_dyn/test_image.jpg",
+                        "img_width": 1000,
+                        "img_height": 800,
+                    }],
+                },
+            })
+            .to_string(),
+        });
+        let responses = [
+            json!({ "code": -352, "message": "-352" }),
+            json!({ "code": 0, "data": { "card": {
+                "desc": {
+                    "type": 1,
+                    "dynamic_id_str": "1",
+                    "timestamp": 1704067200,
+                    "user_profile": { "info": { "uname": "测试用户", "face": "" } },
+                },
+                "card": forward_card.to_string(),
+            } } }),
+        ];
+
+        // 网页接口和app接口分别由两个HTTP服务响应
+        let [web, app] = responses.map(|body| json_response(body.to_string().as_bytes(), ""));
+        let (web_url, web_server) = serve_once(web).await;
+        let (app_url, app_server) = serve_once(app).await;
+
+        let bili = BiliConfig {
+            api_base_url: Some(web_url),
+            vc_api_base_url: Some(app_url),
+            app_access_key: Some("ACCESS_KEY".to_string()),
+            ..test_bili_config()
+        };
+        let dynamic = BiliDynamic::fetch(
+            &bili,
+            &RenderConfig::default(),
+            &Client::new(),
+            &FixtureFetcher::new(),
+            1,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(1, dynamic.dynamic_id);
+        assert_eq!("测试用户", dynamic.author.uname);
+        assert_eq!("转发评论//@原作者: 原动态", dynamic.content.to_plain_text());
+        assert_eq!(0, dynamic.content.missing_pics());
+        assert_eq!(1, dynamic.content.pic_urls(false).len());
+        draw_dynamic(&dynamic, &RenderConfig::default()).unwrap();
+
+        assert!(web_server
+            .await
+            .unwrap()
+            .contains("/x/polymer/web-dynamic/v1/detail"));
+        let request = app_server.await.unwrap();
+        let request_line = request.lines().next().unwrap();
+        assert!(request_line.contains("get_dynamic_detail"));
+        assert!(request_line.contains("access_key=ACCESS_KEY") && request_line.contains("sign="));
+
+        // 不支持的动态类型
+        assert!(legacy_detail_item(8, "1", "{}", Value::Null).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_render_archived() {
+        let dir = std::env::temp_dir().join("bili-dynamic-spider-archive");
+        for (path, file) in [
+            ("i0.hdslb.com/bfs/face/test_face.png", "face.png"),
+            ("i0.hdslb.com/bfs/emote/test_doge.png", "emoji.png"),
+        ] {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::copy(format!("test_resources/{}", file), path).unwrap();
+        }
+
+        let bili = test_bili_config();
+        let detail = load_detail_fixture("detail_word.json");
+        let render = RenderConfig::default();
+        let image = render_archived(&bili, &render, &detail, &dir)
+            .await
+            .unwrap();
+
+        // 与从内存中的图片绘制的结果相同
+        let (_, expected) = render_fixture("detail_word.json").await;
+        assert_eq!(expected, image);
+
+        // 缺少归档的图片时使用默认头像
+        let fallback = render_archived(&bili, &render, &detail, &dir.join("missing"))
+            .await
+            .unwrap();
+        assert_ne!(expected, fallback);
+    }
+
+    #[tokio::test]
+    async fn test_render_word_fixture() {
+        let (dynamic, image) = render_fixture("detail_word.json").await;
+
+        assert!(matches!(dynamic.content, Content::Word { texts: _ }));
+        assert!(dynamic.author.vip);
+        assert_eq!(740, image.width());
+        assert!(image.height() > 150 && image.height() < 10000);
+    }
+
+    #[tokio::test]
+    async fn test_blur_flagged() {
+        let bili = test_bili_config();
+        let mut response = load_detail_fixture("detail_draw.json");
+        let item = &mut response["data"]["item"];
+        item["modules"]["module_dispute"] = json!({"title": "内容可能引起不适", "desc": ""});
+        assert!(is_flagged(item));
+
+        let target = TargetConfig {
+            blur_flagged: true,
+            ..test_target()
+        };
+        let render = RenderConfig::default();
+        let fetcher = FixtureFetcher::new();
+        let mut dynamic = BiliDynamic::from_detail_json(&bili, &render, None, &fetcher, item)
+            .await
+            .unwrap();
+        dynamic.blur_flagged = target.blur_flagged;
+        let Content::Draw { flagged, .. } = &dynamic.content else {
+            panic!("expect draw content, got {:?}", dynamic.content);
+        };
+        assert!(flagged);
+
+        // 只有图片模糊, 卡片大小和文字不变
+        let blurred = draw_dynamic(&dynamic, &render).unwrap();
+        let (plain_dynamic, plain) = render_fixture("detail_draw.json").await;
+        assert_eq!(plain.dimensions(), blurred.dimensions());
+        assert_ne!(plain, blurred);
+        assert_eq!(
+            plain_dynamic.content.to_plain_text(),
+            dynamic.content.to_plain_text()
+        );
+
+        // 模糊的图片不发送原图地址
+        assert!(dynamic.content.pic_urls(true).is_empty());
+
+        // 目标没有开启时照常绘制
+        dynamic.blur_flagged = false;
+        assert_eq!(plain, draw_dynamic(&dynamic, &render).unwrap());
+        assert!(!dynamic.content.pic_urls(false).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_render_draw_fixture() {
+        let (dynamic, image) = render_fixture("detail_draw.json").await;
+
+        let Content::Draw {
+            texts: _,
+            cover: None,
+            pics,
+            missing_pics: 0,
+            location: None,
+            flagged: false,
+        } = &dynamic.content
+        else {
+            panic!("expect draw content, got {:?}", dynamic.content);
+        };
+        assert_eq!(2, pics.len());
+        assert_eq!(
+            vec![
+                "https://i0.hdslb.com/bfs/new_dyn/test_image.jpg",
+                "https://i0.hdslb.com/bfs/new_dyn/test_background.jpg"
+            ],
+            dynamic.content.pic_urls(false)
+        );
+        assert_eq!(740, image.width());
+        // 两张图片排成一行, 每张边长355
+        assert!(image.height() > 150 + 355 && image.height() < 10000);
+
+        // 两张图片都不是正方形, 开启后绘制边框
+        assert!(pics.iter().all(|pic| pic.cropped));
+        let render = RenderConfig {
+            mark_cropped: true,
+            ..RenderConfig::default()
+        };
+        let marked = draw_dynamic(&dynamic, &render).unwrap();
+        let gray_pixels = |image: &RgbaImage| image.pixels().filter(|p| **p == GRAY).count();
+        assert!(gray_pixels(&marked) >= gray_pixels(&image) + 4 * 355);
+    }
+
+    #[test]
+    fn test_crops_to_square() {
+        assert!(!crops_to_square(1080.0, 1080.0, 355));
+        assert!(crops_to_square(1080.0, 1440.0, 355));
+        assert!(crops_to_square(1920.0, 1080.0, 355));
+        // 缩放后长边只多出不到半个像素时不算裁剪
+        assert!(!crops_to_square(1000.0, 1001.0, 100));
+    }
+
+    #[tokio::test]
+    async fn test_render_forward_fixture() {
+        let (dynamic, image) = render_fixture("detail_forward.json").await;
+
+        let Content::Forward {
+            texts: _,
+            original_author,
+            original_avatar: None,
+            original,
+        } = &dynamic.content
+        else {
+            panic!("expect forward content, got {:?}", dynamic.content);
+        };
+        assert_eq!("原作者", original_author);
+        assert!(matches!(**original, Content::Word { texts: _ }));
+        assert_eq!(740, image.width());
+        assert!(image.height() > 150 && image.height() < 10000);
+
+        // 原动态使用更小的字号
+        let render = RenderConfig {
+            forward_text_size: Some(20.0),
+            forward_emoji_size: Some(18.0),
+            ..RenderConfig::default()
+        };
+        let smaller = draw_dynamic(&dynamic, &render).unwrap();
+        assert!(smaller.height() < image.height());
+
+        // 原动态背景使用配置的颜色
+        let background = Color([1, 2, 3]);
+        let render = RenderConfig {
+            forward_background: background,
+            ..RenderConfig::default()
+        };
+        let themed = draw_dynamic(&dynamic, &render).unwrap();
+        let background = Rgba::from(background);
+        assert!(!image.pixels().any(|p| *p == background));
+        assert!(themed.pixels().any(|p| *p == background));
+
+        // 开启后在 @原作者 前绘制原作者头像, 文字右移
+        let render = RenderConfig {
+            forward_show_avatar: true,
+            ..RenderConfig::default()
+        };
+        let (dynamic, with_avatar) = render_fixture_with("detail_forward.json", &render).await;
+        let Content::Forward {
+            original_avatar: Some(_),
+            ..
+        } = &dynamic.content
+        else {
+            panic!("expect original avatar, got {:?}", dynamic.content);
+        };
+        assert_eq!(image.height(), with_avatar.height());
+        assert_ne!(image, with_avatar);
+    }
+
+    #[tokio::test]
+    async fn test_render_forward_draw_fixture() {
+        let (dynamic, image) = render_fixture("detail_forward_draw.json").await;
+
+        let Content::Forward {
+            texts: _,
+            original_author: _,
+            original_avatar: _,
+            original,
+        } = &dynamic.content
+        else {
+            panic!("expect forward content, got {:?}", dynamic.content);
+        };
+        let Content::Draw {
+            texts: _,
+            cover: None,
+            pics,
+            missing_pics: 0,
+            location: None,
+            flagged: false,
+        } = &**original
+        else {
+            panic!("expect draw original, got {:?}", original);
+        };
+        // 原动态的图片按缩进后的宽度缩放, 不超出灰色区域
+        assert_eq!(1, pics.len());
+        assert_eq!(
+            CARD_WIDTH - 2 * (CONTENT_X + FORWARD_INDENT),
+            pics[0].image.width()
+        );
+        assert_eq!(740, image.width());
+        assert!(image.height() > 150 + pics[0].image.height() && image.height() < 10000);
+    }
+
+    #[tokio::test]
+    async fn test_render_forward_video_fixture() {
+        let (dynamic, image) = render_fixture("detail_forward_video.json").await;
+
+        let Content::Forward {
+            texts: _,
+            original_author: _,
+            original_avatar: _,
+            original,
+        } = &dynamic.content
+        else {
+            panic!("expect forward content, got {:?}", dynamic.content);
+        };
+        let Content::Video {
+            title,
+            cover: Some(cover),
+            duration,
+        } = &**original
+        else {
+            panic!("expect video original, got {:?}", original);
+        };
+        assert!(title.starts_with("这是一个视频的标题"));
+        assert_eq!(ARCHIVE_COVER_SIZE, cover.dimensions());
+        assert_eq!(Some("03:21"), duration.as_deref());
+        assert_eq!(
+            "推荐这个视频//@原作者: 这是一个视频的标题, 长度足够在封面右侧换行显示",
+            dynamic.content.to_plain_text()
+        );
+
+        // 封面缩略图画在缩进后的位置, 卡片保持紧凑
+        assert_eq!(740, image.width());
+        assert!(image.height() > 150 + cover.height() && image.height() < 600);
+
+        // 封面下载失败时只绘制标题
+        let title = title.clone();
+        let mut without_cover = dynamic;
+        without_cover.content = Content::Forward {
+            texts: Vec::new(),
+            original_author: "原作者".to_string(),
+            original_avatar: None,
+            original: Box::new(Content::Video {
+                title,
+                cover: None,
+                duration: None,
+            }),
+        };
+        let smaller = draw_dynamic(&without_cover, &RenderConfig::default()).unwrap();
+        assert!(smaller.height() < image.height());
+    }
+
+    #[tokio::test]
+    async fn test_render_forward_article_fixture() {
+        let (dynamic, image) = render_fixture("detail_forward_article.json").await;
+
+        let Content::Forward {
+            texts: _,
+            original_author: _,
+            original_avatar: _,
+            original,
+        } = &dynamic.content
+        else {
+            panic!("expect forward content, got {:?}", dynamic.content);
+        };
+        let Content::Article {
+            title,
+            cover: Some(cover),
+        } = &**original
+        else {
+            panic!("expect article original, got {:?}", original);
+        };
+        assert_eq!("专栏标题", title);
+        assert_eq!(ARCHIVE_COVER_SIZE, cover.dimensions());
+        assert_eq!(740, image.width());
+        assert!(image.height() > 150 + cover.height() && image.height() < 600);
+
+        // 旧版专栏的标题和封面在 `major.article` 中
+        let mut response = load_detail_fixture("detail_forward_article.json");
+        response["data"]["item"]["orig"]["modules"]["module_dynamic"]["major"] = serde_json::json!({
+            "type": "MAJOR_TYPE_ARTICLE",
+            "article": {
+                "id": 1,
+                "title": "旧版专栏",
+                "covers": ["https://i0.hdslb.com/bfs/new_dyn/test_image.jpg"],
+            },
+        });
+        let original = Content::from_detail_json(
+            &test_bili_config(),
+            &RenderConfig::default(),
+            None,
+            &FixtureFetcher::new(),
+            &response["data"]["item"]["orig"],
+            CARD_WIDTH,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            original,
+            Content::Article { title, cover: Some(_) } if title == "旧版专栏"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_missing_pics() {
+        let response = load_detail_fixture("detail_draw.json");
+
+        let bili = test_bili_config();
+        let mut fetcher = FixtureFetcher::new();
+        fetcher
+            .images
+            .remove("https://i0.hdslb.com/bfs/new_dyn/test_background.jpg");
+
+        let dynamic = BiliDynamic::from_detail_json(
+            &bili,
+            &RenderConfig::default(),
+            None,
+            &fetcher,
+            &response["data"]["item"],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(1, dynamic.content.missing_pics());
+        // 下载失败的图片以同样大小的占位代替, 相册仍按两张排列
+        let Content::Draw { pics, .. } = &dynamic.content else {
+            panic!("expect draw content, got {:?}", dynamic.content);
+        };
+        assert_eq!(2, pics.len());
+        assert_eq!(pics[0].image.dimensions(), pics[1].image.dimensions());
+        let placeholder = &pics[1].image;
+        assert_eq!(PLACEHOLDER_COLOR, *placeholder.get_pixel(0, 0));
+        assert!(placeholder.pixels().any(|p| *p != PLACEHOLDER_COLOR));
+
+        // 没有可用地址的图片同样以占位代替并计入失败数量
+        let pictures = [
+            json!({
+                "url": "https://i0.hdslb.com/bfs/new_dyn/test_image.jpg",
+                "width": 1000,
+                "height": 1000,
+            }),
+            json!({ "width": 1000, "height": 1000 }),
+        ];
+        let fetcher = SkeletonFetcher::new(FixtureFetcher::new(), true);
+        let (pics, failed) =
+            download_dynamic_images(&fetcher, &pictures, 600, 10, FilterType::Triangle)
+                .await
+                .unwrap();
+        assert_eq!(1, failed);
+        assert_eq!(2, pics.len());
+        assert_eq!(pics[0].image.dimensions(), pics[1].image.dimensions());
+        let draw = Content::Draw {
+            texts: Vec::new(),
+            cover: None,
+            pics,
+            missing_pics: failed,
+            location: None,
+            flagged: false,
+        };
+        assert_eq!(
+            vec!["https://i0.hdslb.com/bfs/new_dyn/test_image.jpg"],
+            draw.pic_urls(false)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_skeleton_cover_size() {
+        let picture = json!({
+            "url": "https://i0.hdslb.com/bfs/new_dyn/test_image.jpg",
+            "width": 1000,
+            "height": 500,
+        });
+        let fetcher = SkeletonFetcher::new(FixtureFetcher::new(), true);
+
+        // 占位图按接口给出的原图宽高比绘制, 而不是正方形
+        let cover = download_cover_image(&fetcher, &picture, 400, FilterType::Triangle)
+            .await
+            .unwrap();
+        assert_eq!((400, 200), cover.dimensions());
+        assert!(cover.pixels().all(|p| *p == PLACEHOLDER_COLOR));
+    }
+
+    #[tokio::test]
+    async fn test_render_big_cover_fixture() {
+        let mut response = load_detail_fixture("detail_draw.json");
+        response["data"]["item"]["modules"]["module_dynamic"]["major"]["opus"]["opusBigCover"] =
+            Value::Bool(true);
+
+        let bili = test_bili_config();
+
+        let dynamic = BiliDynamic::from_detail_json(
+            &bili,
+            &RenderConfig::default(),
+            None,
+            &FixtureFetcher::new(),
+            &response["data"]["item"],
+        )
+        .await
+        .unwrap();
+
+        let Content::Draw {
+            texts: _,
+            cover:
+                Some(AlbumImage {
+                    image: cover,
+                    original_url: _,
+                    cropped: false,
+                }),
+            pics,
+            missing_pics: 0,
+            location: None,
+            flagged: false,
+        } = &dynamic.content
+        else {
+            panic!("expect draw content with cover, got {:?}", dynamic.content);
+        };
+        // 封面通栏且保持比例, 剩下的一张图片仍按相册绘制
+        let (w, h) = FixtureFetcher::new()
+            .fetch_image("https://i0.hdslb.com/bfs/new_dyn/test_image.jpg")
+            .await
+            .map(|img| img.dimensions())
+            .unwrap();
+        assert_eq!(CARD_WIDTH - 2 * CONTENT_X, cover.width());
+        assert_eq!(
+            ((cover.width() as f64) * (h as f64) / (w as f64)).round() as u32,
+            cover.height()
+        );
+        assert_eq!(1, pics.len());
+        // 封面也列出原图地址
+        assert_eq!(2, dynamic.content.pic_urls(false).len());
+
+        let image = draw_dynamic(&dynamic, &RenderConfig::default()).unwrap();
+        assert!(image.height() > 150 + cover.height() + pics[0].image.height());
+    }
+
+    #[test]
+    fn test_normalize_image_url() {
+        let expected = Some("https://i0.hdslb.com/bfs/face/a.jpg".to_string());
+        for url in [
+            "https://i0.hdslb.com/bfs/face/a.jpg",
+            "http://i0.hdslb.com/bfs/face/a.jpg",
+            "//i0.hdslb.com/bfs/face/a.jpg",
+            "i0.hdslb.com/bfs/face/a.jpg",
+        ] {
+            assert_eq!(expected, normalize_image_url(url), "{}", url);
+        }
+        assert_eq!(
+            Some("https://i1.biliimg.com/face.png".to_string()),
+            normalize_image_url("//i1.biliimg.com/face.png")
+        );
+
+        for url in [
+            "https://example.com/face.jpg",
+            "https://evilhdslb.com/face.jpg",
+            "https://i0.hdslb.com.example.com/face.jpg",
+            "ftp://i0.hdslb.com/face.jpg",
+            "",
+        ] {
+            assert_eq!(None, normalize_image_url(url), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_face_fallback() {
+        let bili = test_bili_config();
+        let fetcher = FixtureFetcher::new();
+        let render = RenderConfig::default();
+
+        // 协议相对的地址也能下载到头像
+        let mut response = load_detail_fixture("detail_word.json");
+        let author = &mut response["data"]["item"]["modules"]["module_author"];
+        author["face"] = Value::from("//i0.hdslb.com/bfs/face/test_face.png");
+        let dynamic = BiliDynamic::from_detail_json(
+            &bili,
+            &render,
+            None,
+            &fetcher,
+            &response["data"]["item"],
+        )
+        .await
+        .unwrap();
+        assert_ne!(resource().no_face_image, dynamic.author.avatar_image);
+
+        // 下载失败或地址无效时使用默认头像, 而不是放弃整条动态
+        for face in [
+            "https://i0.hdslb.com/bfs/face/missing.png",
+            "https://example.com/face.png",
+        ] {
+            let mut response = load_detail_fixture("detail_word.json");
+            response["data"]["item"]["modules"]["module_author"]["face"] = Value::from(face);
+            let dynamic = BiliDynamic::from_detail_json(
+                &bili,
+                &render,
+                None,
+                &fetcher,
+                &response["data"]["item"],
+            )
+            .await
+            .unwrap();
+            assert_eq!(resource().no_face_image, dynamic.author.avatar_image);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_header_style() {
+        let (draw, _) = render_fixture("detail_draw.json").await;
+        assert_eq!(
+            "测试用户 发表了新动态\nhttps://t.bilibili.com/1\n",
+            message_header(&draw, 1, HeaderStyle::Full)
+        );
+        assert_eq!(
+            "【测试用户】发布了带图动态\n",
+            message_header(&draw, 1, HeaderStyle::Compact)
+        );
+
+        let (live, _) = render_fixture("detail_live.json").await;
+        assert!(message_header(&live, 1, HeaderStyle::Full).contains("https://live.bilibili.com/"));
+        assert!(!message_header(&live, 1, HeaderStyle::Compact).contains("https://"));
+
+        let render: RenderConfig = toml::from_str("header_style = \"compact\"").unwrap();
+        assert_eq!(HeaderStyle::Compact, render.header_style);
+    }
+
+    #[tokio::test]
+    async fn test_message_order() {
+        let (dynamic, _) = render_fixture("detail_word.json").await;
+        let target = test_target();
+        let chain = |render: &RenderConfig, target: &TargetConfig| {
+            let messages = build_message_chain(&dynamic, 1, "aW1hZ2U=".to_string(), render, target);
+            serde_json::to_value(&messages).unwrap()
+        };
+        let header = serde_json::json!({
+            "type": "Plain",
+            "text": "测试用户 发表了新动态\nhttps://t.bilibili.com/1\n",
+        });
+        let image = serde_json::json!({"type": "Image", "base64": "aW1hZ2U="});
+
+        // 默认先发送开头再发送图片
+        let render = RenderConfig::default();
+        assert_eq!(serde_json::json!([header, image]), chain(&render, &target));
+
+        let render: RenderConfig =
+            toml::from_str("message_order = [\"image\", \"text\", \"header\"]").unwrap();
+        let text = serde_json::json!({
+            "type": "Plain",
+            "text": dynamic.content.to_plain_text(),
+        });
+        assert_eq!(
+            serde_json::json!([image, text, header]),
+            chain(&render, &target)
+        );
+
+        // 没有开启的部分不发送
+        let target = TargetConfig {
+            send_header: false,
+            ..target
+        };
+        assert_eq!(serde_json::json!([image, text]), chain(&render, &target));
+    }
+
+    #[tokio::test]
+    async fn test_charge_only() {
+        let mut response = load_detail_fixture("detail_word.json");
+        let item = &mut response["data"]["item"];
+        item["basic"] = serde_json::json!({"is_only_fans": true});
+        item["modules"]["module_dynamic"] = serde_json::json!({
+            "desc": {
+                "rich_text_nodes": [
+                    {"type": "RICH_TEXT_NODE_TYPE_TEXT", "text": "新视频预告", "orig_text": "新视频预告"},
+                ],
+            },
+            "major": {
+                "type": "MAJOR_TYPE_BLOCKED",
+                "blocked": {"blocked_type": 1, "hint_message": "专属动态\n开通充电后可享"},
+            },
+        });
+
+        let bili = test_bili_config();
+        let render = RenderConfig::default();
+        let dynamic =
+            BiliDynamic::from_detail_json(&bili, &render, None, &FixtureFetcher::new(), item)
+                .await
+                .unwrap();
+        assert!(dynamic.charge_only);
+        assert_eq!(
+            "新视频预告\n专属动态\n开通充电后可享",
+            dynamic.content.to_plain_text()
+        );
+
+        // 用户名一行绘制了橙色的充电专属标记
+        let image = draw_dynamic(&dynamic, &render).unwrap();
+        let (_, name_y) = NORMAL_LAYOUT.name_xy;
+        assert!((0..CARD_WIDTH).any(|x| *image.get_pixel(x, name_y + 2) == ORANGE));
+
+        let (dynamic, image) = render_fixture("detail_word.json").await;
+        assert!(!dynamic.charge_only);
+        assert!(!image.pixels().any(|p| *p == ORANGE));
+    }
+
+    #[tokio::test]
+    async fn test_render_pendant() {
+        let mut response = load_detail_fixture("detail_word.json");
+        response["data"]["item"]["modules"]["module_author"]["pendant"] = serde_json::json!({
+            "image": "https://i0.hdslb.com/bfs/garb/test_pendant.png",
+            "name": "头像框",
+        });
+        let item = &response["data"]["item"];
+
+        let bili = test_bili_config();
+        let mut fetcher = FixtureFetcher::new();
+        fetcher.images.insert(
+            "https://i0.hdslb.com/bfs/garb/test_pendant.png",
+            include_bytes!("../test_resources/image.JPG").as_slice(),
+        );
+        let render = RenderConfig {
+            show_pendant: true,
+            ..RenderConfig::default()
+        };
+
+        let dynamic = BiliDynamic::from_detail_json(&bili, &render, None, &fetcher, item)
+            .await
+            .unwrap();
+        assert!(dynamic.author.pendant_image.is_some());
+
+        // 头像框围绕头像, 覆盖头像外侧的空白
+        let image = draw_dynamic(&dynamic, &render).unwrap();
+        let (face_x, face_y) = NORMAL_LAYOUT.face_xy;
+        assert_ne!(WHITE, *image.get_pixel(face_x - 10, face_y - 10));
+
+        // 关闭时不下载
+        let dynamic =
+            BiliDynamic::from_detail_json(&bili, &RenderConfig::default(), None, &fetcher, item)
+                .await
+                .unwrap();
+        assert!(dynamic.author.pendant_image.is_none());
+        let image = draw_dynamic(&dynamic, &RenderConfig::default()).unwrap();
+        assert_eq!(WHITE, *image.get_pixel(face_x - 10, face_y - 10));
+
+        // 下载失败时跳过
+        let dynamic =
+            BiliDynamic::from_detail_json(&bili, &render, None, &FixtureFetcher::new(), item)
+                .await
+                .unwrap();
+        assert!(dynamic.author.pendant_image.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_render_vip_level_fixture() {
+        let (dynamic, image) = render_fixture("detail_word.json").await;
+        // 旧的API返回中没有等级
+        assert!(dynamic.author.vip && dynamic.author.annual_vip);
+        assert_eq!(None, dynamic.author.level);
+
+        let name_width = imageproc::drawing::text_size(
+            TEXT_SCALE,
+            &resource().text_normal_font,
+            &dynamic.author.uname,
+        )
+        .0;
+        let badge_x = NORMAL_LAYOUT.name_xy.0 + name_width + 10;
+        assert_eq!(
+            PINK,
+            *image.get_pixel(badge_x + 1, NORMAL_LAYOUT.name_xy.1 + 1)
+        );
+
+        let mut response = load_detail_fixture("detail_word.json");
+        let author = &mut response["data"]["item"]["modules"]["module_author"];
+        author["vip"]["type"] = serde_json::json!(1);
+        author["level"] = serde_json::json!(6);
+
+        let bili = test_bili_config();
+        let dynamic = BiliDynamic::from_detail_json(
+            &bili,
+            &RenderConfig::default(),
+            None,
+            &FixtureFetcher::new(),
+            &response["data"]["item"],
+        )
+        .await
+        .unwrap();
+        assert!(dynamic.author.vip && !dynamic.author.annual_vip);
+        assert_eq!(Some(6), dynamic.author.level);
+
+        // 月度大会员只画等级
+        let image = draw_dynamic(&dynamic, &RenderConfig::default()).unwrap();
+        assert_eq!(
+            GRAY,
+            *image.get_pixel(badge_x + 1, NORMAL_LAYOUT.name_xy.1 + 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_render_pinned_fixture() {
+        let mut response = load_detail_fixture("detail_word.json");
+        response["data"]["item"]["modules"]["module_tag"] = serde_json::json!({ "text": "置顶" });
+
+        let bili = test_bili_config();
+
+        let dynamic = BiliDynamic::from_detail_json(
+            &bili,
+            &RenderConfig::default(),
+            None,
+            &FixtureFetcher::new(),
+            &response["data"]["item"],
+        )
+        .await
+        .unwrap();
+        assert!(dynamic.pinned);
+
+        let image = draw_dynamic(&dynamic, &RenderConfig::default()).unwrap();
+        // 置顶标记画在用户名一行的右侧
+        assert_eq!(PINK, *image.get_pixel(CARD_WIDTH - CONTENT_X - 2, 62));
+    }
+
+    #[tokio::test]
+    async fn test_force_image_emoji() {
+        let render = RenderConfig {
+            force_image_emoji: vec![EmojiCodepoint('😀')],
+            emoji_cdn: "https://i0.hdslb.com/bfs/emote/test_{codepoint}.png".to_string(),
+            ..RenderConfig::default()
+        };
+        let mut fetcher = FixtureFetcher::new();
+        fetcher.images.insert(
+            "https://i0.hdslb.com/bfs/emote/test_1f600.png",
+            include_bytes!("../test_resources/emoji.png").as_slice(),
+        );
+
+        let mut res = Vec::new();
+        push_text_node(&mut res, &render, &fetcher, "出去玩了😀!🥰".to_string()).await;
+
+        assert_eq!(3, res.len());
+        assert!(matches!(&res[0], RichTextNode::Text { text } if text == "出去玩了"));
+        assert!(matches!(&res[1], RichTextNode::Emoji { img: _, alt } if alt == "😀"));
+        // 没有列出的emoji仍然使用字体绘制
+        assert!(matches!(&res[2], RichTextNode::Text { text } if text == "!🥰"));
+    }
+
+    #[tokio::test]
+    async fn test_render_live_fixture() {
+        let (dynamic, image) = render_fixture("detail_live.json").await;
+
+        let Content::Live {
+            live_id,
+            live_title,
+            live_cover: _,
+            live_qr: None,
+        } = &dynamic.content
+        else {
+            panic!("expect live content, got {:?}", dynamic.content);
+        };
+        assert_eq!(22222, *live_id);
+        assert_eq!("测试直播间", live_title);
+        assert_eq!(740, image.width());
+        assert!(image.height() > 150 && image.height() < 10000);
+    }
+
+    #[tokio::test]
+    async fn test_render_live_qr() {
+        let render = RenderConfig {
+            live_qr: true,
+            ..RenderConfig::default()
+        };
+        let (dynamic, image) = render_fixture_with("detail_live.json", &render).await;
+
+        let Content::Live {
+            live_id: _,
+            live_title: _,
+            live_cover,
+            live_qr: Some(qr),
+        } = &dynamic.content
+        else {
+            panic!("expect live content with qr, got {:?}", dynamic.content);
+        };
+        assert!(qr.width() <= LIVE_QR_SIZE && qr.width() == qr.height());
+        // 四周留白
+        assert_eq!(WHITE, *qr.get_pixel(0, 0));
+        assert_eq!(WHITE, *qr.get_pixel(qr.width() - 1, qr.height() - 1));
+        assert!(qr.pixels().any(|p| *p == BLACK));
+
+        // 二维码画在封面一行的右侧
+        let has_black_on_right = |image: &RgbaImage| {
+            let x_start = CARD_WIDTH - CONTENT_X - qr.width();
+            let y_start = image.height() - live_cover.height() - 40;
+            (x_start..CARD_WIDTH - CONTENT_X)
+                .any(|x| (y_start..image.height()).any(|y| *image.get_pixel(x, y) == BLACK))
+        };
+        assert!(has_black_on_right(&image));
+
+        let (_, without_qr) = render_fixture("detail_live.json").await;
+        assert!(!has_black_on_right(&without_qr));
+    }
+
+    #[tokio::test]
+    async fn test_render_dynamic_qr() {
+        let render = RenderConfig {
+            dynamic_qr: true,
+            ..RenderConfig::default()
+        };
+        let (dynamic, image) = render_fixture_with("detail_word.json", &render).await;
+        assert_eq!(1000000000000000001, dynamic.dynamic_id);
+
+        // 二维码在内容下方, 卡片变高
+        let without_qr = draw_dynamic(&dynamic, &RenderConfig::default()).unwrap();
+        let qr_size = qr_image(
+            "https://t.bilibili.com/1000000000000000001",
+            CARD_WIDTH / DYNAMIC_QR_WIDTH_DIVISOR,
+        )
+        .unwrap()
+        .height();
+        assert!(qr_size <= CARD_WIDTH / DYNAMIC_QR_WIDTH_DIVISOR);
+        assert_eq!(without_qr.height() + qr_size + 10, image.height());
+        let y = image.height() - 10 - qr_size / 2;
+        assert!((CARD_WIDTH - CONTENT_X - qr_size..CARD_WIDTH - CONTENT_X)
+            .any(|x| *image.get_pixel(x, y) == BLACK));
+    }
+
+    #[tokio::test]
+    async fn test_render_max_height() {
+        let (mut dynamic, _) = render_fixture("detail_word.json").await;
+        dynamic.content = Content::Word {
+            texts: vec![RichTextNode::Text {
+                text: "很长的动态\n".repeat(50),
+            }],
+        };
+
+        let full = draw_dynamic(&dynamic, &RenderConfig::default()).unwrap();
+        let render = RenderConfig {
+            max_height: Some(600),
+            ..RenderConfig::default()
+        };
+        let image = draw_dynamic(&dynamic, &render).unwrap();
+        assert!(image.height() <= 600);
+        assert!(image.height() < full.height());
+        // 截断处绘制了灰色的"阅读全文"
+        let bottom = image.height() - READ_MORE_HEIGHT..image.height();
+        assert!(bottom
+            .flat_map(|y| (0..CARD_WIDTH).map(move |x| (x, y)))
+            .any(|(x, y)| *image.get_pixel(x, y) == GRAY));
+
+        // 高度足够时与不限制相同
+        let render = RenderConfig {
+            max_height: Some(full.height() + READ_MORE_HEIGHT),
+            ..RenderConfig::default()
+        };
+        assert_eq!(full, draw_dynamic(&dynamic, &render).unwrap());
+
+        // 投票选项超出时同样截断
+        dynamic.content = Content::Word {
+            texts: vec![
+                RichTextNode::Text {
+                    text: "投票".to_string(),
+                },
+                RichTextNode::Vote {
+                    info: Some(VoteInfo {
+                        options: (0..30)
+                            .map(|i| VoteOption {
+                                desc: format!("选项{}", i),
+                                count: i,
+                            })
+                            .collect(),
+                        concluded: true,
+                    }),
+                },
+            ],
+        };
+        let full = draw_dynamic(&dynamic, &RenderConfig::default()).unwrap();
+        let render = RenderConfig {
+            max_height: Some(600),
+            ..RenderConfig::default()
+        };
+        let image = draw_dynamic(&dynamic, &render).unwrap();
+        assert!(full.height() > 600);
+        assert!(image.height() <= 600);
+
+        // 直播的标题和封面放不下时不绘制
+        let (live, full) = render_fixture("detail_live.json").await;
+        let max_height = full.height() - 1;
+        let render = RenderConfig {
+            max_height: Some(max_height),
+            ..RenderConfig::default()
+        };
+        let image = draw_dynamic(&live, &render).unwrap();
+        assert!(image.height() <= max_height);
+    }
 }
@@ -1,11 +1,12 @@
 //! Inspired by https://github.com/Starlwr/StarBot/blob/master/starbot/painter/PicGenerator.py
 
 use std::{
+    cmp,
     io::{BufReader, Cursor},
     path::Path,
 };
 
-use ab_glyph::{v2::GlyphImage, Font, GlyphImageFormat, PxScale};
+use ab_glyph::{v2::GlyphImage, Font, GlyphImageFormat, PxScale, ScaleFont};
 use anyhow::{anyhow, Result};
 use image::{
     imageops::{self, FilterType},
@@ -13,9 +14,13 @@ use image::{
 };
 use imageproc::definitions::HasBlack;
 use tracing::debug;
+use unicode_bidi::BidiInfo;
 
 use crate::{resource::Resource, RichTextNode};
 
+/// Extra horizontal space after each emoji drawn from the emoji font
+const EMOJI_SPACING: u32 = 2;
+
 pub struct PicGenerator {
     /// image buffer
     image: RgbaImage,
@@ -73,6 +78,10 @@ impl PicGenerator {
         self
     }
 
+    pub fn row_space(&self) -> u32 {
+        self.row_space
+    }
+
     pub fn set_row_space(&mut self, rs: u32) -> &mut Self {
         self.row_space = rs;
         self
@@ -112,6 +121,7 @@ impl PicGenerator {
 
     /// Draw text on the buffer. If xy is provided draw from xy and don't move internal coordinate, otherwise
     /// move the coordinate to the next row after concatenating all the given texts in a single row.
+    /// A `\n` inside a text continues drawing on the next line from the starting x.
     pub fn draw_text(
         &mut self,
         texts: &[&str],
@@ -120,59 +130,91 @@ impl PicGenerator {
         scale: PxScale,
         xy: Option<(u32, u32)>,
     ) -> &mut Self {
-        let (mut cx, cy) = match xy {
+        let (start_x, start_y) = match xy {
             Some((x, y)) => (x, y),
             None => (self.x, self.y),
         };
 
-        // If move position, move past the text's height + row_space
-        let mut text_height = 0;
+        let (mut cx, mut cy) = (start_x, start_y);
+
+        // Height of the line currently being drawn
+        let mut line_height = 0;
 
         for (i, &text) in texts.iter().enumerate() {
             let color = colors.get(i).copied().unwrap_or(Rgba::<u8>::black());
 
-            let (tw, th) = imageproc::drawing::text_size(scale, font, text);
+            for (j, line) in text.split('\n').enumerate() {
+                if j > 0 {
+                    // Empty lines still take the height of the font
+                    cy += cmp::max(line_height, scale.y.ceil() as u32);
+                    cx = start_x;
+                    line_height = 0;
+                }
 
-            if text_height < th {
-                text_height = th;
-            }
+                let (tw, th) = imageproc::drawing::text_size(scale, font, line);
 
-            imageproc::drawing::draw_text_mut(
-                &mut self.image,
-                color,
-                cx as i32,
-                cy as i32,
-                scale,
-                font,
-                text,
-            );
+                if line_height < th {
+                    line_height = th;
+                }
 
-            cx += tw;
+                imageproc::drawing::draw_text_mut(
+                    &mut self.image,
+                    color,
+                    cx as i32,
+                    cy as i32,
+                    scale,
+                    font,
+                    line,
+                );
+
+                cx += tw;
+            }
         }
 
+        // If move position, move past the text's height + row_space
         if xy.is_none() {
-            self.y += text_height + self.row_space;
+            self.y = cy + line_height + self.row_space;
         }
 
         self
     }
 
-    /// Draw a rectangle on the buffer. This won't move the coordinate
+    /// Fill a rectangle with its top left corner at `(x, y)`. Parts outside the buffer on the
+    /// left, top and right are clipped; if the rectangle reaches below the buffer, the buffer
+    /// grows to fit it first. This won't move the coordinate
     pub fn draw_rectangle(
         &mut self,
-        x: u32,
-        y: u32,
+        x: i32,
+        y: i32,
         height: u32,
         width: u32,
         color: Rgba<u8>,
     ) -> &mut Self {
-        let rect = imageproc::rect::Rect::at(x as i32, y as i32).of_size(width, height);
+        let right = (x as i64 + width as i64).clamp(0, self.width() as i64) as u32;
+        let bottom = (y as i64 + height as i64).clamp(0, u32::MAX as i64) as u32;
+        if bottom > self.height() {
+            self.grow_to(bottom);
+        }
 
+        let (left, top) = (x.max(0) as u32, y.max(0) as u32);
+        if left >= right || top >= bottom {
+            return self;
+        }
+
+        let rect =
+            imageproc::rect::Rect::at(left as i32, top as i32).of_size(right - left, bottom - top);
         imageproc::drawing::draw_filled_rect_mut(&mut self.image, rect, color);
 
         self
     }
 
+    /// Extend the buffer downward to `height`, the new area is transparent
+    fn grow_to(&mut self, height: u32) {
+        let mut image = RgbaImage::new(self.width(), height);
+        imageops::replace(&mut image, &self.image, 0, 0);
+        self.image = image;
+    }
+
     #[allow(dead_code)]
     pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
         self.image.save(path)?;
@@ -193,7 +235,11 @@ impl PicGenerator {
     }
 }
 
-pub fn create_circular_image(input_image: &RgbaImage, diameter: u32) -> RgbaImage {
+pub fn create_circular_image(
+    input_image: &RgbaImage,
+    diameter: u32,
+    filter: FilterType,
+) -> RgbaImage {
     // Create a transparent background image
     let mut circular_image = RgbaImage::from_pixel(
         diameter,
@@ -213,7 +259,7 @@ pub fn create_circular_image(input_image: &RgbaImage, diameter: u32) -> RgbaImag
         input_image,
         (orig_width as f32 * scale) as u32,
         (orig_height as f32 * scale) as u32,
-        imageops::FilterType::Lanczos3,
+        filter,
     );
 
     // Calculate position to center the resized image
@@ -238,127 +284,437 @@ pub fn create_circular_image(input_image: &RgbaImage, diameter: u32) -> RgbaImag
     circular_image
 }
 
-#[allow(clippy::too_many_arguments)]
+/// Minimum height of each line image produced by [`draw_content_image`]
+pub const LINE_HEIGHT: u32 = 40;
+/// Emoji and icons scale with the text but are never drawn smaller than this
+const MIN_ICON_SIZE: u32 = 16;
+
+/// Side of a bilibili emoji drawn within text of `text_scale`, as tall as the text
+fn emoji_size(text_scale: PxScale) -> u32 {
+    (text_scale.y.round() as u32).max(MIN_ICON_SIZE)
+}
+
+/// Side of an inline icon drawn within text of `text_scale`, a third taller than the text
+fn icon_size(text_scale: PxScale) -> u32 {
+    ((text_scale.y * 4.0 / 3.0).round() as u32).max(MIN_ICON_SIZE)
+}
+
+/// Height of each line image [`draw_content_image`] produces for text of `text_scale` and
+/// emoji of `emoji_scale`, enough for the tallest element of a line
+pub fn line_height(text_scale: PxScale, emoji_scale: PxScale) -> u32 {
+    icon_size(text_scale)
+        .max(emoji_scale.y.round() as u32)
+        .max(LINE_HEIGHT)
+}
+
+/// Lay out rich text into line images, stopping once `max_lines` lines are filled.
+/// Blank lines between paragraphs become `paragraph_space` pixels of space above the next line
+/// and count as a line. Also returns whether any content was left out
 pub fn draw_content_image(
     nodes: &[RichTextNode],
     line_max_width: u32,
     text_scale: PxScale,
     emoji_scale: PxScale,
+    paragraph_space: u32,
     resource: &Resource,
-) -> Vec<RgbaImage> {
-    let mut images = Vec::new();
-
-    let mut current_image = RgbaImage::new(line_max_width, 40);
+    max_lines: usize,
+) -> (Vec<RgbaImage>, bool) {
+    if max_lines == 0 {
+        return (Vec::new(), !nodes.is_empty());
+    }
 
-    let (mut x, mut y) = (0, 0u32);
+    let mut lines = ContentLines::new(
+        line_max_width,
+        line_height(text_scale, emoji_scale),
+        paragraph_space,
+        max_lines,
+    );
+    let emoji_size = emoji_size(text_scale);
+    let icon = |img: &RgbaImage| {
+        let size = icon_size(text_scale);
+        imageops::resize(img, size, size, FilterType::Lanczos3)
+    };
 
     for node in nodes {
-        if let RichTextNode::Text { text } = node {
-            for c in clean_special_chars(text).chars() {
-                if c == '\n' {
-                    images.push(std::mem::replace(
-                        &mut current_image,
-                        RgbaImage::new(line_max_width, 40),
-                    ));
-                    x = 0;
-                    y = 0;
-                    continue;
+        if lines.truncated {
+            break;
+        }
+        match node {
+            RichTextNode::Text { text } => {
+                lines.draw_text(text, text_scale, emoji_scale, resource);
+            }
+            RichTextNode::Emoji { img, alt: _ } => {
+                let resized_image =
+                    imageops::resize(img, emoji_size, emoji_size, FilterType::Lanczos3);
+                lines.draw_icon(&resized_image);
+            }
+            RichTextNode::Goods { text } => {
+                lines.draw_icon(&icon(&resource.goods_image));
+                // 商品名紧跟在图标之后, 没有名称时只绘制图标
+                if let Some(text) = text {
+                    lines.draw_text(text, text_scale, emoji_scale, resource);
+                }
+            }
+            RichTextNode::Lottery { concluded } => {
+                lines.draw_icon(&icon(&resource.lottery_image));
+                if *concluded {
+                    lines.draw_text("抽奖已开奖", text_scale, emoji_scale, resource);
                 }
+            }
+            RichTextNode::Web => lines.draw_icon(&icon(&resource.web_image)),
+            RichTextNode::Bv => lines.draw_icon(&icon(&resource.bv_image)),
+            RichTextNode::Vote { .. } => lines.draw_icon(&icon(&resource.vote_image)),
+        }
+    }
 
-                let s = c.to_string();
-
-                let cwidth = if is_emoji(c) {
-                    let id = resource.emoji_font.glyph_id(c);
-
-                    let image = match resource.emoji_font.glyph_raster_image2(id, u16::MAX) {
-                        Some(glyph_image) => match glyph_to_rgba(&glyph_image) {
-                            Ok(image) => image,
-                            Err(e) => {
-                                debug!("emoji {} 无法从字体图片创建RGBA图片，跳过...: {}", c, e);
-                                continue;
-                            }
-                        },
-                        None => {
-                            debug!("字体无法找到emoji {} 的字体图片，跳过...", c);
-                            continue;
-                        }
-                    };
-
-                    let resized_image = imageops::resize(
-                        &image,
-                        emoji_scale.x as u32,
-                        emoji_scale.y as u32,
-                        FilterType::Lanczos3,
-                    );
-
-                    paste_image_with_alpha(&mut current_image, &resized_image, x, y);
-
-                    emoji_scale.x as u32
-                } else {
-                    let (cwidth, _cheight) =
-                        imageproc::drawing::text_size(text_scale, &resource.text_normal_font, &s);
-
-                    if x + cwidth > line_max_width {
-                        images.push(std::mem::replace(
-                            &mut current_image,
-                            RgbaImage::new(line_max_width, 40),
-                        ));
-                        x = 0;
-                        y = 0;
-                    }
+    let truncated = lines.truncated;
+    (lines.finish(), truncated)
+}
+
+/// Line images produced while laying out rich text, wrapping to a new line whenever
+/// the next glyph or icon doesn't fit in `line_max_width`.
+struct ContentLines {
+    line_max_width: u32,
+    line_height: u32,
+    /// space added above the next line for each blank line
+    paragraph_space: u32,
+    /// blank lines turned into space so far, they count towards `max_lines`
+    blank_lines: usize,
+    /// at most this many lines are produced, everything after is dropped
+    max_lines: usize,
+    /// set once `max_lines` lines are used up, further line breaks are ignored
+    lines_exhausted: bool,
+    /// set once content had to be dropped because of `max_lines`
+    truncated: bool,
+    images: Vec<RgbaImage>,
+    current_image: RgbaImage,
+    /// current x coordinate within the current line
+    x: u32,
+    /// top of the current line within its image, below the space left by blank lines
+    y: u32,
+}
+
+impl ContentLines {
+    fn new(
+        line_max_width: u32,
+        line_height: u32,
+        paragraph_space: u32,
+        max_lines: usize,
+    ) -> ContentLines {
+        ContentLines {
+            line_max_width,
+            line_height,
+            paragraph_space,
+            blank_lines: 0,
+            max_lines,
+            lines_exhausted: false,
+            truncated: false,
+            images: Vec::new(),
+            current_image: RgbaImage::new(line_max_width, line_height),
+            x: 0,
+            y: 0,
+        }
+    }
+
+    /// Finish the current line and start drawing from the beginning of a new one.
+    /// An empty current line isn't kept but grows by `paragraph_space` at the top instead.
+    /// When the current line is the last allowed one, stop drawing instead
+    fn new_line(&mut self) {
+        if self.images.len() + self.blank_lines + 1 >= self.max_lines {
+            self.lines_exhausted = true;
+            return;
+        }
+
+        // 空行(连续的换行)不单独占一行, 而是在下一行上方留出段落间距
+        if self.x == 0 {
+            self.blank_lines += 1;
+            self.y += self.paragraph_space;
+            self.current_image = RgbaImage::new(self.line_max_width, self.line_height + self.y);
+            return;
+        }
 
-                    imageproc::drawing::draw_text_mut(
-                        &mut current_image,
-                        Rgba::<u8>::black(),
-                        x as i32,
-                        y as i32,
-                        text_scale,
-                        &resource.text_normal_font,
-                        &s,
-                    );
+        self.images.push(std::mem::replace(
+            &mut self.current_image,
+            RgbaImage::new(self.line_max_width, self.line_height),
+        ));
+        self.x = 0;
+        self.y = 0;
+    }
+
+    /// Whether the element about to be drawn has no line left to go on, in which case the
+    /// content is truncated. A line break at the very end doesn't truncate anything
+    fn out_of_lines(&mut self) -> bool {
+        if self.lines_exhausted {
+            self.truncated = true;
+        }
+        self.truncated
+    }
 
-                    cwidth
-                };
+    /// Top of an element of `height` centered vertically on the current line
+    fn centered_y(&self, height: u32) -> u32 {
+        self.y + self.line_height.saturating_sub(height) / 2
+    }
 
-                x += cwidth;
+    /// Start a new line if an element of `width` doesn't fit in the current one
+    fn wrap_for(&mut self, width: u32) {
+        if self.x > 0 && self.x + width > self.line_max_width {
+            self.new_line();
+        }
+    }
+
+    /// Draw text character by character, using the emoji font for emoji
+    fn draw_text(
+        &mut self,
+        text: &str,
+        text_scale: PxScale,
+        emoji_scale: PxScale,
+        resource: &Resource,
+    ) {
+        for (i, paragraph) in clean_special_chars(text).split('\n').enumerate() {
+            if i > 0 {
+                self.new_line();
+            }
+            if self.truncated {
+                return;
             }
 
-            continue;
+            let bidi = BidiInfo::new(paragraph, None);
+            if !bidi.has_rtl() {
+                for c in paragraph.chars() {
+                    self.draw_glyph(c, text_scale, emoji_scale, resource);
+                }
+                continue;
+            }
+
+            // 含有从右到左的文字时, 先按逻辑顺序断行, 再把每一行重排为显示顺序
+            let advance = |c| char_advance(c, text_scale, emoji_scale, resource);
+            for (j, line) in visual_lines(&bidi, self.x, self.line_max_width, advance)
+                .iter()
+                .enumerate()
+            {
+                if j > 0 {
+                    self.new_line();
+                }
+                for c in line.chars() {
+                    self.draw_glyph(c, text_scale, emoji_scale, resource);
+                }
+            }
         }
+    }
 
-        let image_to_draw = match node {
-            RichTextNode::Emoji { img } => img,
-            RichTextNode::Web => &resource.web_image,
-            RichTextNode::Bv => &resource.bv_image,
-            RichTextNode::Lottery => &resource.lottery_image,
-            RichTextNode::Vote => &resource.vote_image,
-            RichTextNode::Goods => &resource.goods_image,
-            _ => unreachable!(),
-        };
+    /// Draw a character, using the emoji font for emoji
+    fn draw_glyph(
+        &mut self,
+        c: char,
+        text_scale: PxScale,
+        emoji_scale: PxScale,
+        resource: &Resource,
+    ) {
+        // 变体选择符只决定前一个字符的显示方式, 本身不占宽度
+        if self.truncated || is_variation_selector(c) {
+            return;
+        }
 
-        let resized_image = if matches!(node, RichTextNode::Emoji { img: _ }) {
-            imageops::resize(image_to_draw, 30, 30, imageops::FilterType::Lanczos3)
+        if is_emoji(c) {
+            let (image, cwidth) = match resource.emoji_image(c) {
+                Some(image) => (image, emoji_scale.x as u32 + EMOJI_SPACING),
+                None => match emoji_image(&resource.emoji_font, c)
+                    .or_else(|| resource.colr_emoji_image(c, emoji_scale.y.ceil() as u32))
+                {
+                    Some(image) => (image, emoji_advance(&resource.emoji_font, c, emoji_scale)),
+                    None => {
+                        self.draw_missing_emoji(c, text_scale, emoji_scale, resource);
+                        return;
+                    }
+                },
+            };
+
+            let resized_image = imageops::resize(
+                &image,
+                emoji_scale.x as u32,
+                emoji_scale.y as u32,
+                FilterType::Lanczos3,
+            );
+
+            self.wrap_for(cwidth);
+            if self.out_of_lines() {
+                return;
+            }
+            let y = self.centered_y(resized_image.height());
+            paste_image_with_alpha(&mut self.current_image, &resized_image, self.x, y);
+            self.x += cwidth;
         } else {
-            imageops::resize(image_to_draw, 40, 40, imageops::FilterType::Lanczos3)
-        };
+            self.draw_char(c, text_scale, resource.text_font(c));
+        }
+    }
+
+    /// Draw a single character with `font` and move past its measured width
+    fn draw_char(&mut self, c: char, scale: PxScale, font: &impl Font) {
+        let s = c.to_string();
 
-        let image_width = resized_image.width();
+        let (cwidth, _cheight) = imageproc::drawing::text_size(scale, font, &s);
 
-        if x + image_width > line_max_width {
-            images.push(std::mem::replace(
-                &mut current_image,
-                RgbaImage::new(line_max_width, 40),
-            ));
-            x = 0;
-            y = 0;
+        self.wrap_for(cwidth);
+        if self.out_of_lines() {
+            return;
         }
 
-        paste_image_with_alpha(&mut current_image, &resized_image, x, y);
+        let y = self.centered_y(scale.y.ceil() as u32);
+        imageproc::drawing::draw_text_mut(
+            &mut self.current_image,
+            Rgba::<u8>::black(),
+            self.x as i32,
+            y as i32,
+            scale,
+            font,
+            &s,
+        );
+
+        self.x += cwidth;
     }
 
-    images.push(current_image);
+    /// Draw an emoji the emoji font has no image for: with the text font if it has the glyph,
+    /// otherwise as a replacement box, so the emoji never leaves a blank gap
+    fn draw_missing_emoji(
+        &mut self,
+        c: char,
+        text_scale: PxScale,
+        emoji_scale: PxScale,
+        resource: &Resource,
+    ) {
+        if let Some(font) = resource.text_font_with_glyph(c) {
+            debug!("字体无法找到emoji {} 的字体图片，使用文字字体绘制", c);
+            self.draw_char(c, text_scale, font);
+            return;
+        }
+
+        debug!("字体无法找到emoji {}，绘制替代方框", c);
+        let (w, h) = (emoji_scale.x as u32, emoji_scale.y as u32);
+        let cwidth = w + EMOJI_SPACING;
+
+        self.wrap_for(cwidth);
+        if self.out_of_lines() {
+            return;
+        }
+
+        let rect = imageproc::rect::Rect::at(self.x as i32 + 2, self.centered_y(h) as i32 + 2)
+            .of_size(w.saturating_sub(4).max(1), h.saturating_sub(4).max(1));
+        imageproc::drawing::draw_hollow_rect_mut(
+            &mut self.current_image,
+            rect,
+            Rgba::<u8>::black(),
+        );
+
+        self.x += cwidth;
+    }
 
-    images
+    /// Draw an icon image inline and move past it
+    fn draw_icon(&mut self, img: &RgbaImage) {
+        self.wrap_for(img.width());
+        if self.out_of_lines() {
+            return;
+        }
+        let y = self.centered_y(img.height());
+        paste_image_with_alpha(&mut self.current_image, img, self.x, y);
+        self.x += img.width();
+    }
+
+    fn finish(mut self) -> Vec<RgbaImage> {
+        // 末尾的空行不留下多余的间距
+        if self.x > 0 || self.images.is_empty() {
+            self.images.push(self.current_image);
+        }
+        self.images
+    }
+}
+
+/// Horizontal space taken by an emoji glyph, including spacing so adjacent emoji don't touch.
+/// Uses the font's advance for the glyph and falls back to `scale.x` when the font reports none.
+fn emoji_advance(font: &impl Font, c: char, scale: PxScale) -> u32 {
+    let advance = font.as_scaled(scale).h_advance(font.glyph_id(c)).round() as u32;
+
+    let advance = if advance > 0 {
+        cmp::max(advance, scale.x as u32)
+    } else {
+        scale.x as u32
+    };
+
+    advance + EMOJI_SPACING
+}
+
+/// Horizontal space `ContentLines::draw_glyph` takes for `c`
+fn char_advance(c: char, text_scale: PxScale, emoji_scale: PxScale, resource: &Resource) -> u32 {
+    if is_variation_selector(c) {
+        return 0;
+    }
+
+    let emoji_font = &resource.emoji_font;
+    if is_emoji(c) {
+        if resource.emoji_image(c).is_some() {
+            return emoji_scale.x as u32 + EMOJI_SPACING;
+        }
+        if emoji_font
+            .glyph_raster_image2(emoji_font.glyph_id(c), u16::MAX)
+            .is_some()
+            || resource.has_colr_emoji(c)
+        {
+            return emoji_advance(emoji_font, c, emoji_scale);
+        }
+        if resource.text_font_with_glyph(c).is_none() {
+            return emoji_scale.x as u32 + EMOJI_SPACING;
+        }
+    }
+
+    imageproc::drawing::text_size(text_scale, resource.text_font(c), &c.to_string()).0
+}
+
+/// Break bidi text into lines of at most `max_width` in logical order, the first line starting
+/// at `start_x`, then reorder each line for display. Each paragraph starts a new line
+fn visual_lines(
+    bidi: &BidiInfo,
+    start_x: u32,
+    max_width: u32,
+    advance: impl Fn(char) -> u32,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut x = start_x;
+
+    for para in &bidi.paragraphs {
+        let mut line_start = para.range.start;
+
+        for (i, c) in bidi.text[para.range.clone()].char_indices() {
+            let i = para.range.start + i;
+            let width = advance(c);
+
+            if x > 0 && x + width > max_width {
+                lines.push(bidi.reorder_line(para, line_start..i).into_owned());
+                x = 0;
+                line_start = i;
+            }
+            x += width;
+        }
+
+        lines.push(
+            bidi.reorder_line(para, line_start..para.range.end)
+                .into_owned(),
+        );
+        x = 0;
+    }
+
+    lines
+}
+
+/// Raster image of an emoji from the emoji font, `None` if the font doesn't have one
+fn emoji_image(font: &impl Font, c: char) -> Option<RgbaImage> {
+    let glyph_image = font.glyph_raster_image2(font.glyph_id(c), u16::MAX)?;
+
+    match glyph_to_rgba(&glyph_image) {
+        Ok(image) => Some(image),
+        Err(e) => {
+            debug!("emoji {} 无法从字体图片创建RGBA图片: {}", c, e);
+            None
+        }
+    }
 }
 
 fn glyph_to_rgba(glyph_image: &GlyphImage<'_>) -> Result<RgbaImage> {
@@ -440,13 +796,12 @@ fn is_point_in_circle(x: u32, y: u32, center: (u32, u32), radius: u32) -> bool {
 }
 
 fn clean_special_chars(s: &str) -> String {
-    s.replace(
-        [
-            char::from_u32(8203).unwrap(),
-            char::from_u32(65039).unwrap(),
-        ],
-        "",
-    )
+    // 零宽空格和emoji变体选择符
+    s.replace(['\u{200B}', '\u{FE0F}'], "")
+}
+
+fn is_variation_selector(c: char) -> bool {
+    matches!(c as u32, 0xFE00..=0xFE0F)
 }
 
 /// Checks if a given character is an emoji
@@ -500,6 +855,38 @@ mod tests {
         base_img.save("test_data/combined_image.png").unwrap();
     }
 
+    #[test]
+    fn test_draw_rectangle_clipped() {
+        let color = Rgba([1, 2, 3, 255]);
+        let count = |gen: &PicGenerator| gen.image.pixels().filter(|p| **p == color).count();
+
+        // 超出左边和上边的部分被裁掉
+        let mut gen = PicGenerator::new(10, 10);
+        gen.draw_rectangle(-5, -5, 8, 8, color);
+        assert_eq!(9, count(&gen));
+        assert_eq!(color, *gen.image.get_pixel(0, 0));
+
+        // 超出右边的部分被裁掉
+        let mut gen = PicGenerator::new(10, 10);
+        gen.draw_rectangle(8, 0, 1, 100, color);
+        assert_eq!(2, count(&gen));
+
+        // 完全在画布外时什么也不画
+        let mut gen = PicGenerator::new(10, 10);
+        gen.draw_rectangle(20, 0, 5, 5, color);
+        gen.draw_rectangle(-20, 0, 5, 5, color);
+        assert_eq!(0, count(&gen));
+        assert_eq!(10, gen.height());
+
+        // 超出下边时画布先变高
+        let mut gen = PicGenerator::new(10, 10);
+        gen.draw_rectangle(0, 0, 10, 10, WHITE);
+        gen.draw_rectangle(0, 5, 10, 10, color);
+        assert_eq!(15, gen.height());
+        assert_eq!(100, count(&gen));
+        assert_eq!(WHITE, *gen.image.get_pixel(0, 4));
+    }
+
     #[test]
     fn test_paste_image_alpha() {
         let mut base = ImageReader::open("test_resources/background.jpg")
@@ -522,15 +909,268 @@ mod tests {
         base.save("test_data/combined_image_alpha.png").unwrap();
     }
 
+    #[test]
+    fn test_emoji_advance() {
+        let res = Resource::load_from_dir("./resource", &[]).unwrap();
+
+        let scale = PxScale::from(25.0);
+
+        let advance = emoji_advance(&res.emoji_font, '😀', scale);
+
+        assert!(advance >= scale.x as u32 + EMOJI_SPACING);
+    }
+
+    #[test]
+    fn test_draw_text_multiline() {
+        let res = Resource::load_from_dir("./resource", &[]).unwrap();
+        let scale = PxScale::from(30.0);
+
+        let mut single = PicGenerator::new(500, 500);
+        single.draw_text(&["用户名"], &[], &res.text_normal_font, scale, None);
+
+        let mut multi = PicGenerator::new(500, 500);
+        multi.draw_text(&["用户名\n第二行"], &[], &res.text_normal_font, scale, None);
+
+        assert!(multi.y() >= single.y() + scale.y as u32);
+    }
+
+    #[test]
+    fn test_missing_emoji_glyph() {
+        let res = Resource::load_from_dir("./resource", &[]).unwrap();
+        let (text_scale, emoji_scale) = (PxScale::from(40.0), PxScale::from(35.0));
+
+        // 🥰 两种字体都没有, 画替代方框; ★ 只有文字字体有
+        for (c, width) in [
+            ('🥰', emoji_scale.x as u32 + EMOJI_SPACING),
+            (
+                '★',
+                imageproc::drawing::text_size(text_scale, &res.text_normal_font, "★").0,
+            ),
+        ] {
+            assert!(emoji_image(&res.emoji_font, c).is_none());
+
+            let mut lines = ContentLines::new(500, LINE_HEIGHT, 0, usize::MAX);
+            lines.draw_text(&c.to_string(), text_scale, emoji_scale, &res);
+
+            assert!(width > 0);
+            assert_eq!(width, lines.x);
+            assert!(lines.current_image.pixels().any(|p| p.0[3] > 0));
+        }
+
+        // 变体选择符不占宽度
+        let mut lines = ContentLines::new(500, LINE_HEIGHT, 0, usize::MAX);
+        lines.draw_text("\u{FE0F}", text_scale, emoji_scale, &res);
+        assert_eq!(0, lines.x);
+    }
+
+    #[test]
+    fn test_visual_lines_bidi() {
+        let advance = |_| 10;
+
+        // 从右到左的部分反转, 从左到右的部分不变
+        let bidi = BidiInfo::new("abc אבג def", None);
+        assert!(bidi.has_rtl());
+        assert_eq!(
+            vec!["abc גבא def".to_string()],
+            visual_lines(&bidi, 0, 1000, advance)
+        );
+
+        // 按逻辑顺序断行后每行分别重排
+        let bidi = BidiInfo::new("אבגדה", None);
+        assert_eq!(
+            vec!["גבא".to_string(), "הד".to_string()],
+            visual_lines(&bidi, 0, 30, advance)
+        );
+        // 第一行从已有内容之后开始
+        assert_eq!(
+            vec!["א".to_string(), "דגב".to_string(), "ה".to_string()],
+            visual_lines(&bidi, 20, 30, advance)
+        );
+
+        // 纯中文/英文不需要重排
+        assert!(!BidiInfo::new("你好 hello", None).has_rtl());
+    }
+
+    #[test]
+    fn test_draw_content_limited() {
+        let res = Resource::load_from_dir("./resource", &[]).unwrap();
+        let node = [RichTextNode::Text {
+            text: "第一行\n第二行\n第三行".to_string(),
+        }];
+
+        let (images, truncated) =
+            draw_content_image(&node, 1000, 40.0.into(), 35.0.into(), 0, &res, 2);
+        assert_eq!(2, images.len());
+        assert!(truncated);
+
+        let (images, truncated) =
+            draw_content_image(&node, 1000, 40.0.into(), 35.0.into(), 0, &res, 3);
+        assert_eq!(3, images.len());
+        assert!(!truncated);
+
+        let (images, truncated) =
+            draw_content_image(&node, 1000, 40.0.into(), 35.0.into(), 0, &res, 0);
+        assert!(images.is_empty());
+        assert!(truncated);
+    }
+
+    #[test]
+    fn test_paragraph_space() {
+        let res = Resource::load_from_dir("./resource", &[]).unwrap();
+        let heights = |text: &str| {
+            let node = [RichTextNode::Text {
+                text: text.to_string(),
+            }];
+            let (images, _) =
+                draw_content_image(&node, 1000, 30.0.into(), 25.0.into(), 20, &res, usize::MAX);
+            images.iter().map(RgbaImage::height).collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec![LINE_HEIGHT, LINE_HEIGHT], heights("第一行\n第二行"));
+        // 每个空行在下一行上方留出段落间距
+        assert_eq!(
+            vec![LINE_HEIGHT, LINE_HEIGHT + 20],
+            heights("第一段\n\n第二段")
+        );
+        assert_eq!(
+            vec![LINE_HEIGHT, LINE_HEIGHT + 40],
+            heights("第一段\n\n\n第二段")
+        );
+        // 末尾的空行不留下间距
+        assert_eq!(vec![LINE_HEIGHT], heights("第一段\n\n"));
+
+        // 空行计入行数限制
+        let node = [RichTextNode::Text {
+            text: "第一段\n\n第二段".to_string(),
+        }];
+        let (images, truncated) =
+            draw_content_image(&node, 1000, 30.0.into(), 25.0.into(), 20, &res, 2);
+        assert_eq!(1, images.len());
+        assert!(truncated);
+    }
+
+    #[test]
+    fn test_emoji_from_dir() {
+        let dir = std::env::temp_dir().join("bili-dynamic-spider-painter-emoji");
+        std::fs::create_dir_all(&dir).unwrap();
+        RgbaImage::from_pixel(72, 72, Rgba([255, 0, 0, 255]))
+            .save(dir.join("1f600.png"))
+            .unwrap();
+
+        let node = [RichTextNode::Text {
+            text: "😀😃".to_string(),
+        }];
+        let red_width = |res: &Resource| {
+            let (images, _) =
+                draw_content_image(&node, 1000, 30.0.into(), 25.0.into(), 0, res, usize::MAX);
+            let red: Vec<u32> = images[0]
+                .enumerate_pixels()
+                .filter(|(_, _, p)| p[0] > 200 && p[1] < 50 && p[2] < 50 && p[3] > 200)
+                .map(|(x, _, _)| x)
+                .collect();
+            red.iter().max().map(|x| x + 1 - red.iter().min().unwrap())
+        };
+
+        let res = Resource::load_from_dir("./resource", &[]).unwrap();
+        assert_eq!(None, red_width(&res));
+
+        // 目录中有的emoji使用图片绘制, 没有的仍使用字体
+        let res = res.with_emoji_dir(&dir);
+        assert_eq!(Some(25), red_width(&res));
+        assert!(emoji_image(&res.emoji_font, '😃').is_some());
+        assert_eq!(
+            emoji_advance(&res.emoji_font, '😃', 25.0.into()),
+            char_advance('😃', 30.0.into(), 25.0.into(), &res)
+        );
+        assert_eq!(27, char_advance('😀', 30.0.into(), 25.0.into(), &res));
+    }
+
+    #[test]
+    fn test_emoji_scales_with_text() {
+        let res = Resource::load_from_dir("./resource", &[]).unwrap();
+        let node = [RichTextNode::Emoji {
+            img: RgbaImage::from_pixel(100, 100, Rgba([255, 0, 0, 255])),
+            alt: "[红]".to_string(),
+        }];
+
+        // 绘制出的emoji的宽和高, emoji在行内垂直居中
+        let emoji_extent = |text_scale: f32| {
+            let (images, _) = draw_content_image(
+                &node,
+                1000,
+                text_scale.into(),
+                25.0.into(),
+                0,
+                &res,
+                usize::MAX,
+            );
+            let line = &images[0];
+            let red: Vec<(u32, u32)> = line
+                .enumerate_pixels()
+                .filter(|(_, _, p)| p[0] > 128 && p[3] > 128)
+                .map(|(x, y, _)| (x, y))
+                .collect();
+            let width = red.iter().map(|(x, _)| x).max().unwrap() + 1;
+            let top = *red.iter().map(|(_, y)| y).min().unwrap();
+            let height = red.iter().map(|(_, y)| y).max().unwrap() + 1 - top;
+            assert_eq!((line.height() - height) / 2, top);
+            (line.height(), width, height)
+        };
+
+        assert_eq!((LINE_HEIGHT, 30, 30), emoji_extent(30.0));
+        // 大字号时emoji和行高一起变大
+        assert_eq!((80, 60, 60), emoji_extent(60.0));
+        // 小字号时不小于最小尺寸
+        assert_eq!(
+            (LINE_HEIGHT, MIN_ICON_SIZE, MIN_ICON_SIZE),
+            emoji_extent(4.0)
+        );
+    }
+
+    #[test]
+    fn test_line_vertical_center() {
+        let res = Resource::load_from_dir("./resource", &[]).unwrap();
+        // 文字, 表情和图标混排
+        let node = [
+            RichTextNode::Text {
+                text: "口".to_string(),
+            },
+            RichTextNode::Emoji {
+                img: RgbaImage::from_pixel(100, 100, Rgba([255, 0, 0, 255])),
+                alt: "[红]".to_string(),
+            },
+            RichTextNode::Web,
+        ];
+        let (images, _) =
+            draw_content_image(&node, 1000, 30.0.into(), 25.0.into(), 0, &res, usize::MAX);
+        let line = &images[0];
+
+        // 每一列非透明像素的上下边界的中点都接近行的中线
+        let center = |xs: std::ops::Range<u32>| {
+            let ys: Vec<u32> = xs
+                .flat_map(|x| (0..line.height()).map(move |y| (x, y)))
+                .filter(|&(x, y)| line.get_pixel(x, y)[3] > 128)
+                .map(|(_, y)| y)
+                .collect();
+            (ys.iter().min().unwrap() + ys.iter().max().unwrap() + 1) as f32 / 2.0
+        };
+        let middle = line.height() as f32 / 2.0;
+        let (text_width, _) = imageproc::drawing::text_size(30.0, &res.text_normal_font, "口");
+        assert!((center(0..text_width) - middle).abs() <= 4.0);
+        assert!((center(text_width..text_width + 30) - middle).abs() <= 1.0);
+        assert!((center(text_width + 30..line.width()) - middle).abs() <= 1.0);
+    }
+
     #[test]
     fn test_gen_emoji() {
         let node = vec![RichTextNode::Text {
             text: "你是脑残吗😀🥰👿💩😡🥰😸".to_string(),
         }];
 
-        let res = Resource::load_from_dir("./resource").unwrap();
+        let res = Resource::load_from_dir("./resource", &[]).unwrap();
 
-        let images = draw_content_image(&node, 1000, 40.0.into(), 35.0.into(), &res);
+        let (images, _) =
+            draw_content_image(&node, 1000, 40.0.into(), 35.0.into(), 0, &res, usize::MAX);
 
         let mut gen = PicGenerator::new(1000, 1000);
 
@@ -1,18 +1,67 @@
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
 
-use ab_glyph::FontArc;
+use ab_glyph::{Font, FontArc};
+use anyhow::bail;
 use image::{ImageReader, RgbaImage};
-use lazy_static::lazy_static;
+use tracing::warn;
 
-lazy_static! {
-    pub static ref RESOURCE: Resource =
-        Resource::load_from_dir("./resource").expect("加载资源失败");
+use crate::colr::ColrFont;
+
+pub const DEFAULT_RESOURCE_DIR: &str = "./resource";
+
+/// Files that must be present in the resource directory
+const REQUIRED_FILES: [&str; 9] = [
+    "normal.ttf",
+    "emoji.ttf",
+    "face.png",
+    "link.png",
+    "video.png",
+    "box.png",
+    "tick.png",
+    "tb.png",
+    "vip.png",
+];
+
+/// 定位图标, 较早的资源目录中没有, 缺少时只绘制地点名称
+const LOCATION_IMAGE: &str = "location.png";
+
+static RESOURCE: OnceLock<Resource> = OnceLock::new();
+
+/// Load resources used for drawing from `dir`, with `fallback_fonts` (relative to `dir`) tried
+/// in order for characters `normal.ttf` lacks, and emoji drawn from the images in `emoji_dir`
+/// when present. Called at startup so missing files are reported before anything is rendered
+pub fn init(
+    dir: impl AsRef<Path>,
+    fallback_fonts: &[PathBuf],
+    emoji_dir: Option<&Path>,
+) -> anyhow::Result<()> {
+    let mut resource = Resource::load_from_dir(dir, fallback_fonts)?;
+    if let Some(emoji_dir) = emoji_dir {
+        resource = resource.with_emoji_dir(emoji_dir);
+    }
+    // 已经加载过时保留之前的资源
+    let _ = RESOURCE.set(resource);
+    Ok(())
+}
+
+/// Resources loaded by [`init`], loading from [`DEFAULT_RESOURCE_DIR`] if it was never called
+pub fn resource() -> &'static Resource {
+    RESOURCE
+        .get_or_init(|| Resource::load_from_dir(DEFAULT_RESOURCE_DIR, &[]).expect("加载资源失败"))
 }
 
 #[derive(Debug)]
 pub struct Resource {
     pub text_normal_font: FontArc,
+    /// 依次用于 `text_normal_font` 中没有的字符
+    pub text_fallback_fonts: Vec<FontArc>,
     pub emoji_font: FontArc,
+    /// `emoji_font` 带有COLR表时用于绘制其中的彩色图层emoji
+    emoji_colr_font: Option<ColrFont>,
     pub no_face_image: RgbaImage,
     pub vip_image: RgbaImage,
     pub web_image: RgbaImage,
@@ -20,6 +69,18 @@ pub struct Resource {
     pub lottery_image: RgbaImage,
     pub vote_image: RgbaImage,
     pub goods_image: RgbaImage,
+    /// 资源目录中没有 `location.png` 时为 `None`
+    pub location_image: Option<RgbaImage>,
+    /// 优先于 `emoji_font` 使用的emoji图片
+    emoji_images: Option<EmojiImages>,
+}
+
+/// 目录中以 `{codepoint}.png` 命名的emoji图片(如Twemoji), 读取后按字符缓存
+#[derive(Debug)]
+struct EmojiImages {
+    dir: PathBuf,
+    /// 目录中没有的emoji缓存为 `None`
+    cache: Mutex<HashMap<char, Option<RgbaImage>>>,
 }
 
 struct ResourceLoader<P> {
@@ -27,11 +88,36 @@ struct ResourceLoader<P> {
 }
 
 impl Resource {
-    pub fn load_from_dir(dir: impl AsRef<Path>) -> anyhow::Result<Resource> {
+    pub fn load_from_dir(
+        dir: impl AsRef<Path>,
+        fallback_fonts: &[PathBuf],
+    ) -> anyhow::Result<Resource> {
+        let dir = dir.as_ref();
+        let missing: Vec<String> = REQUIRED_FILES
+            .iter()
+            .map(PathBuf::from)
+            .chain(fallback_fonts.iter().cloned())
+            .filter(|file| !dir.join(file).is_file())
+            .map(|file| file.display().to_string())
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "资源目录 {} 缺少文件: {}",
+                dir.display(),
+                missing.join(", ")
+            );
+        }
+
         let loader = ResourceLoader { base_dir: dir };
 
         let text_normal_font = loader.load_font("normal.ttf")?;
-        let emoji_font = loader.load_font("emoji.ttf")?;
+        let text_fallback_fonts = fallback_fonts
+            .iter()
+            .map(|font| loader.load_font(font))
+            .collect::<anyhow::Result<_>>()?;
+        let emoji_bytes = loader.read("emoji.ttf")?;
+        let emoji_colr_font = ColrFont::from_slice(&emoji_bytes);
+        let emoji_font = FontArc::try_from_vec(emoji_bytes)?;
         let no_face_image = loader.load_image("face.png")?;
         let web_image = loader.load_image("link.png")?;
         let bv_image = loader.load_image("video.png")?;
@@ -39,10 +125,17 @@ impl Resource {
         let vote_image = loader.load_image("tick.png")?;
         let goods_image = loader.load_image("tb.png")?;
         let vip_image = loader.load_image("vip.png")?;
+        let location_image = if dir.join(LOCATION_IMAGE).is_file() {
+            Some(loader.load_image(LOCATION_IMAGE)?)
+        } else {
+            None
+        };
 
         Ok(Resource {
             text_normal_font,
+            text_fallback_fonts,
             emoji_font,
+            emoji_colr_font,
             no_face_image,
             vip_image,
             web_image,
@@ -50,15 +143,90 @@ impl Resource {
             lottery_image,
             vote_image,
             goods_image,
+            location_image,
+            emoji_images: None,
         })
     }
+
+    /// Draw emoji with the images in `dir` named by their lowercase hex codepoint, e.g.
+    /// `1f600.png`, falling back to `emoji_font` for emoji without an image
+    pub fn with_emoji_dir(mut self, dir: impl Into<PathBuf>) -> Resource {
+        self.emoji_images = Some(EmojiImages {
+            dir: dir.into(),
+            cache: Mutex::new(HashMap::new()),
+        });
+        self
+    }
+}
+
+impl Resource {
+    /// The first text font that has a glyph for `c`, `None` if no text font has one
+    pub fn text_font_with_glyph(&self, c: char) -> Option<&FontArc> {
+        first_font_with_glyph(
+            std::iter::once(&self.text_normal_font).chain(&self.text_fallback_fonts),
+            c,
+        )
+    }
+
+    /// Image of emoji `c` from the emoji directory, `None` if there is no directory or no
+    /// image for `c` in it
+    pub fn emoji_image(&self, c: char) -> Option<RgbaImage> {
+        let images = self.emoji_images.as_ref()?;
+        let mut cache = images.cache.lock().unwrap();
+
+        cache
+            .entry(c)
+            .or_insert_with(|| {
+                let path = images.dir.join(format!("{:x}.png", c as u32));
+                if !path.is_file() {
+                    return None;
+                }
+                match image::open(&path) {
+                    Ok(image) => Some(image.into_rgba8()),
+                    Err(e) => {
+                        warn!("无法读取emoji图片 {}: {}", path.display(), e);
+                        None
+                    }
+                }
+            })
+            .clone()
+    }
+
+    /// Image of emoji `c` composited from the COLR layers of `emoji_font` at `height` pixels,
+    /// `None` if the font has no COLR table or no color glyph for `c`
+    pub fn colr_emoji_image(&self, c: char, height: u32) -> Option<RgbaImage> {
+        self.emoji_colr_font.as_ref()?.render(c, height)
+    }
+
+    /// Whether `emoji_font` has a COLR color glyph for `c`
+    pub fn has_colr_emoji(&self, c: char) -> bool {
+        self.emoji_colr_font
+            .as_ref()
+            .is_some_and(|font| font.has_glyph(c))
+    }
+
+    /// The text font to draw `c` with, `text_normal_font` if no text font has the glyph
+    pub fn text_font(&self, c: char) -> &FontArc {
+        self.text_font_with_glyph(c)
+            .unwrap_or(&self.text_normal_font)
+    }
+}
+
+fn first_font_with_glyph<'a>(
+    mut fonts: impl Iterator<Item = &'a FontArc>,
+    c: char,
+) -> Option<&'a FontArc> {
+    fonts.find(|font| font.glyph_id(c).0 != 0)
 }
 
 impl<P: AsRef<Path>> ResourceLoader<P> {
     fn load_font(&self, relative_path: impl AsRef<Path>) -> anyhow::Result<FontArc> {
+        Ok(FontArc::try_from_vec(self.read(relative_path)?)?)
+    }
+
+    fn read(&self, relative_path: impl AsRef<Path>) -> anyhow::Result<Vec<u8>> {
         let path = self.base_dir.as_ref().join(relative_path);
-        let bytes = std::fs::read(path)?;
-        Ok(FontArc::try_from_vec(bytes)?)
+        Ok(std::fs::read(path)?)
     }
 
     fn load_image(&self, relative_path: impl AsRef<Path>) -> anyhow::Result<RgbaImage> {
@@ -69,3 +237,76 @@ impl<P: AsRef<Path>> ResourceLoader<P> {
             .into_rgba8())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_resource_files() {
+        let dir = std::env::temp_dir().join("bili-dynamic-spider-missing-resource");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy("./resource/face.png", dir.join("face.png")).unwrap();
+
+        let err = Resource::load_from_dir(&dir, &[PathBuf::from("fallback.ttf")])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(&dir.display().to_string()));
+        assert!(err.contains("normal.ttf") && err.contains("vip.png"));
+        assert!(err.contains("fallback.ttf"));
+        assert!(!err.contains("face.png"));
+
+        assert!(Resource::load_from_dir(DEFAULT_RESOURCE_DIR, &[]).is_ok());
+    }
+
+    #[test]
+    fn test_optional_location_image() {
+        let dir = std::env::temp_dir().join("bili-dynamic-spider-no-location");
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in REQUIRED_FILES {
+            std::fs::copy(Path::new(DEFAULT_RESOURCE_DIR).join(file), dir.join(file)).unwrap();
+        }
+
+        // 没有定位图标的旧资源目录仍然可以使用
+        let res = Resource::load_from_dir(&dir, &[]).unwrap();
+        assert!(res.location_image.is_none());
+        let res = Resource::load_from_dir(DEFAULT_RESOURCE_DIR, &[]).unwrap();
+        assert!(res.location_image.is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_text_font_fallback() {
+        let res =
+            Resource::load_from_dir(DEFAULT_RESOURCE_DIR, &[PathBuf::from("cloud.ttf")]).unwrap();
+        assert_eq!(1, res.text_fallback_fonts.len());
+
+        // emoji字体没有汉字, 依次尝试后面的字体
+        let emoji_font = &res.emoji_font;
+        let normal_font = &res.text_normal_font;
+        let font = first_font_with_glyph([emoji_font, normal_font].into_iter(), '中').unwrap();
+        assert!(std::ptr::eq(normal_font, font));
+        assert!(first_font_with_glyph([emoji_font].into_iter(), '中').is_none());
+
+        assert!(std::ptr::eq(&res.text_normal_font, res.text_font('中')));
+    }
+
+    #[test]
+    fn test_emoji_image() {
+        let dir = std::env::temp_dir().join("bili-dynamic-spider-emoji-image");
+        std::fs::create_dir_all(&dir).unwrap();
+        let red = RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 255]));
+        red.save(dir.join("1f600.png")).unwrap();
+        std::fs::write(dir.join("1f601.png"), b"not a png").unwrap();
+
+        let res = Resource::load_from_dir(DEFAULT_RESOURCE_DIR, &[]).unwrap();
+        assert!(res.emoji_image('😀').is_none());
+
+        let res = res.with_emoji_dir(&dir);
+        assert_eq!(Some(red), res.emoji_image('😀'));
+        // 图片损坏或不存在时使用emoji字体
+        assert!(res.emoji_image('😁').is_none());
+        assert!(res.emoji_image('😂').is_none());
+    }
+}
@@ -0,0 +1,132 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// 统计使用的动态类型, 由旧版动态类型编号(`DbEntry::type_`)统一映射
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DynamicKind {
+    Word,
+    Draw,
+    Forward,
+    Live,
+    Video,
+    Article,
+    Other,
+}
+
+impl DynamicKind {
+    pub fn from_legacy_type(type_: i64) -> DynamicKind {
+        match type_ {
+            1 => DynamicKind::Forward,
+            2 => DynamicKind::Draw,
+            4 => DynamicKind::Word,
+            8 => DynamicKind::Video,
+            64 => DynamicKind::Article,
+            4200 => DynamicKind::Live,
+            _ => DynamicKind::Other,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            DynamicKind::Word => "word",
+            DynamicKind::Draw => "draw",
+            DynamicKind::Forward => "forward",
+            DynamicKind::Live => "live",
+            DynamicKind::Video => "video",
+            DynamicKind::Article => "article",
+            DynamicKind::Other => "other",
+        }
+    }
+}
+
+/// 一条动态的处理结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    Sent,
+    Skipped,
+    Failed,
+}
+
+impl Outcome {
+    pub fn label(self) -> &'static str {
+        match self {
+            Outcome::Sent => "sent",
+            Outcome::Skipped => "skipped",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+/// 按动态类型和处理结果分类的计数
+#[derive(Debug, Default)]
+pub struct DynamicStats {
+    counts: BTreeMap<(DynamicKind, Outcome), u64>,
+}
+
+impl DynamicStats {
+    pub const fn new() -> DynamicStats {
+        DynamicStats {
+            counts: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, kind: DynamicKind, outcome: Outcome) {
+        *self.counts.entry((kind, outcome)).or_default() += 1;
+    }
+
+    /// 一行文字的汇总, 例如 `draw sent=3 failed=1, word sent=2`. 没有处理过动态时为空
+    pub fn summary(&self) -> String {
+        let mut kinds: Vec<(DynamicKind, Vec<String>)> = Vec::new();
+
+        for (&(kind, outcome), count) in &self.counts {
+            let item = format!("{}={}", outcome.label(), count);
+            match kinds.last_mut() {
+                Some((last, items)) if *last == kind => items.push(item),
+                _ => kinds.push((kind, vec![item])),
+            }
+        }
+
+        kinds
+            .iter()
+            .map(|(kind, items)| format!("{} {}", kind.label(), items.join(" ")))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// 所有用户共用的计数
+static STATS: Mutex<DynamicStats> = Mutex::new(DynamicStats::new());
+
+/// 记录一条类型为 `type_`(旧版编号) 的动态的处理结果
+pub fn record(type_: i64, outcome: Outcome) {
+    STATS
+        .lock()
+        .unwrap()
+        .record(DynamicKind::from_legacy_type(type_), outcome);
+}
+
+/// 启动以来的汇总, 见 [`DynamicStats::summary`]
+pub fn summary() -> String {
+    STATS.lock().unwrap().summary()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut stats = DynamicStats::new();
+        assert_eq!("", stats.summary());
+
+        stats.record(DynamicKind::from_legacy_type(4), Outcome::Sent);
+        stats.record(DynamicKind::from_legacy_type(2), Outcome::Failed);
+        stats.record(DynamicKind::from_legacy_type(2), Outcome::Sent);
+        stats.record(DynamicKind::from_legacy_type(2), Outcome::Sent);
+        stats.record(DynamicKind::from_legacy_type(2048), Outcome::Skipped);
+
+        assert_eq!(
+            "word sent=1, draw sent=2 failed=1, other skipped=1",
+            stats.summary()
+        );
+    }
+}
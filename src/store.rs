@@ -0,0 +1,183 @@
+//! 监听目标在数据库中的动态记录
+
+use sled::{Batch, Db, IVec, Tree};
+use tracing::info;
+
+/// 开启 `db.single_tree` 时所有目标共用的树
+pub const SINGLE_TREE_NAME: &str = "targets";
+
+/// 一个监听目标的数据库记录. 默认每个目标使用名为 [`TargetConfig::tree_name`] 的单独的树;
+/// 开启 `db.single_tree` 时所有目标共用一棵树, 键为 `{tree_name}:{key}`.
+/// 两种方式下读写使用的键相同, 前缀只在这里添加和去掉
+///
+/// [`TargetConfig::tree_name`]: crate::config::TargetConfig::tree_name
+#[derive(Debug, Clone)]
+pub struct TargetTree {
+    tree: Tree,
+    prefix: Vec<u8>,
+}
+
+impl TargetTree {
+    pub fn open(db: &Db, name: &str, single_tree: bool) -> sled::Result<TargetTree> {
+        if single_tree {
+            Ok(TargetTree {
+                tree: db.open_tree(SINGLE_TREE_NAME)?,
+                prefix: single_tree_prefix(name),
+            })
+        } else {
+            Ok(TargetTree {
+                tree: db.open_tree(name)?,
+                prefix: Vec::new(),
+            })
+        }
+    }
+
+    fn key(&self, key: impl AsRef<[u8]>) -> Vec<u8> {
+        [self.prefix.as_slice(), key.as_ref()].concat()
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> sled::Result<Option<IVec>> {
+        self.tree.get(self.key(key))
+    }
+
+    pub fn insert(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl Into<IVec>,
+    ) -> sled::Result<Option<IVec>> {
+        self.tree.insert(self.key(key), value)
+    }
+
+    pub fn remove(&self, key: impl AsRef<[u8]>) -> sled::Result<Option<IVec>> {
+        self.tree.remove(self.key(key))
+    }
+
+    pub fn contains_key(&self, key: impl AsRef<[u8]>) -> sled::Result<bool> {
+        self.tree.contains_key(self.key(key))
+    }
+
+    /// 原子地写入多条记录
+    pub fn insert_all(
+        &self,
+        entries: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> sled::Result<()> {
+        let mut batch = Batch::default();
+        for (key, value) in entries {
+            batch.insert(self.key(key), value);
+        }
+        self.tree.apply_batch(batch)
+    }
+
+    /// 按键的顺序遍历该目标的所有记录, 返回的键不含前缀
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = sled::Result<(IVec, IVec)>> {
+        let len = self.prefix.len();
+        self.tree
+            .scan_prefix(&self.prefix)
+            .map(move |item| item.map(|(k, v)| (IVec::from(&k[len..]), v)))
+    }
+}
+
+fn single_tree_prefix(name: &str) -> Vec<u8> {
+    format!("{}:", name).into_bytes()
+}
+
+/// 是否是旧版本为单个目标创建的树: 用户UID或者 `topic_{id}`
+fn is_target_tree(name: &[u8]) -> bool {
+    let Ok(name) = std::str::from_utf8(name) else {
+        return false;
+    };
+    let id = name.strip_prefix("topic_").unwrap_or(name);
+    !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// 把每个目标单独的树合并到共用的树中并删除旧的树, 返回合并的树的数量.
+/// 共用的树中已经有的记录保留不变
+pub fn migrate_to_single_tree(db: &Db) -> anyhow::Result<usize> {
+    let single = db.open_tree(SINGLE_TREE_NAME)?;
+    let mut migrated = 0;
+
+    for name in db.tree_names() {
+        if !is_target_tree(&name) {
+            continue;
+        }
+        let tree = db.open_tree(&name)?;
+        let prefix = single_tree_prefix(&String::from_utf8_lossy(&name));
+
+        let mut batch = Batch::default();
+        let mut count = 0;
+        for item in tree.iter() {
+            let (k, v) = item?;
+            let key = [prefix.as_slice(), &k].concat();
+            if !single.contains_key(&key)? {
+                batch.insert(key, v);
+                count += 1;
+            }
+        }
+        single.apply_batch(batch)?;
+        single.flush()?;
+        db.drop_tree(&name)?;
+
+        info!(
+            "已将目标 {} 的 {} 条记录合并到共用的数据库树中",
+            String::from_utf8_lossy(&name),
+            count
+        );
+        migrated += 1;
+    }
+
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_tree() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let a = TargetTree::open(&db, "1", true).unwrap();
+        let b = TargetTree::open(&db, "12", true).unwrap();
+
+        a.insert("5", "a5").unwrap();
+        a.insert("meta", "a").unwrap();
+        b.insert("5", "b5").unwrap();
+        b.insert_all([(b"6".to_vec(), b"b6".to_vec())]).unwrap();
+
+        assert_eq!(Some(IVec::from("a5")), a.get("5").unwrap());
+        assert_eq!(Some(IVec::from("b5")), b.get("5").unwrap());
+        assert!(!a.contains_key("6").unwrap());
+
+        let keys: Vec<IVec> = b.iter().map(|item| item.unwrap().0).collect();
+        assert_eq!(vec![IVec::from("5"), IVec::from("6")], keys);
+
+        b.remove("5").unwrap();
+        assert!(a.contains_key("5").unwrap());
+        // 只有默认的树和共用的树
+        assert_eq!(2, db.tree_names().len());
+    }
+
+    #[test]
+    fn test_migrate_to_single_tree() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let old = TargetTree::open(&db, "1234", false).unwrap();
+        old.insert("5", "old").unwrap();
+        old.insert("6", "old").unwrap();
+        let topic = TargetTree::open(&db, "topic_7", false).unwrap();
+        topic.insert("8", "topic").unwrap();
+        // 共用的树中已有的记录保留不变
+        let single = TargetTree::open(&db, "1234", true).unwrap();
+        single.insert("6", "new").unwrap();
+
+        assert_eq!(2, migrate_to_single_tree(&db).unwrap());
+        assert_eq!(Some(IVec::from("old")), single.get("5").unwrap());
+        assert_eq!(Some(IVec::from("new")), single.get("6").unwrap());
+        let topic = TargetTree::open(&db, "topic_7", true).unwrap();
+        assert_eq!(Some(IVec::from("topic")), topic.get("8").unwrap());
+
+        let names = db.tree_names();
+        assert!(!names
+            .iter()
+            .any(|name| name == b"1234" || name == b"topic_7"));
+        assert_eq!(0, migrate_to_single_tree(&db).unwrap());
+    }
+}